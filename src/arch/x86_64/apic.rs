@@ -0,0 +1,141 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use x2apic::lapic::{xapic_base, LocalApic, LocalApicBuilder, TimerDivide, TimerMode};
+use x86::msr::{wrmsr, IA32_TSC_DEADLINE};
+
+use crate::mem::addr::PhysAddr;
+
+use super::{
+    cpu_local::{cpu_id, get_kpcr, get_lapic, MAX_CPUS},
+    get_cpuid_feature_info,
+    idt::{LAPIC_ERROR_IRQ, LAPIC_TIMER_IRQ, SPURIOUS_IRQ},
+    time,
+};
+
+const CALIBRATION_MS: u32 = 10;
+
+// the pic is wired to the bsp's LINT0, and LINT1 is where NMIs come in
+const LVT_EXTINT: u32 = 0x700;
+const LVT_NMI: u32 = 0x400;
+const X2APIC_LVT_LINT0_MSR: u32 = 0x835;
+const X2APIC_LVT_LINT1_MSR: u32 = 0x836;
+const XAPIC_LVT_LINT0: usize = 0x350;
+const XAPIC_LVT_LINT1: usize = 0x360;
+
+// how fast the lapic timer counts down, measured against the pit. only needed when there's
+// no tsc-deadline mode.
+static TIMER_COUNTS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+// what to put in the destination field of an ipi to each cpu. the raw id register already
+// has the id where the icr wants it, in both xapic and x2apic mode.
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+fn has_tsc_deadline() -> bool {
+    get_cpuid_feature_info().has_tsc_deadline()
+}
+
+fn build_lapic(timer_mode: TimerMode, timer_initial: u32) -> LocalApic {
+    let base = PhysAddr::new(unsafe { xapic_base() } as usize).as_hhdm_virt();
+    LocalApicBuilder::new()
+        .timer_vector(LAPIC_TIMER_IRQ as usize)
+        .error_vector(LAPIC_ERROR_IRQ as usize)
+        .spurious_vector(SPURIOUS_IRQ as usize)
+        .timer_mode(timer_mode)
+        .timer_divide(TimerDivide::Div16)
+        .timer_initial(timer_initial)
+        .set_xapic_base(base.value() as u64)
+        .build()
+        .expect("Error building local APIC")
+}
+
+// LocalApic::enable() turns off both LINT pins, but the bsp still gets the keyboard and serial
+// ports through the pic
+unsafe fn restore_virtual_wire() {
+    if get_cpuid_feature_info().has_x2apic() {
+        unsafe {
+            wrmsr(X2APIC_LVT_LINT0_MSR, LVT_EXTINT as u64);
+            wrmsr(X2APIC_LVT_LINT1_MSR, LVT_NMI as u64);
+        }
+    } else {
+        let base = PhysAddr::new(unsafe { xapic_base() } as usize).as_hhdm_virt();
+        unsafe {
+            core::ptr::write_volatile((base + XAPIC_LVT_LINT0).as_raw_ptr_mut::<u32>(), LVT_EXTINT);
+            core::ptr::write_volatile((base + XAPIC_LVT_LINT1).as_raw_ptr_mut::<u32>(), LVT_NMI);
+        }
+    }
+}
+
+fn install(lapic: LocalApic) {
+    APIC_IDS[cpu_id()].store(unsafe { lapic.id() }, Ordering::Relaxed);
+    get_kpcr().cpu_local.lapic = Some(lapic);
+}
+
+fn timer_mode() -> TimerMode {
+    if has_tsc_deadline() {
+        TimerMode::TscDeadline
+    } else {
+        TimerMode::OneShot
+    }
+}
+
+pub fn init_bsp() {
+    let mut lapic = build_lapic(TimerMode::OneShot, 0);
+    unsafe {
+        lapic.enable();
+        restore_virtual_wire();
+
+        if !has_tsc_deadline() {
+            lapic.disable_timer();
+            lapic.set_timer_initial(u32::MAX);
+            time::pit_busy_wait_ms(CALIBRATION_MS);
+            let elapsed = u32::MAX - lapic.timer_current();
+            lapic.set_timer_initial(0);
+            lapic.enable_timer();
+            TIMER_COUNTS_PER_MS.store((elapsed / CALIBRATION_MS).max(1), Ordering::Relaxed);
+        }
+        lapic.set_timer_mode(timer_mode());
+    }
+    install(lapic);
+    arm_timer(Some(time::get_uptime_ns() + time::TICK_NS));
+}
+
+pub fn init_ap() {
+    let mut lapic = build_lapic(timer_mode(), 0);
+    unsafe {
+        lapic.enable();
+    }
+    install(lapic);
+    arm_timer(Some(time::get_uptime_ns() + time::TICK_NS));
+}
+
+// sets this cpu's timer to go off at the given uptime, or never
+pub fn arm_timer(deadline_ns: Option<usize>) {
+    let Some(lapic) = get_lapic() else {
+        return;
+    };
+    if has_tsc_deadline() {
+        // zero disarms it, and anything in the past goes off right away
+        let tsc = deadline_ns.map_or(0, |ns| time::uptime_ns_to_tsc(ns).max(1));
+        unsafe { wrmsr(IA32_TSC_DEADLINE, tsc) };
+    } else {
+        let counts = deadline_ns.map_or(0, |ns| {
+            let delta = ns.saturating_sub(time::get_uptime_ns());
+            let per_ms = TIMER_COUNTS_PER_MS.load(Ordering::Relaxed) as usize;
+            (delta * per_ms / 1000000).clamp(1, u32::MAX as usize) as u32
+        });
+        unsafe { lapic.set_timer_initial(counts) };
+    }
+}
+
+pub fn eoi() {
+    if let Some(lapic) = get_lapic() {
+        unsafe { lapic.end_of_interrupt() };
+    }
+}
+
+pub fn send_ipi(cpu: usize, vector: u8) {
+    let dest = APIC_IDS[cpu].load(Ordering::Relaxed);
+    if let Some(lapic) = get_lapic() {
+        unsafe { lapic.send_ipi(vector, dest) };
+    }
+}
@@ -1,9 +1,22 @@
+use core::{
+    mem::offset_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x2apic::lapic::LocalApic;
 use x86::msr::{rdmsr, IA32_GS_BASE};
 use x86_64::structures::{gdt::GlobalDescriptorTable, tss::TaskStateSegment};
 
+// the cpu count is a bitmask in a few places
+pub const MAX_CPUS: usize = usize::BITS as usize;
+
+// until the bsp has its kpcr, gs holds whatever the bootloader left in it
+static CPU_LOCAL_READY: AtomicBool = AtomicBool::new(false);
+
 pub struct CpuLocalData {
     pub kernel_sp: usize,
     pub gdt: GlobalDescriptorTable,
+    pub lapic: Option<LocalApic>,
 }
 
 #[repr(C, packed)]
@@ -11,6 +24,7 @@ pub struct Kpcr {
     pub tss: TaskStateSegment,
     pub cpu_local: &'static mut CpuLocalData,
     pub user_rsp0_tmp: usize,
+    pub cpu_id: usize,
 }
 
 pub fn get_kpcr() -> &'static mut Kpcr {
@@ -20,3 +34,31 @@ pub fn get_kpcr() -> &'static mut Kpcr {
 pub fn get_tss() -> &'static mut TaskStateSegment {
     unsafe { &mut *(rdmsr(IA32_GS_BASE) as *mut _) }
 }
+
+pub fn set_cpu_local_ready() {
+    CPU_LOCAL_READY.store(true, Ordering::Release);
+}
+
+// the bsp is always cpu 0, and the aps are numbered in the order limine lists them
+pub fn cpu_id() -> usize {
+    if !CPU_LOCAL_READY.load(Ordering::Acquire) {
+        return 0;
+    }
+    let id: usize;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, gs:[{off}]",
+            out(reg) id,
+            off = const(offset_of!(Kpcr, cpu_id)),
+            options(nostack, readonly, preserves_flags),
+        );
+    }
+    id
+}
+
+pub fn get_lapic() -> Option<&'static mut LocalApic> {
+    if !CPU_LOCAL_READY.load(Ordering::Acquire) {
+        return None;
+    }
+    get_kpcr().cpu_local.lapic.as_mut()
+}
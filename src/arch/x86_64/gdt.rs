@@ -13,9 +13,9 @@ use x86_64::{
     },
 };
 
-use crate::mem::consts::KERNEL_STACK_SIZE;
+use crate::mem::consts::{KERNEL_STACK_SIZE, PAGE_SIZE};
 
-use super::cpu_local::{get_kpcr, get_tss, CpuLocalData, Kpcr};
+use super::cpu_local::{get_kpcr, get_tss, set_cpu_local_ready, CpuLocalData, Kpcr};
 
 pub const KERNEL_CS_IDX: u16 = 1;
 pub const KERNEL_DS_IDX: u16 = 2;
@@ -23,8 +23,6 @@ pub const TSS_IDX: u16 = 3;
 pub const USER_DS_IDX: u16 = 5;
 pub const USER_CS_IDX: u16 = 6;
 
-static mut STACK: [u8; KERNEL_STACK_SIZE] = [0; KERNEL_STACK_SIZE];
-
 lazy_static! {
     static ref BOOT_GDT: (GlobalDescriptorTable, [SegmentSelector; 2]) = {
         let mut gdt = GlobalDescriptorTable::new();
@@ -48,7 +46,8 @@ pub fn init_boot() {
     }
 }
 
-pub fn init() {
+// every cpu gets its own gdt, tss and kpcr
+pub fn init(cpu_id: usize) {
     unsafe {
         let kpcr_layout = Layout::new::<Kpcr>();
         let kpcr_ptr = alloc_zeroed(kpcr_layout) as *mut Kpcr;
@@ -56,19 +55,27 @@ pub fn init() {
 
         let tls_layout = Layout::new::<CpuLocalData>();
         let tls_ptr = alloc_zeroed(tls_layout) as *mut CpuLocalData;
+        tls_ptr.write(CpuLocalData {
+            kernel_sp: 0,
+            gdt: GlobalDescriptorTable::new(),
+            lapic: None,
+        });
         get_kpcr().cpu_local = &mut *tls_ptr;
+        get_kpcr().cpu_id = cpu_id;
     }
+    set_cpu_local_ready();
 
     let tss = get_tss();
     *tss = TaskStateSegment::new();
 
-    tss.privilege_stack_table[0] = x86_64::VirtAddr::new(
-        unsafe {
-            #[allow(static_mut_refs)]
-            STACK.as_mut_ptr()
-        } as u64
-            + KERNEL_STACK_SIZE as u64,
-    );
+    // only used until the first context switch puts a task's kernel stack here
+    let stack = unsafe {
+        alloc_zeroed(Layout::from_size_align_unchecked(
+            KERNEL_STACK_SIZE,
+            PAGE_SIZE,
+        ))
+    };
+    tss.privilege_stack_table[0] = x86_64::VirtAddr::new(stack as u64 + KERNEL_STACK_SIZE as u64);
 
     let gdt = &mut get_kpcr().cpu_local.gdt;
     *gdt = GlobalDescriptorTable::new();
@@ -14,11 +14,17 @@ use x86_64::{
     structures::idt::{InterruptDescriptorTable, PageFaultErrorCode},
 };
 
+use super::{apic, cpu_local::cpu_id, smp};
 use crate::{
     backtrace,
     fs::devfs::{input::KBD_DEVICE, tty::TTY},
     mem::addr::VirtAddr,
-    task::get_scheduler,
+    task::{
+        get_scheduler,
+        signal::{SigInfo, SEGV_ACCERR, SEGV_MAPERR, SIGSEGV, SIGTRAP, SI_KERNEL, TRAP_TRACE},
+        signaled_status,
+        wait_queue::WaitQueue,
+    },
     util::IrqMutex,
 };
 
@@ -32,6 +38,15 @@ pub const TIMER_IRQ: u8 = PIC_1_OFFSET;
 pub const KEYBOARD_IRQ: u8 = PIC_1_OFFSET + 1;
 pub const COM2_IRQ: u8 = PIC_1_OFFSET + 3;
 
+pub const LAPIC_TIMER_IRQ: u8 = 0x40;
+pub const RESCHEDULE_IPI: u8 = 0x41;
+pub const TLB_SHOOTDOWN_IPI: u8 = 0x42;
+pub const LAPIC_ERROR_IRQ: u8 = 0x43;
+pub const SPURIOUS_IRQ: u8 = 0xff;
+
+// whoever is waiting for input on the second serial port
+pub static SERIAL1_WAIT_QUEUE: WaitQueue = WaitQueue::new();
+
 lazy_static! {
     pub static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -40,7 +55,10 @@ lazy_static! {
             idt.divide_error.set_handler_addr(x86_64::VirtAddr::new(divide_error_handler as u64));
             idt.debug.set_handler_addr(x86_64::VirtAddr::new(debug_handler as u64));
             idt.non_maskable_interrupt.set_handler_addr(x86_64::VirtAddr::new(nmi_handler as u64));
-            idt.breakpoint.set_handler_addr(x86_64::VirtAddr::new(breakpoint_handler as u64));
+            // int3 has to work from userspace for a debugger's breakpoints
+            idt.breakpoint
+            .set_handler_addr(x86_64::VirtAddr::new(breakpoint_handler as u64))
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
             idt.overflow.set_handler_addr(x86_64::VirtAddr::new(overflow_handler as u64));
             idt.bound_range_exceeded
             .set_handler_addr(x86_64::VirtAddr::new(bound_range_exceeded_handler as u64));
@@ -74,9 +92,14 @@ lazy_static! {
             idt.security_exception
             .set_handler_addr(x86_64::VirtAddr::new(security_exception_handler as u64));
 
-            idt[TIMER_IRQ].set_handler_addr(x86_64::VirtAddr::new(timer_handler as u64));
             idt[KEYBOARD_IRQ].set_handler_addr(x86_64::VirtAddr::new(keyboard_handler as u64));
             idt[COM2_IRQ].set_handler_addr(x86_64::VirtAddr::new(com2_handler as u64));
+
+            idt[LAPIC_TIMER_IRQ].set_handler_addr(x86_64::VirtAddr::new(lapic_timer_handler as u64));
+            idt[RESCHEDULE_IPI].set_handler_addr(x86_64::VirtAddr::new(reschedule_handler as u64));
+            idt[TLB_SHOOTDOWN_IPI].set_handler_addr(x86_64::VirtAddr::new(tlb_shootdown_handler as u64));
+            idt[LAPIC_ERROR_IRQ].set_handler_addr(x86_64::VirtAddr::new(lapic_error_handler as u64));
+            idt[SPURIOUS_IRQ].set_handler_addr(x86_64::VirtAddr::new(spurious_handler as u64));
         }
 
 
@@ -204,10 +227,15 @@ interrupt_handler!(virtualization_handler, 0x14, no_error!());
 interrupt_handler!(vmm_communication_exception_handler, 0x1D, has_error!());
 interrupt_handler!(security_exception_handler, 0x1E, has_error!());
 
-interrupt_handler!(timer_handler, 32, no_error!());
 interrupt_handler!(keyboard_handler, 33, no_error!());
 interrupt_handler!(com2_handler, 35, no_error!());
 
+interrupt_handler!(lapic_timer_handler, 0x40, no_error!());
+interrupt_handler!(reschedule_handler, 0x41, no_error!());
+interrupt_handler!(tlb_shootdown_handler, 0x42, no_error!());
+interrupt_handler!(lapic_error_handler, 0x43, no_error!());
+interrupt_handler!(spurious_handler, 0xff, no_error!());
+
 use x86::irq::*;
 
 #[no_mangle]
@@ -216,25 +244,58 @@ extern "C" fn x64_handle_interrupt(vector: u8, stack_frame: *mut InterruptErrorF
     let error_code = stack_frame.code;
 
     match vector {
-        TIMER_IRQ => {
-            super::time::pit_irq();
+        LAPIC_TIMER_IRQ => {
+            apic::eoi();
+            cpu_tick(stack_frame);
+        }
+        RESCHEDULE_IPI => {
+            apic::eoi();
             let sched = get_scheduler();
-            notify_eoi(TIMER_IRQ);
             sched.preempt();
+            if stack_frame.frame.is_user_mode() {
+                sched.handle_async_signals(&mut stack_frame.frame);
+            }
+        }
+        TLB_SHOOTDOWN_IPI => {
+            smp::handle_tlb_shootdown();
+            apic::eoi();
+        }
+        LAPIC_ERROR_IRQ => {
+            log::warn!("Local APIC error on CPU {}", cpu_id());
+            apic::eoi();
         }
+        // spurious interrupts don't get an EOI
+        SPURIOUS_IRQ => {}
         KEYBOARD_IRQ => {
             do_keyboard_input();
             notify_eoi(KEYBOARD_IRQ);
         }
         COM2_IRQ => {
             notify_eoi(COM2_IRQ);
+            get_scheduler().wake_all(&SERIAL1_WAIT_QUEUE);
         }
         DIVIDE_ERROR_VECTOR => {
             log::error!("\nEXCEPTION: DIVIDE ERROR\n{:#x?}", stack_frame);
             panic!("Divide error");
         }
         DEBUG_VECTOR => {
-            log::error!("\nEXCEPTION: DEBUG EXCEPTION\n{:#x?}", stack_frame);
+            if stack_frame.frame.is_user_mode() {
+                // the trap flag went off after a PTRACE_SINGLESTEP
+                get_scheduler().force_fault_signal(
+                    &mut stack_frame.frame,
+                    SIGTRAP,
+                    &SigInfo::new(SIGTRAP, TRAP_TRACE),
+                );
+            } else {
+                log::error!("\nEXCEPTION: DEBUG EXCEPTION\n{:#x?}", stack_frame);
+            }
+        }
+        BREAKPOINT_VECTOR if stack_frame.frame.is_user_mode() => {
+            get_scheduler().force_fault_signal(
+                &mut stack_frame.frame,
+                SIGTRAP,
+                &SigInfo::new(SIGTRAP, SI_KERNEL),
+            );
         }
         NONMASKABLE_INTERRUPT_VECTOR => {
             log::error!("\nEXCEPTION: NON-MASKABLE INTERRUPT\n{:#x?}", stack_frame);
@@ -299,11 +360,15 @@ extern "C" fn x64_handle_interrupt(vector: u8, stack_frame: *mut InterruptErrorF
                 let fsbase = rdmsr(IA32_FS_BASE);
                 log::debug!("FSBASE: {:#x}", fsbase);
             }
-            if stack_frame.frame.is_user_mode() {
-                backtrace::unwind_user_stack_from(stack_frame.frame.rbp, stack_frame.frame.rip);
-                get_scheduler().exit_current(1);
+            if !stack_frame.frame.is_user_mode() {
+                panic!("General Protection Fault");
             }
-            panic!("General Protection Fault");
+            backtrace::unwind_user_stack_from(stack_frame.frame.rbp, stack_frame.frame.rip);
+            get_scheduler().force_fault_signal(
+                &mut stack_frame.frame,
+                SIGSEGV,
+                &SigInfo::new(SIGSEGV, SI_KERNEL),
+            );
         }
         PAGE_FAULT_VECTOR => {
             let accessed_address = x86_64::registers::control::Cr2::read_raw();
@@ -328,7 +393,22 @@ extern "C" fn x64_handle_interrupt(vector: u8, stack_frame: *mut InterruptErrorF
                     log::error!("Exception IP {:#x}", rip);
                     log::error!("Faulted access address {:#x}", accessed_address);
                     log::error!("Error: {:?}", e);
-                    panic!("User page fault");
+                    if stack_frame.frame.is_user_mode() {
+                        let code = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+                        {
+                            SEGV_ACCERR
+                        } else {
+                            SEGV_MAPERR
+                        };
+                        get_scheduler().force_fault_signal(
+                            &mut stack_frame.frame,
+                            SIGSEGV,
+                            &SigInfo::fault(SIGSEGV, code, accessed_address as usize),
+                        );
+                    } else {
+                        // the kernel tripped over a bad pointer that userspace handed it
+                        get_scheduler().exit_group(signaled_status(SIGSEGV));
+                    }
                 }
             } else {
                 log::error!(
@@ -416,13 +496,29 @@ pub fn unmask_irq(irq: u8) {
     unsafe { outb(port as u16, val) };
 }
 
+// every cpu's lapic timer goes off either for its next tick or for the next deadline
+fn cpu_tick(stack_frame: &mut InterruptErrorFrame) {
+    let sched = get_scheduler();
+    sched.tick_cpu_itimers();
+    sched.check_cpu_limit();
+    sched.preempt();
+    if stack_frame.frame.is_user_mode() {
+        sched.handle_async_signals(&mut stack_frame.frame);
+    }
+}
+
+pub fn init_ap() {
+    IDT.load();
+}
+
 pub fn init() {
     IDT.load();
     unsafe {
         PICS.lock().initialize();
     }
 
-    unmask_irq(TIMER_IRQ);
+    // the pit keeps counting for calibration, but its interrupts aren't wanted anymore
+    mask_irq(TIMER_IRQ);
     unmask_irq(KEYBOARD_IRQ);
     unmask_irq(COM2_IRQ);
 }
@@ -26,13 +26,17 @@ use crate::{
         allocator::{KERNEL_FRAME_ALLOCATOR, KERNEL_PAGE_ALLOCATOR},
         consts::KERNEL_STACK_SIZE,
     },
-    serial::serial1_recv,
-    task::{current_task, get_scheduler, Task},
+    serial::{serial1_ready, serial1_recv, SERIAL1},
+    task::{current_task, get_scheduler, runqueue::SchedPolicy, Task},
 };
 
+pub mod apic;
 pub mod cpu_local;
 pub mod gdt;
 pub mod idt;
+pub mod ptrace;
+pub mod signal;
+pub mod smp;
 pub mod syscall;
 pub mod task;
 pub mod time;
@@ -91,28 +95,7 @@ pub fn arch_main() {
     time::init(boot_time.as_secs() as i64);
 
     log::info!("Initializing FPU mechanisms.");
-    let features = get_cpuid_feature_info();
-    assert!(features.has_fxsave_fxstor(), "FXSAVE/FXRSTOR not available");
-    assert!(features.has_mmx(), "MMX not available");
-    assert!(features.has_fpu(), "FPU not available");
-    assert!(features.has_sse(), "SSE not available");
-    unsafe {
-        // enable FXSAVE and FXRSTOR
-        controlregs::cr4_write(controlregs::cr4() | Cr4::CR4_ENABLE_SSE | Cr4::CR4_UNMASKED_SSE);
-        log::trace!("CR4_ENABLE_SSE and CR4_UNMASKED_SSE set.");
-
-        // controlregs::xcr0_write(
-        //     controlregs::xcr0()
-        //         | Xcr0::XCR0_SSE_STATE
-        //         | Xcr0::XCR0_FPU_MMX_STATE
-        //         | Xcr0::XCR0_AVX_STATE,
-        // );
-        // log::trace!("XCR0_SSE_STATE, XCR0_FPU_MMX_STATE, and XCR0_AVX_STATE set.");
-        controlregs::cr0_write(controlregs::cr0() & !Cr0::CR0_EMULATE_COPROCESSOR);
-        log::trace!("CR0_EMULATE_COPROCESSOR cleared.");
-        controlregs::cr0_write(controlregs::cr0() | Cr0::CR0_MONITOR_COPROCESSOR);
-        log::trace!("CR0_MONITOR_COPROCESSOR set.");
-    }
+    init_fpu();
 
     log::info!("Initializing boot GDT.");
     gdt::init_boot();
@@ -156,7 +139,7 @@ pub fn arch_main() {
     }
 
     log::info!("Loading GDT.");
-    gdt::init();
+    gdt::init(0);
 
     log::info!("Loading IDT.");
     idt::init();
@@ -167,17 +150,23 @@ pub fn arch_main() {
     log::info!("Initializing task scheduler.");
     crate::task::init();
 
+    log::info!("Starting other CPUs.");
+    smp::init();
+
     log::info!("Starting init process.");
 
     let sched = get_scheduler();
 
     fs::devfs::init();
+    crate::userland::binfmt::init();
 
     log::info!("Welcome to K4DOS!");
 
     {
-        let task = Task::new_kernel(sched, poll_serial1, true);
-        sched.push_runnable(task, true);
+        let task = Task::new_kernel(sched, read_serial1, true);
+        // like the reaper, it mostly sits asleep waiting for input
+        task.sched.lock().policy = SchedPolicy::Idle;
+        sched.push_runnable(task);
     }
 
     // god_mode::init();
@@ -187,6 +176,32 @@ pub fn arch_main() {
     }
 }
 
+// every cpu has its own control registers to set up
+pub fn init_fpu() {
+    let features = get_cpuid_feature_info();
+    assert!(features.has_fxsave_fxstor(), "FXSAVE/FXRSTOR not available");
+    assert!(features.has_mmx(), "MMX not available");
+    assert!(features.has_fpu(), "FPU not available");
+    assert!(features.has_sse(), "SSE not available");
+    unsafe {
+        // enable FXSAVE and FXRSTOR
+        controlregs::cr4_write(controlregs::cr4() | Cr4::CR4_ENABLE_SSE | Cr4::CR4_UNMASKED_SSE);
+        log::trace!("CR4_ENABLE_SSE and CR4_UNMASKED_SSE set.");
+
+        // controlregs::xcr0_write(
+        //     controlregs::xcr0()
+        //         | Xcr0::XCR0_SSE_STATE
+        //         | Xcr0::XCR0_FPU_MMX_STATE
+        //         | Xcr0::XCR0_AVX_STATE,
+        // );
+        // log::trace!("XCR0_SSE_STATE, XCR0_FPU_MMX_STATE, and XCR0_AVX_STATE set.");
+        controlregs::cr0_write(controlregs::cr0() & !Cr0::CR0_EMULATE_COPROCESSOR);
+        log::trace!("CR0_EMULATE_COPROCESSOR cleared.");
+        controlregs::cr0_write(controlregs::cr0() | Cr0::CR0_MONITOR_COPROCESSOR);
+        log::trace!("CR0_MONITOR_COPROCESSOR set.");
+    }
+}
+
 pub fn startup_init() {
     let exe = "/bin/sh";
     let file = get_root()
@@ -235,10 +250,13 @@ pub fn startup_init() {
         .unwrap();
 }
 
-fn poll_serial1() {
+fn read_serial1() {
+    // setting the port up is what turns on its receive interrupt
+    lazy_static::initialize(&SERIAL1);
     loop {
-        let c = serial1_recv();
-        if let Some(c) = c {
+        // the com2 irq wakes us up as soon as there's something to read
+        idt::SERIAL1_WAIT_QUEUE.sleep_until(serial1_ready);
+        while let Some(c) = serial1_recv() {
             // TTY.get().unwrap().input_char(c);
             loop {
                 if let Ok(mut lock) = GOD_MODE_FIFO.get().unwrap().try_lock() {
@@ -250,7 +268,6 @@ fn poll_serial1() {
                 interrupts::enable_and_hlt();
             }
         }
-        interrupts::enable_and_hlt();
     }
 }
 
@@ -0,0 +1,114 @@
+use crate::{
+    kbail,
+    mem::addr::{is_canonical_virtaddr, VirtAddr},
+    util::KResult,
+};
+
+use super::{
+    idt::InterruptFrame,
+    task::{RFLAGS_TF, RFLAGS_USER_CHANGEABLE},
+};
+
+// struct user_regs_struct, which is what PTRACE_GETREGS hands out and gdb expects in a core
+// file's pr_reg
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct UserRegs {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub orig_rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub eflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
+}
+
+impl UserRegs {
+    // orig_rax is the syscall the task is in the middle of, if any
+    pub fn new(frame: &InterruptFrame, fs_base: VirtAddr, orig_rax: Option<usize>) -> UserRegs {
+        UserRegs {
+            r15: frame.r15 as u64,
+            r14: frame.r14 as u64,
+            r13: frame.r13 as u64,
+            r12: frame.r12 as u64,
+            rbp: frame.rbp as u64,
+            rbx: frame.rbx as u64,
+            r11: frame.r11 as u64,
+            r10: frame.r10 as u64,
+            r9: frame.r9 as u64,
+            r8: frame.r8 as u64,
+            rax: frame.rax as u64,
+            rcx: frame.rcx as u64,
+            rdx: frame.rdx as u64,
+            rsi: frame.rsi as u64,
+            rdi: frame.rdi as u64,
+            orig_rax: orig_rax.map_or(u64::MAX, |syscall| syscall as u64),
+            rip: frame.rip as u64,
+            cs: frame.cs as u64,
+            eflags: frame.rflags as u64,
+            rsp: frame.rsp as u64,
+            ss: frame.ss as u64,
+            fs_base: fs_base.value() as u64,
+            ..Default::default()
+        }
+    }
+
+    // PTRACE_SETREGS. like sigreturn(2), only the flags userspace could have changed itself
+    // get through, and the segments stay the user ones. fs_base and gs_base can't be changed
+    // from here.
+    pub fn apply(&self, frame: &mut InterruptFrame) -> KResult<()> {
+        // the way back to userspace would fault in the kernel on either of these
+        if !is_canonical_virtaddr(self.rip as usize) || !is_canonical_virtaddr(self.rsp as usize) {
+            kbail!(EIO, "UserRegs::apply(): non-canonical rip or rsp");
+        }
+        frame.r15 = self.r15 as usize;
+        frame.r14 = self.r14 as usize;
+        frame.r13 = self.r13 as usize;
+        frame.r12 = self.r12 as usize;
+        frame.rbp = self.rbp as usize;
+        frame.rbx = self.rbx as usize;
+        frame.r11 = self.r11 as usize;
+        frame.r10 = self.r10 as usize;
+        frame.r9 = self.r9 as usize;
+        frame.r8 = self.r8 as usize;
+        frame.rax = self.rax as usize;
+        frame.rcx = self.rcx as usize;
+        frame.rdx = self.rdx as usize;
+        frame.rsi = self.rsi as usize;
+        frame.rdi = self.rdi as usize;
+        frame.rip = self.rip as usize;
+        frame.rsp = self.rsp as usize;
+        frame.rflags = (frame.rflags & !RFLAGS_USER_CHANGEABLE)
+            | (self.eflags as usize & RFLAGS_USER_CHANGEABLE);
+        Ok(())
+    }
+}
+
+// the trap flag makes the cpu raise a debug exception after the next user instruction
+pub fn set_single_step(frame: &mut InterruptFrame, enabled: bool) {
+    if enabled {
+        frame.rflags |= RFLAGS_TF;
+    } else {
+        frame.rflags &= !RFLAGS_TF;
+    }
+}
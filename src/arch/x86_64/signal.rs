@@ -0,0 +1,64 @@
+use crate::task::signal::{SigInfo, SigStack};
+
+// what a signal handler finds on its stack. it's laid out the same as on linux, so libc's
+// ucontext_t and sigreturn trampolines work with it.
+
+// the general purpose registers in ucontext_t's uc_mcontext
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SigContext {
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rsp: u64,
+    pub rip: u64,
+    pub eflags: u64,
+    pub cs: u16,
+    pub gs: u16,
+    pub fs: u16,
+    pub ss: u16,
+    pub err: u64,
+    pub trapno: u64,
+    pub oldmask: u64,
+    pub cr2: u64,
+    // where the fxsave area went, or 0 if there isn't one
+    pub fpstate: u64,
+    pub reserved: [u64; 8],
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct UContext {
+    pub uc_flags: u64,
+    pub uc_link: u64,
+    pub uc_stack: SigStack,
+    pub uc_mcontext: SigContext,
+    // the mask to go back to after the handler, in userspace's numbering
+    pub uc_sigmask: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct RtSigFrame {
+    // where the handler returns to, which calls rt_sigreturn(2)
+    pub pretcode: u64,
+    pub uc: UContext,
+    pub info: SigInfo,
+}
+
+// the legacy area fxsave writes, without any of the xsave extensions
+pub const FXSAVE_SIZE: usize = 512;
+pub const FXSAVE_MXCSR: usize = 24;
+pub const FXSAVE_MXCSR_MASK: usize = 28;
@@ -0,0 +1,171 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use limine::{request::SmpRequest, smp::Cpu};
+use x86::{controlregs, tlb};
+use x86_64::instructions::interrupts;
+
+use crate::{
+    mem::{addr::VirtAddr, consts::PAGE_SIZE},
+    task::get_scheduler,
+};
+
+use super::{
+    apic,
+    cpu_local::{cpu_id, MAX_CPUS},
+    gdt, idt, init_fpu, syscall,
+};
+
+static SMP: SmpRequest = SmpRequest::new();
+
+// cpus that have finished booting, one bit each
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+// the aps start out on limine's page tables, which don't have the kernel heap
+static KERNEL_CR3: AtomicUsize = AtomicUsize::new(0);
+
+// past this many pages it's cheaper to throw out the whole tlb
+const MAX_INVLPG_PAGES: usize = 32;
+
+// there's only ever one shootdown in flight
+static SHOOTDOWN_LOCK: AtomicBool = AtomicBool::new(false);
+static SHOOTDOWN_START: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_PAGES: AtomicUsize = AtomicUsize::new(0);
+// the cpus that haven't flushed yet
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+pub fn cpu_count() -> usize {
+    SMP.get_response()
+        .map_or(1, |resp| resp.cpus().len().clamp(1, MAX_CPUS))
+}
+
+pub fn is_online(cpu: usize) -> bool {
+    ONLINE.load(Ordering::Acquire) & (1 << cpu) != 0
+}
+
+// the bsp is cpu 0, and everyone else is numbered in the order limine lists them
+fn ap_cpu_id(cpu: &Cpu) -> usize {
+    let resp = SMP.get_response().unwrap();
+    let bsp = resp.bsp_lapic_id();
+    resp.cpus()
+        .iter()
+        .filter(|c| c.lapic_id != bsp)
+        .position(|c| c.lapic_id == cpu.lapic_id)
+        .unwrap()
+        + 1
+}
+
+pub fn init() {
+    apic::init_bsp();
+    ONLINE.fetch_or(1, Ordering::SeqCst);
+
+    let Some(resp) = SMP.get_response() else {
+        log::warn!("No SMP response from Limine, running on the BSP only.");
+        return;
+    };
+    KERNEL_CR3.store(unsafe { controlregs::cr3() } as usize, Ordering::SeqCst);
+    for cpu in resp.cpus() {
+        if cpu.lapic_id == resp.bsp_lapic_id() || ap_cpu_id(cpu) >= MAX_CPUS {
+            continue;
+        }
+        cpu.goto_address.write(ap_entry);
+    }
+
+    let count = cpu_count();
+    while ONLINE.load(Ordering::Acquire).count_ones() as usize != count {
+        // the ones that are already up may be waiting on us to flush
+        handle_tlb_shootdown();
+        core::hint::spin_loop();
+    }
+    log::info!("All {} CPUs online.", count);
+}
+
+unsafe extern "C" fn ap_entry(cpu: &Cpu) -> ! {
+    let id = ap_cpu_id(cpu);
+    unsafe {
+        controlregs::cr3_write(KERNEL_CR3.load(Ordering::SeqCst) as u64);
+    }
+    // nothing that takes a lock can run before this cpu knows who it is
+    gdt::init(id);
+    idt::init_ap();
+    unsafe {
+        syscall::init();
+    }
+    init_fpu();
+    apic::init_ap();
+    get_scheduler().start_cpu();
+
+    // shootdowns reach us from here on, so anything from before that has to go now
+    ONLINE.fetch_or(1 << id, Ordering::SeqCst);
+    unsafe { tlb::flush_all() };
+    log::info!("CPU {} (LAPIC ID {}) online.", id, cpu.lapic_id);
+
+    // this becomes the cpu's idle thread the first time its timer goes off
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+pub fn send_reschedule(cpu: usize) {
+    if is_online(cpu) {
+        apic::send_ipi(cpu, idt::RESCHEDULE_IPI);
+    }
+}
+
+fn flush_local(start: usize, pages: usize) {
+    if pages > MAX_INVLPG_PAGES {
+        unsafe { tlb::flush_all() };
+    } else {
+        for page in 0..pages {
+            unsafe { tlb::flush(start + page * PAGE_SIZE) };
+        }
+    }
+}
+
+// called from the ipi, and from anywhere a cpu might spin with interrupts off
+pub fn handle_tlb_shootdown() {
+    let bit = 1 << cpu_id();
+    if SHOOTDOWN_PENDING.load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+    flush_local(
+        SHOOTDOWN_START.load(Ordering::Relaxed),
+        SHOOTDOWN_PAGES.load(Ordering::Relaxed),
+    );
+    SHOOTDOWN_PENDING.fetch_and(!bit, Ordering::Release);
+}
+
+// makes every other cpu forget what it had cached for these pages, and waits until they have
+pub fn tlb_shootdown(start: VirtAddr, pages: usize) {
+    if ONLINE.load(Ordering::Acquire).count_ones() < 2 {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let others = ONLINE.load(Ordering::Acquire) & !(1 << cpu_id());
+        if others == 0 {
+            return;
+        }
+        while SHOOTDOWN_LOCK
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // whoever has it might be waiting on us
+            handle_tlb_shootdown();
+            core::hint::spin_loop();
+        }
+
+        SHOOTDOWN_START.store(start.value(), Ordering::Relaxed);
+        SHOOTDOWN_PAGES.store(pages, Ordering::Relaxed);
+        SHOOTDOWN_PENDING.store(others, Ordering::Release);
+        for cpu in (0..MAX_CPUS).filter(|cpu| others & (1 << cpu) != 0) {
+            apic::send_ipi(cpu, idt::TLB_SHOOTDOWN_IPI);
+        }
+        while SHOOTDOWN_PENDING.load(Ordering::Acquire) & others != 0 {
+            core::hint::spin_loop();
+        }
+
+        SHOOTDOWN_LOCK.store(false, Ordering::Release);
+    });
+}
+
+pub fn tlb_shootdown_all() {
+    tlb_shootdown(VirtAddr::null(), usize::MAX);
+}
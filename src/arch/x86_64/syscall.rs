@@ -3,6 +3,7 @@ use core::mem::offset_of;
 use x86::msr::{rdmsr, wrmsr};
 
 use crate::mem::kernel_addr_space_scope;
+use crate::task::current_task;
 use crate::userland::syscall::{
     errno_to_isize, syscall_name_by_number, SyscallHandler, QUIET_SYSCALLS,
 };
@@ -92,6 +93,11 @@ pub unsafe extern "C" fn syscall_entry() {
                 "
         test dword ptr [rsp + 4], 0xFFFF8000
         jnz 2f
+        // sysret would clobber rcx and r11, which sigreturn(2) might have just restored
+        cmp rcx, [rsp]
+        jne 3f
+        cmp r11, [rsp + 16]
+        jne 3f
 
         pop rcx
         add rsp, 8
@@ -105,6 +111,7 @@ pub unsafe extern "C" fn syscall_entry() {
     2:
         xor rcx, rcx
         xor r11, r11
+    3:
         cli
         swapgs
         iretq
@@ -145,6 +152,9 @@ fn handle_syscall(
     n: usize,
     frame: *mut InterruptFrame,
 ) -> isize {
+    // don't hold on to the task here, exit(2) never comes back to drop it
+    current_task().cpu_clock.lock().enter_kernel();
+
     let mut handler = SyscallHandler {
         frame: unsafe { &mut *frame },
     };
@@ -162,8 +172,10 @@ fn handle_syscall(
             );
         }
     }
+    // dispatch() already put the return value in the frame, unless a signal handler or
+    // sigreturn(2) replaced it
     let retval = errno_to_isize(&res);
-    handler.frame.rax = retval as usize;
+    current_task().cpu_clock.lock().exit_kernel();
     retval
 }
 
@@ -178,7 +190,8 @@ pub unsafe fn init() {
     unsafe {
         wrmsr(x86::msr::IA32_STAR, star);
         wrmsr(x86::msr::IA32_LSTAR, syscall_entry as *const u8 as u64);
-        wrmsr(x86::msr::IA32_FMASK, 0x200);
+        // a single-stepped syscall instruction mustn't go on trapping in the kernel
+        wrmsr(x86::msr::IA32_FMASK, 0x300);
 
         wrmsr(x86::msr::IA32_CSTAR, 0);
 
@@ -1,35 +1,44 @@
-use core::{alloc::Layout, ptr::Unique, slice::SlicePattern};
+use core::{alloc::Layout, mem::offset_of, ptr::Unique, slice::SlicePattern};
 
 use alloc::{alloc::alloc_zeroed, boxed::Box, vec::Vec};
 use x86::{
     cpuid::CpuId,
-    msr::{rdmsr, wrmsr, IA32_FS_BASE, IA32_GS_BASE},
+    msr::{wrmsr, IA32_FS_BASE},
     tlb,
 };
 use x86_64::instructions::interrupts;
 
 use crate::{
-    fs::FileRef,
+    kbail,
     mem::{
-        addr::VirtAddr,
+        addr::{is_canonical_virtaddr, VirtAddr},
         addr_space::AddressSpace,
         allocator::alloc_kernel_frames,
-        consts::{KERNEL_STACK_SIZE, PAGE_SIZE, USER_STACK_BOTTOM, USER_STACK_TOP},
+        consts::{KERNEL_STACK_SIZE, PAGE_SIZE, USER_STACK_TOP},
     },
     task::{
-        signal::Signal,
+        get_scheduler,
+        signal::{KSigAction, SigInfo, SigStack, Signal, SA_RESTORER},
         vmem::{MMapFlags, MMapKind, MMapProt, Vmem},
     },
-    userland::elf::{self, AuxvType, SymTabEntry},
+    userland::elf::{AuxvType, SymTabEntry, UserlandEntry},
     util::{stack::Stack, KResult},
 };
 
 use super::{
     cpu_local::get_tss,
-    gdt::{KERNEL_CS_IDX, KERNEL_DS_IDX, USER_DS_IDX},
+    gdt::{KERNEL_CS_IDX, KERNEL_DS_IDX, USER_CS_IDX, USER_DS_IDX},
     idt::{InterruptErrorFrame, InterruptFrame},
+    signal::{RtSigFrame, SigContext, UContext, FXSAVE_MXCSR, FXSAVE_MXCSR_MASK, FXSAVE_SIZE},
 };
 
+pub(super) const RFLAGS_TF: usize = 1 << 8;
+const RFLAGS_DF: usize = 1 << 10;
+// CF, PF, AF, ZF, SF, TF, DF, OF, RF and AC
+pub(super) const RFLAGS_USER_CHANGEABLE: usize = 0x50dd5;
+// what fxsave reports when it leaves the mask out
+const DEFAULT_MXCSR_MASK: u32 = 0xffbf;
+
 fn fxsave(fpu: &mut Box<[u8]>) {
     unsafe {
         core::arch::asm!("fxsave [{}]", in(reg) (**fpu).as_ptr(), in("rax") u64::MAX, in("rdx") u64::MAX)
@@ -47,9 +56,7 @@ pub fn arch_context_switch(prev: &mut ArchTask, next: &mut ArchTask) {
         // prev.fsbase = VirtAddr::new(rdmsr(IA32_FS_BASE) as usize);
         // prev.gsbase = VirtAddr::new(rdmsr(IA32_GS_BASE) as usize);
         wrmsr(IA32_FS_BASE, next.fsbase.value() as u64);
-        // swapgs();
-
-        wrmsr(IA32_GS_BASE, next.gsbase.value() as u64);
+        // gs already points at this cpu's kpcr, which a task can't carry with it to another cpu
         get_tss().privilege_stack_table[0] = x86_64::VirtAddr::new(
             (next.kernel_stack.as_ptr() as usize + next.kernel_stack.len()) as u64,
         );
@@ -197,7 +204,6 @@ pub struct ArchTask {
     user: bool,
     pub(crate) address_space: AddressSpace,
     fsbase: VirtAddr,
-    gsbase: VirtAddr,
     fpu_storage: Option<Box<[u8]>>,
     pub symtab: Option<Vec<SymTabEntry>>,
 }
@@ -210,7 +216,6 @@ impl ArchTask {
             kernel_stack: alloc::vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice(),
             user: false,
             fsbase: VirtAddr::null(),
-            gsbase: VirtAddr::null(),
             fpu_storage: None,
             symtab: None,
         }
@@ -248,37 +253,46 @@ impl ArchTask {
             kernel_stack: alloc::vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice(),
             user: false,
             fsbase: VirtAddr::null(),
-            gsbase: unsafe { VirtAddr::new(rdmsr(IA32_GS_BASE) as usize) },
             fpu_storage: None,
             symtab: None,
         }
     }
 
+    pub fn is_user(&self) -> bool {
+        self.user
+    }
+
     pub fn exec(
         &mut self,
         vmem: &mut Vmem,
-        file: FileRef,
+        userland_entry: UserlandEntry,
         argv: &[&[u8]],
         envp: &[&[u8]],
+        stack_size: usize,
+        commit: impl FnOnce(),
     ) -> KResult<()> {
         interrupts::disable();
-        let userland_entry = elf::load_elf(file)?;
 
-        self.kernel_stack = alloc::vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice();
+        // we're still running on the old stack, so it has to outlive anything that might park us
+        let old_kernel_stack = core::mem::replace(
+            &mut self.kernel_stack,
+            alloc::vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice(),
+        );
         self.fsbase = userland_entry.fsbase.unwrap_or(VirtAddr::null());
-        self.gsbase = unsafe { VirtAddr::new_unchecked(rdmsr(IA32_GS_BASE) as usize) };
 
         self.user = true;
         self.address_space = userland_entry.addr_space;
 
         *vmem = userland_entry.vmem;
+        let auxv = userland_entry.hdr.map(|(ty, value)| (ty as usize, value));
+        vmem.set_exec_info(&auxv, argv);
 
         self.address_space.switch();
 
         // userland_entry
         self.address_space.with_mapper(|mut mapper| {
             vmem.map_area(
-                USER_STACK_BOTTOM,
+                USER_STACK_TOP - stack_size,
                 USER_STACK_TOP,
                 MMapFlags::empty(),
                 MMapProt::PROT_READ | MMapProt::PROT_WRITE | MMapProt::PROT_EXEC,
@@ -336,10 +350,27 @@ impl ArchTask {
         assert_eq!(stack.top() % 16, 0);
 
         self.fpu_storage = Some(Self::alloc_fpu_storage());
-        self.context = Unique::dangling();
         self.symtab = userland_entry.symtab;
+
+        // there's no going back from here
+        commit();
+
+        // what the new program starts with, which a tracer gets to look at first
+        let mut frame = InterruptFrame {
+            rip: userland_entry.entry_point.value(),
+            cs: ((USER_CS_IDX << 3) | 3) as usize,
+            rflags: 0x200,
+            rsp: stack.top(),
+            ss: ((USER_DS_IDX << 3) | 3) as usize,
+            ..Default::default()
+        };
+        get_scheduler().ptrace_exec(&mut frame);
+        interrupts::disable();
+
+        self.context = Unique::dangling();
+        drop(old_kernel_stack);
         unsafe {
-            exec_entry(userland_entry.entry_point.value(), stack.top(), 0x200);
+            exec_entry(frame.rip, frame.rsp, frame.rflags);
         }
     }
 
@@ -348,6 +379,8 @@ impl ArchTask {
 
         let address_space = self.address_space.fork(true)?;
         unsafe { tlb::flush_all() };
+        // the parent's threads on other cpus have to see their pages go copy-on-write too
+        super::smp::tlb_shootdown_all();
 
         let switch_stack = Self::alloc_switch_stack()?.as_raw_ptr_mut::<u8>();
         let mut old_rsp = self.kernel_stack.as_ptr() as usize + self.kernel_stack.len();
@@ -377,24 +410,28 @@ impl ArchTask {
             user: true,
             kernel_stack: alloc::vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice(),
             fsbase: self.fsbase,
-            gsbase: self.gsbase,
             fpu_storage: Some(fpu_storage),
             symtab: self.symtab.clone(),
         })
     }
 
     pub fn clone_process(
-        &self,
-        entry_point: VirtAddr,
+        &mut self,
+        share_vm: bool,
         user_stack: VirtAddr,
-        args: VirtAddr,
-        r8: usize,
-        r9: usize,
+        tls: Option<VirtAddr>,
         syscall_frame: &InterruptFrame,
     ) -> KResult<Self> {
         assert!(self.user, "Cannot clone a kernel task");
 
-        let address_space = AddressSpace::current().fork(true)?;
+        let address_space = if share_vm {
+            self.address_space.share()
+        } else {
+            let address_space = self.address_space.fork(true)?;
+            unsafe { tlb::flush_all() };
+            super::smp::tlb_shootdown_all();
+            address_space
+        };
         let switch_stack = Self::alloc_switch_stack()?.as_raw_ptr_mut::<u8>();
 
         let mut new_rsp = switch_stack as usize;
@@ -402,16 +439,12 @@ impl ArchTask {
 
         let new_frame = unsafe { new_stack.offset::<InterruptErrorFrame>() };
         *new_frame = InterruptErrorFrame::default();
+        new_frame.frame = *syscall_frame;
 
-        new_frame.frame.cs = syscall_frame.cs;
-        new_frame.frame.ss = syscall_frame.ss;
-
-        new_frame.frame.r8 = r8;
-        new_frame.frame.r9 = r9;
-        new_frame.frame.rdi = args.value();
-
-        new_frame.frame.rip = entry_point.value();
-        new_frame.frame.rsp = user_stack.value();
+        new_frame.frame.rax = 0x0; // clone return value
+        if !user_stack.is_null() {
+            new_frame.frame.rsp = user_stack.value();
+        }
         new_frame.frame.rflags = 0x200;
 
         let context = unsafe { new_stack.offset::<Context>() };
@@ -426,8 +459,7 @@ impl ArchTask {
             address_space,
             user: true,
             fpu_storage: Some(fpu_storage),
-            gsbase: self.gsbase,
-            fsbase: self.fsbase,
+            fsbase: tls.unwrap_or(self.fsbase),
             kernel_stack: alloc::vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice(),
             symtab: self.symtab.clone(),
         })
@@ -449,6 +481,20 @@ impl ArchTask {
         Ok(alloc_kernel_frames(1)?.start_address().as_hhdm_virt() + PAGE_SIZE)
     }
 
+    pub fn fsbase(&self) -> VirtAddr {
+        self.fsbase
+    }
+
+    // what the fpu has in it right now, which is the user's state as long as the kernel
+    // doesn't touch it
+    pub fn save_fpu_state() -> [u8; FXSAVE_SIZE] {
+        let mut fpu = Self::alloc_fpu_storage();
+        fxsave(&mut fpu);
+        let mut state = [0; FXSAVE_SIZE];
+        state.copy_from_slice(&fpu[..FXSAVE_SIZE]);
+        state
+    }
+
     pub fn set_fsbase(&mut self, addr: VirtAddr) {
         self.fsbase = addr;
         unsafe {
@@ -456,11 +502,16 @@ impl ArchTask {
         }
     }
 
+    // builds an rt_sigframe on the user's stack, or the top of the alternate stack if one's
+    // given, and points the frame at the handler
     pub fn setup_signal_stack(
         frame: &mut InterruptFrame,
         signal: Signal,
-        handler: VirtAddr,
-        _syscall_result: isize,
+        action: &KSigAction,
+        info: &SigInfo,
+        old_mask: u64,
+        uc_stack: &SigStack,
+        altstack_top: Option<usize>,
     ) -> KResult<()> {
         const TRAMPOLINE: &[u8] = &[
             0xb8, 0x0f, 0x00, 0x00, 0x00, // mov eax, 15
@@ -471,27 +522,141 @@ impl ArchTask {
         if frame.cs & 0x3 == 0 {
             return Ok(());
         }
-        let mut rsp = frame.rsp;
+        let mut rsp = altstack_top.unwrap_or(frame.rsp);
         let mut stack = Stack::new(&mut rsp);
-        // red zone
-        stack.skip_by(128);
-        unsafe {
-            stack.push_bytes(TRAMPOLINE);
-            stack.push(stack.top());
+        if altstack_top.is_none() {
+            // red zone
+            stack.skip_by(128);
         }
 
-        frame.rip = handler.value();
-        frame.rsp = rsp;
+        let pretcode = if action.sa_flags & SA_RESTORER != 0 {
+            action.sa_restorer
+        } else {
+            stack.skip_by(TRAMPOLINE.len());
+            unsafe { VirtAddr::new(stack.top()).write_bytes_user(TRAMPOLINE) }?;
+            stack.top()
+        };
+
+        // nothing in the kernel touches the fpu, so it still has what userspace left in it
+        let mut fpu = Self::alloc_fpu_storage();
+        fxsave(&mut fpu);
+        stack.skip_by(FXSAVE_SIZE);
+        stack.align_down(64);
+        let fpstate = VirtAddr::new(stack.top());
+        unsafe { fpstate.write_bytes_user(&fpu[..FXSAVE_SIZE]) }?;
+
+        // the handler starts out as if it had just been called, with its return address on top
+        stack.skip_by(core::mem::size_of::<RtSigFrame>());
+        stack.align_down(16);
+        stack.skip_by(8);
+        let sigframe = VirtAddr::new(stack.top());
+
+        let uc_mcontext = SigContext {
+            r8: frame.r8 as u64,
+            r9: frame.r9 as u64,
+            r10: frame.r10 as u64,
+            r11: frame.r11 as u64,
+            r12: frame.r12 as u64,
+            r13: frame.r13 as u64,
+            r14: frame.r14 as u64,
+            r15: frame.r15 as u64,
+            rdi: frame.rdi as u64,
+            rsi: frame.rsi as u64,
+            rbp: frame.rbp as u64,
+            rbx: frame.rbx as u64,
+            rdx: frame.rdx as u64,
+            rax: frame.rax as u64,
+            rcx: frame.rcx as u64,
+            rsp: frame.rsp as u64,
+            rip: frame.rip as u64,
+            eflags: frame.rflags as u64,
+            cs: frame.cs as u16,
+            ss: frame.ss as u16,
+            fpstate: fpstate.value() as u64,
+            ..Default::default()
+        };
+        let rt_frame = RtSigFrame {
+            pretcode: pretcode as u64,
+            uc: UContext {
+                uc_stack: *uc_stack,
+                uc_mcontext,
+                uc_sigmask: old_mask,
+                ..Default::default()
+            },
+            info: *info,
+        };
+        unsafe { sigframe.write_user(rt_frame) }?;
+
+        frame.rip = action.sa_handler;
+        frame.rsp = sigframe.value();
         frame.rdi = signal as usize;
+        frame.rsi = sigframe.value() + offset_of!(RtSigFrame, info);
+        frame.rdx = sigframe.value() + offset_of!(RtSigFrame, uc);
+        // the handler isn't variadic, so it gets no vector registers
+        frame.rax = 0;
+        frame.rflags &= !(RFLAGS_DF | RFLAGS_TF);
 
         Ok(())
     }
 
-    pub fn setup_sigreturn_stack(
-        &self,
-        current_frame: &mut InterruptFrame,
-        signaled_frame: &InterruptFrame,
-    ) {
-        *current_frame = *signaled_frame;
+    // undoes setup_signal_stack() once the handler returns, and hands back the rest of the
+    // context for the caller to restore
+    pub fn restore_signal_stack(frame: &mut InterruptFrame) -> KResult<UContext> {
+        // the handler's ret already popped pretcode
+        let sigframe = VirtAddr::new(frame.rsp - 8);
+        let rt_frame = unsafe { sigframe.read_user::<RtSigFrame>() }?;
+        let mcontext = rt_frame.uc.uc_mcontext;
+        // the way back to userspace would fault in the kernel on either of these
+        if !is_canonical_virtaddr(mcontext.rip as usize)
+            || !is_canonical_virtaddr(mcontext.rsp as usize)
+        {
+            kbail!(EFAULT, "restore_signal_stack(): non-canonical rip or rsp");
+        }
+
+        if mcontext.fpstate != 0 {
+            let mut fpu = Self::alloc_fpu_storage();
+            fxsave(&mut fpu);
+            let mxcsr_mask = match u32::from_le_bytes(
+                fpu[FXSAVE_MXCSR_MASK..FXSAVE_MXCSR_MASK + 4]
+                    .try_into()
+                    .unwrap(),
+            ) {
+                0 => DEFAULT_MXCSR_MASK,
+                mask => mask,
+            };
+            unsafe {
+                VirtAddr::new(mcontext.fpstate as usize).read_bytes_user(&mut fpu[..FXSAVE_SIZE])
+            }?;
+            // setting a reserved bit in mxcsr would fault in the kernel
+            let mxcsr = u32::from_le_bytes(fpu[FXSAVE_MXCSR..FXSAVE_MXCSR + 4].try_into().unwrap());
+            fpu[FXSAVE_MXCSR..FXSAVE_MXCSR + 4]
+                .copy_from_slice(&(mxcsr & mxcsr_mask).to_le_bytes());
+            fxrstor(&mut fpu);
+        }
+
+        frame.r8 = mcontext.r8 as usize;
+        frame.r9 = mcontext.r9 as usize;
+        frame.r10 = mcontext.r10 as usize;
+        frame.r11 = mcontext.r11 as usize;
+        frame.r12 = mcontext.r12 as usize;
+        frame.r13 = mcontext.r13 as usize;
+        frame.r14 = mcontext.r14 as usize;
+        frame.r15 = mcontext.r15 as usize;
+        frame.rdi = mcontext.rdi as usize;
+        frame.rsi = mcontext.rsi as usize;
+        frame.rbp = mcontext.rbp as usize;
+        frame.rbx = mcontext.rbx as usize;
+        frame.rdx = mcontext.rdx as usize;
+        frame.rax = mcontext.rax as usize;
+        frame.rcx = mcontext.rcx as usize;
+        frame.rsp = mcontext.rsp as usize;
+        frame.rip = mcontext.rip as usize;
+        // userspace only gets to pick the flags it could have changed itself
+        frame.rflags = (frame.rflags & !RFLAGS_USER_CHANGEABLE)
+            | (mcontext.eflags as usize & RFLAGS_USER_CHANGEABLE);
+        frame.cs = ((USER_CS_IDX << 3) | 3) as usize;
+        frame.ss = ((USER_DS_IDX << 3) | 3) as usize;
+
+        Ok(rt_frame.uc)
     }
 }
@@ -1,32 +1,62 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use x86::io::{inb, outb};
+use x86::{
+    cpuid::CpuId,
+    io::{inb, outb},
+    time::rdtsc,
+};
 
-use crate::{userland::syscall::syscall_impl::time::TimeSpec, util::IrqMutex};
+use crate::userland::syscall::syscall_impl::time::TimeSpec;
 
+// the pit doesn't interrupt anyone anymore, it only runs so the other clocks can be measured
+// against it. at this rate its counter wraps once a millisecond.
 const PIT_FREQUENCY_HZ: usize = 1000;
 pub const PIT_DIVIDEND: usize = 1193182;
 
-static UPTIME_RAW: AtomicUsize = AtomicUsize::new(0);
-static UPTIME_SEC: AtomicUsize = AtomicUsize::new(0);
+// how often a busy cpu stops to see if something else should run instead
+const TICK_HZ: usize = 1000;
+pub const TICK_NS: usize = 1000000000 / TICK_HZ;
 
-pub static EPOCH: AtomicUsize = AtomicUsize::new(usize::MAX);
-pub static RT_CLOCK: IrqMutex<TimeSpec> = IrqMutex::new(TimeSpec {
-    tv_sec: 0,
-    tv_nsec: 0,
-});
+const TSC_CALIBRATION_MS: u32 = 50;
+
+// the tsc reading that uptime counts from
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+// nanoseconds per tsc cycle, as a 32.32 fixed point number
+static TSC_MULT: AtomicU64 = AtomicU64::new(0);
+// the tscs of different cpus can be a little out of step, but the clock must never go backwards
+static LAST_NS: AtomicUsize = AtomicUsize::new(0);
+
+pub static EPOCH: AtomicUsize = AtomicUsize::new(0);
 
 pub fn get_uptime_ns() -> usize {
-    let ts = get_rt_clock();
-    (ts.tv_sec * 1000000000 + ts.tv_nsec) as usize
+    let mult = TSC_MULT.load(Ordering::Relaxed);
+    if mult == 0 {
+        return 0;
+    }
+    let cycles = unsafe { rdtsc() }.saturating_sub(TSC_BASE.load(Ordering::Relaxed));
+    let now = ((cycles as u128 * mult as u128) >> 32) as usize;
+    LAST_NS.fetch_max(now, Ordering::Relaxed).max(now)
 }
 
 pub fn get_uptime_ms() -> usize {
     get_uptime_ns() / 1000000
 }
 
+// what the tsc will read at the given uptime
+pub fn uptime_ns_to_tsc(ns: usize) -> u64 {
+    let khz = TSC_KHZ.load(Ordering::Relaxed);
+    TSC_BASE.load(Ordering::Relaxed) + (ns as u128 * khz as u128 / 1000000) as u64
+}
+
+// how long one tsc cycle takes, rounded up
+pub fn resolution_ns() -> usize {
+    1000000_usize.div_ceil(TSC_KHZ.load(Ordering::Relaxed).max(1) as usize)
+}
+
 pub fn get_rt_clock() -> TimeSpec {
-    *RT_CLOCK.lock()
+    let epoch = EPOCH.load(Ordering::Relaxed) * 1000000000;
+    TimeSpec::from_nanos(epoch + get_uptime_ns())
 }
 
 pub fn get_pit_count() -> u16 {
@@ -58,33 +88,43 @@ pub fn set_pit_frequency(frequency: usize) {
     set_reload_value(new_divisor as u16);
 }
 
-pub fn pit_irq() {
-    {
-        let interval = TimeSpec {
-            tv_sec: 0,
-            tv_nsec: (1000000000 / PIT_FREQUENCY_HZ) as isize,
-        };
-
-        let mut clk = RT_CLOCK.lock();
-
-        if clk.tv_nsec + interval.tv_nsec > 999999999 {
-            let diff = (clk.tv_nsec + interval.tv_nsec) - 1000000000;
-
-            clk.tv_nsec = diff;
-            clk.tv_sec += 1;
-        } else {
-            clk.tv_nsec += interval.tv_nsec;
+// the pit's irq is masked, so watch its counter wrap instead
+pub fn pit_busy_wait_ms(ms: u32) {
+    let mut last = get_pit_count();
+    let mut wrapped = 0;
+    while wrapped < ms {
+        let count = get_pit_count();
+        if count > last {
+            wrapped += 1;
         }
+        last = count;
+        core::hint::spin_loop();
     }
+}
 
-    let value = UPTIME_RAW.fetch_add(1, Ordering::Relaxed);
-    if value % PIT_FREQUENCY_HZ == 0 {
-        UPTIME_SEC.fetch_add(1, Ordering::Relaxed);
+fn calibrate_tsc() {
+    let has_invariant_tsc = CpuId::new()
+        .get_advanced_power_mgmt_info()
+        .is_some_and(|info| info.has_invariant_tsc());
+    if !has_invariant_tsc {
+        log::warn!("TSC isn't invariant, so the clock may drift when the CPU changes speed.");
     }
+
+    // start timing on a wrap so we don't count a partial millisecond
+    pit_busy_wait_ms(1);
+    let start = unsafe { rdtsc() };
+    pit_busy_wait_ms(TSC_CALIBRATION_MS);
+    let end = unsafe { rdtsc() };
+
+    let khz = ((end - start) / TSC_CALIBRATION_MS as u64).max(1);
+    TSC_KHZ.store(khz, Ordering::Relaxed);
+    TSC_BASE.store(end, Ordering::Relaxed);
+    TSC_MULT.store((1000000 << 32) / khz, Ordering::Relaxed);
+    log::info!("TSC runs at {}.{:03} MHz.", khz / 1000, khz % 1000);
 }
 
 pub fn init(boot_time: i64) {
     EPOCH.store(boot_time as usize, Ordering::SeqCst);
-    RT_CLOCK.lock().tv_sec = boot_time as isize;
     set_pit_frequency(PIT_FREQUENCY_HZ);
+    calibrate_tsc();
 }
@@ -13,9 +13,14 @@ use spin::Once;
 use crate::{
     fb_print,
     graphics::{self, render_text_buf},
-    kerror,
+    kbail, kerror,
     mem::addr::VirtAddr,
-    task::{current_task, get_scheduler, group::TaskGroup, signal::SIGINT, wait_queue::WaitQueue},
+    task::{
+        current_task, get_scheduler,
+        group::{SessionId, TaskGroup},
+        signal::{Signal, SIGCONT, SIGHUP, SIGINT, SIGTSTP, SIGTTIN, SIGTTOU},
+        wait_queue::WaitQueue,
+    },
     userland::buffer::{UserBuffer, UserBufferMut, UserBufferReader, UserBufferWriter},
     util::{
         ctypes::c_int, errno::Errno, error::KResult, lock::IrqMutex, ringbuffer::RingBuffer, KError,
@@ -50,6 +55,7 @@ bitflags! {
     pub struct LFlag: u32 {
         const ICANON = 0o0000002;
         const ECHO   = 0o0000010;
+        const TOSTOP = 0o0000400;
     }
 }
 
@@ -142,6 +148,7 @@ pub struct LineDiscipline {
     buf: IrqMutex<RingBuffer<u8, 4096>>,
     termios: IrqMutex<Termios>,
     foreground_group: IrqMutex<Weak<IrqMutex<TaskGroup>>>,
+    session: IrqMutex<Option<SessionId>>,
 }
 
 impl LineDiscipline {
@@ -152,6 +159,7 @@ impl LineDiscipline {
             buf: IrqMutex::new(RingBuffer::new()),
             termios: IrqMutex::new(Termios::default()),
             foreground_group: IrqMutex::new(Weak::new()),
+            session: IrqMutex::new(None),
         }
     }
 
@@ -171,9 +179,107 @@ impl LineDiscipline {
         *self.foreground_group.lock() = pg;
     }
 
-    fn _is_current_foreground(&self) -> bool {
-        let pg = &*self.foreground_group.lock();
-        current_task().belongs_to_group(pg) || pg.upgrade().is_none()
+    pub fn session(&self) -> Option<SessionId> {
+        *self.session.lock()
+    }
+
+    // the controlling process is gone, so the foreground job loses its terminal
+    pub fn hangup(&self) {
+        let fg = self.foreground_group();
+        *self.session.lock() = None;
+        *self.foreground_group.lock() = Weak::new();
+        if let Some(pg) = fg {
+            let mut pg = pg.lock();
+            pg.signal(SIGHUP);
+            pg.signal(SIGCONT);
+        }
+    }
+
+    // background jobs that touch their controlling terminal get stopped by `signal` instead
+    fn check_background_access(&self, signal: Signal) -> KResult<()> {
+        let current = current_task();
+        if self.session() != Some(current.sid()) {
+            return Ok(());
+        }
+        let fg = self.foreground_group.lock().clone();
+        if fg.upgrade().is_none() || current.belongs_to_group(&fg) {
+            return Ok(());
+        }
+        if current.is_signal_ignored_or_blocked(signal) {
+            if signal == SIGTTIN {
+                kbail!(EIO, "tty: background read with SIGTTIN ignored");
+            }
+            return Ok(());
+        }
+        let pg = current.group.lock().upgrade();
+        if let Some(pg) = pg {
+            pg.lock().signal(signal);
+        }
+        kbail!(EINTR, "tty: background job stopped")
+    }
+
+    pub fn check_write_access(&self) -> KResult<()> {
+        if self.termios.lock().lflag.contains(LFlag::TOSTOP) {
+            self.check_background_access(SIGTTOU)?;
+        }
+        Ok(())
+    }
+
+    pub fn ioctl(self: &Arc<Self>, cmd: usize, arg: usize) -> KResult<isize> {
+        let current = current_task();
+        match cmd {
+            TIOCGPGRP => {
+                let group = self
+                    .foreground_group()
+                    .unwrap_or_else(|| current.group.lock().upgrade().unwrap());
+                let id = group.lock().pgid();
+                let arg = VirtAddr::new(arg);
+                unsafe { arg.write_user(id) }?;
+            }
+            TIOCSPGRP => {
+                if self.session().is_some_and(|sid| sid != current.sid()) {
+                    kbail!(ENOTTY, "ioctl(): not our controlling terminal");
+                }
+                self.check_background_access(SIGTTOU)?;
+                let arg = VirtAddr::new(arg);
+                let pgid = unsafe { arg.read_user::<c_int>()? };
+                let pg = get_scheduler().find_or_create_group(pgid);
+                self.set_foreground_group(Arc::downgrade(&pg));
+            }
+            TIOCSCTTY => {
+                if !current.is_session_leader() || current.controlling_tty().is_some() {
+                    kbail!(
+                        EPERM,
+                        "ioctl(): only a session leader without a terminal can take one"
+                    );
+                }
+                if self.session().is_some_and(|sid| sid != current.sid()) {
+                    kbail!(EPERM, "ioctl(): terminal belongs to another session");
+                }
+                *self.session.lock() = Some(current.sid());
+                let group = current.group.lock().clone();
+                self.set_foreground_group(group);
+                for thread in get_scheduler().thread_group(current.tgid()) {
+                    thread.set_controlling_tty(Some(self.clone()));
+                }
+            }
+            TIOCNOTTY => {
+                let is_ctty = current
+                    .controlling_tty()
+                    .is_some_and(|tty| Arc::ptr_eq(&tty, self));
+                if !is_ctty {
+                    kbail!(ENOTTY, "ioctl(): not our controlling terminal");
+                }
+                if current.is_session_leader() {
+                    self.hangup();
+                }
+                for thread in get_scheduler().thread_group(current.tgid()) {
+                    thread.set_controlling_tty(None);
+                }
+            }
+            _ => return Err(kerror!(ENOTTY, "ioctl(): command not found")),
+        }
+        Ok(0)
     }
 
     pub fn write<F>(&self, buf: UserBuffer<'_>, callback: F) -> KResult<usize>
@@ -195,6 +301,11 @@ impl LineDiscipline {
                             pg.lock().signal(SIGINT);
                         }
                     }
+                    0x1a if termios.is_cooked() => {
+                        if let Some(pg) = self.foreground_group() {
+                            pg.lock().signal(SIGTSTP);
+                        }
+                    }
                     0x08 if termios.is_cooked() => {
                         if !current_line.is_empty() {
                             current_line.pop();
@@ -247,12 +358,8 @@ impl LineDiscipline {
         } else {
             None
         };
+        self.check_background_access(SIGTTIN)?;
         self.wait_queue.sleep_signalable_until(timeout, || {
-            // todo: figure out how to get this working
-            // if !self.is_current_foreground() {
-            //     return Ok(None)
-            // }
-
             let mut buf_lock = self.buf.lock();
             while writer.remaining_len() > 0 {
                 if let Some(slice) = buf_lock.pop_slice(writer.remaining_len()) {
@@ -279,14 +386,14 @@ impl Default for LineDiscipline {
 
 pub struct Tty {
     name: String,
-    discipline: LineDiscipline,
+    discipline: Arc<LineDiscipline>,
 }
 
 impl Tty {
     pub fn new(name: &str) -> Self {
         Self {
             name: name.to_owned(),
-            discipline: LineDiscipline::new(),
+            discipline: Arc::new(LineDiscipline::new()),
         }
     }
 
@@ -306,13 +413,22 @@ impl Tty {
                     graphics::backspace();
                 }
                 LineControl::Echo(ch) => {
-                    self.write(0, UserBuffer::from_slice(&[ch]), &OpenFlags::empty())
-                        .ok();
+                    self.output(UserBuffer::from_slice(&[ch])).ok();
                 }
             })
             .ok();
     }
 
+    fn output(&self, buf: UserBuffer<'_>) -> KResult<usize> {
+        let reader = UserBufferReader::from_buf(buf);
+        let total_len = parse(reader)?;
+        if total_len > 0 {
+            render_text_buf();
+            get_scheduler().wake_all(&POLL_WAIT_QUEUE);
+        }
+        Ok(total_len)
+    }
+
     pub fn set_foreground_group(&self, pg: Weak<IrqMutex<TaskGroup>>) {
         self.discipline.set_foreground_group(pg)
     }
@@ -327,9 +443,11 @@ const TCGETS: usize = 0x5401;
 const TCSETS: usize = 0x5402;
 const TCSETSW: usize = 0x5403;
 
+const TIOCSCTTY: usize = 0x540e;
 const TIOCGPGRP: usize = 0x540f;
 const TIOCSPGRP: usize = 0x5410;
 const TIOCGWINSZ: usize = 0x5413;
+const TIOCNOTTY: usize = 0x5422;
 
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -349,25 +467,11 @@ impl File for Tty {
                 unsafe { arg.write_user(termios) }?;
             }
             TCSETS | TCSETSW => {
+                self.discipline.check_background_access(SIGTTOU)?;
                 let arg = VirtAddr::new(arg);
                 let termios = unsafe { arg.read_user::<Termios>()? };
                 *self.discipline.termios.lock() = termios;
             }
-            TIOCGPGRP => {
-                let group = self
-                    .discipline
-                    .foreground_group()
-                    .unwrap_or(current_task().group.borrow().upgrade().unwrap());
-                let id = group.lock().pgid();
-                let arg = VirtAddr::new(arg);
-                unsafe { arg.write_user(id) }?;
-            }
-            TIOCSPGRP => {
-                let arg = VirtAddr::new(arg);
-                let pgid = unsafe { arg.read_user::<c_int>()? };
-                let pg = get_scheduler().find_or_create_group(pgid);
-                self.discipline.set_foreground_group(Arc::downgrade(&pg));
-            }
             TIOCGWINSZ => {
                 let winsize = WinSize {
                     ws_row: vga_text::BUFFER_HEIGHT as u16,
@@ -378,7 +482,7 @@ impl File for Tty {
                 let arg = VirtAddr::new(arg);
                 unsafe { arg.write_user(winsize) }?;
             }
-            _ => return Err(kerror!(ENOTTY, "ioctl(): command not found")),
+            _ => return self.discipline.ioctl(cmd, arg),
         }
 
         Ok(0)
@@ -411,15 +515,8 @@ impl File for Tty {
     }
 
     fn write(&self, _offset: usize, buf: UserBuffer<'_>, _options: &OpenFlags) -> KResult<usize> {
-        // let mut tmp = [0; 1];
-        // let mut total_len = 0;
-        let reader = UserBufferReader::from_buf(buf);
-        let total_len = parse(reader)?;
-        if total_len > 0 {
-            render_text_buf();
-            get_scheduler().wake_all(&POLL_WAIT_QUEUE);
-        }
-        Ok(total_len)
+        self.discipline.check_write_access()?;
+        self.output(buf)
     }
 
     fn poll(&self) -> KResult<PollStatus> {
@@ -615,7 +712,7 @@ fn parse(mut reader: UserBufferReader) -> KResult<usize> {
 pub struct PtyMaster {
     wait_queue: WaitQueue,
     buf: IrqMutex<Vec<u8>>,
-    discipline: LineDiscipline,
+    discipline: Arc<LineDiscipline>,
 }
 
 impl PtyMaster {
@@ -623,7 +720,7 @@ impl PtyMaster {
         let master = Arc::new(PtyMaster {
             wait_queue: WaitQueue::new(),
             buf: IrqMutex::new(Vec::new()),
-            discipline: LineDiscipline::new(),
+            discipline: Arc::new(LineDiscipline::new()),
         });
         let slave = Arc::new(PtySlave::new(master.clone()));
         Ok((master, slave))
@@ -735,6 +832,7 @@ impl File for PtySlave {
     }
 
     fn write(&self, _offset: usize, buf: UserBuffer<'_>, _options: &OpenFlags) -> KResult<usize> {
+        self.master.discipline.check_write_access()?;
         let mut written_len = 0;
         let mut master_buf = self.master.buf.lock();
         let mut reader = UserBufferReader::from_buf(buf);
@@ -771,10 +869,11 @@ impl File for PtySlave {
         })
     }
 
-    fn ioctl(&self, cmd: usize, _arg: usize) -> KResult<isize> {
+    fn ioctl(&self, cmd: usize, arg: usize) -> KResult<isize> {
         const TIOCSPTLCK: usize = 0x40045431;
         match cmd {
             TIOCSPTLCK => Ok(0),
+            TIOCSCTTY | TIOCNOTTY | TIOCGPGRP | TIOCSPGRP => self.master.discipline.ioctl(cmd, arg),
             _ => {
                 log::warn!("ioctl(): unknown cmd for PtySlave ({:#x})", cmd);
                 Ok(0)
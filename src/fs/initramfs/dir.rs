@@ -6,8 +6,8 @@ use alloc::{
 
 use crate::{
     fs::{
-        alloc_inode_no, DirEntry, Directory, FileMode, FileRef, FileType, FsNode, INode, Stat,
-        S_IFDIR,
+        alloc_inode_no, DirEntry, Directory, FileMode, FileRef, FileType, FsNode, GId, INode, Stat,
+        UId, S_IFDIR,
     },
     kerror,
     util::{lock::IrqMutex, KResult},
@@ -40,6 +40,16 @@ impl InitRamFsDir {
         }
     }
 
+    pub fn chmod(&self, mode: FileMode) {
+        self.inner.lock().stat.mode = FileMode::new(S_IFDIR | mode.permissions());
+    }
+
+    pub fn chown(&self, uid: UId, gid: GId) {
+        let mut inner = self.inner.lock();
+        inner.stat.uid = uid;
+        inner.stat.gid = gid;
+    }
+
     pub fn add_dir(&self, name: String) -> Arc<InitRamFsDir> {
         let dir = Arc::new(InitRamFsDir::new(name, alloc_inode_no()));
         self.inner.lock().children.push(INode::Dir(dir.clone()));
@@ -1,7 +1,8 @@
-use alloc::{string::String, vec, vec::Vec};
+use alloc::{string::String, vec::Vec};
 
 use crate::{
-    fs::{opened_file::OpenFlags, File, FileMode, FsNode, Stat, S_IFREG},
+    fs::{opened_file::OpenFlags, File, FileMode, FsNode, GId, Stat, UId, S_IFREG},
+    kbail, kerror,
     userland::buffer::{UserBuffer, UserBufferMut, UserBufferReader, UserBufferWriter},
     util::{lock::IrqMutex, KResult},
 };
@@ -24,6 +25,17 @@ impl InitRamFsFile {
             }),
         }
     }
+
+    pub fn chmod(&self, mode: FileMode) {
+        let mut stat = self.stat.lock();
+        stat.mode = FileMode::new(S_IFREG | mode.permissions());
+    }
+
+    pub fn chown(&self, uid: UId, gid: GId) {
+        let mut stat = self.stat.lock();
+        stat.uid = uid;
+        stat.gid = gid;
+    }
 }
 
 impl FsNode for InitRamFsFile {
@@ -42,19 +54,22 @@ impl File for InitRamFsFile {
         writer.write_bytes(&lock[offset..])
     }
 
-    fn write(&self, offset: usize, buf: UserBuffer<'_>, options: &OpenFlags) -> KResult<usize> {
+    fn write(&self, offset: usize, buf: UserBuffer<'_>, _options: &OpenFlags) -> KResult<usize> {
         let mut reader = UserBufferReader::from_buf(buf);
         let mut data = self.data.lock();
-        let data_len = data.len();
-        if data.is_empty() {
-            data.extend_from_slice(&vec![0u8; offset + reader.remaining_len()]);
-        } else if offset + reader.remaining_len() < isize::MAX as usize
-            && (offset + reader.remaining_len() > data_len || options.contains(OpenFlags::O_APPEND))
-        {
-            data.push(0u8);
+        let end = offset
+            .checked_add(reader.remaining_len())
+            .filter(|&end| end <= isize::MAX as usize)
+            .ok_or(kerror!(EFBIG, "write(): file too large"))?;
+        if end > data.len() {
+            // running the kernel heap dry panics, so a file that can't grow is an error instead
+            let additional = end - data.len();
+            if data.try_reserve(additional).is_err() {
+                kbail!(ENOSPC, "write(): no memory left for the file");
+            }
+            data.resize(end, 0);
         }
-
-        reader.read_bytes(&mut data[offset..])
+        reader.read_bytes(&mut data[offset..end])
     }
 
     fn stat(&self) -> KResult<Stat> {
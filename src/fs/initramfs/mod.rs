@@ -15,7 +15,7 @@ use crate::{
             symlink::InitRamFsSymlink,
         },
         path::{Components, Path, PathBuf},
-        DirRef, FileMode, FileSize, FsNode, INode, Stat,
+        DirRef, FileMode, FileSize, FsNode, GId, INode, Stat, UId,
     },
     kbail, kerror,
     util::{align_up, IrqMutex, KResult},
@@ -131,8 +131,8 @@ impl InitRamFs {
 
             let ino = parse_hex_field(image.consume_bytes(8)?)?;
             let mode = FileMode::new(parse_hex_field(image.consume_bytes(8)?)? as u32);
-            let _uid = parse_hex_field(image.consume_bytes(8)?)?;
-            let _gid = parse_hex_field(image.consume_bytes(8)?)?;
+            let uid = UId::new(parse_hex_field(image.consume_bytes(8)?)? as u32);
+            let gid = GId::new(parse_hex_field(image.consume_bytes(8)?)? as u32);
             let _nlink = parse_hex_field(image.consume_bytes(8)?)?;
             let _mtime = parse_hex_field(image.consume_bytes(8)?)?;
             let filesize = parse_hex_field(image.consume_bytes(8)?)?;
@@ -208,6 +208,8 @@ impl InitRamFs {
                     stat: Stat {
                         inode_no: ino,
                         mode,
+                        uid,
+                        gid,
                         ..Stat::zeroed()
                     },
                 }));
@@ -220,6 +222,8 @@ impl InitRamFs {
                         stat: Stat {
                             inode_no: ino,
                             mode,
+                            uid,
+                            gid,
                             ..Stat::zeroed()
                         },
                         name: filename.clone(),
@@ -233,6 +237,8 @@ impl InitRamFs {
                     stat: IrqMutex::new(Stat {
                         inode_no: ino,
                         mode,
+                        uid,
+                        gid,
                         size: FileSize(filesize as isize),
                         ..Stat::zeroed()
                     }),
@@ -7,6 +7,7 @@ use crate::{
         DirRef, INode,
     },
     kbail,
+    task::cred::{current_credentials, Access, Credentials},
     util::KResult,
 };
 
@@ -74,6 +75,7 @@ impl RootFs {
             path,
             follow_symlinks,
             MAX_SYMLINK_FOLLOW_DEPTH,
+            &current_credentials(),
         )
     }
 
@@ -83,6 +85,7 @@ impl RootFs {
         path: &Path,
         follow_symlinks: bool,
         symlink_follow_limit: usize,
+        cred: &Credentials,
     ) -> KResult<PathComponent> {
         let mut parent = lookup_from.clone();
         let mut components = path.components().peekable();
@@ -95,7 +98,9 @@ impl RootFs {
                     .unwrap_or(&self.root_path)
                     .clone(),
                 _ => {
-                    let inode = parent.inode.as_dir()?.lookup(name)?;
+                    let dir = parent.inode.as_dir()?;
+                    cred.check_access(&dir.stat()?, Access::X_OK)?;
+                    let inode = dir.lookup(name)?;
                     PathComponent {
                         parent_dir: Some(Box::new(parent.clone())),
                         name: Arc::new(name.to_owned()),
@@ -126,6 +131,7 @@ impl RootFs {
                             &dst,
                             follow_symlinks,
                             symlink_follow_limit - 1,
+                            cred,
                         )?;
 
                         match dst_path.inode {
@@ -160,6 +166,7 @@ impl RootFs {
                             &dst,
                             follow_symlinks,
                             symlink_follow_limit - 1,
+                            cred,
                         );
                     }
                     _ => return Ok(path_comp),
@@ -10,13 +10,14 @@ use crate::{
     util::{ctypes::c_short, KResult},
 };
 
-use self::{opened_file::OpenFlags, path::PathBuf, pipe::Pipe};
+use self::{opened_file::OpenFlags, path::PathBuf, pipe::Pipe, timerfd::TimerFd};
 
 pub mod devfs;
 pub mod initramfs;
 pub mod opened_file;
 pub mod path;
 pub mod pipe;
+pub mod timerfd;
 
 pub type FileRef = Arc<dyn File + Send + Sync>;
 pub type DirRef = Arc<dyn Directory + Send + Sync>;
@@ -79,15 +80,39 @@ pub struct NLink(usize);
 pub struct FileSize(pub isize);
 
 /// The user ID.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct UId(u32);
 
+impl UId {
+    pub const ROOT: UId = UId(0);
+
+    pub const fn new(uid: u32) -> UId {
+        UId(uid)
+    }
+
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+}
+
 /// The Group ID.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct GId(u32);
 
+impl GId {
+    pub const ROOT: GId = GId(0);
+
+    pub const fn new(gid: u32) -> GId {
+        GId(gid)
+    }
+
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+}
+
 /// The size in bytes of a block file file system I/O operations.
 #[derive(Debug, Copy, Clone)]
 #[repr(transparent)]
@@ -148,6 +173,9 @@ pub const S_IFDIR: u32 = 0o040000;
 pub const S_IFREG: u32 = 0o100000;
 pub const S_IFLNK: u32 = 0o120000;
 
+pub const S_ISUID: u32 = 0o4000;
+pub const S_ISGID: u32 = 0o2000;
+
 pub const O_ACCMODE: u32 = 0o3;
 
 // FIXME: OpenFlags also define these values.
@@ -169,6 +197,18 @@ impl FileMode {
         self.0 & O_ACCMODE
     }
 
+    pub fn permissions(self) -> u32 {
+        self.0 & 0o7777
+    }
+
+    pub fn is_setuid(self) -> bool {
+        self.0 & S_ISUID != 0
+    }
+
+    pub fn is_setgid(self) -> bool {
+        self.0 & S_ISGID != 0
+    }
+
     pub fn is_directory(self) -> bool {
         (self.0 & S_IFMT) == S_IFDIR
     }
@@ -222,6 +262,11 @@ pub trait File: FsNode {
     fn write(&self, _offset: usize, _buf: UserBuffer<'_>, _options: &OpenFlags) -> KResult<usize> {
         Err(kerror!(EBADF, "write(): not implemented"))
     }
+
+    /// `timerfd_settime(2)` and `timerfd_gettime(2)`.
+    fn as_timerfd(&self) -> Option<&TimerFd> {
+        None
+    }
 }
 
 pub trait Symlink: FsNode {
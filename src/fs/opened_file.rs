@@ -7,6 +7,7 @@ use crossbeam_utils::atomic::AtomicCell;
 
 use crate::{
     kerror,
+    task::rlimit::{current_rlimit, RLIMIT_NOFILE},
     userland::buffer::{UserBuffer, UserBufferMut},
     util::{ctypes::c_int, error::KResult},
 };
@@ -15,10 +16,16 @@ use super::{
     devfs::socket::Socket,
     path::PathComponent,
     pipe::{Pipe, PIPE_FS},
+    timerfd::TimerFd,
     DirEntry, DirRef, FileRef, FsNode, INode, PollStatus,
 };
 
-const FD_MAX: c_int = 1024;
+pub const FD_MAX: c_int = 1024;
+
+// RLIMIT_NOFILE can only lower the table size, never raise it
+fn fd_limit() -> c_int {
+    current_rlimit(RLIMIT_NOFILE).min(FD_MAX as u64) as c_int
+}
 
 bitflags! {
     #[derive(Clone, Copy, Debug)]
@@ -255,21 +262,24 @@ impl OpenedFileTable {
     }
 
     fn alloc_fd(&mut self, gte: Option<i32>) -> KResult<FileDesc> {
-        let (mut i, gte) = match gte {
-            Some(gte) => (gte, gte),
-            None => ((self.prev_fd + 1) % FD_MAX, 0),
-        };
-
-        while i != self.prev_fd && i >= gte {
-            if matches!(self.files.get(i as usize), Some(None) | None) {
-                self.prev_fd = i;
-                return Ok(i);
+        let max = fd_limit();
+        let is_free = |fd: c_int| matches!(self.files.get(fd as usize), Some(None) | None);
+        let fd = match gte {
+            Some(gte) if gte >= max => {
+                return Err(kerror!(
+                    EINVAL,
+                    "alloc_fd(): minimum fd above RLIMIT_NOFILE"
+                ))
             }
+            Some(gte) => (gte.max(0)..max).find(|&fd| is_free(fd)),
+            None => (1..=max)
+                .map(|i| (self.prev_fd + i).rem_euclid(max))
+                .find(|&fd| is_free(fd)),
+        };
 
-            i = (i + 1) % FD_MAX;
-        }
-
-        Err(kerror!(ENFILE, "alloc_fd(): cannot alloc file descriptor"))
+        let fd = fd.ok_or(kerror!(EMFILE, "alloc_fd(): cannot alloc file descriptor"))?;
+        self.prev_fd = fd;
+        Ok(fd)
     }
 
     pub fn close_all(&mut self) {
@@ -316,6 +326,9 @@ impl OpenedFileTable {
     }
 
     pub fn dup2(&mut self, old_fd: FileDesc, new_fd: FileDesc) -> KResult<FileDesc> {
+        if new_fd < 0 || new_fd >= fd_limit() {
+            return Err(kerror!(EBADF, "dup2(): new fd above RLIMIT_NOFILE"));
+        }
         let old_file = self.get(old_fd)?;
         let options = old_file.options();
         self.open_with_fd(new_fd, old_file.opened_file, options)?;
@@ -347,6 +360,26 @@ impl OpenedFileTable {
         Ok(fd)
     }
 
+    pub fn open_timerfd(&mut self, clock: usize, options: OpenFlags) -> KResult<FileDesc> {
+        let fd = self.alloc_fd(None)?;
+        let timerfd = Arc::new(TimerFd::new(clock));
+        self.open_with_fd(
+            fd,
+            OpenedFile::new(
+                PathComponent {
+                    parent_dir: None,
+                    name: Arc::new(timerfd.get_name()),
+                    inode: INode::File(timerfd),
+                },
+                options,
+                0,
+            )
+            .into(),
+            options,
+        )?;
+        Ok(fd)
+    }
+
     pub fn open_pipe(&mut self, options: OpenFlags) -> KResult<Arc<Pipe>> {
         let write_fd = self.alloc_fd(None)?;
         let read_fd = self.alloc_fd(Some(write_fd + 1))?;
@@ -0,0 +1,76 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{string::String, sync::Arc};
+
+use crate::{
+    kbail,
+    task::timer::{Timer, TimerNotify},
+    userland::buffer::{UserBufferMut, UserBufferWriter},
+    util::KResult,
+};
+
+use super::{opened_file::OpenFlags, File, FsNode, PollStatus};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TimerFd {
+    id: usize,
+    timer: Arc<Timer>,
+}
+
+impl TimerFd {
+    pub fn new(clock: usize) -> TimerFd {
+        TimerFd {
+            id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+            timer: Timer::new(clock, TimerNotify::Wake),
+        }
+    }
+
+    pub fn timer(&self) -> &Arc<Timer> {
+        &self.timer
+    }
+}
+
+impl Drop for TimerFd {
+    fn drop(&mut self) {
+        // nobody can read it anymore, so don't keep it ticking on the deadline queue
+        self.timer.disarm();
+    }
+}
+
+impl FsNode for TimerFd {
+    fn get_name(&self) -> String {
+        alloc::format!("anon_inode:[timerfd{}]", self.id)
+    }
+}
+
+impl File for TimerFd {
+    fn poll(&self) -> KResult<PollStatus> {
+        let mut status = PollStatus::empty();
+        if self.timer.expirations() > 0 {
+            status |= PollStatus::POLLIN;
+        }
+        Ok(status)
+    }
+
+    fn read(&self, _offset: usize, buf: UserBufferMut, options: &OpenFlags) -> KResult<usize> {
+        if buf.len() < core::mem::size_of::<u64>() {
+            kbail!(EINVAL, "TimerFd::read(): buffer too small");
+        }
+        let mut writer = UserBufferWriter::from_buf(buf);
+        let nonblock = options.contains(OpenFlags::O_NONBLOCK);
+        let expirations = self.timer.wait_queue().sleep_signalable_until(None, || {
+            match self.timer.take_expirations() {
+                0 if nonblock => kbail!(EAGAIN, "TimerFd::read(): timer hasn't expired"),
+                0 => Ok(None),
+                n => Ok(Some(n)),
+            }
+        })?;
+        writer.write(expirations)?;
+        Ok(writer.written_len())
+    }
+
+    fn as_timerfd(&self) -> Option<&TimerFd> {
+        Some(self)
+    }
+}
@@ -20,7 +20,7 @@ pub fn init() {
     GOD_MODE_FIFO.call_once(|| BlockingMutex::new(VecDeque::new()));
     let sched = get_scheduler();
     GOD_MODE_TASK.call_once(|| Task::new_kernel(sched, god_mode_repl, true));
-    sched.push_runnable(GOD_MODE_TASK.get().unwrap().clone(), false);
+    sched.push_runnable(GOD_MODE_TASK.get().unwrap().clone());
 }
 
 fn read_cmd() -> String {
@@ -110,6 +110,10 @@ pub fn god_mode_repl() {
                     serial1_println!();
                 }
             }
+            "t" | "test" => {
+                serial1_println!("Running self tests.");
+                crate::selftest::run_all();
+            }
             "h" | "heap" => {
                 let lock = GLOBAL_ALLOC.try_lock();
                 if let Some(lock) = lock {
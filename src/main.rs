@@ -32,6 +32,7 @@ pub mod util;
 #[macro_use]
 pub mod graphics;
 pub mod god_mode;
+pub mod selftest;
 
 use mem::addr::VirtAddr;
 
@@ -2,6 +2,7 @@ use core::fmt::{self};
 use core::mem::align_of;
 use core::ops::*;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 use crate::kbail;
 use crate::task::current_task;
@@ -320,6 +321,16 @@ impl VirtAddr {
         Ok(())
     }
 
+    // a locked cmpxchg on a user word, which counts as a write even when it fails. returns the
+    // value that was there either way.
+    pub unsafe fn cmpxchg_user(&self, current: u32, new: u32) -> KResult<Result<u32, u32>> {
+        self.align_ok::<u32>()?;
+        (*self + size_of::<u32>()).user_ok()?;
+        let _guard = current_task().arch_mut().address_space.temporarily_switch();
+        let word = unsafe { &*self.as_raw_ptr::<AtomicU32>() };
+        Ok(word.compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst))
+    }
+
     pub unsafe fn write_bytes(&self, bytes: &[u8]) -> KResult<usize> {
         if self.is_null() {
             kbail!(EFAULT, "write_bytes(): null VirtAddr");
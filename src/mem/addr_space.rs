@@ -74,6 +74,17 @@ impl AddressSpace {
         Self { cr3 }
     }
 
+    /// Returns another handle to the same page tables, for tasks that share an address space.
+    pub fn share(&self) -> Self {
+        let cr3 = unsafe {
+            AllocatedFrames::assume_allocated(FrameRange::new(
+                Frame::containing_address(self.cr3()),
+                Frame::containing_address(self.cr3()),
+            ))
+        };
+        Self { cr3 }
+    }
+
     pub fn cr3(&self) -> PhysAddr {
         self.cr3.start_address()
     }
@@ -26,7 +26,7 @@ pub fn alloc_kernel_frames(count: usize) -> KResult<AllocatedFrames> {
     KERNEL_FRAME_ALLOCATOR
         .get()
         .ok_or(kerror!("KERNEL_FRAME_ALLOCATOR not initialized"))?
-        .try_lock()?
+        .lock_checked()?
         .allocate(count)
 }
 
@@ -34,7 +34,7 @@ pub fn alloc_kernel_frames_at(start: Frame, count: usize) -> KResult<AllocatedFr
     KERNEL_FRAME_ALLOCATOR
         .get()
         .ok_or(kerror!("KERNEL_FRAME_ALLOCATOR not initialized"))?
-        .try_lock()?
+        .lock_checked()?
         .allocate_at(start, count)
 }
 
@@ -42,7 +42,7 @@ pub fn alloc_kernel_pages(count: usize) -> KResult<AllocatedPages> {
     KERNEL_PAGE_ALLOCATOR
         .get()
         .ok_or(kerror!("KERNEL_PAGE_ALLOCATOR not initialized"))?
-        .try_lock()?
+        .lock_checked()?
         .allocate(count)
 }
 
@@ -50,7 +50,7 @@ pub fn alloc_kernel_pages_at(start: Page, count: usize) -> KResult<AllocatedPage
     KERNEL_PAGE_ALLOCATOR
         .get()
         .ok_or(kerror!("KERNEL_PAGE_ALLOCATOR not initialized"))?
-        .try_lock()?
+        .lock_checked()?
         .allocate_at(start, count)
 }
 
@@ -58,7 +58,7 @@ pub fn free_kernel_frames(frames: &mut AllocatedFrames, merge: bool) -> KResult<
     KERNEL_FRAME_ALLOCATOR
         .get()
         .ok_or(kerror!("KERNEL_FRAME_ALLOCATOR not initialized"))?
-        .try_lock()?
+        .lock_checked()?
         .free(frames, merge);
     Ok(())
 }
@@ -67,7 +67,7 @@ pub fn free_kernel_pages(pages: &mut AllocatedPages, merge: bool) -> KResult<()>
     KERNEL_PAGE_ALLOCATOR
         .get()
         .ok_or(kerror!("KERNEL_PAGE_ALLOCATOR not initialized"))?
-        .try_lock()?
+        .lock_checked()?
         .free(pages, merge);
     Ok(())
 }
@@ -22,7 +22,6 @@ pub const MIN_HIGH_VADDR: VirtAddr = unsafe { VirtAddr::new_unchecked(0xffff_800
 pub const USER_VALLOC_BASE: VirtAddr = unsafe { VirtAddr::new_unchecked(0x0000_000a_0000_0000) };
 pub const USER_VALLOC_END: VirtAddr = unsafe { VirtAddr::new_unchecked(0x0000_0fff_0000_0000) };
 pub const USER_STACK_TOP: VirtAddr = unsafe { VirtAddr::new_unchecked(0x0000_0fff_ffff_e000) };
-pub const USER_STACK_BOTTOM: VirtAddr = USER_STACK_TOP.const_sub(USER_STACK_SIZE);
 
 pub const KERNEL_HEAP_START: VirtAddr = unsafe { VirtAddr::new_unchecked(0xFFFF_FE80_0000_0000) };
 pub const KERNEL_HEAP_SIZE: usize = 1024 * 1024 * 1024; // 1024 MiB
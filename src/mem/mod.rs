@@ -77,7 +77,7 @@ impl KernelAddrSpaceGuard {
         let _kernel_addr_space = KERNEL_ADDR_SPACE
             .get()
             .ok_or(kerror!("KERNEL_ADDR_SPACE not initialized"))?
-            .try_lock()?;
+            .lock_checked()?;
 
         _kernel_addr_space.switch();
 
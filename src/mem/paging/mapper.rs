@@ -2,6 +2,7 @@ use x86::tlb;
 use x86_64::structures::paging::PageTableFlags;
 
 use crate::{
+    arch::smp,
     mem::{
         addr::{PhysAddr, VirtAddr},
         allocator::alloc_kernel_frames,
@@ -27,6 +28,7 @@ impl PageFlush {
 
     pub fn flush(self) {
         unsafe { tlb::flush(self.0.start_address().value()) }
+        smp::tlb_shootdown(self.0.start_address(), 1);
     }
 }
 
@@ -123,6 +125,8 @@ impl<'a> Mapper<'a> {
             p1[addr.p1_index()].set_flags(flags);
             unsafe { tlb::flush(addr.value()) };
         }
+        // other cpus could still be holding on to the old permissions
+        smp::tlb_shootdown(mp.pages().start_address(), mp.pages().size_in_pages());
         mp.flags = flags;
     }
 
@@ -136,6 +140,7 @@ impl<'a> Mapper<'a> {
             p1[addr.p1_index()].set_unused();
             unsafe { tlb::flush(addr.value()) };
         }
+        smp::tlb_shootdown(mp.pages().start_address(), mp.pages().size_in_pages());
         let MappedPages { pages, frames, .. } = mp;
         (pages, frames)
     }
@@ -149,6 +154,7 @@ impl<'a> Mapper<'a> {
         let old_frame = p1[addr.p1_index()].frame();
         p1[addr.p1_index()].set_unused();
         unsafe { tlb::flush(addr.value()) };
+        smp::tlb_shootdown(addr, 1);
         old_frame
     }
 
@@ -160,5 +166,6 @@ impl<'a> Mapper<'a> {
         let p1 = p2.next_table_mut(addr.p2_index()).unwrap();
         p1[addr.p1_index()].set_flags(flags);
         unsafe { tlb::flush(addr.value()) };
+        smp::tlb_shootdown(addr, 1);
     }
 }
@@ -0,0 +1,33 @@
+use crate::{
+    task::{futex, vmem},
+    util::KResult,
+};
+
+type SelfTest = fn() -> KResult<()>;
+
+// checks that only mean something on a running kernel, since the kernel can't be built for the
+// host's test harness. god mode runs them all with `t`.
+const SELF_TESTS: &[(&str, SelfTest)] = &[
+    (
+        "brk won't grow into another mapping",
+        vmem::selftests::brk_refuses_overlap,
+    ),
+    (
+        "a robust owner's death wakes a private waiter",
+        futex::selftests::robust_death_wakes_private_waiter,
+    ),
+];
+
+pub fn run_all() {
+    let mut failed = 0;
+    for (name, test) in SELF_TESTS {
+        match test() {
+            Ok(()) => serial1_println!("ok    {}", name),
+            Err(err) => {
+                failed += 1;
+                serial1_println!("FAIL  {}: {:?}", name, err);
+            }
+        }
+    }
+    serial1_println!("{} passed, {} failed", SELF_TESTS.len() - failed, failed);
+}
@@ -71,16 +71,19 @@ pub fn _print1(args: ::core::fmt::Arguments) {
     });
 }
 
+// whether there's a byte waiting, without taking it
+#[inline]
+pub fn serial1_ready() -> bool {
+    unsafe { inb(SERIAL1_IOPORT + 5) & 0x1 != 0 }
+}
+
 #[inline]
 pub fn serial1_recv() -> Option<u8> {
     // #[cfg(debug_assertions)]
-    unsafe {
-        let line_sts = inb(SERIAL1_IOPORT + 5);
-        if line_sts & 0x1 != 0 {
-            return Some(inb(SERIAL1_IOPORT));
-        }
-        None
+    if serial1_ready() {
+        return Some(unsafe { inb(SERIAL1_IOPORT) });
     }
+    None
     // #[cfg(not(debug_assertions))]
     // None
 
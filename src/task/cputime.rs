@@ -0,0 +1,124 @@
+use core::ops::{Add, AddAssign};
+
+use crate::arch::time;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuTime {
+    pub user_ns: usize,
+    pub system_ns: usize,
+}
+
+impl CpuTime {
+    pub fn total_ns(&self) -> usize {
+        self.user_ns + self.system_ns
+    }
+}
+
+impl Add for CpuTime {
+    type Output = CpuTime;
+
+    fn add(self, rhs: CpuTime) -> CpuTime {
+        CpuTime {
+            user_ns: self.user_ns + rhs.user_ns,
+            system_ns: self.system_ns + rhs.system_ns,
+        }
+    }
+}
+
+impl AddAssign for CpuTime {
+    fn add_assign(&mut self, rhs: CpuTime) {
+        *self = *self + rhs;
+    }
+}
+
+// charges a thread's time to user or system mode as it crosses between them
+pub struct CpuClock {
+    total: CpuTime,
+    in_kernel: bool,
+    // uptime at the last charge, or None while the thread isn't on the cpu
+    running_since: Option<usize>,
+    // charged, but not yet counted against ITIMER_VIRTUAL and ITIMER_PROF
+    uncounted: CpuTime,
+}
+
+impl CpuClock {
+    pub const fn new(in_kernel: bool) -> CpuClock {
+        CpuClock {
+            total: CpuTime {
+                user_ns: 0,
+                system_ns: 0,
+            },
+            in_kernel,
+            running_since: None,
+            uncounted: CpuTime {
+                user_ns: 0,
+                system_ns: 0,
+            },
+        }
+    }
+
+    fn charge(&mut self) {
+        let Some(since) = self.running_since else {
+            return;
+        };
+        let now = time::get_uptime_ns();
+        let elapsed = now.saturating_sub(since);
+        let spent = if self.in_kernel {
+            CpuTime {
+                user_ns: 0,
+                system_ns: elapsed,
+            }
+        } else {
+            CpuTime {
+                user_ns: elapsed,
+                system_ns: 0,
+            }
+        };
+        self.total += spent;
+        self.uncounted += spent;
+        self.running_since = Some(now);
+    }
+
+    pub fn in_kernel(&self) -> bool {
+        self.in_kernel
+    }
+
+    pub fn enter_kernel(&mut self) {
+        self.charge();
+        self.in_kernel = true;
+    }
+
+    pub fn exit_kernel(&mut self) {
+        self.charge();
+        self.in_kernel = false;
+    }
+
+    pub fn switch_in(&mut self) {
+        self.running_since = Some(time::get_uptime_ns());
+    }
+
+    pub fn switch_out(&mut self) {
+        self.charge();
+        self.running_since = None;
+    }
+
+    pub fn read(&mut self) -> CpuTime {
+        self.charge();
+        self.total
+    }
+
+    // everything spent since the last time the interval timers asked
+    pub fn take_uncounted(&mut self) -> CpuTime {
+        self.charge();
+        core::mem::take(&mut self.uncounted)
+    }
+}
+
+// shared by a whole thread group, so it outlives the threads and children it counts
+#[derive(Debug, Default)]
+pub struct ProcessTimes {
+    pub exited_threads: CpuTime,
+    pub children: CpuTime,
+    // RLIMIT_CPU is only looked at again once the group reaches this many seconds
+    pub next_cpu_limit_check: u64,
+}
@@ -0,0 +1,274 @@
+use alloc::vec::Vec;
+use bitflags::bitflags;
+
+use crate::{
+    fs::{GId, Stat, UId},
+    kbail,
+    util::KResult,
+};
+
+use super::SCHEDULER;
+
+pub const NGROUPS_MAX: usize = 65536;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Access: u32 {
+        const X_OK = 1;
+        const W_OK = 2;
+        const R_OK = 4;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub uid: UId,
+    pub euid: UId,
+    pub suid: UId,
+    pub gid: GId,
+    pub egid: GId,
+    pub sgid: GId,
+    pub groups: Vec<GId>,
+    // PR_SET_NO_NEW_PRIVS: nothing we exec can end up with more privileges than we have
+    pub no_new_privs: bool,
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::root()
+    }
+}
+
+// kernel code running outside of any task acts as root
+pub fn current_credentials() -> Credentials {
+    SCHEDULER
+        .get()
+        .and_then(|sched| sched.current_task_opt())
+        .map(|task| task.credentials())
+        .unwrap_or_default()
+}
+
+impl Credentials {
+    pub const fn root() -> Credentials {
+        Credentials {
+            uid: UId::ROOT,
+            euid: UId::ROOT,
+            suid: UId::ROOT,
+            gid: GId::ROOT,
+            egid: GId::ROOT,
+            sgid: GId::ROOT,
+            groups: Vec::new(),
+            no_new_privs: false,
+        }
+    }
+
+    pub fn is_privileged(&self) -> bool {
+        self.euid == UId::ROOT
+    }
+
+    pub fn in_group(&self, gid: GId) -> bool {
+        self.egid == gid || self.groups.contains(&gid)
+    }
+
+    // ptrace(2) wants every id to match, so nobody gets to trace a setuid program they started
+    pub fn can_trace(&self, target: &Credentials) -> bool {
+        self.is_privileged()
+            || ([target.uid, target.euid, target.suid]
+                .iter()
+                .all(|&id| id == self.uid)
+                && [target.gid, target.egid, target.sgid]
+                    .iter()
+                    .all(|&id| id == self.gid))
+    }
+
+    // kill(2) lets us signal anything that runs as either of our uids, going by its real or
+    // saved one so a setuid program can't shut out the user who started it
+    pub fn can_signal(&self, target: &Credentials) -> bool {
+        self.is_privileged()
+            || [self.uid, self.euid]
+                .iter()
+                .any(|&id| id == target.uid || id == target.suid)
+    }
+
+    // access(2) checks against the real ids instead of the effective ones
+    pub fn with_real_ids(&self) -> Credentials {
+        Credentials {
+            euid: self.uid,
+            egid: self.gid,
+            ..self.clone()
+        }
+    }
+
+    pub fn check_access(&self, stat: &Stat, access: Access) -> KResult<()> {
+        let mode = stat.mode;
+        let perms = mode.permissions();
+        if self.is_privileged() {
+            // root can do anything, except run files nobody is allowed to run
+            if !access.contains(Access::X_OK) || mode.is_directory() || perms & 0o111 != 0 {
+                return Ok(());
+            }
+            kbail!(EACCES, "check_access(): no execute bits set");
+        }
+
+        let (uid, gid) = (stat.uid, stat.gid);
+        let granted = if uid == self.euid {
+            perms >> 6
+        } else if self.in_group(gid) {
+            perms >> 3
+        } else {
+            perms
+        } & 0o7;
+        if granted & access.bits() != access.bits() {
+            kbail!(EACCES, "check_access(): permission denied");
+        }
+        Ok(())
+    }
+
+    // set-user-ID and set-group-ID executables switch the effective ids on exec, unless
+    // `nosuid` says the new program mustn't gain anything over its caller
+    pub fn exec(&mut self, stat: &Stat, nosuid: bool) {
+        let nosuid = nosuid || self.no_new_privs;
+        let mode = stat.mode;
+        if mode.is_setuid() && !nosuid {
+            self.euid = stat.uid;
+        }
+        if mode.is_setgid() && !nosuid {
+            self.egid = stat.gid;
+        }
+        self.suid = self.euid;
+        self.sgid = self.egid;
+    }
+
+    pub fn setuid(&mut self, uid: UId) -> KResult<()> {
+        if self.is_privileged() {
+            self.uid = uid;
+            self.suid = uid;
+        } else if uid != self.uid && uid != self.suid {
+            kbail!(EPERM, "setuid(): not permitted");
+        }
+        self.euid = uid;
+        Ok(())
+    }
+
+    pub fn setgid(&mut self, gid: GId) -> KResult<()> {
+        if self.is_privileged() {
+            self.gid = gid;
+            self.sgid = gid;
+        } else if gid != self.gid && gid != self.sgid {
+            kbail!(EPERM, "setgid(): not permitted");
+        }
+        self.egid = gid;
+        Ok(())
+    }
+
+    pub fn setreuid(&mut self, ruid: Option<UId>, euid: Option<UId>) -> KResult<()> {
+        if !self.is_privileged() {
+            if ruid.is_some_and(|id| id != self.uid && id != self.euid) {
+                kbail!(EPERM, "setreuid(): not permitted");
+            }
+            if euid.is_some_and(|id| id != self.uid && id != self.euid && id != self.suid) {
+                kbail!(EPERM, "setreuid(): not permitted");
+            }
+        }
+        let old_uid = self.uid;
+        if let Some(id) = ruid {
+            self.uid = id;
+        }
+        if let Some(id) = euid {
+            self.euid = id;
+        }
+        if ruid.is_some() || euid.is_some_and(|id| id != old_uid) {
+            self.suid = self.euid;
+        }
+        Ok(())
+    }
+
+    pub fn setregid(&mut self, rgid: Option<GId>, egid: Option<GId>) -> KResult<()> {
+        if !self.is_privileged() {
+            if rgid.is_some_and(|id| id != self.gid && id != self.egid) {
+                kbail!(EPERM, "setregid(): not permitted");
+            }
+            if egid.is_some_and(|id| id != self.gid && id != self.egid && id != self.sgid) {
+                kbail!(EPERM, "setregid(): not permitted");
+            }
+        }
+        let old_gid = self.gid;
+        if let Some(id) = rgid {
+            self.gid = id;
+        }
+        if let Some(id) = egid {
+            self.egid = id;
+        }
+        if rgid.is_some() || egid.is_some_and(|id| id != old_gid) {
+            self.sgid = self.egid;
+        }
+        Ok(())
+    }
+
+    pub fn setresuid(
+        &mut self,
+        ruid: Option<UId>,
+        euid: Option<UId>,
+        suid: Option<UId>,
+    ) -> KResult<()> {
+        if !self.is_privileged() {
+            let allowed = |id: UId| id == self.uid || id == self.euid || id == self.suid;
+            if [ruid, euid, suid]
+                .into_iter()
+                .flatten()
+                .any(|id| !allowed(id))
+            {
+                kbail!(EPERM, "setresuid(): not permitted");
+            }
+        }
+        if let Some(id) = ruid {
+            self.uid = id;
+        }
+        if let Some(id) = euid {
+            self.euid = id;
+        }
+        if let Some(id) = suid {
+            self.suid = id;
+        }
+        Ok(())
+    }
+
+    pub fn setresgid(
+        &mut self,
+        rgid: Option<GId>,
+        egid: Option<GId>,
+        sgid: Option<GId>,
+    ) -> KResult<()> {
+        if !self.is_privileged() {
+            let allowed = |id: GId| id == self.gid || id == self.egid || id == self.sgid;
+            if [rgid, egid, sgid]
+                .into_iter()
+                .flatten()
+                .any(|id| !allowed(id))
+            {
+                kbail!(EPERM, "setresgid(): not permitted");
+            }
+        }
+        if let Some(id) = rgid {
+            self.gid = id;
+        }
+        if let Some(id) = egid {
+            self.egid = id;
+        }
+        if let Some(id) = sgid {
+            self.sgid = id;
+        }
+        Ok(())
+    }
+
+    pub fn setgroups(&mut self, groups: Vec<GId>) -> KResult<()> {
+        if !self.is_privileged() {
+            kbail!(EPERM, "setgroups(): not permitted");
+        }
+        if groups.len() > NGROUPS_MAX {
+            kbail!(EINVAL, "setgroups(): too many groups");
+        }
+        self.groups = groups;
+        Ok(())
+    }
+}
@@ -0,0 +1,432 @@
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::{
+    kbail, kerror,
+    mem::{
+        addr::{PhysAddr, VirtAddr},
+        consts::PAGE_SIZE,
+    },
+    util::{ctypes::c_int, IrqMutex, KResult},
+};
+
+use super::{current_task, get_scheduler, vmem::MMapProt, Task, TaskState};
+
+pub const FUTEX_WAIT: c_int = 0;
+pub const FUTEX_WAKE: c_int = 1;
+pub const FUTEX_REQUEUE: c_int = 3;
+pub const FUTEX_CMP_REQUEUE: c_int = 4;
+pub const FUTEX_WAIT_BITSET: c_int = 9;
+pub const FUTEX_WAKE_BITSET: c_int = 10;
+
+pub const FUTEX_PRIVATE_FLAG: c_int = 128;
+pub const FUTEX_CLOCK_REALTIME: c_int = 256;
+pub const FUTEX_CMD_MASK: c_int = !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME);
+
+pub const FUTEX_BITSET_MATCH_ANY: u32 = u32::MAX;
+
+pub const FUTEX_WAITERS: u32 = 0x80000000;
+pub const FUTEX_OWNER_DIED: u32 = 0x40000000;
+pub const FUTEX_TID_MASK: u32 = 0x3fffffff;
+
+const ROBUST_LIST_LIMIT: usize = 2048;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct RobustListHead {
+    pub list: usize,
+    pub futex_offset: isize,
+    pub list_op_pending: usize,
+}
+
+pub static FUTEX_TABLE: FutexTable = FutexTable::new();
+
+struct FutexWaiter {
+    task: Arc<Task>,
+    bitset: u32,
+}
+
+// a futex in private memory only ever meets threads of the same address space, so where it
+// sits in that address space is enough. one in a shared mapping goes by the frame behind the
+// word, so every mapping of it shares a queue.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum FutexKey {
+    Private(usize, VirtAddr),
+    Shared(PhysAddr),
+}
+
+pub struct FutexTable {
+    waiters: IrqMutex<BTreeMap<FutexKey, VecDeque<FutexWaiter>>>,
+}
+
+impl FutexTable {
+    pub const fn new() -> FutexTable {
+        FutexTable {
+            waiters: IrqMutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn wait(
+        &self,
+        uaddr: VirtAddr,
+        val: u32,
+        timeout: Option<usize>,
+        bitset: u32,
+        private: bool,
+    ) -> KResult<()> {
+        if bitset == 0 {
+            kbail!(EINVAL, "FutexTable::wait(): empty bitset");
+        }
+        let key = futex_key(uaddr, private)?;
+        let current = current_task();
+        {
+            let mut waiters = self.waiters.lock();
+            if unsafe { uaddr.read_user::<u32>() }? != val {
+                kbail!(EAGAIN, "FutexTable::wait(): futex word changed");
+            }
+            // a waker on another cpu can get to us as soon as the lock drops
+            current.set_state(TaskState::Waiting);
+            waiters.entry(key).or_default().push_back(FutexWaiter {
+                task: current.clone(),
+                bitset,
+            });
+        }
+
+        let res = get_scheduler().block(timeout);
+
+        // whoever woke us up also took us off the queue, so if we're still on it we either
+        // ran out of time or got interrupted by a signal
+        if self.remove(key, &current) {
+            res?;
+            kbail!(ETIMEDOUT, "FutexTable::wait(): timeout reached");
+        }
+        Ok(())
+    }
+
+    pub fn wake(
+        &self,
+        uaddr: VirtAddr,
+        count: usize,
+        bitset: u32,
+        private: bool,
+    ) -> KResult<usize> {
+        if bitset == 0 {
+            kbail!(EINVAL, "FutexTable::wake(): empty bitset");
+        }
+        let key = futex_key(uaddr, private)?;
+        let woken = Self::take(&mut self.waiters.lock(), key, count, bitset);
+        let n = woken.len();
+        for waiter in woken {
+            get_scheduler().resume_task(waiter.task);
+        }
+        Ok(n)
+    }
+
+    pub fn requeue(
+        &self,
+        uaddr: VirtAddr,
+        count: usize,
+        uaddr2: VirtAddr,
+        requeue_count: usize,
+        cmp: Option<u32>,
+        private: bool,
+    ) -> KResult<(usize, usize)> {
+        let key = futex_key(uaddr, private)?;
+        let key2 = futex_key(uaddr2, private)?;
+
+        let mut waiters = self.waiters.lock();
+        if let Some(cmp) = cmp {
+            if unsafe { uaddr.read_user::<u32>() }? != cmp {
+                kbail!(EAGAIN, "FutexTable::requeue(): futex word changed");
+            }
+        }
+
+        let woken = Self::take(&mut waiters, key, count, FUTEX_BITSET_MATCH_ANY);
+        let moved = Self::take(&mut waiters, key, requeue_count, FUTEX_BITSET_MATCH_ANY);
+        let n_moved = moved.len();
+        if n_moved > 0 {
+            waiters.entry(key2).or_default().extend(moved);
+        }
+        drop(waiters);
+
+        let n_woken = woken.len();
+        for waiter in woken {
+            get_scheduler().resume_task(waiter.task);
+        }
+        Ok((n_woken, n_moved))
+    }
+
+    pub fn forget(&self, task: &Arc<Task>) {
+        let mut waiters = self.waiters.lock();
+        for queue in waiters.values_mut() {
+            queue.retain(|w| w.task.pid() != task.pid());
+        }
+        waiters.retain(|_, queue| !queue.is_empty());
+    }
+
+    fn take(
+        waiters: &mut BTreeMap<FutexKey, VecDeque<FutexWaiter>>,
+        key: FutexKey,
+        count: usize,
+        bitset: u32,
+    ) -> Vec<FutexWaiter> {
+        let mut taken = Vec::new();
+        if let Some(queue) = waiters.get_mut(&key) {
+            let mut i = 0;
+            while i < queue.len() && taken.len() < count {
+                if queue[i].bitset & bitset != 0 {
+                    taken.push(queue.remove(i).unwrap());
+                } else {
+                    i += 1;
+                }
+            }
+            if queue.is_empty() {
+                waiters.remove(&key);
+            }
+        }
+        taken
+    }
+
+    fn remove(&self, key: FutexKey, task: &Arc<Task>) -> bool {
+        let mut waiters = self.waiters.lock();
+        let Some(queue) = waiters.get_mut(&key) else {
+            return false;
+        };
+        let len = queue.len();
+        queue.retain(|w| w.task.pid() != task.pid());
+        let removed = queue.len() != len;
+        if queue.is_empty() {
+            waiters.remove(&key);
+        }
+        removed
+    }
+}
+
+impl Default for FutexTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// like linux, FUTEX_PRIVATE_FLAG only promises the word isn't shared. a word in private memory
+// is keyed the same way without it, so a private waiter still hears a non-private wake.
+fn futex_key(uaddr: VirtAddr, private: bool) -> KResult<FutexKey> {
+    uaddr.align_ok::<u32>()?;
+    uaddr.user_ok()?;
+    let current = current_task();
+    let vmem = current.vmem();
+    let area = vmem
+        .lock()
+        .area_containing(uaddr, uaddr)
+        .map(|area| (area.is_shared(), area.prot));
+    let prot = match area {
+        Some((true, prot)) if !private => prot,
+        _ => return Ok(FutexKey::Private(Arc::as_ptr(&vmem) as usize, uaddr)),
+    };
+
+    // a copy-on-write page gets a frame of its own on the first write, after which a waker
+    // would find the word somewhere else. so write to it first, if it can be written at all.
+    if prot.contains(MMapProt::PROT_WRITE) {
+        let val = unsafe { uaddr.read_user::<u32>() }?;
+        unsafe { uaddr.cmpxchg_user(val, val) }?.ok();
+    } else {
+        // still make sure demand-paged memory is actually backed by a frame
+        unsafe { uaddr.read_user::<u32>() }?;
+    }
+    let (paddr, flags) = current
+        .arch_mut()
+        .address_space
+        .with_mapper(|mapper| mapper.translate(uaddr))
+        .ok_or(kerror!(EFAULT, "futex_key(): address not mapped"))?;
+    if !flags.contains(PageTableFlags::PRESENT) {
+        kbail!(EFAULT, "futex_key(): address not mapped");
+    }
+    Ok(FutexKey::Shared(paddr + uaddr.value() % PAGE_SIZE))
+}
+
+// marks every lock still held by a dying thread as owner-died, and wakes one of its waiters
+pub fn exit_robust_list(head_addr: VirtAddr, tid: u32) {
+    let Ok(head) = (unsafe { head_addr.read_user::<RobustListHead>() }) else {
+        return;
+    };
+    let futex_of = |entry: usize| VirtAddr::new((entry as isize + head.futex_offset) as usize);
+
+    // the low bit only marks PI futexes, which we don't distinguish
+    let mut entry = head.list & !1;
+    for _ in 0..ROBUST_LIST_LIMIT {
+        if entry == head_addr.value() || entry == 0 {
+            break;
+        }
+        let Ok(next) = (unsafe { VirtAddr::new(entry).read_user::<usize>() }) else {
+            break;
+        };
+        // the pending entry is handled on its own below
+        if entry != head.list_op_pending & !1 {
+            handle_futex_death(futex_of(entry), tid);
+        }
+        entry = next & !1;
+    }
+
+    if head.list_op_pending & !1 != 0 {
+        handle_futex_death(futex_of(head.list_op_pending & !1), tid);
+    }
+}
+
+fn handle_futex_death(uaddr: VirtAddr, tid: u32) {
+    let Ok(mut val) = (unsafe { uaddr.read_user::<u32>() }) else {
+        return;
+    };
+    // the other threads are still running, and may be taking the lock or queueing up on it
+    loop {
+        if val & FUTEX_TID_MASK != tid {
+            return;
+        }
+        let new_val = (val & FUTEX_WAITERS) | FUTEX_OWNER_DIED;
+        match unsafe { uaddr.cmpxchg_user(val, new_val) } {
+            Ok(Ok(_)) => break,
+            Ok(Err(actual)) => val = actual,
+            Err(_) => return,
+        }
+    }
+    // a wake without FUTEX_PRIVATE_FLAG reaches waiters of either kind: private memory is keyed
+    // by address space whatever the flag says, and only shared mappings go by frame
+    if val & FUTEX_WAITERS != 0 {
+        FUTEX_TABLE
+            .wake(uaddr, 1, FUTEX_BITSET_MATCH_ANY, false)
+            .ok();
+    }
+}
+
+pub(crate) mod selftests {
+    use crate::{
+        kbail,
+        mem::{addr::VirtAddr, addr_space::AddressSpace, consts::PAGE_SIZE},
+        task::{
+            current_task, get_scheduler,
+            vmem::{MMapFlags, MMapProt, Vmem},
+            Task,
+        },
+        util::{errno::Errno, IrqMutex, KResult},
+    };
+    use alloc::sync::Arc;
+
+    use super::{
+        RobustListHead, FUTEX_BITSET_MATCH_ANY, FUTEX_OWNER_DIED, FUTEX_TABLE, FUTEX_TID_MASK,
+        FUTEX_WAITERS,
+    };
+
+    const PAGE: usize = 0x2000_0000;
+    const HEAD: usize = PAGE;
+    const ENTRY: usize = PAGE + 0x40;
+    const LOCK: usize = PAGE + 0x80;
+
+    // what the waiter got back, and the lock word it found once it was woken
+    type WaiterResult = (Result<(), Option<Errno>>, u32);
+    static WAITER_RESULT: IrqMutex<Option<WaiterResult>> = IrqMutex::new(None);
+
+    // the owner takes a robust mutex in private memory, lets a FUTEX_WAIT_PRIVATE waiter queue
+    // up on it and dies. the wake from its robust list has to get to that waiter.
+    pub fn robust_death_wakes_private_waiter() -> KResult<()> {
+        let mut addr_space = AddressSpace::new()?;
+        let mut vmem = Vmem::new();
+        let flags = MMapFlags::MAP_FIXED | MMapFlags::MAP_PRIVATE | MMapFlags::MAP_ANONYMOUS;
+        let prot = MMapProt::PROT_READ | MMapProt::PROT_WRITE;
+        addr_space.with_mapper(|mut mapper| {
+            vmem.mmap(
+                VirtAddr::new(PAGE),
+                PAGE_SIZE,
+                prot,
+                flags,
+                -1,
+                0,
+                &mut mapper,
+            )
+        })?;
+        let vmem = Arc::new(IrqMutex::new(vmem));
+        *WAITER_RESULT.lock() = None;
+
+        let sched = get_scheduler();
+        let owner = Task::new_kernel(sched, owner, true);
+        *owner.vmem.borrow_mut() = vmem.clone();
+        owner.arch_mut().address_space = addr_space.share();
+        sched.push_runnable(owner);
+
+        let mut result = None;
+        for _ in 0..200 {
+            result = WAITER_RESULT.lock().take();
+            if result.is_some() {
+                break;
+            }
+            sched.sleep(Some(10)).ok();
+        }
+        addr_space.with_mapper(|mut mapper| vmem.lock().clear(&mut mapper));
+
+        let Some((waited, word)) = result else {
+            kbail!("robust_death_wakes_private_waiter(): waiter never came back");
+        };
+        if let Err(errno) = waited {
+            log::warn!("robust futex waiter failed with {:?}", errno);
+            kbail!("robust_death_wakes_private_waiter(): waiter wasn't woken");
+        }
+        if word != FUTEX_WAITERS | FUTEX_OWNER_DIED {
+            log::warn!("robust futex word is {:#x} after its owner died", word);
+            kbail!("robust_death_wakes_private_waiter(): lock wasn't marked as orphaned");
+        }
+        Ok(())
+    }
+
+    fn owner() {
+        let current = current_task();
+        let tid = current.pid().as_usize() as u32 & FUTEX_TID_MASK;
+        let head = RobustListHead {
+            list: ENTRY,
+            futex_offset: (LOCK - ENTRY) as isize,
+            list_op_pending: 0,
+        };
+        let setup = unsafe {
+            VirtAddr::new(LOCK)
+                .write_user(tid | FUTEX_WAITERS)
+                .and_then(|_| VirtAddr::new(ENTRY).write_user(HEAD))
+                .and_then(|_| VirtAddr::new(HEAD).write_user(head))
+        };
+        if let Err(err) = setup {
+            *WAITER_RESULT.lock() = Some((Err(err.errno()), 0));
+            get_scheduler().exit_current(0);
+            return;
+        }
+        current.robust_list.store(VirtAddr::new(HEAD));
+
+        let sched = get_scheduler();
+        let waiter = Task::new_kernel(sched, waiter, true);
+        *waiter.vmem.borrow_mut() = current.vmem();
+        waiter.arch_mut().address_space = current.arch_mut().address_space.share();
+        sched.push_runnable(waiter.clone());
+
+        // only die once the waiter is really asleep on the lock
+        for _ in 0..100 {
+            let queued = FUTEX_TABLE
+                .waiters
+                .lock()
+                .values()
+                .any(|queue| queue.iter().any(|w| Arc::ptr_eq(&w.task, &waiter)));
+            if queued {
+                break;
+            }
+            sched.sleep(Some(10)).ok();
+        }
+        sched.exit_current(0);
+    }
+
+    fn waiter() {
+        let lock = VirtAddr::new(LOCK);
+        let res = unsafe { lock.read_user::<u32>() }
+            .and_then(|val| FUTEX_TABLE.wait(lock, val, Some(1000), FUTEX_BITSET_MATCH_ANY, true));
+        let word = unsafe { lock.read_user::<u32>() }.unwrap_or(0);
+        *WAITER_RESULT.lock() = Some((res.map_err(|err| err.errno()), word));
+        get_scheduler().exit_current(0);
+    }
+}
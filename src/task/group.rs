@@ -5,9 +5,14 @@ use alloc::{
 
 use crate::util::IrqMutex;
 
-use super::{get_scheduler, signal::Signal, Task};
+use super::{
+    get_scheduler,
+    signal::{SigInfo, Signal, SignalTarget, SI_KERNEL},
+    Task,
+};
 
 pub type PgId = i32;
+pub type SessionId = i32;
 
 pub struct TaskGroup {
     pgid: PgId,
@@ -41,6 +46,10 @@ impl TaskGroup {
         }
     }
 
+    pub fn tasks(&self) -> Vec<Arc<Task>> {
+        self.tasks.iter().filter_map(Weak::upgrade).collect()
+    }
+
     pub fn gc_dropped_processes(&mut self) {
         self.tasks.retain(|task| task.upgrade().is_some());
         if self.tasks.is_empty() {
@@ -49,8 +58,14 @@ impl TaskGroup {
     }
 
     pub fn signal(&mut self, signal: Signal) {
-        for task in self.tasks.iter() {
-            get_scheduler().send_signal_to(task.upgrade().unwrap(), signal);
+        self.signal_with(SigInfo::new(signal, SI_KERNEL));
+    }
+
+    pub fn signal_with(&mut self, info: SigInfo) {
+        for task in self.tasks.iter().filter_map(Weak::upgrade) {
+            get_scheduler()
+                .queue_signal(task, info, SignalTarget::Process)
+                .ok();
         }
     }
 }
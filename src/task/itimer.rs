@@ -0,0 +1,96 @@
+use alloc::sync::Arc;
+
+use crate::{
+    kbail,
+    userland::syscall::syscall_impl::time::{CLOCK_REALTIME, ITIMER_REAL},
+    util::KResult,
+};
+
+use super::{
+    signal::{SigInfo, SignalTarget, SIGALRM, SI_KERNEL},
+    timer::{Timer, TimerNotify},
+    Task,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IntervalTimer {
+    // nanoseconds until it fires, zero while it's disarmed
+    pub value: usize,
+    // what value gets reloaded with after firing, zero for a one-shot timer
+    pub interval: usize,
+}
+
+impl IntervalTimer {
+    // returns whether the timer fired
+    fn advance(&mut self, elapsed: usize) -> bool {
+        if self.value == 0 || elapsed == 0 {
+            return false;
+        }
+        if self.value > elapsed {
+            self.value -= elapsed;
+            return false;
+        }
+        self.value = self.interval;
+        true
+    }
+}
+
+// ITIMER_REAL, ITIMER_VIRTUAL and ITIMER_PROF, shared by every thread in a process
+#[derive(Default)]
+pub struct IntervalTimers {
+    timers: [IntervalTimer; 3],
+    // ITIMER_REAL goes by the wall clock, so it waits on the deadline queue like any other timer
+    real: Option<Arc<Timer>>,
+}
+
+impl IntervalTimers {
+    pub fn get(&self, which: usize) -> KResult<IntervalTimer> {
+        if which == ITIMER_REAL {
+            return Ok(self
+                .real
+                .as_ref()
+                .map_or_else(Default::default, |t| t.get()));
+        }
+        match self.timers.get(which) {
+            Some(timer) => Ok(*timer),
+            None => kbail!(EINVAL, "IntervalTimers::get(): invalid timer"),
+        }
+    }
+
+    // returns the timer as it was before. SIGALRM goes to `owner` when ITIMER_REAL fires.
+    pub fn set(
+        &mut self,
+        which: usize,
+        timer: IntervalTimer,
+        owner: &Arc<Task>,
+    ) -> KResult<IntervalTimer> {
+        let old = self.get(which)?;
+        if which == ITIMER_REAL {
+            let real = self.real.get_or_insert_with(|| {
+                Timer::new(
+                    CLOCK_REALTIME,
+                    TimerNotify::Signal {
+                        task: Arc::downgrade(owner),
+                        info: SigInfo::new(SIGALRM, SI_KERNEL),
+                        target: SignalTarget::Process,
+                    },
+                )
+            });
+            real.set(timer);
+        } else {
+            self.timers[which] = timer;
+        }
+        Ok(old)
+    }
+
+    pub fn advance(&mut self, which: usize, elapsed: usize) -> bool {
+        self.timers[which].advance(elapsed)
+    }
+
+    // the process is gone, so nothing should be left on the deadline queue for it
+    pub fn clear(&mut self) {
+        if let Some(real) = self.real.take() {
+            real.disarm();
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use core::{
     cell::UnsafeCell,
+    mem::size_of,
     sync::atomic::{AtomicUsize, Ordering},
 };
 
@@ -10,7 +11,7 @@ use alloc::{
 use atomic_refcell::AtomicRefCell;
 use crossbeam_utils::atomic::AtomicCell;
 use spin::Once;
-use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::{instructions::interrupts, structures::idt::PageFaultErrorCode};
 
 use crate::{
     arch::{
@@ -18,25 +19,52 @@ use crate::{
         task::ArchTask,
     },
     fs::{
+        devfs::tty::LineDiscipline,
         initramfs::{get_root, root::RootFs},
         opened_file::{FileDesc, LocalOpenedFile, OpenedFileTable},
         FileRef,
     },
-    mem::addr::VirtAddr,
+    kbail,
+    mem::{
+        addr::VirtAddr,
+        consts::{PAGE_SIZE, USER_STACK_SIZE},
+    },
+    userland::elf,
     util::{ctypes::c_int, IrqMutex, KResult},
 };
 
 use self::{
-    group::{PgId, TaskGroup},
+    cputime::{CpuClock, CpuTime, ProcessTimes},
+    cred::Credentials,
+    futex::{exit_robust_list, FUTEX_BITSET_MATCH_ANY, FUTEX_TABLE},
+    group::{PgId, SessionId, TaskGroup},
+    itimer::IntervalTimers,
+    ptrace::Ptrace,
+    rlimit::{RLimits, Resource, RLIMIT_STACK, RLIM_INFINITY},
+    runqueue::SchedEntity,
     scheduler::Scheduler,
-    signal::{SigSet, SignalDelivery, SignalMask},
+    seccomp::SeccompFilter,
+    signal::{
+        RestartBlock, SigAction, SigPending, SigSet, SigStack, Signal, SignalDelivery, SignalMask, MINSIGSTKSZ,
+        SIGSEGV, SS_AUTODISARM, SS_DISABLE, SS_ONSTACK,
+    },
+    timer::PosixTimers,
     vmem::Vmem,
     wait_queue::WaitQueue,
 };
 
+pub mod cputime;
+pub mod cred;
+pub mod futex;
 pub mod group;
+pub mod itimer;
+pub mod ptrace;
+pub mod rlimit;
+pub mod runqueue;
 pub mod scheduler;
+pub mod seccomp;
 pub mod signal;
+pub mod timer;
 pub mod vmem;
 pub mod wait_queue;
 
@@ -72,10 +100,45 @@ impl TaskId {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CloneFlags: usize {
+        const CLONE_VM             = 0x00000100;
+        const CLONE_FS             = 0x00000200;
+        const CLONE_FILES          = 0x00000400;
+        const CLONE_SIGHAND        = 0x00000800;
+        const CLONE_VFORK          = 0x00004000;
+        const CLONE_THREAD         = 0x00010000;
+        const CLONE_SETTLS         = 0x00080000;
+        const CLONE_PARENT_SETTID  = 0x00100000;
+        const CLONE_CHILD_CLEARTID = 0x00200000;
+    }
+}
+
+// wait statuses, laid out the way WIFEXITED() and friends expect them
+pub const fn exited_status(code: c_int) -> c_int {
+    (code & 0xff) << 8
+}
+
+pub const fn signaled_status(signal: Signal) -> c_int {
+    signal & 0x7f
+}
+
+pub const fn core_dumped_status(signal: Signal) -> c_int {
+    signaled_status(signal) | 0x80
+}
+
+pub const fn stopped_status(signal: Signal) -> c_int {
+    ((signal & 0xff) << 8) | 0x7f
+}
+
+pub const CONTINUED_STATUS: c_int = 0xffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TaskState {
     Runnable,
     Waiting,
+    Stopped,
     ExitedWith(c_int),
 }
 
@@ -85,7 +148,9 @@ pub struct Task {
     arch: UnsafeCell<ArchTask>,
     state: AtomicCell<TaskState>,
 
-    pid: TaskId,
+    // a thread that execs takes over its group leader's pid
+    pid: AtomicCell<TaskId>,
+    tgid: TaskId,
 
     pub(crate) start_time: Once<usize>,
 
@@ -94,36 +159,95 @@ pub struct Task {
 
     parent: IrqMutex<Weak<Task>>,
     pub(crate) children: Arc<IrqMutex<Vec<Arc<Task>>>>,
-    pub(crate) group: AtomicRefCell<Weak<IrqMutex<TaskGroup>>>,
-
-    vmem: Arc<IrqMutex<Vmem>>,
+    // other tasks change these for us in setpgid(2), setsid(2) and the tty ioctls, possibly
+    // while we're looking at them from another cpu
+    pub(crate) group: IrqMutex<Weak<IrqMutex<TaskGroup>>>,
+    sid: AtomicCell<SessionId>,
+    pub(crate) cred: Arc<IrqMutex<Credentials>>,
+    pub(crate) rlimits: Arc<IrqMutex<RLimits>>,
+    pub(crate) cpu_clock: IrqMutex<CpuClock>,
+    pub(crate) sched: IrqMutex<SchedEntity>,
+    pub(crate) process_times: Arc<IrqMutex<ProcessTimes>>,
+    pub(crate) itimers: Arc<IrqMutex<IntervalTimers>>,
+    pub(crate) posix_timers: Arc<IrqMutex<PosixTimers>>,
+    // set by exit_group(2), and what the parent gets told instead of however the leader exited
+    pub(crate) group_exit_status: Arc<AtomicCell<Option<c_int>>>,
+    // only meaningful while the terminal still belongs to our session
+    ctty: IrqMutex<Option<Arc<LineDiscipline>>>,
+
+    vmem: AtomicRefCell<Arc<IrqMutex<Vmem>>>,
+    pub(crate) clear_child_tid: AtomicCell<VirtAddr>,
+    pub(crate) robust_list: AtomicCell<VirtAddr>,
+
+    vfork_pending: AtomicCell<bool>,
+    vfork_done: WaitQueue,
+    // a parent can only move its children between process groups until they exec
+    did_exec: AtomicCell<bool>,
+    // a stop or continue the parent hasn't collected through wait4/waitid yet
+    pub(crate) wait_event: AtomicCell<Option<c_int>>,
 
     pub(crate) signals: Arc<IrqMutex<SignalDelivery>>,
-    signaled_frame: AtomicCell<Option<InterruptFrame>>,
     sigset: Arc<IrqMutex<SigSet>>,
+    // signals sent to this thread in particular
+    pub(crate) pending: IrqMutex<SigPending>,
+    // the mask to put back once rt_sigsuspend(2) has delivered its signal
+    pub(crate) saved_sigmask: AtomicCell<Option<SigSet>>,
+    // where restart_syscall(2) carries on from
+    pub(crate) restart_block: AtomicCell<Option<RestartBlock>>,
+    pub(crate) sigaltstack: AtomicCell<SigStack>,
+
+    // set while someone has us under ptrace(2)
+    pub(crate) ptrace: IrqMutex<Option<Ptrace>>,
+    // whoever we're tracing ourselves
+    pub(crate) tracees: IrqMutex<Vec<Arc<Task>>>,
+    // the newest of our syscall filters, which stay with us across fork and exec
+    pub(crate) seccomp: IrqMutex<Option<Arc<SeccompFilter>>>,
 }
 
 unsafe impl Sync for Task {}
 
 impl Task {
-    pub fn new_idle(sched: &mut Scheduler) -> Arc<Task> {
+    pub fn new_idle(sched: &Scheduler) -> Arc<Task> {
         let pid = TaskId::new(0);
         let group = sched.find_or_create_group(0);
         let t = Arc::new_cyclic(|sref| Self {
             sref: sref.clone(),
             arch: UnsafeCell::new(ArchTask::new_idle()),
             state: AtomicCell::new(TaskState::Runnable),
-            pid,
+            pid: AtomicCell::new(pid),
+            tgid: pid,
             start_time: Once::new(),
             parent: IrqMutex::new(Weak::new()),
             children: Arc::new(IrqMutex::new(Vec::new())),
             root_fs: Arc::new(IrqMutex::new(get_root().unwrap().clone())),
             opened_files: Arc::new(IrqMutex::new(OpenedFileTable::new())),
-            vmem: Arc::new(IrqMutex::new(Vmem::new())),
-            signaled_frame: AtomicCell::new(None),
+            vmem: AtomicRefCell::new(Arc::new(IrqMutex::new(Vmem::new()))),
+            clear_child_tid: AtomicCell::new(VirtAddr::null()),
+            robust_list: AtomicCell::new(VirtAddr::null()),
+            vfork_pending: AtomicCell::new(false),
+            vfork_done: WaitQueue::new(),
+            did_exec: AtomicCell::new(false),
+            wait_event: AtomicCell::new(None),
+            cred: Arc::new(IrqMutex::new(Credentials::root())),
+            rlimits: Arc::new(IrqMutex::new(RLimits::new())),
+            cpu_clock: IrqMutex::new(CpuClock::new(true)),
+            sched: IrqMutex::new(SchedEntity::new()),
+            process_times: Arc::new(IrqMutex::new(ProcessTimes::default())),
+            itimers: Arc::new(IrqMutex::new(IntervalTimers::default())),
+            posix_timers: Arc::new(IrqMutex::new(PosixTimers::default())),
+            group_exit_status: Arc::new(AtomicCell::new(None)),
+            sid: AtomicCell::new(0),
+            ctty: IrqMutex::new(None),
             signals: Arc::new(IrqMutex::new(SignalDelivery::new())),
-            sigset: Arc::new(IrqMutex::new(SigSet::ZERO)),
-            group: AtomicRefCell::new(Arc::downgrade(&group)),
+            sigset: Arc::new(IrqMutex::new(SigSet::EMPTY)),
+            pending: IrqMutex::new(SigPending::new()),
+            saved_sigmask: AtomicCell::new(None),
+            restart_block: AtomicCell::new(None),
+            sigaltstack: AtomicCell::new(SigStack::DISABLED),
+            ptrace: IrqMutex::new(None),
+            tracees: IrqMutex::new(Vec::new()),
+            seccomp: IrqMutex::new(None),
+            group: IrqMutex::new(Arc::downgrade(&group)),
         });
         group.lock().add(Arc::downgrade(&t));
         t
@@ -138,42 +262,121 @@ impl Task {
                 VirtAddr::new(entry_point as usize),
                 enable_interrupts,
             )),
-            group: AtomicRefCell::new(Arc::downgrade(&group)),
+            group: IrqMutex::new(Arc::downgrade(&group)),
             state: AtomicCell::new(TaskState::Runnable),
-            pid,
+            pid: AtomicCell::new(pid),
+            tgid: pid,
             start_time: Once::new(),
             parent: IrqMutex::new(Weak::new()),
             children: Arc::new(IrqMutex::new(Vec::new())),
             root_fs: Arc::new(IrqMutex::new(get_root().unwrap().clone())),
             opened_files: Arc::new(IrqMutex::new(OpenedFileTable::new())),
-            vmem: Arc::new(IrqMutex::new(Vmem::new())),
-            signaled_frame: AtomicCell::new(None),
+            vmem: AtomicRefCell::new(Arc::new(IrqMutex::new(Vmem::new()))),
+            clear_child_tid: AtomicCell::new(VirtAddr::null()),
+            robust_list: AtomicCell::new(VirtAddr::null()),
+            vfork_pending: AtomicCell::new(false),
+            vfork_done: WaitQueue::new(),
+            did_exec: AtomicCell::new(false),
+            wait_event: AtomicCell::new(None),
+            cred: Arc::new(IrqMutex::new(Credentials::root())),
+            rlimits: Arc::new(IrqMutex::new(RLimits::new())),
+            cpu_clock: IrqMutex::new(CpuClock::new(true)),
+            sched: IrqMutex::new(SchedEntity::new()),
+            process_times: Arc::new(IrqMutex::new(ProcessTimes::default())),
+            itimers: Arc::new(IrqMutex::new(IntervalTimers::default())),
+            posix_timers: Arc::new(IrqMutex::new(PosixTimers::default())),
+            group_exit_status: Arc::new(AtomicCell::new(None)),
+            sid: AtomicCell::new(0),
+            ctty: IrqMutex::new(None),
             signals: Arc::new(IrqMutex::new(SignalDelivery::new())),
-            sigset: Arc::new(IrqMutex::new(SigSet::ZERO)),
+            sigset: Arc::new(IrqMutex::new(SigSet::EMPTY)),
+            pending: IrqMutex::new(SigPending::new()),
+            saved_sigmask: AtomicCell::new(None),
+            restart_block: AtomicCell::new(None),
+            sigaltstack: AtomicCell::new(SigStack::DISABLED),
+            ptrace: IrqMutex::new(None),
+            tracees: IrqMutex::new(Vec::new()),
+            seccomp: IrqMutex::new(None),
         });
         group.lock().add(Arc::downgrade(&t));
         t
     }
 
     pub fn exec(&self, file: FileRef, argv: &[&[u8]], envp: &[&[u8]]) -> KResult<()> {
+        let stat = file.stat()?;
+        // the stack gets mapped up front, so RLIMIT_STACK can only ever shrink it
+        let stack_size =
+            self.rlimit(RLIMIT_STACK).min(USER_STACK_SIZE as u64) as usize & !(PAGE_SIZE - 1);
+        if stack_size == 0 {
+            kbail!(ENOMEM, "exec(): RLIMIT_STACK too small for a stack");
+        }
+        // leave at least three quarters of the stack to the program itself
+        let args_size: usize = argv
+            .iter()
+            .chain(envp)
+            .map(|arg| arg.len() + 1 + size_of::<usize>())
+            .sum();
+        if args_size > stack_size / 4 {
+            kbail!(E2BIG, "exec(): arguments don't fit on the stack");
+        }
+        // a tracer would get to do whatever a setuid program can
+        let mut cred = self.credentials();
+        cred.exec(&stat, self.is_traced());
+        // the new image is built off to the side, so a file that won't load leaves us as we
+        // were. the loader switches page tables, so nothing may move us in the meantime.
+        let userland_entry = interrupts::without_interrupts(|| elf::load_elf(file))?;
+        // past this point there's no going back to the old program
+        get_scheduler().exec_thread_group(&self.sref.upgrade().unwrap())?;
         {
+            // like on linux, the tid only gets cleared if someone else can still see it
+            let shared_vm = Arc::strong_count(&self.vmem.borrow()) > 1;
+            self.release_futexes(shared_vm);
             self.opened_files.lock().close_cloexec_files();
-            self.arch_mut().address_space.with_mapper(|mut mapper| {
-                self.vmem.lock().clear(&mut mapper);
-            });
-            *self.signals.lock() = SignalDelivery::new();
-            *self.sigset.lock() = SigSet::ZERO;
-            self.signaled_frame.store(None);
+            if shared_vm {
+                // someone else still lives in our old address space, so leave it be
+                *self.vmem.borrow_mut() = Arc::new(IrqMutex::new(Vmem::new()));
+            } else {
+                self.arch_mut().address_space.with_mapper(|mut mapper| {
+                    self.vmem.borrow().lock().clear(&mut mapper);
+                });
+            }
+            // the mask and anything pending live on into the new program
+            self.signals.lock().exec();
+            self.sigaltstack.store(SigStack::DISABLED);
+            self.posix_timers.lock().clear();
         }
-        let lock = &mut self.vmem.lock();
-        unsafe { self.vmem.force_unlock() };
-        self.arch_mut().exec(lock, file, argv, envp)
+        // we don't touch the parent's address space anymore
+        self.release_vfork_parent();
+
+        // exec doesn't return on success, so don't keep any guards alive across it
+        let lock = {
+            let vmem = self.vmem.borrow();
+            let mut lock = vmem.lock();
+            &mut *lock as *mut Vmem
+        };
+        // exec leaves straight for userspace instead of going back through the syscall exit
+        self.cpu_clock.lock().exit_kernel();
+        // the new ids only take hold once the new program has its stack
+        let res = self.arch_mut().exec(
+            unsafe { &mut *lock },
+            userland_entry,
+            argv,
+            envp,
+            stack_size,
+            || {
+                *self.cred.lock() = cred;
+                self.did_exec.store(true);
+            },
+        );
+        // the old program is gone, so there's nobody left to hand the error to
+        get_scheduler().exit_group(signaled_status(SIGSEGV));
+        res
     }
 
     pub fn make_child(&self, arch: UnsafeCell<ArchTask>) -> Arc<Task> {
         let pid = TaskId::allocate();
 
-        let group = self.group.borrow().upgrade().unwrap();
+        let group = self.group.lock().upgrade().unwrap();
         let new = Arc::new_cyclic(|sref| Self {
             sref: sref.clone(),
             arch,
@@ -181,20 +384,43 @@ impl Task {
             opened_files: Arc::new(IrqMutex::new(self.opened_files.lock().clone())), // todo: deeper clone
             children: Arc::new(IrqMutex::new(Vec::new())),
             parent: IrqMutex::new(Weak::new()),
-            group: AtomicRefCell::new(Arc::downgrade(&group)),
+            group: IrqMutex::new(Arc::downgrade(&group)),
             state: AtomicCell::new(TaskState::Runnable),
-            pid,
+            pid: AtomicCell::new(pid),
+            tgid: pid,
             start_time: Once::new(),
-            vmem: Arc::new(IrqMutex::new(Vmem::new())),
-            signals: Arc::new(IrqMutex::new(SignalDelivery::new())),
-            signaled_frame: AtomicCell::new(None),
-            sigset: Arc::new(IrqMutex::new(SigSet::ZERO)),
+            vmem: AtomicRefCell::new(Arc::new(IrqMutex::new(Vmem::new()))),
+            clear_child_tid: AtomicCell::new(VirtAddr::null()),
+            robust_list: AtomicCell::new(VirtAddr::null()),
+            vfork_pending: AtomicCell::new(false),
+            vfork_done: WaitQueue::new(),
+            did_exec: AtomicCell::new(false),
+            wait_event: AtomicCell::new(None),
+            cred: Arc::new(IrqMutex::new(self.credentials())),
+            rlimits: Arc::new(IrqMutex::new(self.rlimits.lock().clone())),
+            // a forked child picks up right where its parent returns to userspace
+            cpu_clock: IrqMutex::new(CpuClock::new(false)),
+            sched: IrqMutex::new(self.sched.lock().fork()),
+            process_times: Arc::new(IrqMutex::new(ProcessTimes::default())),
+            itimers: Arc::new(IrqMutex::new(IntervalTimers::default())),
+            posix_timers: Arc::new(IrqMutex::new(PosixTimers::default())),
+            group_exit_status: Arc::new(AtomicCell::new(None)),
+            sid: AtomicCell::new(self.sid()),
+            ctty: IrqMutex::new(self.ctty.lock().clone()),
+            signals: Arc::new(IrqMutex::new(self.signals.lock().fork())),
+            sigset: Arc::new(IrqMutex::new(*self.sigset.lock())),
+            pending: IrqMutex::new(SigPending::new()),
+            saved_sigmask: AtomicCell::new(None),
+            restart_block: AtomicCell::new(None),
+            sigaltstack: AtomicCell::new(self.sigaltstack.load()),
+            ptrace: IrqMutex::new(None),
+            tracees: IrqMutex::new(Vec::new()),
+            seccomp: IrqMutex::new(self.seccomp.lock().clone()),
         });
         self.add_child(new.clone());
-        new.signals.lock().clone_from(&self.signals.lock());
-        new.vmem.lock().fork_from(&self.vmem.lock());
+        new.vmem().lock().fork_from(&mut self.vmem().lock());
         group.lock().add(Arc::downgrade(&new));
-        get_scheduler().push_runnable(new.clone(), false);
+        get_scheduler().push_runnable(new.clone());
         new
     }
 
@@ -205,41 +431,160 @@ impl Task {
 
     pub fn clone_process(
         &self,
-        entry_point: VirtAddr,
+        flags: CloneFlags,
         user_stack: VirtAddr,
-        args: VirtAddr,
-        r8: usize,
-        r9: usize,
+        tls: VirtAddr,
+        clear_child_tid: VirtAddr,
         syscall_frame: &InterruptFrame,
-    ) -> Arc<Task> {
-        let arch = UnsafeCell::new(
-            self.arch_mut()
-                .clone_process(entry_point, user_stack, args, r8, r9, syscall_frame)
-                .unwrap(),
-        );
+    ) -> KResult<Arc<Task>> {
+        let share_vm = flags.contains(CloneFlags::CLONE_VM);
+        let tls = flags.contains(CloneFlags::CLONE_SETTLS).then_some(tls);
+        let arch = UnsafeCell::new(self.arch_mut().clone_process(
+            share_vm,
+            user_stack,
+            tls,
+            syscall_frame,
+        )?);
         let pid = TaskId::allocate();
+        let is_thread = flags.contains(CloneFlags::CLONE_THREAD);
 
-        let group = self.group.borrow().upgrade().unwrap();
+        let group = self.group.lock().upgrade().unwrap();
         let t = Arc::new_cyclic(|sref| Self {
             sref: sref.clone(),
             arch,
-            opened_files: Arc::new(IrqMutex::new(self.opened_files.lock().clone())), // todo: deeper clone
+            opened_files: if flags.contains(CloneFlags::CLONE_FILES) {
+                self.opened_files.clone()
+            } else {
+                Arc::new(IrqMutex::new(self.opened_files.lock().clone())) // todo: deeper clone
+            },
             state: AtomicCell::new(TaskState::Runnable),
-            pid,
+            pid: AtomicCell::new(pid),
+            tgid: if is_thread { self.tgid } else { pid },
             start_time: Once::new(),
-            root_fs: Arc::new(IrqMutex::new(self.root_fs.lock().clone())), // todo: actually fork the root fs
-            children: Arc::new(IrqMutex::new(Vec::new())),
-            parent: IrqMutex::new(Weak::new()),
-            group: AtomicRefCell::new(Arc::downgrade(&group)),
-            signals: Arc::new(IrqMutex::new(self.signals.lock().clone())),
-            signaled_frame: AtomicCell::new(None),
-            sigset: Arc::new(IrqMutex::new(SigSet::ZERO)),
-            vmem: self.vmem.clone(), // important: we don't fork_from here
+            root_fs: if flags.contains(CloneFlags::CLONE_FS) {
+                self.root_fs.clone()
+            } else {
+                Arc::new(IrqMutex::new(self.root_fs.lock().clone()))
+            },
+            children: if is_thread {
+                self.children.clone()
+            } else {
+                Arc::new(IrqMutex::new(Vec::new()))
+            },
+            parent: IrqMutex::new(if is_thread {
+                self.parent.lock().clone()
+            } else {
+                Weak::new()
+            }),
+            group: IrqMutex::new(Arc::downgrade(&group)),
+            signals: if flags.contains(CloneFlags::CLONE_SIGHAND) {
+                self.signals.clone()
+            } else {
+                Arc::new(IrqMutex::new(self.signals.lock().fork()))
+            },
+            sigset: Arc::new(IrqMutex::new(*self.sigset.lock())),
+            pending: IrqMutex::new(SigPending::new()),
+            saved_sigmask: AtomicCell::new(None),
+            restart_block: AtomicCell::new(None),
+            // a new thread would be sharing the stack with whoever set it up
+            sigaltstack: AtomicCell::new(if share_vm && !flags.contains(CloneFlags::CLONE_VFORK) {
+                SigStack::DISABLED
+            } else {
+                self.sigaltstack.load()
+            }),
+            ptrace: IrqMutex::new(None),
+            tracees: IrqMutex::new(Vec::new()),
+            seccomp: IrqMutex::new(self.seccomp.lock().clone()),
+            vmem: AtomicRefCell::new(if share_vm {
+                self.vmem()
+            } else {
+                let mut vmem = Vmem::new();
+                vmem.fork_from(&mut self.vmem().lock());
+                Arc::new(IrqMutex::new(vmem))
+            }),
+            clear_child_tid: AtomicCell::new(if flags.contains(CloneFlags::CLONE_CHILD_CLEARTID) {
+                clear_child_tid
+            } else {
+                VirtAddr::null()
+            }),
+            robust_list: AtomicCell::new(VirtAddr::null()),
+            vfork_pending: AtomicCell::new(flags.contains(CloneFlags::CLONE_VFORK)),
+            vfork_done: WaitQueue::new(),
+            did_exec: AtomicCell::new(false),
+            wait_event: AtomicCell::new(None),
+            cred: if is_thread {
+                self.cred.clone()
+            } else {
+                Arc::new(IrqMutex::new(self.credentials()))
+            },
+            rlimits: if is_thread {
+                self.rlimits.clone()
+            } else {
+                Arc::new(IrqMutex::new(self.rlimits.lock().clone()))
+            },
+            cpu_clock: IrqMutex::new(CpuClock::new(false)),
+            sched: IrqMutex::new(self.sched.lock().fork()),
+            process_times: if is_thread {
+                self.process_times.clone()
+            } else {
+                Arc::new(IrqMutex::new(ProcessTimes::default()))
+            },
+            itimers: if is_thread {
+                self.itimers.clone()
+            } else {
+                Arc::new(IrqMutex::new(IntervalTimers::default()))
+            },
+            posix_timers: if is_thread {
+                self.posix_timers.clone()
+            } else {
+                Arc::new(IrqMutex::new(PosixTimers::default()))
+            },
+            group_exit_status: if is_thread {
+                self.group_exit_status.clone()
+            } else {
+                Arc::new(AtomicCell::new(None))
+            },
+            sid: AtomicCell::new(self.sid()),
+            ctty: IrqMutex::new(self.ctty.lock().clone()),
         });
-        self.add_child(t.clone());
+        if !is_thread {
+            self.add_child(t.clone());
+        }
         group.lock().add(Arc::downgrade(&t));
-        get_scheduler().push_runnable(t.clone(), false);
-        t
+        Ok(t)
+    }
+
+    // hands the robust futexes we still hold on to their waiters, and wakes up whoever joins on
+    // us through CLONE_CHILD_CLEARTID. `clear_tid` is false when nobody else can see the write,
+    // because the address space is going away with us.
+    pub(crate) fn release_futexes(&self, clear_tid: bool) {
+        let robust_list = self.robust_list.swap(VirtAddr::null());
+        if !robust_list.is_null() {
+            exit_robust_list(robust_list, self.pid().as_usize() as u32);
+        }
+
+        let clear_child_tid = self.clear_child_tid.swap(VirtAddr::null());
+        if clear_tid && !clear_child_tid.is_null() {
+            unsafe { clear_child_tid.write_user::<c_int>(0) }.ok();
+            // like linux, wake it as a shared futex. that still reaches joiners waiting on it as
+            // a private one, since the tid lives in private memory.
+            FUTEX_TABLE
+                .wake(clear_child_tid, 1, FUTEX_BITSET_MATCH_ANY, false)
+                .ok();
+        }
+    }
+
+    // blocks the calling task until this vfork child has exec'd or exited. only SIGKILL gets
+    // us out early, everything else waits until the child is done with our memory.
+    pub fn wait_for_vfork_done(&self) -> KResult<()> {
+        self.vfork_done
+            .sleep_killable_until(|| !self.vfork_pending.load())
+    }
+
+    pub fn release_vfork_parent(&self) {
+        if self.vfork_pending.swap(false) {
+            get_scheduler().wake_all(&self.vfork_done);
+        }
     }
 
     fn add_child(&self, child: Arc<Task>) {
@@ -254,39 +599,129 @@ impl Task {
     }
 
     pub fn pid(&self) -> TaskId {
-        self.pid
+        self.pid.load()
+    }
+
+    pub fn tgid(&self) -> TaskId {
+        self.tgid
+    }
+
+    pub fn is_thread_group_leader(&self) -> bool {
+        self.pid() == self.tgid
+    }
+
+    pub fn parent(&self) -> Option<Arc<Task>> {
+        self.parent.lock().upgrade()
     }
 
     pub fn ppid(&self) -> TaskId {
         if let Some(parent) = self.parent.lock().upgrade() {
-            parent.pid
+            parent.tgid
         } else {
             TaskId::new(0)
         }
     }
 
+    pub fn did_exec(&self) -> bool {
+        self.did_exec.load()
+    }
+
     pub fn pgid(&self) -> Option<PgId> {
-        Some(self.group.borrow().upgrade()?.lock().pgid())
+        let group = self.group.lock().upgrade()?;
+        let pgid = group.lock().pgid();
+        Some(pgid)
+    }
+
+    pub fn credentials(&self) -> Credentials {
+        self.cred.lock().clone()
+    }
+
+    pub fn thread_cpu_time(&self) -> CpuTime {
+        self.cpu_clock.lock().read()
+    }
+
+    // every thread in the group, including the ones that already exited
+    pub fn process_cpu_time(&self) -> CpuTime {
+        let mut total = self.process_times.lock().exited_threads;
+        for thread in get_scheduler().thread_group(self.tgid) {
+            total += thread.thread_cpu_time();
+        }
+        total
+    }
+
+    // what a parent collects on reaping us: our own time plus everything we reaped ourselves
+    pub fn cumulative_cpu_time(&self) -> CpuTime {
+        self.process_cpu_time() + self.process_times.lock().children
+    }
+
+    // the soft limit, which is the one that actually gets enforced
+    pub fn rlimit(&self, resource: Resource) -> u64 {
+        self.rlimits
+            .lock()
+            .get(resource)
+            .map_or(RLIM_INFINITY, |limit| limit.rlim_cur)
+    }
+
+    pub fn sid(&self) -> SessionId {
+        self.sid.load()
+    }
+
+    pub fn is_session_leader(&self) -> bool {
+        self.sid() == self.tgid.as_usize() as SessionId
+    }
+
+    // starts a new session with no controlling terminal
+    pub fn set_sid(&self, sid: SessionId) {
+        self.sid.store(sid);
+        *self.ctty.lock() = None;
+    }
+
+    pub fn controlling_tty(&self) -> Option<Arc<LineDiscipline>> {
+        let tty = self.ctty.lock().clone();
+        tty.filter(|tty| tty.session() == Some(self.sid()))
+    }
+
+    pub fn set_controlling_tty(&self, tty: Option<Arc<LineDiscipline>>) {
+        *self.ctty.lock() = tty;
+    }
+
+    pub fn is_traced(&self) -> bool {
+        self.ptrace
+            .lock()
+            .as_ref()
+            .is_some_and(|p| !p.is_detached())
     }
 
     pub fn get_state(&self) -> TaskState {
         self.state.load()
     }
 
+    // another cpu can kill us at any moment, and once we're dead we stay that way
     pub fn set_state(&self, state: TaskState) {
-        if !matches!(self.get_state(), TaskState::ExitedWith(_)) {
-            self.state.store(state)
-        } else {
-            unreachable!();
-        }
+        self.state
+            .fetch_update(|old| (!matches!(old, TaskState::ExitedWith(_))).then_some(state))
+            .ok();
     }
 
     fn set_parent(&self, parent: Weak<Task>) {
         *self.parent.lock() = parent;
     }
 
+    pub fn join_group(&self, pgid: PgId) {
+        let new = get_scheduler().find_or_create_group(pgid);
+        let mut group = self.group.lock();
+        if let Some(old) = group.upgrade() {
+            if Arc::ptr_eq(&old, &new) {
+                return;
+            }
+            old.lock().remove(&self.sref);
+        }
+        new.lock().add(self.sref.clone());
+        *group = Arc::downgrade(&new);
+    }
+
     pub fn belongs_to_group(&self, pg: &Weak<IrqMutex<TaskGroup>>) -> bool {
-        Weak::ptr_eq(&self.group.borrow(), pg)
+        Weak::ptr_eq(&self.group.lock(), pg)
     }
 
     pub fn get_opened_file_by_fd(&self, fd: FileDesc) -> KResult<LocalOpenedFile> {
@@ -294,7 +729,7 @@ impl Task {
     }
 
     pub fn vmem(&self) -> Arc<IrqMutex<Vmem>> {
-        self.vmem.clone()
+        self.vmem.borrow().clone()
     }
 
     pub fn handle_page_fault(
@@ -304,9 +739,12 @@ impl Task {
         reason: PageFaultErrorCode,
     ) -> KResult<()> {
         let addr_space = &mut self.arch_mut().address_space;
-        self.vmem
-            .try_lock()?
-            .handle_page_fault(addr_space, faulted_addr, stack_frame, reason)
+        self.vmem.borrow().lock_checked()?.handle_page_fault(
+            addr_space,
+            faulted_addr,
+            stack_frame,
+            reason,
+        )
     }
 
     pub fn set_signal_mask(
@@ -318,25 +756,65 @@ impl Task {
     ) -> KResult<()> {
         let mut sigset = self.sigset.lock();
         if !oldset.is_null() {
-            let slice = sigset.as_raw_slice();
-            assert_eq!(slice.len(), 8);
-            unsafe { oldset.write_bytes_user(slice) }?;
+            unsafe { oldset.write_user(sigset.to_user()) }?;
         }
 
         if !set.is_null() {
-            let new_set = unsafe { set.read_user::<[u8; 8]>()? };
-            let new_set = SigSet::new(new_set);
-            match how {
-                SignalMask::Block => *sigset |= new_set,
-                SignalMask::Unblock => *sigset &= !new_set,
-                SignalMask::Set => *sigset = new_set,
-            }
+            let new_set = SigSet::from_user(unsafe { set.read_user::<u64>()? });
+            let new_set = match how {
+                SignalMask::Block => *sigset | new_set,
+                SignalMask::Unblock => *sigset & !new_set,
+                SignalMask::Set => new_set,
+            };
+            *sigset = new_set.blockable();
         }
 
         Ok(())
     }
 
+    pub fn is_signal_ignored_or_blocked(&self, signal: Signal) -> bool {
+        self.signals.lock().get_action(signal) == SigAction::Ignore
+            || self.sigset.lock().contains(signal)
+    }
+
+    pub fn signal_mask(&self) -> SigSet {
+        *self.sigset.lock()
+    }
+
+    pub fn set_signal_mask_to(&self, set: SigSet) {
+        *self.sigset.lock() = set.blockable();
+    }
+
+    // sigaltstack(2), from a thread whose stack pointer is at sp
+    pub fn set_sigaltstack(&self, ss: &SigStack, sp: usize) -> KResult<()> {
+        if self.sigaltstack.load().contains(sp) {
+            kbail!(
+                EPERM,
+                "set_sigaltstack(): can't change the stack while it's in use"
+            );
+        }
+        let new = match ss.ss_flags & !SS_AUTODISARM {
+            SS_DISABLE => SigStack::DISABLED,
+            0 | SS_ONSTACK => {
+                if ss.ss_size < MINSIGSTKSZ {
+                    kbail!(ENOMEM, "set_sigaltstack(): stack is too small");
+                }
+                SigStack::new(ss.ss_sp, ss.ss_flags & SS_AUTODISARM, ss.ss_size)
+            }
+            _ => kbail!(EINVAL, "set_sigaltstack(): invalid flags"),
+        };
+        self.sigaltstack.store(new);
+        Ok(())
+    }
+
+    // blocked signals stay pending without interrupting anything
     pub fn has_pending_signals(&self) -> bool {
-        self.signals.lock().is_pending()
+        !(self.pending_signals() & !self.signal_mask()).is_empty()
+    }
+
+    // everything that could be delivered to this thread, blocked or not
+    pub fn pending_signals(&self) -> SigSet {
+        let shared = self.signals.lock().pending().signals();
+        shared | self.pending.lock().signals()
     }
 }
@@ -0,0 +1,207 @@
+use core::mem::size_of;
+
+use alloc::sync::{Arc, Weak};
+
+use crate::{
+    arch::{
+        idt::InterruptFrame,
+        ptrace::{set_single_step, UserRegs},
+    },
+    kbail,
+    mem::{
+        addr::VirtAddr,
+        consts::{MAX_LOW_VADDR, PAGE_SIZE},
+    },
+    util::{ctypes::c_int, KResult},
+};
+
+use super::{
+    signal::{SigInfo, Signal},
+    Task,
+};
+
+pub const PTRACE_TRACEME: c_int = 0;
+pub const PTRACE_PEEKTEXT: c_int = 1;
+pub const PTRACE_PEEKDATA: c_int = 2;
+pub const PTRACE_POKETEXT: c_int = 4;
+pub const PTRACE_POKEDATA: c_int = 5;
+pub const PTRACE_CONT: c_int = 7;
+pub const PTRACE_KILL: c_int = 8;
+pub const PTRACE_SINGLESTEP: c_int = 9;
+pub const PTRACE_GETREGS: c_int = 12;
+pub const PTRACE_SETREGS: c_int = 13;
+pub const PTRACE_ATTACH: c_int = 16;
+pub const PTRACE_DETACH: c_int = 17;
+pub const PTRACE_SYSCALL: c_int = 24;
+pub const PTRACE_SETOPTIONS: c_int = 0x4200;
+pub const PTRACE_GETSIGINFO: c_int = 0x4202;
+
+// syscall stops report SIGTRAP | 0x80, so they can't be mistaken for a real SIGTRAP
+pub const PTRACE_O_TRACESYSGOOD: usize = 0x1;
+pub const PTRACE_O_EXITKILL: usize = 0x100000;
+pub const PTRACE_SUPPORTED_OPTIONS: usize = PTRACE_O_TRACESYSGOOD | PTRACE_O_EXITKILL;
+
+// what the tracer asked for when it last let the tracee go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtraceResume {
+    Continue,
+    // stop again on the way into and out of the next syscall
+    Syscall,
+    // stop again after one instruction
+    SingleStep,
+}
+
+pub struct Ptrace {
+    tracer: Weak<Task>,
+    pub options: usize,
+    pub resume: PtraceResume,
+    // a stop the tracer hasn't collected through wait4/waitid yet
+    pub event: Option<c_int>,
+    stopped: bool,
+    // the tracee's registers while it's stopped. it's parked in the kernel with the frame on
+    // its own kernel stack, so nobody but the tracer touches them until it's let go.
+    frame: Option<VirtAddr>,
+    // the syscall the tracee stopped in, which the tracer sees (and can change) as orig_rax
+    pub syscall: Option<usize>,
+    // the signal it stopped for
+    pub siginfo: Option<SigInfo>,
+    // what the tracer wants delivered once the tracee is running again, or 0 for nothing
+    signal: Signal,
+}
+
+impl Ptrace {
+    pub fn new(tracer: &Arc<Task>) -> Ptrace {
+        Ptrace {
+            tracer: Arc::downgrade(tracer),
+            options: 0,
+            resume: PtraceResume::Continue,
+            event: None,
+            stopped: false,
+            frame: None,
+            syscall: None,
+            siginfo: None,
+            signal: 0,
+        }
+    }
+
+    pub fn tracer(&self) -> Option<Arc<Task>> {
+        self.tracer.upgrade()
+    }
+
+    pub fn is_traced_by(&self, tracer: &Task) -> bool {
+        self.tracer().is_some_and(|t| t.pid() == tracer.pid())
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // the tracee is about to park itself, with the tracer's wait4 reporting `status`
+    pub fn stop(
+        &mut self,
+        frame: &mut InterruptFrame,
+        status: c_int,
+        siginfo: Option<SigInfo>,
+        syscall: Option<usize>,
+    ) {
+        self.stopped = true;
+        self.event = Some(status);
+        self.frame = Some(VirtAddr::new(frame as *mut InterruptFrame as usize));
+        self.siginfo = siginfo;
+        self.syscall = syscall;
+        self.signal = 0;
+    }
+
+    // the tracee is running again, and takes whatever signal the tracer left it
+    pub fn finish_stop(&mut self) -> Signal {
+        self.stopped = false;
+        self.frame = None;
+        self.signal
+    }
+
+    pub fn frame(&mut self) -> Option<&mut InterruptFrame> {
+        let frame = self.frame.filter(|_| self.stopped)?;
+        Some(unsafe { &mut *frame.as_raw_ptr_mut::<InterruptFrame>() })
+    }
+
+    pub fn regs(&mut self, fs_base: VirtAddr) -> Option<UserRegs> {
+        let syscall = self.syscall;
+        Some(UserRegs::new(self.frame()?, fs_base, syscall))
+    }
+
+    pub fn set_regs(&mut self, regs: &UserRegs) -> KResult<()> {
+        let Some(frame) = self.frame() else {
+            kbail!(ESRCH, "Ptrace::set_regs(): tracee isn't stopped");
+        };
+        regs.apply(frame)?;
+        // changing orig_rax at a syscall stop changes the syscall, and -1 skips it
+        if self.syscall.is_some() {
+            self.syscall = Some(regs.orig_rax as usize);
+        }
+        Ok(())
+    }
+
+    // the caller still has to wake the tracee up
+    pub fn resume(&mut self, resume: PtraceResume, signal: Signal) {
+        if let Some(frame) = self.frame() {
+            set_single_step(frame, resume == PtraceResume::SingleStep);
+        }
+        self.resume = resume;
+        self.signal = signal;
+        self.stopped = false;
+        self.event = None;
+    }
+
+    // the tracee sees that it isn't traced anymore once it's running again
+    pub fn detach(&mut self, signal: Signal) {
+        self.tracer = Weak::new();
+        self.resume(PtraceResume::Continue, signal);
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.tracer.strong_count() == 0
+    }
+}
+
+// PTRACE_PEEKDATA and PTRACE_POKEDATA go straight through the tracee's page tables, since it's
+// the tracer that's running
+pub fn read_word(tracee: &Task, addr: VirtAddr) -> KResult<usize> {
+    let mut word = [0; size_of::<usize>()];
+    copy_word(tracee, addr, &mut word, false)?;
+    Ok(usize::from_ne_bytes(word))
+}
+
+pub fn write_word(tracee: &Task, addr: VirtAddr, value: usize) -> KResult<()> {
+    let mut word = value.to_ne_bytes();
+    copy_word(tracee, addr, &mut word, true)
+}
+
+fn copy_word(
+    tracee: &Task,
+    addr: VirtAddr,
+    word: &mut [u8; size_of::<usize>()],
+    write: bool,
+) -> KResult<()> {
+    if addr.is_null() || addr.value() > MAX_LOW_VADDR.value() - word.len() {
+        kbail!(EIO, "copy_word(): address isn't in userspace");
+    }
+    let vmem = tracee.vmem();
+    let mut vmem = vmem.lock();
+    let addr_space = &mut tracee.arch_mut().address_space;
+    // the word may straddle two pages
+    let mut done = 0;
+    while done < word.len() {
+        let addr = addr + done;
+        let offset = addr.value() % PAGE_SIZE;
+        let len = (PAGE_SIZE - offset).min(word.len() - done);
+        let mem = vmem.ptrace_page(addr_space, addr, write)?.as_hhdm_virt() + offset;
+        let bytes = &mut word[done..done + len];
+        if write {
+            unsafe { mem.write_bytes(bytes) }?;
+        } else {
+            unsafe { mem.read_bytes(bytes) }?;
+        }
+        done += len;
+    }
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use crate::{
+    fs::opened_file::FD_MAX,
+    kbail,
+    mem::consts::USER_STACK_SIZE,
+    util::{ctypes::c_int, KResult},
+};
+
+use super::SCHEDULER;
+
+pub type Resource = c_int;
+
+pub const RLIMIT_CPU: Resource = 0;
+pub const RLIMIT_FSIZE: Resource = 1;
+pub const RLIMIT_DATA: Resource = 2;
+pub const RLIMIT_STACK: Resource = 3;
+pub const RLIMIT_CORE: Resource = 4;
+pub const RLIMIT_RSS: Resource = 5;
+pub const RLIMIT_NPROC: Resource = 6;
+pub const RLIMIT_NOFILE: Resource = 7;
+pub const RLIMIT_MEMLOCK: Resource = 8;
+pub const RLIMIT_AS: Resource = 9;
+pub const RLIMIT_LOCKS: Resource = 10;
+pub const RLIMIT_SIGPENDING: Resource = 11;
+pub const RLIMIT_MSGQUEUE: Resource = 12;
+pub const RLIMIT_NICE: Resource = 13;
+pub const RLIMIT_RTPRIO: Resource = 14;
+pub const RLIMIT_RTTIME: Resource = 15;
+pub const RLIM_NLIMITS: usize = 16;
+
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct RLimit {
+    pub rlim_cur: u64,
+    pub rlim_max: u64,
+}
+
+impl RLimit {
+    pub const INFINITY: RLimit = RLimit::new(RLIM_INFINITY, RLIM_INFINITY);
+
+    pub const fn new(rlim_cur: u64, rlim_max: u64) -> RLimit {
+        RLimit { rlim_cur, rlim_max }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RLimits([RLimit; RLIM_NLIMITS]);
+
+impl Default for RLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const MAX_QUEUED_SIGNALS: u64 = 4096;
+
+// what a process gets when nobody has touched its limits
+const DEFAULT_RLIMITS: [RLimit; RLIM_NLIMITS] = {
+    let mut limits = [RLimit::INFINITY; RLIM_NLIMITS];
+    limits[RLIMIT_STACK as usize] = RLimit::new(USER_STACK_SIZE as u64, RLIM_INFINITY);
+    // like linux, nobody gets a core file unless they ask for one, since it lives in the
+    // kernel heap along with the rest of the ramfs
+    limits[RLIMIT_CORE as usize] = RLimit::new(0, RLIM_INFINITY);
+    limits[RLIMIT_NOFILE as usize] = RLimit::new(FD_MAX as u64, FD_MAX as u64);
+    // only root gets to raise its priority unless someone hands out these two
+    limits[RLIMIT_NICE as usize] = RLimit::new(0, 0);
+    limits[RLIMIT_RTPRIO as usize] = RLimit::new(0, 0);
+    // every queued real-time signal is a kernel allocation somebody else could be making
+    limits[RLIMIT_SIGPENDING as usize] = RLimit::new(MAX_QUEUED_SIGNALS, MAX_QUEUED_SIGNALS);
+    limits
+};
+
+// kernel code running outside of any task is only held to the defaults
+pub fn current_rlimit(resource: Resource) -> u64 {
+    SCHEDULER
+        .get()
+        .and_then(|sched| sched.current_task_opt())
+        .map(|task| task.rlimit(resource))
+        .unwrap_or(DEFAULT_RLIMITS[resource as usize].rlim_cur)
+}
+
+impl RLimits {
+    pub const fn new() -> RLimits {
+        RLimits(DEFAULT_RLIMITS)
+    }
+
+    pub fn get(&self, resource: Resource) -> KResult<RLimit> {
+        if resource < 0 || resource as usize >= RLIM_NLIMITS {
+            kbail!(EINVAL, "RLimits::get(): invalid resource");
+        }
+        Ok(self.0[resource as usize])
+    }
+
+    pub fn set(&mut self, resource: Resource, new: RLimit, privileged: bool) -> KResult<()> {
+        let old = self.get(resource)?;
+        if new.rlim_cur > new.rlim_max {
+            kbail!(EINVAL, "RLimits::set(): soft limit above hard limit");
+        }
+        if new.rlim_max > old.rlim_max && !privileged {
+            kbail!(
+                EPERM,
+                "RLimits::set(): not permitted to raise the hard limit"
+            );
+        }
+        // the descriptor table can't grow past FD_MAX no matter who asks
+        if resource == RLIMIT_NOFILE && new.rlim_max > FD_MAX as u64 {
+            kbail!(EPERM, "RLimits::set(): RLIMIT_NOFILE above FD_MAX");
+        }
+        self.0[resource as usize] = new;
+        Ok(())
+    }
+}
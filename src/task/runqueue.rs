@@ -0,0 +1,280 @@
+use core::cmp::Reverse;
+
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    kbail,
+    util::{ctypes::c_int, KError, KResult},
+};
+
+use super::{Task, TaskId};
+
+pub const SCHED_OTHER: c_int = 0;
+pub const SCHED_FIFO: c_int = 1;
+pub const SCHED_RR: c_int = 2;
+pub const SCHED_BATCH: c_int = 3;
+pub const SCHED_IDLE: c_int = 5;
+pub const SCHED_RESET_ON_FORK: c_int = 0x40000000;
+
+pub const NICE_MIN: c_int = -20;
+pub const NICE_MAX: c_int = 19;
+pub const RT_PRIORITY_MIN: c_int = 1;
+pub const RT_PRIORITY_MAX: c_int = 99;
+
+// how long a SCHED_RR task runs before the next one of the same priority gets a turn
+pub const RR_TIMESLICE_NS: usize = 100_000_000;
+// a task waking up from a sleep gets to cut in line, but only by this much
+const SLEEPER_CREDIT_NS: u64 = 6_000_000;
+
+const NICE_0_WEIGHT: u64 = 1024;
+const IDLE_WEIGHT: u64 = 3;
+// the same table linux uses: every step of nice is worth about 10% of the cpu
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    Other,
+    Fifo,
+    RoundRobin,
+    Batch,
+    Idle,
+}
+
+impl SchedPolicy {
+    pub fn is_realtime(self) -> bool {
+        matches!(self, SchedPolicy::Fifo | SchedPolicy::RoundRobin)
+    }
+}
+
+impl TryFrom<c_int> for SchedPolicy {
+    type Error = KError<'static>;
+
+    fn try_from(policy: c_int) -> KResult<SchedPolicy> {
+        Ok(match policy {
+            SCHED_OTHER => SchedPolicy::Other,
+            SCHED_FIFO => SchedPolicy::Fifo,
+            SCHED_RR => SchedPolicy::RoundRobin,
+            SCHED_BATCH => SchedPolicy::Batch,
+            SCHED_IDLE => SchedPolicy::Idle,
+            _ => kbail!(EINVAL, "SchedPolicy::try_from(): invalid policy"),
+        })
+    }
+}
+
+impl From<SchedPolicy> for c_int {
+    fn from(policy: SchedPolicy) -> c_int {
+        match policy {
+            SchedPolicy::Other => SCHED_OTHER,
+            SchedPolicy::Fifo => SCHED_FIFO,
+            SchedPolicy::RoundRobin => SCHED_RR,
+            SchedPolicy::Batch => SCHED_BATCH,
+            SchedPolicy::Idle => SCHED_IDLE,
+        }
+    }
+}
+
+// everything the scheduler needs to know about a task to decide when it runs
+pub struct SchedEntity {
+    pub policy: SchedPolicy,
+    // 1 to 99 for SCHED_FIFO and SCHED_RR, always 0 otherwise
+    pub rt_priority: c_int,
+    pub nice: c_int,
+    pub reset_on_fork: bool,
+    // cpu time in ns, scaled down by the task's weight
+    vruntime: u64,
+    running_since: Option<usize>,
+    // how long it's run since it last went to the back of its queue
+    slice_used: usize,
+    yielded: bool,
+    // the cpu it last ran on, which probably still has its working set cached
+    pub(super) cpu: Option<usize>,
+    pub(super) on_cpu: bool,
+    // whose run queue it's sitting on, if anyone's
+    pub(super) queued: Option<usize>,
+}
+
+impl SchedEntity {
+    pub const fn new() -> SchedEntity {
+        SchedEntity {
+            policy: SchedPolicy::Other,
+            rt_priority: 0,
+            nice: 0,
+            reset_on_fork: false,
+            vruntime: 0,
+            running_since: None,
+            slice_used: 0,
+            yielded: false,
+            cpu: None,
+            on_cpu: false,
+            queued: None,
+        }
+    }
+
+    // children start out on the same footing as their parent
+    pub fn fork(&self) -> SchedEntity {
+        let mut child = SchedEntity {
+            policy: self.policy,
+            rt_priority: self.rt_priority,
+            nice: self.nice,
+            reset_on_fork: self.reset_on_fork,
+            vruntime: self.vruntime,
+            ..SchedEntity::new()
+        };
+        if self.reset_on_fork {
+            if child.policy.is_realtime() {
+                child.policy = SchedPolicy::Other;
+                child.rt_priority = 0;
+            }
+            child.nice = child.nice.max(0);
+            child.reset_on_fork = false;
+        }
+        child
+    }
+
+    fn weight(&self) -> u64 {
+        match self.policy {
+            SchedPolicy::Idle => IDLE_WEIGHT,
+            _ => NICE_TO_WEIGHT[(self.nice - NICE_MIN) as usize],
+        }
+    }
+
+    pub fn switch_in(&mut self, now: usize) {
+        self.running_since = Some(now);
+    }
+
+    pub fn switch_out(&mut self, now: usize) {
+        let Some(since) = self.running_since.take() else {
+            return;
+        };
+        let elapsed = now.saturating_sub(since);
+        self.slice_used += elapsed;
+        self.vruntime += elapsed as u64 * NICE_0_WEIGHT / self.weight();
+    }
+
+    // sched_yield(2): let everyone else go first the next time around
+    pub fn yield_now(&mut self) {
+        self.yielded = true;
+    }
+
+    // vruntimes only mean something relative to the queue they're on
+    pub fn migrate(&mut self, from_min_vruntime: u64, to_min_vruntime: u64) {
+        self.vruntime = (self.vruntime.saturating_sub(from_min_vruntime)) + to_min_vruntime;
+    }
+}
+
+impl Default for SchedEntity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct RunQueue {
+    // SCHED_FIFO and SCHED_RR tasks, highest priority first and in order within a priority
+    realtime: BTreeMap<(Reverse<c_int>, i64), Arc<Task>>,
+    // everyone else, whoever has had the least weighted cpu time first
+    fair: BTreeMap<(u64, i64), Arc<Task>>,
+    min_vruntime: u64,
+    // breaks ties between equal keys; the front of a queue counts down, the back counts up
+    front_ticket: i64,
+    back_ticket: i64,
+}
+
+impl RunQueue {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> RunQueue {
+        RunQueue {
+            realtime: BTreeMap::new(),
+            fair: BTreeMap::new(),
+            min_vruntime: 0,
+            front_ticket: 0,
+            back_ticket: 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.realtime.len() + self.fair.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn min_vruntime(&self) -> u64 {
+        self.min_vruntime
+    }
+
+    pub fn remove(&mut self, pid: TaskId) {
+        self.realtime.retain(|_, t| t.pid() != pid);
+        self.fair.retain(|_, t| t.pid() != pid);
+    }
+
+    fn insert(&mut self, task: Arc<Task>, entity: &mut SchedEntity, front: bool) {
+        let ticket = if front {
+            self.front_ticket -= 1;
+            self.front_ticket
+        } else {
+            self.back_ticket += 1;
+            self.back_ticket
+        };
+        if !front {
+            entity.slice_used = 0;
+        }
+        if entity.policy.is_realtime() {
+            self.realtime
+                .insert((Reverse(entity.rt_priority), ticket), task);
+        } else {
+            self.fair.insert((entity.vruntime, ticket), task);
+        }
+    }
+
+    // a task that just became runnable
+    pub fn push(&mut self, task: Arc<Task>, entity: &mut SchedEntity) {
+        self.remove(task.pid());
+        // don't let a long sleep bank up enough credit to hog the cpu afterwards
+        entity.vruntime = entity
+            .vruntime
+            .max(self.min_vruntime.saturating_sub(SLEEPER_CREDIT_NS));
+        self.insert(task, entity, false);
+    }
+
+    // the task that was running, still runnable after being interrupted or yielding
+    pub fn requeue(&mut self, task: Arc<Task>, entity: &mut SchedEntity) {
+        self.remove(task.pid());
+        let yielded = core::mem::take(&mut entity.yielded);
+        let front = match entity.policy {
+            SchedPolicy::Fifo => !yielded,
+            SchedPolicy::RoundRobin => !yielded && entity.slice_used < RR_TIMESLICE_NS,
+            _ => {
+                if yielded {
+                    if let Some(&(last, _)) = self.fair.keys().next_back() {
+                        entity.vruntime = entity.vruntime.max(last);
+                    }
+                }
+                false
+            }
+        };
+        self.insert(task, entity, front);
+    }
+
+    // the caller still has to check that the task is runnable and nobody else has claimed it
+    pub fn pop_next(&mut self) -> Option<Arc<Task>> {
+        if let Some((_, task)) = self.realtime.pop_first() {
+            return Some(task);
+        }
+        let ((vruntime, _), task) = self.fair.pop_first()?;
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+        Some(task)
+    }
+
+    // gives another cpu whoever here would have waited the longest anyway
+    pub fn steal(&mut self) -> Option<Arc<Task>> {
+        if let Some((_, task)) = self.fair.pop_last() {
+            return Some(task);
+        }
+        self.realtime.pop_last().map(|(_, task)| task)
+    }
+}
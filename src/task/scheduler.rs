@@ -1,46 +1,129 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use alloc::{
     collections::{BTreeMap, VecDeque},
-    sync::Arc,
+    sync::{Arc, Weak},
     vec::Vec,
 };
-use spin::RwLock;
-use x86_64::instructions::interrupts::enable_and_hlt;
+use spin::{Once, RwLock};
+use x86_64::instructions::interrupts;
 
 use crate::{
     arch::{
-        self,
+        self, apic,
+        cpu_local::cpu_id,
         idt::InterruptFrame,
-        startup_init,
+        smp, startup_init,
         task::{arch_context_switch, ArchTask},
         time,
     },
     fs::POLL_WAIT_QUEUE,
-    kerror,
-    mem::addr::VirtAddr,
+    kbail, kerror,
     task::JOIN_WAIT_QUEUE,
-    util::{ctypes::c_int, IrqMutex, KResult},
+    userland::{
+        coredump,
+        syscall::{
+            syscall_impl::time::{ITIMER_PROF, ITIMER_VIRTUAL},
+            NEVER_RESTARTED_SYSCALLS, NOT_RESTARTED_AFTER_HANDLER, RESTARTED_FROM_BLOCK,
+            SYS_EXECVE, SYS_RESTART_SYSCALL,
+        },
+    },
+    util::{ctypes::c_int, errno::Errno, IrqMutex, KResult},
 };
 
 use super::{
+    core_dumped_status,
+    futex::FUTEX_TABLE,
     get_scheduler,
     group::{PgId, TaskGroup},
-    signal::{SigAction, Signal, SIGCHLD},
+    ptrace::{Ptrace, PtraceResume, PTRACE_O_EXITKILL, PTRACE_O_TRACESYSGOOD},
+    rlimit::{RLIMIT_CPU, RLIMIT_SIGPENDING, RLIM_INFINITY},
+    runqueue::{RunQueue, SchedPolicy},
+    signal::{
+        KSigAction, SigAction, SigInfo, SigSet, SigStack, Signal, SignalTarget, CORE_SIGNALS,
+        SA_NODEFER, SA_ONSTACK, SA_RESETHAND, SA_RESTART, SIGCONT, SIGKILL, SIGPROF, SIGRTMIN,
+        SIGSEGV, SIGSTOP, SIGSYS, SIGTRAP, SIGVTALRM, SIGXCPU, SI_KERNEL, SI_USER, SS_AUTODISARM,
+        STOP_SIGNALS,
+    },
+    signaled_status, stopped_status,
+    timer::Timer,
     wait_queue::WaitQueue,
-    Task, TaskId, TaskState,
+    Task, TaskId, TaskState, CONTINUED_STATUS,
 };
 
+static REAPER_WAIT_QUEUE: WaitQueue = WaitQueue::new();
+
+// sends a syscall that was interrupted with EINTR back around to run again. the frame still
+// has the arguments it came in with, and syscall is two bytes long.
+fn restart_syscall(frame: &mut InterruptFrame, syscall: usize, handler: Option<&KSigAction>) {
+    if frame.rax as isize != -(Errno::EINTR as isize) || NEVER_RESTARTED_SYSCALLS.contains(&syscall)
+    {
+        return;
+    }
+    if let Some(action) = handler {
+        if action.sa_flags & SA_RESTART == 0 || NOT_RESTARTED_AFTER_HANDLER.contains(&syscall) {
+            return;
+        }
+    }
+    frame.rax = if RESTARTED_FROM_BLOCK.contains(&syscall) {
+        SYS_RESTART_SYSCALL
+    } else {
+        syscall
+    };
+    frame.rip -= 2;
+}
+
+// something on the deadline queue: a sleeping task to wake up, or a timer to fire
+enum Deadline {
+    Wake(Arc<Task>),
+    Timer(Arc<Timer>),
+}
+
+impl Deadline {
+    fn is_task(&self, pid: TaskId) -> bool {
+        matches!(self, Deadline::Wake(t) if t.pid() == pid)
+    }
+}
+
+// everything the scheduler keeps separately for each cpu
+struct CpuSched {
+    run_queue: IrqMutex<RunQueue>,
+    current_task: RwLock<Option<Arc<Task>>>,
+    idle_thread: Once<Arc<Task>>,
+    preempt_task: Once<Arc<Task>>,
+    online: AtomicBool,
+    // running something other than its idle thread
+    busy: AtomicBool,
+}
+
+impl CpuSched {
+    fn new() -> CpuSched {
+        CpuSched {
+            run_queue: IrqMutex::new(RunQueue::new()),
+            current_task: RwLock::new(None),
+            idle_thread: Once::new(),
+            preempt_task: Once::new(),
+            online: AtomicBool::new(false),
+            busy: AtomicBool::new(false),
+        }
+    }
+
+    fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    fn load(&self) -> usize {
+        self.run_queue.lock().len() + self.busy.load(Ordering::Relaxed) as usize
+    }
+}
+
 pub struct Scheduler {
     tasks: Arc<IrqMutex<BTreeMap<TaskId, Arc<Task>>>>,
 
-    run_queue: Arc<IrqMutex<VecDeque<Arc<Task>>>>,
+    cpus: Vec<CpuSched>,
     waiting_queue: Arc<IrqMutex<VecDeque<Arc<Task>>>>,
-    #[allow(clippy::type_complexity)]
-    deadline_waiting_queue: Arc<IrqMutex<VecDeque<(Arc<Task>, usize)>>>,
-
-    idle_thread: Option<Arc<Task>>,
-    preempt_task: Option<Arc<Task>>,
+    deadline_waiting_queue: Arc<IrqMutex<VecDeque<(Deadline, usize)>>>,
 
-    current_task: Arc<RwLock<Option<Arc<Task>>>>,
     exited_tasks: Arc<IrqMutex<Vec<Arc<Task>>>>,
 
     pub(super) task_groups: IrqMutex<BTreeMap<PgId, Arc<IrqMutex<TaskGroup>>>>,
@@ -48,99 +131,202 @@ pub struct Scheduler {
 
 impl Scheduler {
     pub fn new() -> Arc<Self> {
-        let mut s = Self {
+        let s = Self {
             tasks: Arc::new(IrqMutex::new(BTreeMap::new())),
-            run_queue: Arc::new(IrqMutex::new(VecDeque::new())),
+            cpus: (0..smp::cpu_count()).map(|_| CpuSched::new()).collect(),
             waiting_queue: Arc::new(IrqMutex::new(VecDeque::new())),
             deadline_waiting_queue: Arc::new(IrqMutex::new(VecDeque::new())),
-            idle_thread: None,
-            preempt_task: None,
-            current_task: Arc::new(RwLock::new(None)),
             task_groups: IrqMutex::new(BTreeMap::new()),
             exited_tasks: Arc::new(IrqMutex::new(Vec::new())),
         };
-        let idle_thread = Task::new_idle(&mut s);
+        s.start_cpu();
         let init_task = Task::new_kernel(&s, startup_init, false);
-        s.push_runnable(init_task, false);
-        let preempt_task = Task::new_kernel(&s, preempt, false);
+        s.push_runnable(init_task);
         let reaper_task = Task::new_kernel(&s, reap, true);
-        s.idle_thread = Some(idle_thread);
-        s.preempt_task = Some(preempt_task);
-        s.push_runnable(reaper_task, false);
+        // it spends nearly all of its time halted, so it shouldn't take turns from real work
+        reaper_task.sched.lock().policy = SchedPolicy::Idle;
+        s.push_runnable(reaper_task);
         Arc::new(s)
     }
 
-    pub fn push_runnable(&self, task: Arc<Task>, priority: bool) {
-        task.state.store(TaskState::Runnable);
-        let mut queue = self.run_queue.lock();
-        self.tasks.lock().try_insert(task.pid, task.clone()).ok();
-        self.waiting_queue.lock().retain(|t| t.pid != task.pid);
-        let already_in_queue = queue.iter().any(|t| t.pid == task.pid);
-        if !already_in_queue {
-            if priority {
-                queue.push_front(task);
-            } else {
-                queue.push_back(task);
+    // gives the calling cpu an idle thread and a preempt task, and starts handing it work
+    pub fn start_cpu(&self) {
+        let cpu = self.this_cpu();
+        cpu.idle_thread.call_once(|| Task::new_idle(self));
+        cpu.preempt_task
+            .call_once(|| Task::new_kernel(self, preempt, false));
+        cpu.online.store(true, Ordering::Release);
+    }
+
+    // only stable while interrupts are off, since otherwise we could be moved to another cpu
+    fn this_cpu(&self) -> &CpuSched {
+        &self.cpus[cpu_id()]
+    }
+
+    // stay where the caches are warm unless somewhere else is clearly less busy
+    fn pick_cpu(&self, last: Option<usize>) -> usize {
+        let loads = self
+            .cpus
+            .iter()
+            .enumerate()
+            .filter(|(_, cpu)| cpu.is_online())
+            .map(|(i, cpu)| (i, cpu.load()))
+            .collect::<Vec<_>>();
+        let (best, best_load) = loads
+            .iter()
+            .copied()
+            .min_by_key(|&(_, load)| load)
+            .unwrap_or((0, 0));
+        match last.and_then(|last| loads.iter().find(|&&(i, _)| i == last)) {
+            Some(&(last, load)) if load < best_load + 2 => last,
+            _ => best,
+        }
+    }
+
+    pub fn push_runnable(&self, task: Arc<Task>) {
+        self.tasks.lock().try_insert(task.pid(), task.clone()).ok();
+        let target = {
+            let mut entity = task.sched.lock();
+            if matches!(task.get_state(), TaskState::ExitedWith(_)) {
+                // killed while it was asleep; it must never run again
+                return;
             }
+            task.set_state(TaskState::Runnable);
+            self.waiting_queue.lock().retain(|t| t.pid() != task.pid());
+            self.deadline_waiting_queue
+                .lock()
+                .retain(|(d, _)| !d.is_task(task.pid()));
+            // still running somewhere, so it'll be put back on a queue when it's switched out
+            if entity.on_cpu || entity.queued.is_some() {
+                return;
+            }
+            let target = self.pick_cpu(entity.cpu);
+            self.cpus[target]
+                .run_queue
+                .lock()
+                .push(task.clone(), &mut entity);
+            entity.queued = Some(target);
+            target
+        };
+        // an idle cpu has no tick coming to notice it has work, even if it's this one
+        if !self.cpus[target].busy.load(Ordering::Relaxed) {
+            smp::send_reschedule(target);
         }
     }
 
-    pub fn push_waiting(&self, task: Arc<Task>) {
-        task.state.store(TaskState::Waiting);
-        let mut queue = self.waiting_queue.lock();
-        self.tasks.lock().try_insert(task.pid, task.clone()).ok();
-        self.run_queue.lock().retain(|t| t.pid != task.pid);
-        self.deadline_waiting_queue
-            .lock()
-            .retain(|(t, _)| t.pid != task.pid);
-        let already_in_queue = queue.iter().any(|t| t.pid == task.pid);
-        if !already_in_queue {
-            queue.push_back(task);
+    // sched_setscheduler(2) and friends may have moved it to a different queue
+    pub fn requeue(&self, task: &Arc<Task>) {
+        let mut entity = task.sched.lock();
+        if let Some(cpu) = entity.queued {
+            self.cpus[cpu]
+                .run_queue
+                .lock()
+                .push(task.clone(), &mut entity);
         }
     }
 
-    pub fn push_deadline_waiting(&self, task: Arc<Task>, duration: usize) {
-        task.state.store(TaskState::Waiting);
-        let mut queue = self.deadline_waiting_queue.lock();
-        self.tasks.lock().try_insert(task.pid, task.clone()).ok();
-        self.run_queue.lock().retain(|t| t.pid != task.pid);
-        self.waiting_queue.lock().retain(|t| t.pid != task.pid);
-        let already_in_queue = queue.iter().any(|(t, _)| t.pid == task.pid);
-        if !already_in_queue {
-            let deadline = arch::time::get_uptime_ms() + duration;
-            queue.push_back((task, deadline));
+    // takes the current task off the cpu until someone wakes it up, unless they already have
+    pub fn park(&self, duration: Option<usize>) {
+        let task = self.current_task();
+        {
+            let _entity = task.sched.lock();
+            match task.get_state() {
+                TaskState::Runnable => return,
+                TaskState::Waiting => {
+                    if let Some(duration) = duration {
+                        let deadline = arch::time::get_uptime_ms().saturating_add(duration);
+                        let mut queue = self.deadline_waiting_queue.lock();
+                        if !queue.iter().any(|(d, _)| d.is_task(task.pid())) {
+                            queue.push_back((Deadline::Wake(task.clone()), deadline));
+                        }
+                    } else {
+                        let mut queue = self.waiting_queue.lock();
+                        if !queue.iter().any(|t| t.pid() == task.pid()) {
+                            queue.push_back(task.clone());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.preempt();
+    }
+
+    // like park(), but a pending signal keeps us from going to sleep or cuts the sleep short
+    pub fn block(&self, duration: Option<usize>) -> KResult<()> {
+        let task = self.current_task();
+        if task.has_pending_signals() {
+            self.resume_task(task.clone());
+        } else {
+            self.park(duration);
+        }
+
+        if task.has_pending_signals() {
+            Err(kerror!(EINTR, "block(): pending signals"))
+        } else {
+            Ok(())
         }
     }
 
+    // `deadline` is in ms of uptime, like everything else on the deadline queue
+    pub fn arm_timer(&self, timer: Arc<Timer>, deadline: usize) {
+        self.deadline_waiting_queue
+            .lock()
+            .push_back((Deadline::Timer(timer), deadline));
+    }
+
+    pub fn disarm_timer(&self, timer: &Arc<Timer>) {
+        self.deadline_waiting_queue
+            .lock()
+            .retain(|(d, _)| !matches!(d, Deadline::Timer(t) if Arc::ptr_eq(t, timer)));
+    }
+
+    // in ns of uptime, for the timer of a cpu with nothing better to do
+    fn next_deadline(&self) -> Option<usize> {
+        self.deadline_waiting_queue
+            .lock()
+            .iter()
+            .map(|&(_, deadline)| deadline * 1000000)
+            .min()
+    }
+
     fn check_deadline(&self) {
         let time = arch::time::get_uptime_ms();
         let mut queue = self.deadline_waiting_queue.lock();
         for _ in 0..queue.len() {
-            if let Some((task, deadline)) = queue.pop_front() {
+            if let Some((entry, deadline)) = queue.pop_front() {
                 if deadline <= time {
                     // time's up!
                     drop(queue);
-                    self.push_runnable(task, true);
+                    match entry {
+                        Deadline::Wake(task) => self.push_runnable(task),
+                        Deadline::Timer(timer) => {
+                            // periodic timers go right back on the queue
+                            if let Some(next) = timer.fire(time) {
+                                self.arm_timer(timer, next);
+                            }
+                        }
+                    }
                     queue = self.deadline_waiting_queue.lock();
                 } else {
-                    queue.push_back((task, deadline));
+                    queue.push_back((entry, deadline));
                 }
             }
         }
     }
 
     pub fn current_task_opt(&self) -> Option<Arc<Task>> {
-        let current = self.current_task.try_read()?;
-        let clone = current.as_ref().cloned();
-        drop(current);
-        clone
+        interrupts::without_interrupts(|| {
+            let current = self.this_cpu().current_task.try_read()?;
+            current.as_ref().cloned()
+        })
     }
 
     pub fn current_task(&self) -> Arc<Task> {
-        let current = self.current_task.read();
-        let clone = current.as_ref().unwrap().clone();
-        drop(current);
-        clone
+        interrupts::without_interrupts(|| {
+            let current = self.this_cpu().current_task.read();
+            current.as_ref().unwrap().clone()
+        })
     }
 
     pub fn find_task(&self, pid: TaskId) -> Option<Arc<Task>> {
@@ -161,31 +347,213 @@ impl Scheduler {
 
     pub fn exit_current(&self, status: c_int) {
         let current = self.current_task();
-        if current.pid.as_usize() == 1 {
-            panic!("init (pid=1) tried to exit with status {}", status);
+        self.exit_task(current, status);
+        self.wake_all(&JOIN_WAIT_QUEUE);
+        self.preempt();
+    }
+
+    pub fn exit_group(&self, status: c_int) {
+        let current = self.current_task();
+        // the first one to get here decides the status and takes the rest of the group down.
+        // the others, and every thread killed along the way, just go with that status.
+        match current
+            .group_exit_status
+            .compare_exchange(None, Some(status))
+        {
+            Ok(_) => {
+                self.kill_other_threads(&current);
+                self.exit_current(status);
+            }
+            Err(group_status) => self.exit_current(group_status.unwrap_or(status)),
         }
+    }
 
-        current.set_state(TaskState::ExitedWith(status));
-        if let Some(parent) = current.parent.lock().upgrade() {
-            let mut parent_signals = parent.signals.lock();
-            if parent_signals.get_action(SIGCHLD) == SigAction::Ignore {
-                parent.children.lock().retain(|p| p.pid != current.pid);
-            } else {
-                log::debug!("Sending SIGCHLD to {}", parent.pid.as_usize());
-                parent_signals.signal(SIGCHLD);
+    // like linux's zap_other_threads(). a thread can't be torn down from the outside while it
+    // may be in the middle of a syscall, so each one gets a SIGKILL and exits on its own the
+    // next time it heads back to userspace or wakes up.
+    fn kill_other_threads(&self, current: &Arc<Task>) {
+        for thread in self.thread_group(current.tgid) {
+            if thread.pid() == current.pid() {
+                continue;
+            }
+            self.queue_signal(
+                thread.clone(),
+                SigInfo::new(SIGKILL, SI_KERNEL),
+                SignalTarget::Thread,
+            )
+            .ok();
+            // one spinning in userspace on another cpu only notices on an interrupt
+            let running_on = {
+                let entity = thread.sched.lock();
+                entity.cpu.filter(|_| entity.on_cpu)
+            };
+            if let Some(cpu) = running_on.filter(|&cpu| cpu != cpu_id()) {
+                smp::send_reschedule(cpu);
+            }
+        }
+    }
+
+    // exec(2) leaves only the calling thread behind, and it takes over the leader's pid so the
+    // parent still sees the same child
+    pub fn exec_thread_group(&self, current: &Arc<Task>) -> KResult<()> {
+        let tgid = current.tgid;
+        // the leader may already be a zombie that only the parent still knows about
+        let leader = self.find_task(tgid).or_else(|| {
+            let parent = current.parent()?;
+            let children = parent.children.lock();
+            children.iter().find(|c| c.pid() == tgid).cloned()
+        });
+        // the group looks like it's exiting until the others are gone, so they don't try to
+        // take us down with them. exit_group(2) or another exec may have gotten there first.
+        if current
+            .group_exit_status
+            .compare_exchange(None, Some(signaled_status(SIGKILL)))
+            .is_err()
+        {
+            kbail!(EAGAIN, "exec_thread_group(): the thread group is already exiting");
+        }
+        self.kill_other_threads(current);
+        // someone else's SIGKILL leaves the status set, since we're going down too
+        JOIN_WAIT_QUEUE.sleep_killable_until(|| self.thread_group(tgid).len() == 1)?;
+        current.group_exit_status.store(None);
+        if current.is_thread_group_leader() {
+            return Ok(());
+        }
+
+        let old_pid = current.pid();
+        // like linux's de_thread(), the old leader goes away under our old tid. it has to give
+        // up the pid before we take it, or reaping it could take us out of the task list.
+        if let Some(leader) = leader.as_ref() {
+            leader.pid.store(old_pid);
+        }
+        current.pid.store(tgid);
+        {
+            let mut tasks = self.tasks.lock();
+            tasks.remove(&old_pid);
+            tasks.insert(tgid, current.clone());
+        }
+        if let Some(leader) = leader {
+            let parent = leader.parent();
+            if let Some(parent) = parent.as_ref() {
+                for child in parent.children.lock().iter_mut() {
+                    if Arc::ptr_eq(child, &leader) {
+                        *child = current.clone();
+                    }
+                }
+            }
+            current.set_parent(parent.as_ref().map_or_else(Weak::new, Arc::downgrade));
+        }
+        Ok(())
+    }
+
+    fn exit_task(&self, task: Arc<Task>, status: c_int) {
+        {
+            let mut entity = task.sched.lock();
+            // only the first exit gets to tear it down
+            if matches!(task.get_state(), TaskState::ExitedWith(_)) {
+                return;
+            }
+            task.set_state(TaskState::ExitedWith(status));
+            if let Some(cpu) = entity.queued.take() {
+                self.cpus[cpu].run_queue.lock().remove(task.pid());
             }
         }
 
-        current.opened_files.lock().close_all();
-        self.run_queue.lock().retain(|t| t.pid != current.pid);
-        self.waiting_queue.lock().retain(|t| t.pid != current.pid);
+        let spent = task.thread_cpu_time();
+        task.process_times.lock().exited_threads += spent;
+
+        task.release_futexes(true);
+
+        task.release_vfork_parent();
+
+        // our tracees carry on without us, unless they were meant to go down with us
+        let tracees = core::mem::take(&mut *task.tracees.lock());
+        for tracee in tracees {
+            let exitkill = tracee
+                .ptrace
+                .lock()
+                .as_ref()
+                .is_some_and(|p| p.options & PTRACE_O_EXITKILL != 0);
+            self.untrace(tracee.clone(), 0);
+            if exitkill {
+                self.send_signal_to(tracee, SIGKILL);
+            }
+        }
+
+        self.waiting_queue.lock().retain(|t| t.pid() != task.pid());
         self.deadline_waiting_queue
             .lock()
-            .retain(|t| t.0.pid != current.pid);
-        self.tasks.lock().remove(&current.pid);
-        self.exited_tasks.lock().push(current);
-        self.wake_all(&JOIN_WAIT_QUEUE);
-        self.preempt();
+            .retain(|(d, _)| !d.is_task(task.pid()));
+        self.tasks.lock().remove(&task.pid());
+
+        // CLONE_FILES can share the table with other processes too, so it stays open for as
+        // long as any of them is still alive
+        let files_in_use = self
+            .tasks
+            .lock()
+            .values()
+            .any(|t| Arc::ptr_eq(&t.opened_files, &task.opened_files));
+        if !files_in_use {
+            task.opened_files.lock().close_all();
+        }
+
+        // a tracer that isn't also the parent hears about every thread
+        let tracer = task.ptrace.lock().as_ref().and_then(|p| p.tracer());
+        if let Some(tracer) = tracer.filter(|t| t.tgid != task.ppid()) {
+            let info = SigInfo::child(task.pid().as_usize() as c_int, status);
+            self.queue_signal(tracer, info, SignalTarget::Process).ok();
+        }
+
+        // the parent only hears about the thread group once its last thread is gone
+        if !self.is_thread_group_alive(task.tgid) {
+            // zombie threads and the parent's view of the leader still hold on to these, so
+            // going by reference counts would never let them go
+            task.posix_timers.lock().clear();
+            task.itimers.lock().clear();
+
+            if task.tgid.as_usize() == 1 {
+                panic!("init (pid=1) tried to exit with wait status {:#x}", status);
+            }
+
+            if task.is_session_leader() {
+                if let Some(tty) = task.controlling_tty() {
+                    tty.hangup();
+                }
+            }
+
+            let parent = task.parent.lock().upgrade();
+            if let Some(parent) = parent {
+                let nocldwait = parent.signals.lock().is_nocldwait();
+                if nocldwait {
+                    parent.children.lock().retain(|p| p.pid() != task.tgid);
+                } else {
+                    log::debug!("Sending SIGCHLD to {}", parent.pid().as_usize());
+                    let status = task.group_exit_status.load().unwrap_or(status);
+                    let info = SigInfo::child(task.tgid.as_usize() as c_int, status);
+                    self.queue_signal(parent, info, SignalTarget::Process).ok();
+                }
+            }
+        }
+
+        self.exited_tasks.lock().push(task);
+        self.wake_all(&REAPER_WAIT_QUEUE);
+    }
+
+    pub fn thread_group(&self, tgid: TaskId) -> Vec<Arc<Task>> {
+        self.find_tasks(|t| t.tgid == tgid)
+    }
+
+    pub fn find_tasks(&self, filter: impl Fn(&Task) -> bool) -> Vec<Arc<Task>> {
+        self.tasks
+            .lock()
+            .values()
+            .filter(|t| filter(t))
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_thread_group_alive(&self, tgid: TaskId) -> bool {
+        self.tasks.lock().values().any(|t| t.tgid == tgid)
     }
 
     pub fn wake_all(&self, queue: &WaitQueue) {
@@ -196,62 +564,656 @@ impl Scheduler {
     }
 
     pub fn send_signal_to(&self, task: Arc<Task>, signal: Signal) {
-        task.signals.lock().signal(signal);
-        self.resume_task(task);
+        // only real-time signals can fail to queue
+        self.queue_signal(task, SigInfo::new(signal, SI_KERNEL), SignalTarget::Process)
+            .ok();
+    }
+
+    pub fn queue_signal(
+        &self,
+        task: Arc<Task>,
+        info: SigInfo,
+        target: SignalTarget,
+    ) -> KResult<()> {
+        let signal = info.si_signo;
+        let blocked = task.signal_mask();
+        let threads = self.thread_group(task.tgid);
+        let traced = task.is_traced();
+        let ignored = {
+            let mut signals = task.signals.lock();
+            let cancelled: &[Signal] = if signal == SIGCONT {
+                &STOP_SIGNALS
+            } else if STOP_SIGNALS.contains(&signal) {
+                &[SIGCONT]
+            } else {
+                &[]
+            };
+            for &other in cancelled {
+                signals.discard(other);
+                for thread in threads.iter() {
+                    thread.pending.lock().discard(other);
+                }
+            }
+
+            // nobody would ever see it, unless they unblock it after changing the handler. a
+            // tracer still gets to.
+            let ignored = signals.get_action(signal) == SigAction::Ignore
+                && !blocked.contains(signal)
+                && !traced;
+            if !ignored {
+                if signal >= SIGRTMIN {
+                    // linux counts these per user, but a process is as close as we get
+                    let queued = signals.pending().len()
+                        + threads
+                            .iter()
+                            .map(|thread| thread.pending.lock().len())
+                            .sum::<usize>();
+                    if queued as u64 >= task.rlimit(RLIMIT_SIGPENDING) {
+                        kbail!(EAGAIN, "queue_signal(): too many signals queued");
+                    }
+                }
+                match target {
+                    SignalTarget::Process => signals.pending_mut().push(info),
+                    SignalTarget::Thread => task.pending.lock().push(info),
+                }
+            }
+            ignored
+        };
+        // a stopped task has to get back on the cpu to notice it's been killed
+        if signal == SIGCONT || signal == SIGKILL {
+            self.continue_group(&task);
+        }
+        if ignored {
+            return Ok(());
+        }
+
+        // any thread that isn't blocking a process-directed signal can take it
+        let taker = if target == SignalTarget::Thread || !blocked.contains(signal) {
+            Some(task)
+        } else {
+            threads
+                .into_iter()
+                .find(|thread| !thread.signal_mask().contains(signal))
+        };
+        if let Some(taker) = taker {
+            self.resume_task(taker);
+        }
+        Ok(())
+    }
+
+    fn continue_group(&self, task: &Task) {
+        {
+            let mut signals = task.signals.lock();
+            if !signals.is_stopped() {
+                return;
+            }
+            signals.set_stopped(false);
+        }
+        for thread in self.thread_group(task.tgid) {
+            if thread.get_state() == TaskState::Stopped {
+                self.push_runnable(thread);
+            }
+        }
+        self.notify_parent(task, CONTINUED_STATUS);
+    }
+
+    // lets the parent know through SIGCHLD and wait4 that the thread group stopped or continued
+    fn notify_parent(&self, task: &Task, status: c_int) {
+        let Some(parent) = task.parent.lock().upgrade() else {
+            return;
+        };
+        if let Some(leader) = parent.children.lock().iter().find(|c| c.pid() == task.tgid) {
+            leader.wait_event.store(Some(status));
+        }
+        let nocldstop = parent.signals.lock().is_nocldstop();
+        if !nocldstop {
+            let info = SigInfo::child(task.tgid.as_usize() as c_int, status);
+            self.queue_signal(parent, info, SignalTarget::Process).ok();
+        }
+        self.wake_all(&JOIN_WAIT_QUEUE);
     }
 
     pub fn resume_task(&self, task: Arc<Task>) {
-        self.push_runnable(task, false);
+        self.push_runnable(task);
     }
 
-    pub fn try_delivering_signal(
+    // runs on the way back to userspace from the syscall in the frame, which already has its
+    // return value in rax
+    pub fn try_delivering_signal(&self, frame: &mut InterruptFrame, syscall: usize) -> KResult<()> {
+        let current = self.current_task();
+        // a signal that didn't need a handler still woke the syscall up
+        let mut handled_quietly = false;
+        loop {
+            // every thread of a stopped group parks here on its way back to userspace
+            self.stop_current(&current);
+
+            let blocked = current.signal_mask();
+            let pending = current
+                .signals
+                .lock()
+                .dequeue(&mut current.pending.lock(), |signal, _| {
+                    !blocked.contains(signal)
+                });
+            let Some((info, sigaction)) = pending else {
+                break;
+            };
+            let Some((info, sigaction)) =
+                self.trace_signal(&current, frame, info, sigaction, Some(syscall))
+            else {
+                handled_quietly = true;
+                continue;
+            };
+            let signal = info.si_signo;
+            match sigaction {
+                SigAction::Ignore => handled_quietly = true,
+                SigAction::Stop => {
+                    log::trace!(
+                        "stopping pid {} by signal {:?}",
+                        current.pid().as_usize(),
+                        signal
+                    );
+                    current.signals.lock().set_stopped(true);
+                    self.notify_parent(&current, stopped_status(signal));
+                    handled_quietly = true;
+                }
+                SigAction::Terminate => {
+                    log::trace!(
+                        "terminating pid {} by signal {:?}",
+                        current.pid().as_usize(),
+                        signal
+                    );
+                    self.exit_by_signal(&current, frame, signal);
+                }
+                SigAction::Handler { action } => {
+                    log::trace!(
+                        "delivering signal {:?} to pid {} (handler addr {:#x})",
+                        signal,
+                        current.pid().as_usize(),
+                        action.sa_handler
+                    );
+                    restart_syscall(frame, syscall, Some(&action));
+                    return self.run_handler(&current, frame, signal, &action, &info, &blocked);
+                }
+            }
+        }
+
+        // rt_sigsuspend(2) only gets to keep its mask until a handler runs
+        if let Some(mask) = current.saved_sigmask.take() {
+            current.set_signal_mask_to(mask);
+        }
+        // linux never lets a signal that isn't caught interrupt anything
+        if handled_quietly {
+            restart_syscall(frame, syscall, None);
+        }
+        Ok(())
+    }
+
+    // sends the frame off to the handler the next time it goes back to userspace
+    fn run_handler(
         &self,
+        current: &Arc<Task>,
         frame: &mut InterruptFrame,
-        syscall_result: isize,
+        signal: Signal,
+        action: &KSigAction,
+        info: &SigInfo,
+        blocked: &SigSet,
     ) -> KResult<()> {
+        let altstack = current.sigaltstack.load();
+        let onstack = action.sa_flags & SA_ONSTACK != 0
+            && altstack.is_enabled()
+            && !altstack.contains(frame.rsp);
+        ArchTask::setup_signal_stack(
+            frame,
+            signal,
+            action,
+            info,
+            // rt_sigsuspend(2)'s mask is only temporary, so the handler returns to the old one
+            current.saved_sigmask.take().unwrap_or(*blocked).to_user(),
+            &altstack.status(frame.rsp),
+            onstack.then(|| altstack.top()),
+        )?;
+        // like linux, a handler leaves nothing for restart_syscall(2) to pick back up
+        current.restart_block.store(None);
+        if altstack.ss_flags & SS_AUTODISARM != 0 {
+            current.sigaltstack.store(SigStack::DISABLED);
+        }
+
+        let mut set = *blocked | action.sa_mask;
+        if action.sa_flags & SA_NODEFER == 0 {
+            set.add(signal);
+        }
+        current.set_signal_mask_to(set);
+        if action.sa_flags & SA_RESETHAND != 0 {
+            current.signals.lock().reset_action(signal);
+        }
+        Ok(())
+    }
+
+    // a fault the current task caused in userspace. it can't wait around pending, so either
+    // the handler runs right away or the task goes down.
+    pub fn force_fault_signal(&self, frame: &mut InterruptFrame, signal: Signal, info: &SigInfo) {
         let current = self.current_task();
-        if let Some((signal, sigaction)) = current.signals.lock().pop_pending() {
-            let mut set = current.sigset.lock();
-            if !set.get(signal as usize).as_deref().unwrap_or(&true) {
-                match sigaction {
-                    SigAction::Ignore => {}
-                    SigAction::Terminate => {
-                        log::trace!(
-                            "terminating pid {} by signal {:?}",
-                            current.pid.as_usize(),
-                            signal
-                        );
-                        self.exit_current(1);
-                    }
-                    SigAction::Handler { handler } => {
-                        log::trace!(
-                            "delivering signal {:?} to pid {} (handler addr {:#x})",
-                            signal,
-                            current.pid.as_usize(),
-                            handler as usize
-                        );
-                        current.signaled_frame.store(Some(*frame));
-                        set.set(signal as usize, true);
-                        ArchTask::setup_signal_stack(
-                            frame,
-                            signal,
-                            VirtAddr::new(handler as usize),
-                            syscall_result,
-                        )?;
+        // a debugger gets the first look at it, and may decide it never happened
+        let Some(info) = self.ptrace_signal(&current, frame, *info, None) else {
+            return;
+        };
+        let info = &info;
+        let signal = if info.si_signo == signal {
+            signal
+        } else {
+            info.si_signo
+        };
+        let blocked = current.signal_mask();
+        let action = current.signals.lock().get_action(signal);
+        if let SigAction::Handler { action } = action {
+            if !blocked.contains(signal)
+                && self
+                    .run_handler(&current, frame, signal, &action, info, &blocked)
+                    .is_ok()
+            {
+                return;
+            }
+        }
+        self.exit_by_signal(&current, frame, signal);
+    }
+
+    // SECCOMP_RET_KILL_*, which no handler or tracer gets a say in. a thread that goes alone
+    // only leaves a core behind if it was the last one.
+    pub fn seccomp_kill(&self, frame: &InterruptFrame, whole_process: bool) {
+        let current = self.current_task();
+        if whole_process || self.thread_group(current.tgid).len() == 1 {
+            self.exit_by_signal(&current, frame, SIGSYS);
+        } else {
+            self.exit_current(signaled_status(SIGSYS));
+        }
+    }
+
+    // the signal's default action is to kill the whole process, and maybe leave a core behind
+    fn exit_by_signal(&self, current: &Arc<Task>, frame: &InterruptFrame, signal: Signal) {
+        let mut status = signaled_status(signal);
+        if CORE_SIGNALS.contains(&signal) {
+            match coredump::dump_core(current, frame, signal) {
+                Ok(()) => status = core_dumped_status(signal),
+                Err(err) => log::warn!(
+                    "no core dump for pid {}: {:?}",
+                    current.tgid.as_usize(),
+                    err
+                ),
+            }
+        }
+        self.exit_group(status);
+    }
+
+    // a task spinning in userspace never gets to try_delivering_signal, so the timer acts on
+    // whatever doesn't need a handler run: stopping and terminating. a tracer wants to hear
+    // about every signal right away, though, so a tracee takes them all here.
+    pub fn handle_async_signals(&self, frame: &mut InterruptFrame) {
+        let current = self.current_task();
+        loop {
+            self.stop_current(&current);
+
+            let blocked = current.signal_mask();
+            let traced = current.is_traced();
+            let pending =
+                current
+                    .signals
+                    .lock()
+                    .dequeue(&mut current.pending.lock(), |signal, action| {
+                        (traced || matches!(action, SigAction::Stop | SigAction::Terminate))
+                            && !blocked.contains(signal)
+                    });
+            let Some((info, action)) = pending else {
+                return;
+            };
+            let Some((info, action)) = self.trace_signal(&current, frame, info, action, None)
+            else {
+                continue;
+            };
+            match action {
+                SigAction::Ignore => {}
+                SigAction::Stop => {
+                    current.signals.lock().set_stopped(true);
+                    self.notify_parent(&current, stopped_status(info.si_signo));
+                }
+                SigAction::Terminate => {
+                    self.exit_by_signal(&current, frame, info.si_signo);
+                    return;
+                }
+                SigAction::Handler { action } => {
+                    let blocked = current.signal_mask();
+                    if self
+                        .run_handler(&current, frame, info.si_signo, &action, &info, &blocked)
+                        .is_err()
+                    {
+                        self.exit_by_signal(&current, frame, SIGSEGV);
                     }
+                    return;
                 }
             }
         }
+    }
 
-        Ok(())
+    // parks the current task in a ptrace-stop until its tracer lets it go. gives back the signal
+    // the tracer left it, or None if nobody is tracing it.
+    fn ptrace_stop(
+        &self,
+        current: &Arc<Task>,
+        frame: &mut InterruptFrame,
+        status: c_int,
+        siginfo: Option<SigInfo>,
+        syscall: Option<usize>,
+    ) -> Option<Signal> {
+        let tracer = {
+            let mut ptrace = current.ptrace.lock();
+            let ptrace = ptrace.as_mut().filter(|p| !p.is_detached())?;
+            ptrace.stop(frame, status, siginfo, syscall);
+            ptrace.tracer()
+        };
+        // SA_NOCLDSTOP doesn't apply to tracers
+        if let Some(tracer) = tracer {
+            let info = SigInfo::child(current.pid().as_usize() as c_int, status);
+            self.queue_signal(tracer, info, SignalTarget::Process).ok();
+        }
+        self.wake_all(&JOIN_WAIT_QUEUE);
+
+        loop {
+            current.set_state(TaskState::Stopped);
+            let stopped = current
+                .ptrace
+                .lock()
+                .as_ref()
+                .is_some_and(|p| p.is_stopped());
+            // nothing keeps a tracee from being killed
+            if !stopped || current.pending_signals().contains(SIGKILL) {
+                current.set_state(TaskState::Runnable);
+                break;
+            }
+            self.park(None);
+        }
+
+        let mut ptrace = current.ptrace.lock();
+        let signal = ptrace.as_mut().map_or(0, |p| p.finish_stop());
+        if ptrace.as_ref().is_some_and(|p| p.is_detached()) {
+            *ptrace = None;
+        }
+        Some(signal)
+    }
+
+    // a tracee stops for every signal before it's delivered, and the tracer can swap it for
+    // another one or throw it away. gives back whatever is left to deliver.
+    fn ptrace_signal(
+        &self,
+        current: &Arc<Task>,
+        frame: &mut InterruptFrame,
+        info: SigInfo,
+        syscall: Option<usize>,
+    ) -> Option<SigInfo> {
+        if info.si_signo == SIGKILL {
+            return Some(info);
+        }
+        let status = stopped_status(info.si_signo);
+        let Some(signal) = self.ptrace_stop(current, frame, status, Some(info), syscall) else {
+            return Some(info);
+        };
+        match signal {
+            0 => None,
+            signal if signal == info.si_signo => Some(info),
+            // linux makes it look like the tracer sent the new one
+            signal => Some(SigInfo::new(signal, SI_USER)),
+        }
     }
 
-    pub fn restore_signaled_user_stack(&self, current_frame: &mut InterruptFrame) {
+    // ptrace_signal() for a signal that was just dequeued, which gets queued again if the
+    // tracer swapped in one that's blocked
+    fn trace_signal(
+        &self,
+        current: &Arc<Task>,
+        frame: &mut InterruptFrame,
+        info: SigInfo,
+        action: SigAction,
+        syscall: Option<usize>,
+    ) -> Option<(SigInfo, SigAction)> {
+        if !current.is_traced() {
+            return Some((info, action));
+        }
+        let signal = info.si_signo;
+        let info = self.ptrace_signal(current, frame, info, syscall)?;
+        if info.si_signo == signal {
+            return Some((info, action));
+        }
+        if current.signal_mask().contains(info.si_signo) {
+            self.queue_signal(current.clone(), info, SignalTarget::Thread)
+                .ok();
+            return None;
+        }
+        let action = current.signals.lock().get_action(info.si_signo);
+        Some((info, action))
+    }
+
+    // the stop on either side of a syscall under PTRACE_SYSCALL
+    fn ptrace_syscall_stop(
+        &self,
+        current: &Arc<Task>,
+        frame: &mut InterruptFrame,
+        syscall: usize,
+    ) -> Option<Signal> {
+        let sysgood = current
+            .ptrace
+            .lock()
+            .as_ref()
+            .is_some_and(|p| p.options & PTRACE_O_TRACESYSGOOD != 0);
+        let trap = if sysgood { SIGTRAP | 0x80 } else { SIGTRAP };
+        let info = SigInfo::new(SIGTRAP, trap);
+        let signal = self.ptrace_stop(
+            current,
+            frame,
+            stopped_status(trap),
+            Some(info),
+            Some(syscall),
+        )?;
+        // a signal handed to a syscall stop is sent like any other
+        if signal != 0 {
+            self.queue_signal(
+                current.clone(),
+                SigInfo::new(signal, SI_USER),
+                SignalTarget::Thread,
+            )
+            .ok();
+        }
+        Some(signal)
+    }
+
+    fn is_tracing_syscalls(&self, current: &Task) -> bool {
+        current
+            .ptrace
+            .lock()
+            .as_ref()
+            .is_some_and(|p| !p.is_detached() && p.resume == PtraceResume::Syscall)
+    }
+
+    // stops on the way into a syscall if the tracer asked for it. gives back the syscall to run,
+    // which the tracer may have changed through orig_rax, or None if there was no stop.
+    pub fn ptrace_syscall_enter(
+        &self,
+        frame: &mut InterruptFrame,
+        syscall: usize,
+    ) -> Option<usize> {
         let current = self.current_task();
-        if let Some(signaled_frame) = current.signaled_frame.swap(None) {
-            *current_frame = signaled_frame;
+        if !self.is_tracing_syscalls(&current) {
+            return None;
+        }
+        // linux shows -ENOSYS in rax until the syscall has actually run
+        frame.rax = -(Errno::ENOSYS as isize) as usize;
+        self.ptrace_syscall_stop(&current, frame, syscall)?;
+        let syscall = current
+            .ptrace
+            .lock()
+            .as_ref()
+            .and_then(|p| p.syscall)
+            .unwrap_or(syscall);
+        Some(syscall)
+    }
+
+    // stops on the way out of a syscall, with the result already in rax
+    pub fn ptrace_syscall_exit(&self, frame: &mut InterruptFrame, syscall: usize) {
+        let current = self.current_task();
+        if self.is_tracing_syscalls(&current) {
+            self.ptrace_syscall_stop(&current, frame, syscall);
+        }
+    }
+
+    // a tracee that just exec'd stops with SIGTRAP before any of the new program runs, so the
+    // tracer can set its breakpoints. the frame is what it's about to start with.
+    pub fn ptrace_exec(&self, frame: &mut InterruptFrame) {
+        let current = self.current_task();
+        if !current.is_traced() {
+            return;
+        }
+        // execve(2) never goes back through the syscall exit, so its stop happens here
+        frame.rax = 0;
+        if self.is_tracing_syscalls(&current) {
+            self.ptrace_syscall_stop(&current, frame, SYS_EXECVE);
+        }
+        let info = SigInfo::new(SIGTRAP, SI_USER);
+        if let Some(info) = self.ptrace_signal(&current, frame, info, None) {
+            // the new program has to be running before it can take a signal
+            self.queue_signal(current, info, SignalTarget::Thread).ok();
+        }
+    }
+
+    // PTRACE_TRACEME and PTRACE_ATTACH. attaching also stops the tracee, so the tracer has
+    // something to wait for.
+    pub fn ptrace_attach(&self, tracer: &Arc<Task>, tracee: Arc<Task>, stop: bool) -> KResult<()> {
+        if tracee.tgid == tracer.tgid {
+            kbail!(EPERM, "ptrace_attach(): can't trace our own thread group");
+        }
+        if tracee.tgid.as_usize() == 1 {
+            kbail!(EPERM, "ptrace_attach(): can't trace init");
+        }
+        {
+            let mut ptrace = tracee.ptrace.lock();
+            if ptrace.as_ref().is_some_and(|p| !p.is_detached()) {
+                kbail!(EPERM, "ptrace_attach(): already being traced");
+            }
+            *ptrace = Some(Ptrace::new(tracer));
+        }
+        tracer.tracees.lock().push(tracee.clone());
+        if stop {
+            self.send_signal_to(tracee, SIGSTOP);
+        }
+        Ok(())
+    }
+
+    // PTRACE_CONT, PTRACE_SYSCALL and PTRACE_SINGLESTEP, for a tracee that's stopped
+    pub fn ptrace_resume(&self, tracee: Arc<Task>, resume: PtraceResume, signal: Signal) {
+        if let Some(ptrace) = tracee.ptrace.lock().as_mut() {
+            ptrace.resume(resume, signal);
+        }
+        self.resume_task(tracee);
+    }
+
+    pub fn ptrace_detach(&self, tracer: &Task, tracee: Arc<Task>, signal: Signal) {
+        tracer.tracees.lock().retain(|t| t.pid() != tracee.pid());
+        self.untrace(tracee, signal);
+    }
+
+    // lets go of a tracee, which carries on with `signal` if it was stopped
+    fn untrace(&self, tracee: Arc<Task>, signal: Signal) {
+        let stopped = {
+            let mut ptrace = tracee.ptrace.lock();
+            match ptrace.as_mut() {
+                Some(p) if p.is_stopped() => {
+                    p.detach(signal);
+                    true
+                }
+                _ => {
+                    *ptrace = None;
+                    false
+                }
+            }
+        };
+        if stopped {
+            self.resume_task(tracee);
+        }
+    }
+
+    // stays off the cpu for as long as the thread group is stopped
+    fn stop_current(&self, current: &Arc<Task>) {
+        loop {
+            current.set_state(TaskState::Stopped);
+            // SIGCONT clears the flag before it looks for stopped threads to wake
+            if !current.signals.lock().is_stopped() {
+                current.set_state(TaskState::Runnable);
+                return;
+            }
+            self.park(None);
+        }
+    }
+
+    // holds whoever the tick interrupted to RLIMIT_CPU
+    pub fn check_cpu_limit(&self) {
+        let Some(current) = self.current_task_opt() else {
+            return;
+        };
+        let Ok(limit) = current.rlimits.lock().get(RLIMIT_CPU) else {
+            return;
+        };
+        if limit.rlim_cur == RLIM_INFINITY {
+            return;
+        }
+        let seconds = (current.process_cpu_time().total_ns() / 1_000_000_000) as u64;
+        {
+            // past the soft limit, linux nags once every second of cpu time
+            let mut times = current.process_times.lock();
+            if seconds < limit.rlim_cur.max(times.next_cpu_limit_check) {
+                return;
+            }
+            times.next_cpu_limit_check = seconds + 1;
+        }
+        if seconds >= limit.rlim_max {
+            self.send_signal_to(current, SIGKILL);
         } else {
-            log::warn!("User called sigreturn(2) while it is not signaled");
+            self.send_signal_to(current, SIGXCPU);
+        }
+    }
+
+    // the cpu time timers only run for whoever this cpu's tick interrupted, but they count
+    // everything it ran since the last time it was interrupted
+    pub fn tick_cpu_itimers(&self) {
+        let Some(current) = self.current_task_opt() else {
+            return;
+        };
+        let spent = current.cpu_clock.lock().take_uncounted();
+        let mut expired = Vec::new();
+        {
+            let mut itimers = current.itimers.lock();
+            if itimers.advance(ITIMER_VIRTUAL, spent.user_ns) {
+                expired.push(SIGVTALRM);
+            }
+            if itimers.advance(ITIMER_PROF, spent.total_ns()) {
+                expired.push(SIGPROF);
+            }
+        }
+        for signal in expired {
+            self.send_signal_to(current.clone(), signal);
+        }
+    }
+
+    pub fn restore_signaled_user_stack(&self, frame: &mut InterruptFrame) -> KResult<()> {
+        let current = self.current_task();
+        match ArchTask::restore_signal_stack(frame) {
+            Ok(uc) => {
+                current.set_signal_mask_to(SigSet::from_user(uc.uc_sigmask));
+                // the handler may have changed it, or SS_AUTODISARM turned it off. either way
+                // linux doesn't care if this fails.
+                current.set_sigaltstack(&uc.uc_stack, frame.rsp).ok();
+                Ok(())
+            }
+            Err(err) => {
+                // there's nothing sensible left to return to
+                self.send_signal_to(current, SIGSEGV);
+                Err(err)
+            }
         }
     }
 
@@ -259,124 +1221,162 @@ impl Scheduler {
         let mut exited = self.exited_tasks.lock();
 
         for task in exited.iter() {
-            self.tasks.lock().remove(&task.pid);
-            self.run_queue.lock().retain(|t| t.pid != task.pid);
-            self.waiting_queue.lock().retain(|t| t.pid != task.pid);
-            JOIN_WAIT_QUEUE.queue.lock().retain(|t| t.pid != task.pid);
-            POLL_WAIT_QUEUE.queue.lock().retain(|t| t.pid != task.pid);
-            if let Some(group) = task.group.borrow_mut().upgrade() {
+            self.tasks.lock().remove(&task.pid());
+            self.waiting_queue.lock().retain(|t| t.pid() != task.pid());
+            JOIN_WAIT_QUEUE.queue.lock().retain(|t| t.pid() != task.pid());
+            FUTEX_TABLE.forget(task);
+            POLL_WAIT_QUEUE.queue.lock().retain(|t| t.pid() != task.pid());
+            let group = task.group.lock().upgrade();
+            if let Some(group) = group {
                 group.lock().gc_dropped_processes();
             }
-            // assert_eq!(Arc::strong_count(task), 1, "PID {} has dangling references", task.pid.as_usize());
+            // assert_eq!(Arc::strong_count(task), 1, "PID {} has dangling references", task.pid().as_usize());
         }
         exited.clear();
     }
 
     pub fn preempt(&self) {
-        let current = self.current_task.read();
-        if let Some(current_task) = current.as_ref().cloned() {
-            // log::debug!("Switching from PID {:?} to preempt task", current_task.pid);
-            drop(current);
-            arch_context_switch(
-                current_task.arch_mut(),
-                self.preempt_task.as_ref().unwrap().arch_mut(),
-            );
-        } else {
-            // log::debug!("Switching from idle thread to preempt task");
-            drop(current);
-            arch_context_switch(
-                self.idle_thread.as_ref().unwrap().arch_mut(),
-                self.preempt_task.as_ref().unwrap().arch_mut(),
-            );
-        }
+        // we might come back on a different cpu, but we have to leave from this one
+        interrupts::without_interrupts(|| {
+            let cpu = self.this_cpu();
+            let current = cpu.current_task.read().as_ref().cloned();
+            let preempt_task = cpu.preempt_task.get().unwrap();
+            if let Some(current_task) = current {
+                // log::debug!("Switching from PID {:?} to preempt task", current_task.pid());
+                current_task.cpu_clock.lock().switch_out();
+                current_task.sched.lock().switch_out(time::get_uptime_ns());
+                arch_context_switch(current_task.arch_mut(), preempt_task.arch_mut());
+            } else {
+                // log::debug!("Switching from idle thread to preempt task");
+                arch_context_switch(
+                    cpu.idle_thread.get().unwrap().arch_mut(),
+                    preempt_task.arch_mut(),
+                );
+            }
+        });
+    }
+
+    pub fn yield_now(&self) {
+        self.current_task().sched.lock().yield_now();
+        self.preempt();
     }
 
     pub fn sleep(&self, duration: Option<usize>) -> KResult<()> {
-        let task = self.current_task();
+        self.current_task().set_state(TaskState::Waiting);
+        self.block(duration)
+    }
 
-        if let Some(duration) = duration {
-            self.push_deadline_waiting(task.clone(), duration);
-        } else {
-            self.push_waiting(task.clone());
+    // takes one task from the busiest cpu if it has at least two more than we do
+    fn balance(&self, cpu: usize) {
+        let local = self.cpus[cpu].load();
+        let busiest = self
+            .cpus
+            .iter()
+            .enumerate()
+            .filter(|&(i, other)| i != cpu && other.is_online())
+            .map(|(i, other)| (i, other.load()))
+            .max_by_key(|&(_, load)| load);
+        let Some((src, load)) = busiest else {
+            return;
+        };
+        if load < local + 2 {
+            return;
         }
-        self.preempt();
+        let (task, src_min) = {
+            let mut queue = self.cpus[src].run_queue.lock();
+            let Some(task) = queue.steal() else {
+                return;
+            };
+            (task, queue.min_vruntime())
+        };
 
-        if task.has_pending_signals() {
-            Err(kerror!(EINTR, "sleep(): pending signals"))
-        } else {
-            Ok(())
+        let mut entity = task.sched.lock();
+        // someone else got to it between the queue and the lock
+        if entity.queued != Some(src) || entity.on_cpu {
+            return;
+        }
+        entity.queued = None;
+        if task.get_state() != TaskState::Runnable {
+            return;
+        }
+        let mut queue = self.cpus[cpu].run_queue.lock();
+        entity.migrate(src_min, queue.min_vruntime());
+        queue.push(task.clone(), &mut entity);
+        entity.queued = Some(cpu);
+    }
+
+    // pops tasks off our queue until one of them is actually ours to run
+    fn claim_next(&self, cpu: usize) -> Option<Arc<Task>> {
+        loop {
+            let task = self.cpus[cpu].run_queue.lock().pop_next()?;
+            let mut entity = task.sched.lock();
+            if entity.queued != Some(cpu) || entity.on_cpu {
+                continue;
+            }
+            entity.queued = None;
+            if task.get_state() != TaskState::Runnable {
+                continue;
+            }
+            entity.on_cpu = true;
+            entity.cpu = Some(cpu);
+            drop(entity);
+            return Some(task);
         }
     }
 }
 
 pub fn switch() {
     let sched = get_scheduler();
+    let cpu_id = cpu_id();
+    let cpu = &sched.cpus[cpu_id];
 
     sched.check_deadline();
-    let mut queue = sched.run_queue.lock();
-    let current = sched.current_task.try_write();
+    let current = cpu.current_task.try_write();
     if current.is_none() {
         log::warn!("Couldn't lock current task for writing.");
         return;
     }
     let mut current = current.unwrap();
-    let mut task = None;
-    loop {
-        let t = queue.pop_front();
-        if let Some(t) = t {
-            if t.get_state() == TaskState::Runnable {
-                task = Some(t);
-                break;
-            } else {
-                // we'll take the opportunity to purge the run queue of sleeping/dead tasks
-            }
-        } else {
-            break;
+
+    // whoever was running has to compete with everyone else for the next turn
+    if let Some(current_task) = current.as_ref() {
+        let mut entity = current_task.sched.lock();
+        entity.on_cpu = false;
+        if current_task.get_state() == TaskState::Runnable && entity.queued.is_none() {
+            cpu.run_queue
+                .lock()
+                .requeue(current_task.clone(), &mut entity);
+            entity.queued = Some(cpu_id);
         }
     }
+    cpu.busy.store(false, Ordering::Relaxed);
+    sched.balance(cpu_id);
 
-    if let Some(task) = task {
-        if let Some(current_task) = current.as_ref() {
-            if current_task.pid != task.pid {
-                queue.push_back(current_task.clone());
-            }
+    let next = sched.claim_next(cpu_id);
+    cpu.busy.store(next.is_some(), Ordering::Relaxed);
+    // an idle cpu only needs to wake up for the next deadline
+    let next_event = match (&next, sched.next_deadline()) {
+        (Some(_), deadline) => {
+            let tick = time::get_uptime_ns() + time::TICK_NS;
+            Some(deadline.map_or(tick, |deadline| deadline.min(tick)))
         }
-
-        *current = Some(task.clone());
-        drop(queue);
-        drop(current);
+        (None, deadline) => deadline,
+    };
+    apic::arm_timer(next_event);
+    *current = next.clone();
+    drop(current);
+    let preempt_task = cpu.preempt_task.get().unwrap();
+    if let Some(task) = next {
         task.start_time.call_once(time::get_uptime_ms);
-        // log::debug!("Switching from preempt task to PID {}", task.pid.as_usize());
-        arch_context_switch(
-            sched.preempt_task.as_ref().unwrap().arch_mut(),
-            task.arch_mut(),
-        );
+        task.cpu_clock.lock().switch_in();
+        task.sched.lock().switch_in(time::get_uptime_ns());
+        // log::debug!("Switching from preempt task to PID {}", task.pid().as_usize());
+        arch_context_switch(preempt_task.arch_mut(), task.arch_mut());
     } else {
-        if let Some(current_task) = current.as_ref().cloned() {
-            let state = { current_task.get_state() };
-            if state == TaskState::Runnable {
-                drop(current);
-                drop(queue);
-                // log::debug!(
-                //     "Switching from preempt task to PID {}",
-                //     current_task.pid.as_usize()
-                // );
-                arch_context_switch(
-                    sched.preempt_task.as_ref().unwrap().arch_mut(),
-                    current_task.arch_mut(),
-                );
-                return;
-            }
-        }
-
-        *current = None;
-        drop(current);
-        drop(queue);
-
         // log::debug!("Switching from preempt task to idle thread");
         arch_context_switch(
-            sched.preempt_task.as_ref().unwrap().arch_mut(),
-            sched.idle_thread.as_ref().unwrap().arch_mut(),
+            preempt_task.arch_mut(),
+            cpu.idle_thread.get().unwrap().arch_mut(),
         );
     }
 }
@@ -385,7 +1385,8 @@ fn reap() {
     let scheduler = get_scheduler();
     loop {
         scheduler.reap_dead();
-        enable_and_hlt();
+        // sleeping instead of halting lets the cpu go idle, timer and all
+        REAPER_WAIT_QUEUE.sleep_until(|| !scheduler.exited_tasks.lock().is_empty());
     }
 }
 
@@ -0,0 +1,313 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{kbail, util::KResult};
+
+pub const SECCOMP_MODE_DISABLED: usize = 0;
+pub const SECCOMP_MODE_FILTER: usize = 2;
+
+pub const SECCOMP_SET_MODE_STRICT: usize = 0;
+pub const SECCOMP_SET_MODE_FILTER: usize = 1;
+pub const SECCOMP_GET_ACTION_AVAIL: usize = 2;
+
+// filters are run newest first, and the lowest action any of them returns wins
+pub const SECCOMP_RET_KILL_PROCESS: u32 = 0x80000000;
+pub const SECCOMP_RET_KILL_THREAD: u32 = 0x00000000;
+pub const SECCOMP_RET_TRAP: u32 = 0x00030000;
+pub const SECCOMP_RET_ERRNO: u32 = 0x00050000;
+pub const SECCOMP_RET_TRACE: u32 = 0x7ff00000;
+pub const SECCOMP_RET_LOG: u32 = 0x7ffc0000;
+pub const SECCOMP_RET_ALLOW: u32 = 0x7fff0000;
+const SECCOMP_RET_ACTION_FULL: u32 = 0xffff0000;
+const SECCOMP_RET_DATA: u32 = 0x0000ffff;
+
+pub const AUDIT_ARCH_X86_64: u32 = 0xc000003e;
+
+const MAX_ERRNO: u32 = 4095;
+
+// the same limits as linux: one program can't be longer than BPF_MAXINSNS, and a task can't
+// pile up more than MAX_INSNS_PER_PATH across all of its filters
+const BPF_MAXINSNS: usize = 4096;
+const MAX_INSNS_PER_PATH: usize = 32768;
+const BPF_MEMWORDS: usize = 16;
+
+// classic bpf opcodes, which are a class, a size or operation, a mode and a source or'd together
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ST: u16 = 0x02;
+const BPF_STX: u16 = 0x03;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_MISC: u16 = 0x07;
+
+const BPF_W: u16 = 0x00;
+
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_MEM: u16 = 0x60;
+const BPF_LEN: u16 = 0x80;
+
+const BPF_ADD: u16 = 0x00;
+const BPF_SUB: u16 = 0x10;
+const BPF_MUL: u16 = 0x20;
+const BPF_DIV: u16 = 0x30;
+const BPF_OR: u16 = 0x40;
+const BPF_AND: u16 = 0x50;
+const BPF_LSH: u16 = 0x60;
+const BPF_RSH: u16 = 0x70;
+const BPF_NEG: u16 = 0x80;
+const BPF_MOD: u16 = 0x90;
+const BPF_XOR: u16 = 0xa0;
+
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+
+const BPF_K: u16 = 0x00;
+const BPF_X: u16 = 0x08;
+const BPF_A: u16 = 0x10;
+
+const BPF_TAX: u16 = 0x00;
+const BPF_TXA: u16 = 0x80;
+
+// struct sock_filter
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SockFilter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+// struct sock_fprog
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SockFprog {
+    pub len: u16,
+    _pad: [u8; 6],
+    pub filter: usize,
+}
+
+// struct seccomp_data, which is all a filter gets to look at
+#[derive(Debug, Clone, Copy)]
+pub struct SeccompData {
+    pub nr: u32,
+    pub arch: u32,
+    pub instruction_pointer: u64,
+    pub args: [u64; 6],
+}
+
+const SECCOMP_DATA_SIZE: u32 = 64;
+
+impl SeccompData {
+    // the 32-bit word at `offset`, which check() made sure is aligned and in bounds
+    fn load(&self, offset: u32) -> u32 {
+        let field = match offset {
+            0 => return self.nr,
+            4 => return self.arch,
+            8 | 12 => self.instruction_pointer,
+            _ => self.args[(offset as usize - 16) / 8],
+        };
+        if offset % 8 == 0 {
+            field as u32
+        } else {
+            (field >> 32) as u32
+        }
+    }
+}
+
+// what the filters decided to do about a syscall
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompAction {
+    KillProcess,
+    KillThread,
+    Trap(u16),
+    Errno(u16),
+    Trace,
+    Log,
+    Allow,
+}
+
+impl SeccompAction {
+    fn from_ret(ret: u32) -> SeccompAction {
+        let data = (ret & SECCOMP_RET_DATA) as u16;
+        match ret & SECCOMP_RET_ACTION_FULL {
+            SECCOMP_RET_KILL_THREAD => SeccompAction::KillThread,
+            SECCOMP_RET_TRAP => SeccompAction::Trap(data),
+            SECCOMP_RET_ERRNO => SeccompAction::Errno(data.min(MAX_ERRNO as u16)),
+            SECCOMP_RET_TRACE => SeccompAction::Trace,
+            SECCOMP_RET_LOG => SeccompAction::Log,
+            SECCOMP_RET_ALLOW => SeccompAction::Allow,
+            // anything we don't know about is treated as the harshest action there is
+            _ => SeccompAction::KillProcess,
+        }
+    }
+
+    pub fn is_available(action: u32) -> bool {
+        matches!(
+            action,
+            SECCOMP_RET_KILL_PROCESS
+                | SECCOMP_RET_KILL_THREAD
+                | SECCOMP_RET_TRAP
+                | SECCOMP_RET_ERRNO
+                | SECCOMP_RET_TRACE
+                | SECCOMP_RET_LOG
+                | SECCOMP_RET_ALLOW
+        )
+    }
+}
+
+// one installed filter, linked to the ones installed before it. a forked child shares the
+// whole chain with its parent, and only ever adds to the front of its own copy.
+pub struct SeccompFilter {
+    prog: Vec<SockFilter>,
+    prev: Option<Arc<SeccompFilter>>,
+}
+
+impl SeccompFilter {
+    pub fn new(prog: Vec<SockFilter>, prev: Option<Arc<SeccompFilter>>) -> KResult<SeccompFilter> {
+        if prog.is_empty() || prog.len() > BPF_MAXINSNS {
+            kbail!(EINVAL, "SeccompFilter::new(): bad program length");
+        }
+        // every filter costs a few instructions extra, like on linux
+        let total = prog.len()
+            + prev
+                .as_ref()
+                .map_or(0, |prev| prev.iter().map(|f| f.prog.len() + 4).sum());
+        if total > MAX_INSNS_PER_PATH {
+            kbail!(ENOMEM, "SeccompFilter::new(): too many instructions");
+        }
+        check(&prog)?;
+        Ok(SeccompFilter { prog, prev })
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &SeccompFilter> {
+        core::iter::successors(Some(self), |filter| filter.prev.as_deref())
+    }
+
+    // runs every filter in the chain
+    pub fn evaluate(&self, data: &SeccompData) -> SeccompAction {
+        let ret = self
+            .iter()
+            .map(|filter| filter.run(data))
+            .min_by_key(|&ret| (ret & SECCOMP_RET_ACTION_FULL) as i32)
+            .unwrap_or(SECCOMP_RET_ALLOW);
+        SeccompAction::from_ret(ret)
+    }
+
+    // the program was checked when it was installed, so every jump lands inside it and it
+    // can't run off the end without returning
+    fn run(&self, data: &SeccompData) -> u32 {
+        let mut a: u32 = 0;
+        let mut x: u32 = 0;
+        let mut mem = [0u32; BPF_MEMWORDS];
+        let mut pc = 0;
+        loop {
+            let insn = self.prog[pc];
+            let k = insn.k;
+            pc += 1;
+            match insn.code & 0x07 {
+                BPF_LD => {
+                    a = match insn.code & 0xe0 {
+                        BPF_ABS => data.load(k),
+                        BPF_LEN => SECCOMP_DATA_SIZE,
+                        BPF_MEM => mem[k as usize],
+                        _ => k,
+                    }
+                }
+                BPF_LDX => {
+                    x = match insn.code & 0xe0 {
+                        BPF_LEN => SECCOMP_DATA_SIZE,
+                        BPF_MEM => mem[k as usize],
+                        _ => k,
+                    }
+                }
+                BPF_ST => mem[k as usize] = a,
+                BPF_STX => mem[k as usize] = x,
+                BPF_ALU => {
+                    let src = if insn.code & BPF_X != 0 { x } else { k };
+                    a = match insn.code & 0xf0 {
+                        BPF_ADD => a.wrapping_add(src),
+                        BPF_SUB => a.wrapping_sub(src),
+                        BPF_MUL => a.wrapping_mul(src),
+                        // dividing by zero makes the whole program return 0
+                        BPF_DIV | BPF_MOD if src == 0 => return 0,
+                        BPF_DIV => a / src,
+                        BPF_MOD => a % src,
+                        BPF_OR => a | src,
+                        BPF_AND => a & src,
+                        BPF_LSH => a.checked_shl(src).unwrap_or(0),
+                        BPF_RSH => a.checked_shr(src).unwrap_or(0),
+                        BPF_NEG => a.wrapping_neg(),
+                        _ => a ^ src,
+                    }
+                }
+                BPF_JMP => {
+                    let src = if insn.code & BPF_X != 0 { x } else { k };
+                    let taken = match insn.code & 0xf0 {
+                        BPF_JA => {
+                            pc += k as usize;
+                            continue;
+                        }
+                        BPF_JEQ => a == src,
+                        BPF_JGT => a > src,
+                        BPF_JGE => a >= src,
+                        _ => a & src != 0,
+                    };
+                    pc += if taken { insn.jt } else { insn.jf } as usize;
+                }
+                BPF_RET => return if insn.code & 0x18 == BPF_A { a } else { k },
+                _ => {
+                    if insn.code & 0xf8 == BPF_TXA {
+                        a = x;
+                    } else {
+                        x = a;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// only what makes sense against seccomp_data gets in: no packet loads but the aligned word
+// ones, no scratch memory outside of M[], and no way to run off the end
+fn check(prog: &[SockFilter]) -> KResult<()> {
+    for (pc, insn) in prog.iter().enumerate() {
+        let k = insn.k;
+        let jump_ok = |off: usize| pc + 1 + off < prog.len();
+        let ok = match insn.code {
+            c if c == BPF_LD | BPF_W | BPF_ABS => k < SECCOMP_DATA_SIZE && k % 4 == 0,
+            c if c == BPF_LD | BPF_W | BPF_LEN || c == BPF_LDX | BPF_W | BPF_LEN => true,
+            c if c == BPF_LD | BPF_IMM || c == BPF_LDX | BPF_IMM => true,
+            c if c == BPF_LD | BPF_MEM || c == BPF_LDX | BPF_MEM => (k as usize) < BPF_MEMWORDS,
+            c if c == BPF_ST || c == BPF_STX => (k as usize) < BPF_MEMWORDS,
+            c if c == BPF_ALU | BPF_NEG => true,
+            c if c & 0x07 == BPF_ALU && c <= 0xff => match c & 0xf0 {
+                BPF_DIV | BPF_MOD => c & BPF_X != 0 || k != 0,
+                BPF_LSH | BPF_RSH => c & BPF_X != 0 || k < 32,
+                BPF_ADD | BPF_SUB | BPF_MUL | BPF_OR | BPF_AND | BPF_XOR => true,
+                _ => false,
+            },
+            c if c == BPF_JMP | BPF_JA => jump_ok(k as usize),
+            c if c & 0x07 == BPF_JMP && c <= 0xff => {
+                matches!(c & 0xf0, BPF_JEQ | BPF_JGT | BPF_JGE | BPF_JSET)
+                    && jump_ok(insn.jt as usize)
+                    && jump_ok(insn.jf as usize)
+            }
+            c if c == BPF_RET | BPF_K || c == BPF_RET | BPF_A => true,
+            c if c == BPF_MISC | BPF_TAX || c == BPF_MISC | BPF_TXA => true,
+            _ => false,
+        };
+        if !ok {
+            kbail!(EINVAL, "seccomp check(): bad instruction");
+        }
+    }
+    // every path has to end in a return
+    if prog.last().is_none_or(|insn| insn.code & 0x07 != BPF_RET) {
+        kbail!(EINVAL, "seccomp check(): program doesn't end in a return");
+    }
+    Ok(())
+}
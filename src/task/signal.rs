@@ -1,10 +1,15 @@
-use bitvec::prelude::*;
+use core::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not};
+
+use alloc::collections::VecDeque;
 
 use crate::{
     kbail,
+    mem::addr::VirtAddr,
     util::{ctypes::c_int, error::KResult},
 };
 
+use super::CONTINUED_STATUS;
+
 pub type Signal = c_int;
 #[allow(unused)]
 pub const SIGHUP: Signal = 1;
@@ -69,20 +74,78 @@ pub const SIGPWR: Signal = 30;
 #[allow(unused)]
 pub const SIGSYS: Signal = 31;
 
-const SIGMAX: c_int = 32;
+// the real-time signals, which queue up instead of collapsing into one
+pub const SIGRTMIN: Signal = 32;
+pub const SIGRTMAX: Signal = 64;
+
+// one past the last signal
+pub const SIGMAX: c_int = SIGRTMAX + 1;
 
 pub const SIG_DFL: usize = 0;
 pub const SIG_IGN: usize = 1;
 pub const SIG_ERR: usize = usize::MAX;
 
+pub const SA_NOCLDSTOP: u64 = 0x00000001;
+pub const SA_NOCLDWAIT: u64 = 0x00000002;
+pub const SA_SIGINFO: u64 = 0x00000004;
+pub const SA_RESTORER: u64 = 0x04000000;
+pub const SA_ONSTACK: u64 = 0x08000000;
+pub const SA_RESTART: u64 = 0x10000000;
+pub const SA_NODEFER: u64 = 0x40000000;
+pub const SA_RESETHAND: u64 = 0x80000000;
+
+pub const SI_USER: c_int = 0;
+pub const SI_KERNEL: c_int = 0x80;
+pub const SI_QUEUE: c_int = -1;
+pub const SI_TIMER: c_int = -2;
+pub const SI_TKILL: c_int = -6;
+
+pub const SEGV_MAPERR: c_int = 1;
+pub const SEGV_ACCERR: c_int = 2;
+
+pub const TRAP_TRACE: c_int = 2;
+
+pub const SYS_SECCOMP: c_int = 1;
+
+pub const SS_ONSTACK: c_int = 1;
+pub const SS_DISABLE: c_int = 2;
+pub const SS_AUTODISARM: c_int = 0x80000000_u32 as c_int;
+pub const MINSIGSTKSZ: usize = 2048;
+
+// struct sigaction the way rt_sigaction(2) passes it, which isn't how libc lays it out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct KSigAction {
+    pub sa_handler: usize,
+    pub sa_flags: u64,
+    pub sa_restorer: usize,
+    pub sa_mask: SigSet,
+}
+
+impl KSigAction {
+    pub const DEFAULT: KSigAction = KSigAction {
+        sa_handler: SIG_DFL,
+        sa_flags: 0,
+        sa_restorer: 0,
+        sa_mask: SigSet::EMPTY,
+    };
+}
+
+impl Default for KSigAction {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum SigAction {
     Ignore,
     Terminate,
-    Handler { handler: fn() },
+    Stop,
+    Handler { action: KSigAction },
 }
 
-pub const DEFAULT_ACTIONS: [SigAction; SIGMAX as usize] = [
+const DEFAULT_ACTIONS: [SigAction; SIGRTMIN as usize] = [
     /* (unused) */ SigAction::Ignore,
     /* SIGHUP */ SigAction::Terminate,
     /* SIGINT */ SigAction::Terminate,
@@ -97,30 +160,103 @@ pub const DEFAULT_ACTIONS: [SigAction; SIGMAX as usize] = [
     /* SIGSEGV */ SigAction::Terminate,
     /* SIGUSR2 */ SigAction::Ignore,
     /* SIGPIPE */ SigAction::Ignore,
-    /* SIGALRM */ SigAction::Ignore,
+    /* SIGALRM */ SigAction::Terminate,
     /* SIGTERM */ SigAction::Terminate,
     /* SIGSTKFLT */ SigAction::Ignore,
     /* SIGCHLD */ SigAction::Ignore,
-    /* SIGCONT */ SigAction::Terminate,
-    /* SIGSTOP */ SigAction::Ignore,
-    /* SIGTSTP */ SigAction::Ignore,
-    /* SIGTTIN */ SigAction::Ignore,
-    /* SIGTTOU */ SigAction::Ignore,
+    /* SIGCONT */
+    SigAction::Ignore, // resuming happens when it's sent, not when it's delivered
+    /* SIGSTOP */ SigAction::Stop,
+    /* SIGTSTP */ SigAction::Stop,
+    /* SIGTTIN */ SigAction::Stop,
+    /* SIGTTOU */ SigAction::Stop,
     /* SIGURG */ SigAction::Ignore,
-    /* SIGXCPU */ SigAction::Ignore,
+    /* SIGXCPU */ SigAction::Terminate,
     /* SIGXFSZ */ SigAction::Ignore,
-    /* SIGVTALRM */ SigAction::Ignore,
-    /* SIGPROF */ SigAction::Ignore,
+    /* SIGVTALRM */ SigAction::Terminate,
+    /* SIGPROF */ SigAction::Terminate,
     /* SIGWINCH */ SigAction::Ignore,
     /* SIGIO */ SigAction::Ignore,
     /* SIGPWR */ SigAction::Ignore,
-    /* SIGSYS */ SigAction::Ignore,
+    /* SIGSYS */ SigAction::Terminate,
 ];
 
-#[derive(Clone)]
+// nobody expects a real-time signal they didn't ask for
+pub fn default_action(signal: Signal) -> SigAction {
+    DEFAULT_ACTIONS
+        .get(signal as usize)
+        .copied()
+        .unwrap_or(SigAction::Terminate)
+}
+
+// signals in the shared queue can go to any thread of the process, the ones in a thread's own
+// queue only to that thread
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SignalTarget {
+    Process,
+    Thread,
+}
+
+// signals waiting to be delivered. a standard signal only ever waits once, but every real-time
+// signal that's sent waits its turn.
+#[derive(Default)]
+pub struct SigPending {
+    signals: SigSet,
+    queue: VecDeque<SigInfo>,
+}
+
+impl SigPending {
+    pub const fn new() -> SigPending {
+        SigPending {
+            signals: SigSet::EMPTY,
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn signals(&self) -> SigSet {
+        self.signals
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn push(&mut self, info: SigInfo) {
+        let signal = info.si_signo;
+        if signal < SIGRTMIN && self.signals.contains(signal) {
+            return;
+        }
+        self.signals.add(signal);
+        self.queue.push_back(info);
+    }
+
+    pub fn discard(&mut self, signal: Signal) {
+        self.signals.remove(signal);
+        self.queue.retain(|info| info.si_signo != signal);
+    }
+
+    // the lowest numbered signal the filter accepts, and the oldest one of those
+    pub fn pop(&mut self, filter: impl Fn(Signal) -> bool) -> Option<SigInfo> {
+        let signal = self.signals.iter().find(|&signal| filter(signal))?;
+        let index = self.queue.iter().position(|info| info.si_signo == signal)?;
+        let info = self.queue.remove(index)?;
+        if !self.queue.iter().any(|info| info.si_signo == signal) {
+            self.signals.remove(signal);
+        }
+        Some(info)
+    }
+}
+
 pub struct SignalDelivery {
-    pending: u32,
-    actions: [SigAction; SIGMAX as usize],
+    actions: [KSigAction; SIGMAX as usize],
+    // the process-directed signals nobody has taken yet
+    pending: SigPending,
+    // set while the thread group sharing these handlers is stopped
+    stopped: bool,
 }
 
 impl Default for SignalDelivery {
@@ -129,47 +265,375 @@ impl Default for SignalDelivery {
     }
 }
 
+fn resolve_action(signal: Signal, action: &KSigAction) -> SigAction {
+    match action.sa_handler {
+        SIG_DFL => default_action(signal),
+        SIG_IGN => SigAction::Ignore,
+        _ => SigAction::Handler { action: *action },
+    }
+}
+
 impl SignalDelivery {
     pub fn new() -> SignalDelivery {
         SignalDelivery {
-            pending: 0,
-            actions: DEFAULT_ACTIONS,
+            actions: [KSigAction::DEFAULT; SIGMAX as usize],
+            pending: SigPending::new(),
+            stopped: false,
+        }
+    }
+
+    // a forked child gets the handlers, but none of the signals
+    pub fn fork(&self) -> SignalDelivery {
+        SignalDelivery {
+            actions: self.actions,
+            ..SignalDelivery::new()
+        }
+    }
+
+    // the handlers are gone along with the old program, but whatever was ignored stays that way
+    pub fn exec(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.sa_handler != SIG_IGN {
+                *action = KSigAction::DEFAULT;
+            }
         }
     }
 
     pub fn get_action(&self, signal: Signal) -> SigAction {
+        resolve_action(signal, &self.actions[signal as usize])
+    }
+
+    pub fn get_sigaction(&self, signal: Signal) -> KSigAction {
         self.actions[signal as usize]
     }
 
-    pub fn set_action(&mut self, signal: Signal, action: SigAction) -> KResult<()> {
-        if signal > SIGMAX {
+    pub fn set_action(&mut self, signal: Signal, action: KSigAction) -> KResult<()> {
+        if signal <= 0 || signal >= SIGMAX {
             kbail!(EINVAL, "set_action(): signal out of range");
         }
+        if signal == SIGKILL || signal == SIGSTOP {
+            kbail!(
+                EINVAL,
+                "set_action(): SIGKILL and SIGSTOP can't be caught or ignored"
+            );
+        }
 
         self.actions[signal as usize] = action;
+        // whatever was waiting to be ignored doesn't need to wait any longer
+        if self.get_action(signal) == SigAction::Ignore {
+            self.discard(signal);
+        }
         Ok(())
     }
 
-    pub fn is_pending(&self) -> bool {
-        self.pending != 0
+    // SA_RESETHAND: the handler only runs once
+    pub fn reset_action(&mut self, signal: Signal) {
+        self.actions[signal as usize] = KSigAction::DEFAULT;
+    }
+
+    pub fn pending(&self) -> &SigPending {
+        &self.pending
     }
 
-    pub fn signal(&mut self, signal: Signal) {
-        self.pending |= 1 << signal
+    pub fn pending_mut(&mut self) -> &mut SigPending {
+        &mut self.pending
     }
 
-    pub fn pop_pending(&mut self) -> Option<(Signal, SigAction)> {
-        if self.pending == 0 {
-            return None;
+    pub fn discard(&mut self, signal: Signal) {
+        self.pending.discard(signal)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn set_stopped(&mut self, stopped: bool) {
+        self.stopped = stopped
+    }
+
+    // exited children get reaped right away instead of becoming zombies
+    pub fn is_nocldwait(&self) -> bool {
+        let action = self.actions[SIGCHLD as usize];
+        action.sa_handler == SIG_IGN || action.sa_flags & SA_NOCLDWAIT != 0
+    }
+
+    // the parent doesn't want a SIGCHLD when its children stop or continue
+    pub fn is_nocldstop(&self) -> bool {
+        self.actions[SIGCHLD as usize].sa_flags & SA_NOCLDSTOP != 0
+    }
+
+    // takes the next signal the filter accepts, from the thread's own queue before the shared one
+    pub fn dequeue(
+        &mut self,
+        thread_pending: &mut SigPending,
+        filter: impl Fn(Signal, SigAction) -> bool,
+    ) -> Option<(SigInfo, SigAction)> {
+        let actions = &self.actions;
+        let accept =
+            |signal: Signal| filter(signal, resolve_action(signal, &actions[signal as usize]));
+        let info = thread_pending
+            .pop(accept)
+            .or_else(|| self.pending.pop(accept))?;
+        Some((info, self.get_action(info.si_signo)))
+    }
+}
+
+pub const STOP_SIGNALS: [Signal; 4] = [SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU];
+// the ones that leave a core file behind when they kill a process
+pub const CORE_SIGNALS: [Signal; 10] = [
+    SIGQUIT, SIGILL, SIGTRAP, SIGABRT, SIGBUS, SIGFPE, SIGSEGV, SIGXCPU, SIGXFSZ, SIGSYS,
+];
+
+pub const CLD_EXITED: c_int = 1;
+pub const CLD_KILLED: c_int = 2;
+pub const CLD_DUMPED: c_int = 3;
+pub const CLD_STOPPED: c_int = 5;
+pub const CLD_CONTINUED: c_int = 6;
+
+// what restart_syscall(2) picks back up after a signal that didn't need a handler cut a syscall
+// short, like linux's restart_block
+#[derive(Debug, Clone, Copy)]
+pub enum RestartBlock {
+    // the deadline is absolute on the clock, so the restarted sleep only waits out what's left
+    Nanosleep {
+        clockid: usize,
+        deadline: usize,
+        absolute: bool,
+        rem: VirtAddr,
+    },
+}
+
+// siginfo_t, padded out to the 128 bytes userspace expects. the union in the middle is laid
+// out the way SIGCHLD fills it in. kill(2) only uses the pid and uid at the front of it, and
+// sigqueue(3) and timers put their sigval where the status is.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SigInfo {
+    pub si_signo: c_int,
+    pub si_errno: c_int,
+    pub si_code: c_int,
+    _pad0: c_int,
+    pub si_pid: c_int,
+    pub si_uid: u32,
+    pub si_status: c_int,
+    _pad1: c_int,
+    pub si_utime: i64,
+    pub si_stime: i64,
+    _rest: [u64; 10],
+}
+
+impl SigInfo {
+    pub fn new(signo: Signal, code: c_int) -> SigInfo {
+        SigInfo {
+            si_signo: signo,
+            si_code: code,
+            ..Default::default()
+        }
+    }
+
+    // si_addr sits where kill(2) puts si_pid and si_uid
+    pub fn fault(signo: Signal, code: c_int, addr: usize) -> SigInfo {
+        SigInfo {
+            si_signo: signo,
+            si_code: code,
+            si_pid: addr as u32 as c_int,
+            si_uid: (addr >> 32) as u32,
+            ..Default::default()
+        }
+    }
+
+    pub fn user(signo: Signal, code: c_int, pid: c_int, uid: u32) -> SigInfo {
+        SigInfo {
+            si_signo: signo,
+            si_code: code,
+            si_pid: pid,
+            si_uid: uid,
+            ..Default::default()
+        }
+    }
+
+    // SIGSYS from a seccomp filter: si_call_addr where si_addr would be, then si_syscall and
+    // si_arch. the filter's data goes in si_errno.
+    pub fn seccomp(call_addr: usize, syscall: c_int, arch: u32, data: c_int) -> SigInfo {
+        SigInfo {
+            si_errno: data,
+            si_status: syscall,
+            _pad1: arch as c_int,
+            ..SigInfo::fault(SIGSYS, SYS_SECCOMP, call_addr)
+        }
+    }
+
+    pub fn timer(signo: Signal, value: usize) -> SigInfo {
+        let mut info = SigInfo::new(signo, SI_TIMER);
+        info.set_value(value);
+        info
+    }
+
+    // what a parent hears about its child, from the status wait4(2) would give it
+    pub fn child(pid: c_int, wait_status: c_int) -> SigInfo {
+        let (code, status) = match wait_status {
+            CONTINUED_STATUS => (CLD_CONTINUED, SIGCONT),
+            s if s & 0xff == 0x7f => (CLD_STOPPED, (s >> 8) & 0xff),
+            s if s & 0x7f == 0 => (CLD_EXITED, (s >> 8) & 0xff),
+            s if s & 0x80 != 0 => (CLD_DUMPED, s & 0x7f),
+            s => (CLD_KILLED, s & 0x7f),
+        };
+        SigInfo {
+            si_signo: SIGCHLD,
+            si_code: code,
+            si_pid: pid,
+            si_status: status,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_value(&mut self, value: usize) {
+        self.si_status = value as u32 as c_int;
+        self._pad1 = (value >> 32) as u32 as c_int;
+    }
+}
+
+// bit n is signal n + 1, the same as userspace's sigset_t
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct SigSet(u64);
+
+impl SigSet {
+    pub const EMPTY: SigSet = SigSet(0);
+
+    pub const fn from_user(raw: u64) -> SigSet {
+        SigSet(raw)
+    }
+
+    pub const fn to_user(self) -> u64 {
+        self.0
+    }
+
+    fn bit(signal: Signal) -> u64 {
+        if (1..SIGMAX).contains(&signal) {
+            1 << (signal - 1)
+        } else {
+            0
         }
+    }
+
+    pub fn contains(&self, signal: Signal) -> bool {
+        self.0 & Self::bit(signal) != 0
+    }
+
+    pub fn add(&mut self, signal: Signal) {
+        self.0 |= Self::bit(signal)
+    }
+
+    pub fn remove(&mut self, signal: Signal) {
+        self.0 &= !Self::bit(signal)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    // lowest numbered first
+    pub fn iter(self) -> impl Iterator<Item = Signal> {
+        (1..SIGMAX).filter(move |&signal| self.contains(signal))
+    }
 
-        let signal = self.pending.trailing_zeros();
-        self.pending &= !(1 << signal);
-        Some((signal as Signal, self.actions[signal as usize]))
+    // SIGKILL and SIGSTOP can't be blocked, and trying to isn't an error either
+    pub fn blockable(mut self) -> SigSet {
+        self.remove(SIGKILL);
+        self.remove(SIGSTOP);
+        self
     }
 }
 
-pub type SigSet = BitArray<[u8; 8], LocalBits>;
+impl BitOr for SigSet {
+    type Output = SigSet;
+
+    fn bitor(self, rhs: SigSet) -> SigSet {
+        SigSet(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for SigSet {
+    fn bitor_assign(&mut self, rhs: SigSet) {
+        self.0 |= rhs.0
+    }
+}
+
+impl BitAnd for SigSet {
+    type Output = SigSet;
+
+    fn bitand(self, rhs: SigSet) -> SigSet {
+        SigSet(self.0 & rhs.0)
+    }
+}
+
+impl BitAndAssign for SigSet {
+    fn bitand_assign(&mut self, rhs: SigSet) {
+        self.0 &= rhs.0
+    }
+}
+
+impl Not for SigSet {
+    type Output = SigSet;
+
+    fn not(self) -> SigSet {
+        SigSet(!self.0)
+    }
+}
+
+// the stack_t in ucontext_t and sigaltstack(2)
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SigStack {
+    pub ss_sp: usize,
+    pub ss_flags: c_int,
+    _pad: c_int,
+    pub ss_size: usize,
+}
+
+impl SigStack {
+    pub const DISABLED: SigStack = SigStack::new(0, SS_DISABLE, 0);
+
+    pub const fn new(ss_sp: usize, ss_flags: c_int, ss_size: usize) -> SigStack {
+        SigStack {
+            ss_sp,
+            ss_flags,
+            _pad: 0,
+            ss_size,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ss_flags & SS_DISABLE == 0
+    }
+
+    pub fn top(&self) -> usize {
+        self.ss_sp + self.ss_size
+    }
+
+    // linux counts the very top of the stack as being on it, but not the very bottom
+    pub fn contains(&self, sp: usize) -> bool {
+        self.is_enabled() && sp > self.ss_sp && sp - self.ss_sp <= self.ss_size
+    }
+
+    // what sigaltstack(2) and uc_stack tell someone whose stack pointer is at sp
+    pub fn status(&self, sp: usize) -> SigStack {
+        if !self.is_enabled() {
+            SigStack::DISABLED
+        } else if self.contains(sp) {
+            SigStack::new(self.ss_sp, self.ss_flags | SS_ONSTACK, self.ss_size)
+        } else {
+            *self
+        }
+    }
+}
+
+impl Default for SigStack {
+    fn default() -> Self {
+        Self::DISABLED
+    }
+}
 
 #[derive(Clone, Copy, Debug)]
 pub enum SignalMask {
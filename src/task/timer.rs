@@ -0,0 +1,190 @@
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+};
+
+use crate::{
+    arch::time,
+    fs::POLL_WAIT_QUEUE,
+    kbail,
+    util::{ctypes::c_int, IrqMutex, KResult},
+};
+
+use super::{
+    get_scheduler,
+    itimer::IntervalTimer,
+    signal::{SigInfo, SignalTarget},
+    wait_queue::WaitQueue,
+    Task,
+};
+
+// what a timer does when it goes off
+pub enum TimerNotify {
+    // SIGEV_NONE, the owner only ever looks at it through timer_gettime
+    Nothing,
+    // SIGEV_SIGNAL and SIGEV_THREAD_ID
+    Signal {
+        task: Weak<Task>,
+        info: SigInfo,
+        target: SignalTarget,
+    },
+    // readers and pollers of a timerfd
+    Wake,
+}
+
+struct TimerState {
+    // uptime in ms at which it goes off next, None while disarmed
+    deadline: Option<usize>,
+    interval: usize,
+    // expirations nobody has collected yet
+    expirations: u64,
+}
+
+// a one-shot or periodic timer that sits on the scheduler's deadline queue while armed
+pub struct Timer {
+    clock: usize,
+    state: IrqMutex<TimerState>,
+    notify: TimerNotify,
+    wait_queue: WaitQueue,
+}
+
+impl Timer {
+    pub fn new(clock: usize, notify: TimerNotify) -> Arc<Timer> {
+        Arc::new(Timer {
+            clock,
+            state: IrqMutex::new(TimerState {
+                deadline: None,
+                interval: 0,
+                expirations: 0,
+            }),
+            notify,
+            wait_queue: WaitQueue::new(),
+        })
+    }
+
+    // what absolute times given for this timer are measured against
+    pub fn clock(&self) -> usize {
+        self.clock
+    }
+
+    pub fn get(&self) -> IntervalTimer {
+        let state = self.state.lock();
+        let now = time::get_uptime_ms();
+        IntervalTimer {
+            // a timer that's due but hasn't been handled yet still has a nanosecond to go
+            value: state
+                .deadline
+                .map_or(0, |deadline| deadline.saturating_sub(now).max(1) * 1000000),
+            interval: state.interval * 1000000,
+        }
+    }
+
+    // arms the timer relative to now, or disarms it if the value is zero; returns the old setting
+    pub fn set(self: &Arc<Self>, timer: IntervalTimer) -> IntervalTimer {
+        let old = self.get();
+        let sched = get_scheduler();
+        sched.disarm_timer(self);
+
+        let mut state = self.state.lock();
+        state.expirations = 0;
+        state.interval = timer.interval.div_ceil(1000000);
+        if timer.value == 0 {
+            state.deadline = None;
+        } else {
+            let deadline = time::get_uptime_ms().saturating_add(timer.value.div_ceil(1000000));
+            state.deadline = Some(deadline);
+            drop(state);
+            sched.arm_timer(self.clone(), deadline);
+        }
+        old
+    }
+
+    pub fn disarm(self: &Arc<Self>) {
+        get_scheduler().disarm_timer(self);
+        self.state.lock().deadline = None;
+    }
+
+    pub fn expirations(&self) -> u64 {
+        self.state.lock().expirations
+    }
+
+    pub fn take_expirations(&self) -> u64 {
+        core::mem::take(&mut self.state.lock().expirations)
+    }
+
+    pub fn wait_queue(&self) -> &WaitQueue {
+        &self.wait_queue
+    }
+
+    // called by the scheduler once the deadline has passed; returns when to go off next
+    pub(super) fn fire(&self, now: usize) -> Option<usize> {
+        let next = {
+            let mut state = self.state.lock();
+            let deadline = state.deadline?;
+            let mut count = 1;
+            // a periodic timer that fell behind counts every period it missed
+            state.deadline = now
+                .saturating_sub(deadline)
+                .checked_div(state.interval)
+                .map(|missed| {
+                    count += missed as u64;
+                    deadline.saturating_add((missed + 1).saturating_mul(state.interval))
+                });
+            state.expirations += count;
+            state.deadline
+        };
+
+        match &self.notify {
+            TimerNotify::Nothing => {}
+            TimerNotify::Signal { task, info, target } => {
+                // nobody left to signal, so it shouldn't keep the deadline queue busy either
+                let task = task.upgrade()?;
+                get_scheduler().queue_signal(task, *info, *target).ok();
+            }
+            TimerNotify::Wake => {
+                get_scheduler().wake_all(&self.wait_queue);
+                get_scheduler().wake_all(&POLL_WAIT_QUEUE);
+            }
+        }
+        next
+    }
+}
+
+pub type TimerId = c_int;
+
+// the timer_create(2) timers of a process
+#[derive(Default)]
+pub struct PosixTimers {
+    timers: BTreeMap<TimerId, Arc<Timer>>,
+    next_id: TimerId,
+}
+
+impl PosixTimers {
+    pub fn insert(&mut self, timer: Arc<Timer>) -> TimerId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.timers.insert(id, timer);
+        id
+    }
+
+    pub fn get(&self, id: TimerId) -> KResult<Arc<Timer>> {
+        match self.timers.get(&id) {
+            Some(timer) => Ok(timer.clone()),
+            None => kbail!(EINVAL, "PosixTimers::get(): no such timer"),
+        }
+    }
+
+    pub fn remove(&mut self, id: TimerId) -> KResult<()> {
+        let Some(timer) = self.timers.remove(&id) else {
+            kbail!(EINVAL, "PosixTimers::remove(): no such timer");
+        };
+        timer.disarm();
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        for timer in core::mem::take(&mut self.timers).into_values() {
+            timer.disarm();
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use core::sync::atomic::AtomicUsize;
 
-use alloc::vec::Vec;
+use alloc::{collections::BTreeSet, vec::Vec};
 use x86::controlregs::cr3;
 use x86_64::structures::{idt::PageFaultErrorCode, paging::PageTableFlags};
 
@@ -13,7 +13,7 @@ use crate::{
     },
     kbail, kerror,
     mem::{
-        addr::VirtAddr,
+        addr::{PhysAddr, VirtAddr},
         addr_space::AddressSpace,
         allocator::{alloc_kernel_frames, free_kernel_frames, PageAllocator},
         consts::{PAGE_SIZE, USER_STACK_TOP, USER_VALLOC_BASE},
@@ -22,7 +22,10 @@ use crate::{
             units::{AllocatedFrames, Frame, FrameRange, MemoryUnit, Page, PageRange},
         },
     },
-    task::{current_task, get_scheduler, signal::SIGSEGV},
+    task::{
+        current_task,
+        rlimit::{current_rlimit, RLIMIT_AS},
+    },
     userland::buffer::UserBufferMut,
     util::{align_up, KResult},
 };
@@ -40,6 +43,7 @@ bitflags::bitflags! {
 bitflags::bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct MMapFlags: u64 {
+        const MAP_SHARED    = 0x01;
         const MAP_PRIVATE   = 0x02;
         const MAP_FIXED     = 0x10;
         const MAP_ANONYMOUS = 0x20;
@@ -111,6 +115,10 @@ impl VmemArea {
         }
     }
 
+    pub fn is_shared(&self) -> bool {
+        self.flags.contains(MMapFlags::MAP_SHARED)
+    }
+
     pub fn contains_addr(&self, addr: VirtAddr) -> bool {
         (self.start_addr..self.end_addr).contains(&addr)
     }
@@ -153,6 +161,13 @@ pub struct Vmem {
     areas: Vec<VmemArea>,
     next_id: AtomicUsize,
     page_allocator: PageAllocator,
+    brk_start: VirtAddr,
+    brk: VirtAddr,
+    // what exec handed the program, kept around for core dumps
+    auxv: Vec<(usize, usize)>,
+    cmdline: Vec<u8>,
+    // read-only pages a tracer already gave a private copy of, which it can poke in place now
+    ptrace_copies: BTreeSet<Page>,
 }
 
 impl Vmem {
@@ -168,9 +183,32 @@ impl Vmem {
             areas: Vec::new(),
             next_id: AtomicUsize::new(0),
             page_allocator,
+            brk_start: VirtAddr::null(),
+            brk: VirtAddr::null(),
+            auxv: Vec::new(),
+            cmdline: Vec::new(),
+            ptrace_copies: BTreeSet::new(),
         }
     }
 
+    pub fn areas(&self) -> &[VmemArea] {
+        &self.areas
+    }
+
+    // the auxv entries before AT_NULL, and argv with the strings joined by spaces
+    pub fn set_exec_info(&mut self, auxv: &[(usize, usize)], argv: &[&[u8]]) {
+        self.auxv = auxv.to_vec();
+        self.cmdline = argv.join(&b' ');
+    }
+
+    pub fn auxv(&self) -> &[(usize, usize)] {
+        &self.auxv
+    }
+
+    pub fn cmdline(&self) -> &[u8] {
+        &self.cmdline
+    }
+
     pub fn area_containing_mut(
         &mut self,
         start_addr: VirtAddr,
@@ -230,8 +268,12 @@ impl Vmem {
         }
     }
 
-    fn zero_memory(&self, start_addr: VirtAddr, end_addr: VirtAddr) -> KResult<()> {
-        unsafe { start_addr.fill(0, end_addr.value() - start_addr.value()) }?;
+    // RLIMIT_AS counts every mapped byte, whether or not it's backed by a frame yet
+    fn check_as_limit(&self, additional: usize) -> KResult<()> {
+        let total: usize = self.areas.iter().map(VmemArea::size_in_bytes).sum();
+        if (total + additional) as u64 > current_rlimit(RLIMIT_AS) {
+            kbail!(ENOMEM, "Vmem: RLIMIT_AS exceeded");
+        }
         Ok(())
     }
 
@@ -258,6 +300,9 @@ impl Vmem {
         if new_size == 0 {
             return Err(kerror!(EINVAL, "mremap(): new_size is zero"));
         }
+        if new_size > old_size {
+            self.check_as_limit(align_up(new_size - old_size, PAGE_SIZE))?;
+        }
 
         // let new_size_aligned = align_up(new_size, PAGE_SIZE);
         let conflicting_area = self
@@ -335,6 +380,10 @@ impl Vmem {
         if size == 0 {
             kbail!(EINVAL, "mmap(): size is zero");
         }
+        if flags.contains(MMapFlags::MAP_SHARED) {
+            kbail!(ENOSYS, "mmap(): MAP_SHARED isn't supported yet");
+        }
+        self.check_as_limit(align_up(size, PAGE_SIZE))?;
         if flags.contains(MMapFlags::MAP_FIXED) {
             if start_addr.align_down(PAGE_SIZE) != start_addr {
                 kbail!(EINVAL, "mmap(): start_addr not page-aligned");
@@ -397,15 +446,78 @@ impl Vmem {
         kbail!(ENOSYS, "mmap(): not yet implemented for start_addr != null");
     }
 
-    // pub fn brk(&mut self, active_mapper: &mut Mapper, new_brk: VirtAddr) -> KResult<VirtAddr> {
-    //     let current_brk = self
-    //         .areas
-    //         .iter()
-    //         .find(|area| matches!(area.kind, MMapKind::Anonymous))
-    //         .map(|area| area.end_addr)
-    //         .unwrap_or(USER_VALLOC_BASE);
-    //     Ok(current_brk)
-    // }
+    pub fn set_brk_base(&mut self, base: VirtAddr) {
+        self.brk_start = base;
+        self.brk = base;
+    }
+
+    pub fn current_brk(&self) -> VirtAddr {
+        self.brk
+    }
+
+    pub fn brk(&mut self, active_mapper: &mut Mapper, new_brk: VirtAddr) -> KResult<VirtAddr> {
+        let brk_start = self.brk_start;
+        if brk_start == VirtAddr::null() || new_brk < brk_start {
+            kbail!(ENOMEM, "brk(): break would go below the start of the heap");
+        }
+
+        let old_end = self.brk.align_up(PAGE_SIZE);
+        let new_end = new_brk.align_up(PAGE_SIZE);
+        if new_end > old_end {
+            // userspace may have unmapped or mapped over parts of the heap since, so it only
+            // grows into room nobody else has taken
+            if self
+                .areas
+                .iter()
+                .any(|area| area.start_addr < new_end && area.end_addr > old_end)
+            {
+                kbail!(ENOMEM, "brk(): heap would run into another mapping");
+            }
+            self.check_as_limit(new_end - old_end)?;
+            let heap_flags = MMapFlags::MAP_PRIVATE | MMapFlags::MAP_ANONYMOUS;
+            let heap_prot = MMapProt::PROT_READ | MMapProt::PROT_WRITE;
+            // for the same reason, the heap is whatever still ends at the break, if anything
+            let heap = self.areas.iter_mut().find(|area| {
+                area.end_addr == old_end
+                    && area.start_addr >= brk_start
+                    && area.flags == heap_flags
+                    && area.prot == heap_prot
+                    && matches!(area.kind, MMapKind::Anonymous)
+            });
+            if let Some(heap) = heap {
+                heap.end_addr = new_end;
+            } else {
+                self.add_area(old_end, new_end, heap_flags, heap_prot, MMapKind::Anonymous)?;
+            }
+        } else if new_end < old_end {
+            self.remove_range(new_end, old_end);
+            unsafe {
+                self.do_unmap(new_end, old_end, active_mapper);
+            }
+        }
+
+        self.brk = new_brk;
+        Ok(new_brk)
+    }
+
+    // cuts [start, end) out of whatever areas overlap it, without touching the page tables
+    fn remove_range(&mut self, start: VirtAddr, end: VirtAddr) {
+        let mut tails = Vec::new();
+        self.areas.retain_mut(|area| {
+            if area.start_addr >= end || area.end_addr <= start {
+                return true;
+            }
+            if area.end_addr > end {
+                let mut tail = area.clone();
+                tail.start_addr = end;
+                tails.push(tail);
+            }
+            area.end_addr = area.end_addr.min(start);
+            area.start_addr < area.end_addr
+        });
+        self.areas.extend(tails);
+        self.areas.sort_by_key(|area| area.start_address().value());
+    }
 
     fn find_free_space_above(
         &mut self,
@@ -471,6 +583,7 @@ impl Vmem {
             Page::containing_address(end_addr),
         );
         unsafe { self.page_allocator.insert_free_region(range) }
+        self.ptrace_copies.retain(|page| !range.contains(*page));
         for page in range.iter() {
             unsafe {
                 if let Some(frame) = active_mapper.unmap_single(page) {
@@ -563,6 +676,7 @@ impl Vmem {
             }
         }
         self.areas.clear();
+        self.ptrace_copies.clear();
     }
 
     pub fn log(&self) {
@@ -579,10 +693,16 @@ impl Vmem {
         log::debug!("END VIRTUAL MEMORY STATE DUMP");
     }
 
-    pub fn fork_from(&mut self, parent: &Vmem) {
+    pub fn fork_from(&mut self, parent: &mut Vmem) {
         self.areas = parent.areas.clone();
         // self.mp = parent.mp.clone();
         self.page_allocator = parent.page_allocator.clone();
+        self.brk_start = parent.brk_start;
+        self.brk = parent.brk;
+        self.auxv = parent.auxv.clone();
+        self.cmdline = parent.cmdline.clone();
+        // the copies are shared with the child now, so the next poke has to copy them again
+        parent.ptrace_copies.clear();
         self.next_id.store(
             parent.next_id.load(core::sync::atomic::Ordering::Acquire),
             core::sync::atomic::Ordering::Release,
@@ -596,7 +716,25 @@ impl Vmem {
         stack_frame: InterruptErrorFrame,
         reason: PageFaultErrorCode,
     ) -> KResult<()> {
-        let dump_and_exit = || {
+        // a thread on another cpu may have faulted the same page in while we waited for the
+        // lock, in which case there's nothing left to do
+        let mut needed = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if reason.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            needed |= PageTableFlags::WRITABLE;
+        }
+        let resolved = process_addr_space
+            .with_mapper(|mapper| mapper.translate(faulted_addr))
+            .is_some_and(|(_, flags)| {
+                flags.contains(needed)
+                    && !(reason.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+                        && flags.contains(PageTableFlags::NO_EXECUTE))
+            });
+        if resolved {
+            return Ok(());
+        }
+
+        // the caller decides what the task gets for it
+        let segfault = || {
             let current = current_task();
             log::error!("PID: {}", current.pid().as_usize());
             log::error!("Instruction pointer: {:#x}", { stack_frame.frame.rip });
@@ -610,8 +748,7 @@ impl Vmem {
             log::debug!("{:#x?}", stack_frame);
             self.log();
             backtrace::unwind_user_stack_from(stack_frame.frame.rbp, stack_frame.frame.rip);
-            get_scheduler().send_signal_to(current, SIGSEGV);
-            get_scheduler().exit_current(1)
+            kerror!(EFAULT, "handle_page_fault(): segmentation fault")
         };
 
         // log::debug!("User page fault at {:#x}", { stack_frame.frame.rip });
@@ -622,7 +759,7 @@ impl Vmem {
         // backtrace::unwind_user_stack_from(stack_frame.frame.rbp, stack_frame.frame.rip);
         if faulted_addr.align_down(PAGE_SIZE) == VirtAddr::null() {
             log::error!("User segmentation fault: null pointer access");
-            dump_and_exit()
+            return Err(segfault());
         }
 
         let mut faulted_area = None;
@@ -634,68 +771,16 @@ impl Vmem {
         }
 
         if let Some(area) = faulted_area {
+            let page = Page::containing_address(faulted_addr);
             if !reason.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
-                // allocate and map pages
-                let page = Page::containing_address(faulted_addr);
-                let ap = self.page_allocator.allocate_at(page, 1)?;
-                process_addr_space.with_mapper(|mut mapper| mapper.map(ap, area.prot.into()))?;
-                match &area.kind {
-                    MMapKind::Anonymous => {
-                        self.zero_memory(page.start_address(), page.start_address() + PAGE_SIZE)?;
-                    }
-                    MMapKind::File { file, offset, size } => {
-                        let size = size.min(&PAGE_SIZE);
-                        let mut buf = alloc::vec![0; *size];
-                        let user_buf = UserBufferMut::from_slice(&mut buf);
-                        let read = file.read(*offset, user_buf, &OpenFlags::empty())?;
-                        if read == 0 {
-                            self.zero_memory(
-                                page.start_address(),
-                                page.start_address() + PAGE_SIZE,
-                            )?;
-                        } else {
-                            unsafe {
-                                page.start_address().write_bytes_user(&buf)?;
-                            }
-                        }
-                    }
-                }
+                Self::populate_page(&mut self.page_allocator, area, process_addr_space, page)?;
                 return Ok(());
             } else if reason.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
                 if !area.prot.contains(MMapProt::PROT_WRITE) {
                     log::error!("User segmentation fault: illegal write");
-                    dump_and_exit()
+                    return Err(segfault());
                 }
-                // COW
-                let new_frame = alloc_kernel_frames(1)?;
-                let new_page = unsafe {
-                    core::slice::from_raw_parts_mut(
-                        new_frame
-                            .start_address()
-                            .as_hhdm_virt()
-                            .as_raw_ptr_mut::<u8>(),
-                        PAGE_SIZE,
-                    )
-                };
-                let old_page = unsafe {
-                    core::slice::from_raw_parts(
-                        faulted_addr.align_down(PAGE_SIZE).as_raw_ptr::<u8>(),
-                        PAGE_SIZE,
-                    )
-                };
-                new_page.copy_from_slice(old_page);
-                process_addr_space.with_mapper(|mut mapper| -> KResult<()> {
-                    unsafe {
-                        mapper.unmap_single(Page::containing_address(faulted_addr));
-                    }
-                    mapper.map_to_single(
-                        Page::containing_address(faulted_addr),
-                        new_frame.start(),
-                        area.prot.into(),
-                    )?;
-                    Ok(())
-                })?;
-
+                Self::copy_on_write(process_addr_space, page, area.prot.into())?;
                 return Ok(());
             }
             unreachable!(
@@ -703,13 +788,107 @@ impl Vmem {
             )
         } else {
             log::error!("User segmentation fault: illegal access");
-            dump_and_exit()
+            Err(segfault())
         }
+    }
 
-        log::warn!("Unrecoverable page fault at {:#x}", {
-            stack_frame.frame.rip
-        });
-        kbail!(EFAULT, "handle_page_fault(): couldn't handle page fault");
+    // where one of the task's pages is, for a tracer that's peeking or poking at it from its
+    // own address space. a page the task never touched gets faulted in the same as if it had.
+    // a write to a page the task can't write itself, like its code or a page it still shares
+    // copy-on-write after a fork, goes to a private copy instead. that's how a debugger's
+    // breakpoints stay out of everyone else's memory.
+    pub fn ptrace_page(
+        &mut self,
+        process_addr_space: &mut AddressSpace,
+        addr: VirtAddr,
+        write: bool,
+    ) -> KResult<PhysAddr> {
+        let Some(area) = self.areas.iter().find(|area| area.contains_addr(addr)) else {
+            kbail!(EIO, "ptrace_page(): page isn't mapped");
+        };
+        let page = Page::containing_address(addr);
+        let present = |addr_space: &mut AddressSpace| {
+            addr_space
+                .with_mapper(|mapper| mapper.translate(page.start_address()))
+                .filter(|(_, flags)| {
+                    flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+                })
+        };
+        if present(process_addr_space).is_none() {
+            Self::populate_page(&mut self.page_allocator, area, process_addr_space, page)?;
+        }
+        let Some((frame, flags)) = present(process_addr_space) else {
+            kbail!(EIO, "ptrace_page(): page couldn't be faulted in");
+        };
+        if !write || flags.contains(PageTableFlags::WRITABLE) || self.ptrace_copies.contains(&page)
+        {
+            return Ok(frame);
+        }
+
+        let prot = area.prot;
+        let frame = Self::copy_on_write(process_addr_space, page, prot.into())?;
+        if !prot.contains(MMapProt::PROT_WRITE) {
+            // the copy still isn't writable by the task, so remember it's ours already
+            self.ptrace_copies.insert(page);
+        }
+        Ok(frame)
+    }
+
+    // backs a page nobody touched yet with a new frame, filled in the way its area says. it's
+    // all done through the hhdm, so it doesn't matter whose address space is active.
+    fn populate_page(
+        page_allocator: &mut PageAllocator,
+        area: &VmemArea,
+        process_addr_space: &mut AddressSpace,
+        page: Page,
+    ) -> KResult<()> {
+        let ap = page_allocator.allocate_at(page, 1)?;
+        let mp = process_addr_space.with_mapper(|mut mapper| mapper.map(ap, area.prot.into()))?;
+        let mem = unsafe {
+            core::slice::from_raw_parts_mut(
+                mp.frames()
+                    .start_address()
+                    .as_hhdm_virt()
+                    .as_raw_ptr_mut::<u8>(),
+                PAGE_SIZE,
+            )
+        };
+        mem.fill(0);
+        if let MMapKind::File { file, offset, size } = &area.kind {
+            let size = size.min(&PAGE_SIZE);
+            let user_buf = UserBufferMut::from_slice(&mut mem[..*size]);
+            file.read(*offset, user_buf, &OpenFlags::empty())?;
+        }
+        Ok(())
+    }
+
+    // gives the page a copy of its frame to itself, mapped with `flags`, and returns the copy.
+    // the old frame is left to whoever else still maps it, since nothing keeps count of who
+    // shares it after a fork.
+    fn copy_on_write(
+        process_addr_space: &mut AddressSpace,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> KResult<PhysAddr> {
+        process_addr_space.with_mapper(|mut mapper| -> KResult<PhysAddr> {
+            let Some((old_frame, _)) = mapper.translate(page.start_address()) else {
+                kbail!(EFAULT, "copy_on_write(): page isn't mapped");
+            };
+            let new_frame = alloc_kernel_frames(1)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    old_frame.as_hhdm_virt().as_raw_ptr::<u8>(),
+                    new_frame
+                        .start_address()
+                        .as_hhdm_virt()
+                        .as_raw_ptr_mut::<u8>(),
+                    PAGE_SIZE,
+                );
+                mapper.unmap_single(page);
+            }
+            mapper.map_to_single(page, new_frame.start(), flags)?;
+            Ok(new_frame.start_address())
+        })
     }
 }
 
@@ -718,3 +897,65 @@ impl Default for Vmem {
         Self::new()
     }
 }
+
+pub(crate) mod selftests {
+    use crate::{
+        kbail,
+        mem::{addr::VirtAddr, addr_space::AddressSpace, consts::PAGE_SIZE},
+        util::KResult,
+    };
+
+    use super::{MMapFlags, MMapProt, Vmem};
+
+    const HEAP_BASE: usize = 0x1000_0000;
+
+    // the heap is only tracked by where it started, so a mapping dropped in past the break has
+    // to stop it from growing, and one dropped over its start mustn't be mistaken for it
+    pub fn brk_refuses_overlap() -> KResult<()> {
+        let heap = |pages: usize| VirtAddr::new(HEAP_BASE + pages * PAGE_SIZE);
+        let fixed = MMapFlags::MAP_FIXED | MMapFlags::MAP_PRIVATE | MMapFlags::MAP_ANONYMOUS;
+        let mut addr_space = AddressSpace::new()?;
+        let mut vmem = Vmem::new();
+        vmem.set_brk_base(heap(0));
+
+        let res = addr_space.with_mapper(|mut mapper| -> KResult<()> {
+            vmem.brk(&mut mapper, heap(2))?;
+            vmem.mmap(
+                heap(3),
+                PAGE_SIZE,
+                MMapProt::PROT_READ,
+                fixed,
+                -1,
+                0,
+                &mut mapper,
+            )?;
+            if vmem.brk(&mut mapper, heap(4)).is_ok() || vmem.current_brk() != heap(2) {
+                kbail!("brk_refuses_overlap(): heap grew over a fixed mapping");
+            }
+
+            // with the first page of the heap replaced, the rest of it still grows
+            vmem.munmap(&mut mapper, heap(0), heap(1))?;
+            vmem.mmap(
+                heap(0),
+                PAGE_SIZE,
+                MMapProt::PROT_READ,
+                fixed,
+                -1,
+                0,
+                &mut mapper,
+            )?;
+            vmem.brk(&mut mapper, heap(3))?;
+            let first = vmem.area_containing(heap(0), heap(0));
+            if first.is_none_or(|area| area.end_address() != heap(1)) {
+                kbail!("brk_refuses_overlap(): heap grew a mapping that took over its start");
+            }
+            let rest = vmem.area_containing(heap(1), heap(3) - 1);
+            if rest.is_none_or(|area| area.prot != MMapProt::PROT_READ | MMapProt::PROT_WRITE) {
+                kbail!("brk_refuses_overlap(): heap didn't grow");
+            }
+            Ok(())
+        });
+        addr_space.with_mapper(|mut mapper| vmem.clear(&mut mapper));
+        res
+    }
+}
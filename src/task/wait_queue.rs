@@ -1,11 +1,11 @@
 use alloc::{collections::VecDeque, sync::Arc};
 
 use crate::{
-    arch, kbail,
+    arch, kbail, kerror,
     util::{IrqMutex, KResult},
 };
 
-use super::{current_task, get_scheduler, Task, TaskState};
+use super::{current_task, get_scheduler, signal::SIGKILL, Task, TaskState};
 
 pub struct WaitQueue {
     pub(super) queue: IrqMutex<VecDeque<Arc<Task>>>,
@@ -39,14 +39,14 @@ impl WaitQueue {
             current.set_state(TaskState::Waiting);
             {
                 let mut q_lock = self.queue.lock();
-                if !q_lock.iter().any(|t| t.pid == current.pid) {
+                if !q_lock.iter().any(|t| t.pid() == current.pid()) {
                     q_lock.push_back(current.clone());
                 }
             }
 
             if current.has_pending_signals() {
                 scheduler.resume_task(current.clone());
-                self.queue.lock().retain(|t| t.pid != current.pid);
+                self.queue.lock().retain(|t| t.pid() != current.pid());
                 kbail!(
                     EINTR,
                     "sleep_signalable_until(): interrupted by pending signals"
@@ -62,17 +62,74 @@ impl WaitQueue {
             let current = current_task();
             if let Some(ret_val) = ret_value {
                 scheduler.resume_task(current.clone());
-                self.queue.lock().retain(|t| t.pid != current.pid);
+                self.queue.lock().retain(|t| t.pid() != current.pid());
                 return ret_val;
             }
 
-            scheduler.sleep(timeout)?;
+            // whoever wakes us might do it before we're even off the cpu, which block() handles
+            if let Err(err) = scheduler.block(timeout) {
+                self.queue.lock().retain(|t| t.pid() != current.pid());
+                return Err(err);
+            }
 
             if let Some(timeout) = timeout {
-                if arch::time::get_uptime_ms() >= start_time + timeout {
+                if arch::time::get_uptime_ms() >= start_time.saturating_add(timeout) {
+                    self.queue.lock().retain(|t| t.pid() != current.pid());
                     kbail!(EINTR, "sleep_signalable_until(): timeout reached");
                 }
             }
         }
     }
+
+    // like sleep_signalable_until(), but signals only wake us up to check the condition again
+    pub fn sleep_until<F>(&self, mut condition: F)
+    where
+        F: FnMut() -> bool,
+    {
+        let current = current_task();
+        loop {
+            current.set_state(TaskState::Waiting);
+            {
+                let mut q_lock = self.queue.lock();
+                if !q_lock.iter().any(|t| t.pid() == current.pid()) {
+                    q_lock.push_back(current.clone());
+                }
+            }
+            // checked only once we're on the queue, so a wakeup can't slip in between
+            if condition() {
+                get_scheduler().resume_task(current.clone());
+                break;
+            }
+            get_scheduler().park(None);
+        }
+        self.queue.lock().retain(|t| t.pid() != current.pid());
+    }
+
+    // like sleep_until(), but SIGKILL gets us out before the condition holds
+    pub fn sleep_killable_until<F>(&self, mut condition: F) -> KResult<()>
+    where
+        F: FnMut() -> bool,
+    {
+        let current = current_task();
+        let ret = loop {
+            current.set_state(TaskState::Waiting);
+            {
+                let mut q_lock = self.queue.lock();
+                if !q_lock.iter().any(|t| t.pid() == current.pid()) {
+                    q_lock.push_back(current.clone());
+                }
+            }
+            if condition() {
+                get_scheduler().resume_task(current.clone());
+                break Ok(());
+            }
+            if current.pending_signals().contains(SIGKILL) {
+                get_scheduler().resume_task(current.clone());
+                break Err(kerror!(EINTR, "sleep_killable_until(): killed"));
+            }
+            get_scheduler().park(None);
+        };
+        self.queue.lock().retain(|t| t.pid() != current.pid());
+        ret
+    }
 }
@@ -0,0 +1,508 @@
+use alloc::{
+    borrow::ToOwned,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use spin::Once;
+
+use crate::{
+    fs::{
+        alloc_inode_no,
+        initramfs::{dir::InitRamFsDir, get_root, root::RootFs},
+        opened_file::OpenFlags,
+        path::{Path, PathBuf},
+        DirRef, File, FileMode, FileRef, FsNode, INode, Stat, S_IFREG,
+    },
+    kbail, kerror,
+    task::cred::{current_credentials, Access},
+    userland::buffer::{UserBuffer, UserBufferMut, UserBufferReader, UserBufferWriter},
+    util::{IrqMutex, KResult},
+};
+
+// how much of a file the formats get to look at, and how many times one interpreter can hand
+// off to another, the same as on linux
+const HEADER_SIZE: usize = 256;
+const MAX_INTERPRETER_DEPTH: usize = 5;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+static REGISTRY: IrqMutex<Registry> = IrqMutex::new(Registry {
+    enabled: true,
+    entries: Vec::new(),
+});
+static BINFMT_MISC_DIR: Once<DirRef> = Once::new();
+
+struct Registry {
+    enabled: bool,
+    entries: Vec<Arc<BinfmtEntry>>,
+}
+
+enum BinfmtMatch {
+    Magic {
+        offset: usize,
+        magic: Vec<u8>,
+        mask: Option<Vec<u8>>,
+    },
+    Extension(String),
+}
+
+// one line that was written to /proc/sys/fs/binfmt_misc/register, which shows up next to it
+// as a file of its own
+pub struct BinfmtEntry {
+    name: String,
+    matcher: BinfmtMatch,
+    interpreter: String,
+    // the P flag: the interpreter gets the original argv[0] too, after the file's path
+    preserve_argv0: bool,
+    enabled: IrqMutex<bool>,
+    inode_no: usize,
+}
+
+impl BinfmtEntry {
+    // :name:type:offset:magic:mask:interpreter:flags, where the first character can be any
+    // delimiter that doesn't turn up anywhere else
+    fn parse(line: &str) -> KResult<BinfmtEntry> {
+        let line = line.trim_end_matches('\n');
+        let Some(delim) = line.chars().next() else {
+            kbail!(EINVAL, "BinfmtEntry::parse(): empty line");
+        };
+        let fields = line[delim.len_utf8()..].split(delim).collect::<Vec<_>>();
+        let [name, kind, offset, magic, mask, interpreter, flags] = fields[..] else {
+            kbail!(EINVAL, "BinfmtEntry::parse(): wrong number of fields");
+        };
+
+        if name.is_empty()
+            || name.contains('/')
+            || matches!(name, "." | ".." | "register" | "status")
+        {
+            kbail!(EINVAL, "BinfmtEntry::parse(): bad name");
+        }
+        let matcher = match kind {
+            "M" => {
+                let offset = if offset.is_empty() {
+                    0
+                } else {
+                    offset
+                        .parse()
+                        .map_err(|_| kerror!(EINVAL, "BinfmtEntry::parse(): bad offset"))?
+                };
+                let magic = unescape(magic)?;
+                let mask = if mask.is_empty() {
+                    None
+                } else {
+                    Some(unescape(mask)?)
+                };
+                if magic.is_empty() || offset + magic.len() > HEADER_SIZE {
+                    kbail!(EINVAL, "BinfmtEntry::parse(): magic out of range");
+                }
+                if mask.as_ref().is_some_and(|mask| mask.len() != magic.len()) {
+                    kbail!(
+                        EINVAL,
+                        "BinfmtEntry::parse(): mask and magic differ in length"
+                    );
+                }
+                BinfmtMatch::Magic {
+                    offset,
+                    magic,
+                    mask,
+                }
+            }
+            "E" => {
+                if !offset.is_empty() || !mask.is_empty() || magic.is_empty() || magic.contains('/')
+                {
+                    kbail!(EINVAL, "BinfmtEntry::parse(): bad extension");
+                }
+                BinfmtMatch::Extension(magic.to_owned())
+            }
+            _ => kbail!(EINVAL, "BinfmtEntry::parse(): unknown type"),
+        };
+        if !interpreter.starts_with('/') {
+            kbail!(
+                EINVAL,
+                "BinfmtEntry::parse(): interpreter isn't an absolute path"
+            );
+        }
+        if flags.chars().any(|flag| flag != 'P') {
+            kbail!(EINVAL, "BinfmtEntry::parse(): unsupported flags");
+        }
+
+        Ok(BinfmtEntry {
+            name: name.to_owned(),
+            matcher,
+            interpreter: interpreter.to_owned(),
+            preserve_argv0: flags.contains('P'),
+            enabled: IrqMutex::new(true),
+            inode_no: alloc_inode_no(),
+        })
+    }
+
+    fn matches(&self, path: &Path, header: &[u8]) -> bool {
+        if !*self.enabled.lock() {
+            return false;
+        }
+        match &self.matcher {
+            BinfmtMatch::Magic {
+                offset,
+                magic,
+                mask,
+            } => header
+                .get(*offset..*offset + magic.len())
+                .is_some_and(|bytes| {
+                    bytes
+                        .iter()
+                        .zip(magic)
+                        .enumerate()
+                        .all(|(i, (byte, magic))| {
+                            let mask = mask.as_ref().map_or(0xff, |mask| mask[i]);
+                            (byte ^ magic) & mask == 0
+                        })
+                }),
+            BinfmtMatch::Extension(ext) => path
+                .parent_and_basename()
+                .and_then(|(_, name)| name.rsplit_once('.'))
+                .is_some_and(|(_, name_ext)| name_ext == ext),
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mut text = format!(
+            "{}\ninterpreter {}\nflags: {}\n",
+            enabled_str(*self.enabled.lock()),
+            self.interpreter,
+            if self.preserve_argv0 { "P" } else { "" },
+        );
+        match &self.matcher {
+            BinfmtMatch::Magic {
+                offset,
+                magic,
+                mask,
+            } => {
+                text += &format!("offset {}\nmagic {}\n", offset, hex(magic));
+                if let Some(mask) = mask {
+                    text += &format!("mask {}\n", hex(mask));
+                }
+            }
+            BinfmtMatch::Extension(ext) => text += &format!("extension .{}\n", ext),
+        }
+        text
+    }
+}
+
+impl FsNode for BinfmtEntry {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+impl File for BinfmtEntry {
+    fn read(&self, offset: usize, buf: UserBufferMut<'_>, _options: &OpenFlags) -> KResult<usize> {
+        read_text(&self.describe(), offset, buf)
+    }
+
+    // 1 and 0 turn it on and off, -1 gets rid of it
+    fn write(&self, _offset: usize, buf: UserBuffer<'_>, _options: &OpenFlags) -> KResult<usize> {
+        let len = buf.len();
+        match read_control(buf)? {
+            Control::Enable(enabled) => *self.enabled.lock() = enabled,
+            Control::Remove => {
+                REGISTRY
+                    .lock()
+                    .entries
+                    .retain(|entry| entry.name != self.name);
+                if let Some(dir) = BINFMT_MISC_DIR.get() {
+                    dir.unlink(&self.name)?;
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    fn stat(&self) -> KResult<Stat> {
+        Ok(Stat {
+            inode_no: self.inode_no,
+            mode: FileMode::new(S_IFREG | 0o644),
+            ..Stat::zeroed()
+        })
+    }
+}
+
+// writing a line here adds a format
+struct RegisterFile {
+    inode_no: usize,
+}
+
+impl FsNode for RegisterFile {
+    fn get_name(&self) -> String {
+        "register".to_owned()
+    }
+}
+
+impl File for RegisterFile {
+    fn write(&self, _offset: usize, buf: UserBuffer<'_>, _options: &OpenFlags) -> KResult<usize> {
+        check_privileged()?;
+        let len = buf.len();
+        let line = read_line(buf)?;
+        let entry = Arc::new(BinfmtEntry::parse(&line)?);
+        {
+            let mut registry = REGISTRY.lock();
+            if registry
+                .entries
+                .iter()
+                .any(|other| other.name == entry.name)
+            {
+                kbail!(EEXIST, "RegisterFile::write(): name already registered");
+            }
+            registry.entries.push(entry.clone());
+        }
+        if let Some(dir) = BINFMT_MISC_DIR.get() {
+            dir.insert(INode::File(entry));
+        }
+        Ok(len)
+    }
+
+    fn stat(&self) -> KResult<Stat> {
+        Ok(Stat {
+            inode_no: self.inode_no,
+            mode: FileMode::new(S_IFREG | 0o200),
+            ..Stat::zeroed()
+        })
+    }
+}
+
+// turns the whole registry on and off, or empties it
+struct StatusFile {
+    inode_no: usize,
+}
+
+impl FsNode for StatusFile {
+    fn get_name(&self) -> String {
+        "status".to_owned()
+    }
+}
+
+impl File for StatusFile {
+    fn read(&self, offset: usize, buf: UserBufferMut<'_>, _options: &OpenFlags) -> KResult<usize> {
+        let text = format!("{}\n", enabled_str(REGISTRY.lock().enabled));
+        read_text(&text, offset, buf)
+    }
+
+    fn write(&self, _offset: usize, buf: UserBuffer<'_>, _options: &OpenFlags) -> KResult<usize> {
+        let len = buf.len();
+        match read_control(buf)? {
+            Control::Enable(enabled) => REGISTRY.lock().enabled = enabled,
+            Control::Remove => {
+                let entries = core::mem::take(&mut REGISTRY.lock().entries);
+                if let Some(dir) = BINFMT_MISC_DIR.get() {
+                    for entry in entries {
+                        dir.unlink(&entry.name)?;
+                    }
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    fn stat(&self) -> KResult<Stat> {
+        Ok(Stat {
+            inode_no: self.inode_no,
+            mode: FileMode::new(S_IFREG | 0o644),
+            ..Stat::zeroed()
+        })
+    }
+}
+
+enum Control {
+    Enable(bool),
+    Remove,
+}
+
+fn check_privileged() -> KResult<()> {
+    if !current_credentials().is_privileged() {
+        kbail!(EPERM, "binfmt_misc: only root can change formats");
+    }
+    Ok(())
+}
+
+fn read_line(buf: UserBuffer<'_>) -> KResult<String> {
+    let mut bytes = vec![0; buf.len()];
+    UserBufferReader::from_buf(buf).read_bytes(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| kerror!(EINVAL, "binfmt_misc: not valid UTF-8"))
+}
+
+fn read_control(buf: UserBuffer<'_>) -> KResult<Control> {
+    check_privileged()?;
+    match read_line(buf)?.trim_end_matches('\n') {
+        "1" => Ok(Control::Enable(true)),
+        "0" => Ok(Control::Enable(false)),
+        "-1" => Ok(Control::Remove),
+        _ => Err(kerror!(EINVAL, "binfmt_misc: expected 1, 0 or -1")),
+    }
+}
+
+fn read_text(text: &str, offset: usize, buf: UserBufferMut<'_>) -> KResult<usize> {
+    let Some(text) = text.as_bytes().get(offset..) else {
+        return Ok(0);
+    };
+    UserBufferWriter::from_buf(buf).write_bytes(text)
+}
+
+fn enabled_str(enabled: bool) -> &'static str {
+    if enabled {
+        "enabled"
+    } else {
+        "disabled"
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// magic and mask can have \xHH escapes for bytes that can't be typed, and \\ for a backslash
+fn unescape(field: &str) -> KResult<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut rest = field.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match rest {
+            [b'x', hi, lo, tail @ ..] => {
+                let digits = core::str::from_utf8(&[*hi, *lo])
+                    .ok()
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+                let Some(value) = digits else {
+                    kbail!(EINVAL, "unescape(): bad \\x escape");
+                };
+                bytes.push(value);
+                rest = tail;
+            }
+            [b'\\', tail @ ..] => {
+                bytes.push(b'\\');
+                rest = tail;
+            }
+            _ => kbail!(EINVAL, "unescape(): bad escape"),
+        }
+    }
+    Ok(bytes)
+}
+
+// #!interpreter [arg], with everything after the interpreter passed along as one argument
+fn parse_shebang(header: &[u8]) -> KResult<(String, Option<String>)> {
+    let line = header[2..]
+        .split(|&byte| byte == b'\n')
+        .next()
+        .unwrap_or(&[]);
+    let Ok(line) = core::str::from_utf8(line) else {
+        kbail!(ENOEXEC, "parse_shebang(): interpreter line isn't UTF-8");
+    };
+    let line = line.trim_matches([' ', '\t', '\r']);
+    let (interpreter, arg) = match line.split_once([' ', '\t']) {
+        Some((interpreter, arg)) => (interpreter, Some(arg.trim_matches([' ', '\t']))),
+        None => (line, None),
+    };
+    if interpreter.is_empty() {
+        kbail!(ENOEXEC, "parse_shebang(): no interpreter");
+    }
+    let arg = arg.filter(|arg| !arg.is_empty()).map(ToString::to_string);
+    Ok((interpreter.to_owned(), arg))
+}
+
+// works out what execve(2) should load for `file`. scripts and registered formats hand off to
+// their interpreter, which gets the file's path in place of argv[0].
+pub fn resolve_interpreter(
+    root_fs: &RootFs,
+    path: &Path,
+    mut file: FileRef,
+    mut argv: Vec<Vec<u8>>,
+) -> KResult<(FileRef, Vec<Vec<u8>>)> {
+    let mut path = PathBuf::from(path);
+    for _ in 0..=MAX_INTERPRETER_DEPTH {
+        let mut header = [0; HEADER_SIZE];
+        let len = file.read(
+            0,
+            UserBufferMut::from_slice(&mut header),
+            &OpenFlags::empty(),
+        )?;
+        let header = &header[..len];
+
+        let entry = {
+            let registry = REGISTRY.lock();
+            registry
+                .entries
+                .iter()
+                .find(|entry| registry.enabled && entry.matches(&path, header))
+                .cloned()
+        };
+        let file_path = path.as_str().as_bytes().to_vec();
+        let interpreter = if let Some(entry) = entry {
+            if !entry.preserve_argv0 && !argv.is_empty() {
+                argv.remove(0);
+            }
+            argv.insert(0, file_path);
+            entry.interpreter.clone()
+        } else if header.starts_with(b"#!") {
+            let (interpreter, arg) = parse_shebang(header)?;
+            if !argv.is_empty() {
+                argv.remove(0);
+            }
+            argv.insert(0, file_path);
+            if let Some(arg) = arg {
+                argv.insert(0, arg.into_bytes());
+            }
+            interpreter
+        } else if header.starts_with(ELF_MAGIC) {
+            return Ok((file, argv));
+        } else {
+            kbail!(ENOEXEC, "resolve_interpreter(): unknown executable format");
+        };
+        argv.insert(0, interpreter.as_bytes().to_vec());
+
+        file = root_fs
+            .lookup(Path::new(&interpreter), true)?
+            .as_file()?
+            .clone();
+        let stat = file.stat()?;
+        if !stat.mode.is_regular_file() {
+            kbail!(
+                EACCES,
+                "resolve_interpreter(): interpreter isn't a regular file"
+            );
+        }
+        current_credentials().check_access(&stat, Access::X_OK)?;
+        path = PathBuf::from(interpreter);
+    }
+    kbail!(
+        ELOOP,
+        "resolve_interpreter(): too many levels of interpreters"
+    )
+}
+
+fn subdir(dir: DirRef, name: &str) -> DirRef {
+    if let Ok(INode::Dir(sub)) = dir.lookup(name) {
+        return sub;
+    }
+    let sub: DirRef = Arc::new(InitRamFsDir::new(name.to_owned(), alloc_inode_no()));
+    dir.insert(INode::Dir(sub.clone()));
+    sub
+}
+
+// where linux keeps it, even though we've nothing else under /proc
+pub fn init() {
+    let root = get_root().unwrap().root_dir();
+    let dir = ["proc", "sys", "fs", "binfmt_misc"]
+        .into_iter()
+        .fold(root, subdir);
+    dir.insert(INode::File(Arc::new(RegisterFile {
+        inode_no: alloc_inode_no(),
+    })));
+    dir.insert(INode::File(Arc::new(StatusFile {
+        inode_no: alloc_inode_no(),
+    })));
+    BINFMT_MISC_DIR.call_once(|| dir);
+}
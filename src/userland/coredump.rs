@@ -0,0 +1,394 @@
+use core::mem::size_of;
+
+use alloc::{format, sync::Arc, vec::Vec};
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::{
+    arch::{idt::InterruptFrame, ptrace::UserRegs, task::ArchTask},
+    fs::{
+        alloc_inode_no, initramfs::file::InitRamFsFile, opened_file::OpenFlags, path::Path, File,
+        FileMode, INode, S_IFREG,
+    },
+    kbail,
+    mem::{
+        addr::{PhysAddr, VirtAddr},
+        consts::PAGE_SIZE,
+    },
+    task::{
+        cred::Access,
+        rlimit::RLIMIT_CORE,
+        signal::Signal,
+        vmem::{MMapProt, VmemArea},
+        Task,
+    },
+    userland::{buffer::UserBuffer, syscall::syscall_impl::time::TimeVal},
+    util::{align_up, ctypes::c_int, KResult},
+};
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const EV_CURRENT: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELFOSABI_SYSV: u8 = 0;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_PRFPREG: u32 = 2;
+const NT_PRPSINFO: u32 = 3;
+const NT_AUXV: u32 = 6;
+
+// every note gdb looks for is filed under this name
+const NOTE_NAME: &[u8] = b"CORE\0";
+
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Elf64Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Elf64Phdr {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Elf64Nhdr {
+    n_namesz: u32,
+    n_descsz: u32,
+    n_type: u32,
+}
+
+// struct elf_prstatus, with the padding spelled out
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct ElfPrStatus {
+    si_signo: c_int,
+    si_code: c_int,
+    si_errno: c_int,
+    pr_cursig: u16,
+    _pad0: u16,
+    pr_sigpend: u64,
+    pr_sighold: u64,
+    pr_pid: c_int,
+    pr_ppid: c_int,
+    pr_pgrp: c_int,
+    pr_sid: c_int,
+    pr_utime: TimeVal,
+    pr_stime: TimeVal,
+    pr_cutime: TimeVal,
+    pr_cstime: TimeVal,
+    pr_reg: UserRegs,
+    pr_fpvalid: c_int,
+    _pad1: c_int,
+}
+
+// struct elf_prpsinfo
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct ElfPrPsInfo {
+    pr_state: u8,
+    pr_sname: u8,
+    pr_zomb: u8,
+    pr_nice: i8,
+    _pad0: u32,
+    pr_flag: u64,
+    pr_uid: u32,
+    pr_gid: u32,
+    pr_pid: c_int,
+    pr_ppid: c_int,
+    pr_pgrp: c_int,
+    pr_sid: c_int,
+    pr_fname: [u8; 16],
+    pr_psargs: [u8; 80],
+}
+
+fn push_struct<T: Copy>(buf: &mut Vec<u8>, value: &T) {
+    // everything that comes through here is repr(C) without any implicit padding
+    let bytes =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    buf.extend_from_slice(bytes);
+}
+
+fn push_note(buf: &mut Vec<u8>, n_type: u32, desc: &[u8]) {
+    push_struct(
+        buf,
+        &Elf64Nhdr {
+            n_namesz: NOTE_NAME.len() as u32,
+            n_descsz: desc.len() as u32,
+            n_type,
+        },
+    );
+    buf.extend_from_slice(NOTE_NAME);
+    buf.resize(align_up(buf.len(), 4), 0);
+    buf.extend_from_slice(desc);
+    buf.resize(align_up(buf.len(), 4), 0);
+}
+
+fn struct_bytes<T: Copy>(value: &T) -> Vec<u8> {
+    let mut buf = Vec::new();
+    push_struct(&mut buf, value);
+    buf
+}
+
+fn build_notes(task: &Task, frame: &InterruptFrame, signal: Signal) -> Vec<u8> {
+    let pid = task.tgid().as_usize() as c_int;
+    let ppid = task.ppid().as_usize() as c_int;
+    let pgrp = task.pgid().unwrap_or(0);
+    let sid = task.sid();
+    let spent = task.thread_cpu_time();
+    let cred = task.credentials();
+    let vmem = task.vmem();
+    let vmem = vmem.lock();
+
+    let prstatus = ElfPrStatus {
+        si_signo: signal,
+        pr_cursig: signal as u16,
+        pr_sigpend: task.pending_signals().to_user(),
+        pr_sighold: task.signal_mask().to_user(),
+        pr_pid: task.pid().as_usize() as c_int,
+        pr_ppid: ppid,
+        pr_pgrp: pgrp,
+        pr_sid: sid,
+        pr_utime: TimeVal::from_nanos(spent.user_ns),
+        pr_stime: TimeVal::from_nanos(spent.system_ns),
+        pr_reg: UserRegs::new(frame, task.arch_mut().fsbase(), None),
+        pr_fpvalid: 1,
+        ..Default::default()
+    };
+
+    let cmdline = vmem.cmdline();
+    let argv0 = cmdline.split(|&c| c == b' ').next().unwrap_or_default();
+    let fname = argv0.rsplit(|&c| c == b'/').next().unwrap_or_default();
+    let mut prpsinfo = ElfPrPsInfo {
+        pr_state: 0,
+        pr_sname: b'R',
+        pr_zomb: 0,
+        pr_nice: 0,
+        _pad0: 0,
+        pr_flag: 0,
+        pr_uid: cred.uid.as_u32(),
+        pr_gid: cred.gid.as_u32(),
+        pr_pid: pid,
+        pr_ppid: ppid,
+        pr_pgrp: pgrp,
+        pr_sid: sid,
+        pr_fname: [0; 16],
+        pr_psargs: [0; 80],
+    };
+    // both of these stay nul-terminated
+    let len = fname.len().min(prpsinfo.pr_fname.len() - 1);
+    prpsinfo.pr_fname[..len].copy_from_slice(&fname[..len]);
+    let len = cmdline.len().min(prpsinfo.pr_psargs.len() - 1);
+    prpsinfo.pr_psargs[..len].copy_from_slice(&cmdline[..len]);
+
+    let mut auxv = Vec::new();
+    for &(ty, value) in vmem.auxv().iter().chain(&[(0, 0)]) {
+        push_struct(&mut auxv, &(ty as u64));
+        push_struct(&mut auxv, &(value as u64));
+    }
+
+    let mut notes = Vec::new();
+    push_note(&mut notes, NT_PRSTATUS, &struct_bytes(&prstatus));
+    push_note(&mut notes, NT_PRFPREG, &ArchTask::save_fpu_state());
+    push_note(&mut notes, NT_PRPSINFO, &struct_bytes(&prpsinfo));
+    push_note(&mut notes, NT_AUXV, &auxv);
+    notes
+}
+
+fn segment_flags(prot: MMapProt) -> u32 {
+    let mut flags = 0;
+    if prot.contains(MMapProt::PROT_READ) {
+        flags |= PF_R;
+    }
+    if prot.contains(MMapProt::PROT_WRITE) {
+        flags |= PF_W;
+    }
+    if prot.contains(MMapProt::PROT_EXEC) {
+        flags |= PF_X;
+    }
+    flags
+}
+
+// the frame behind a page, if anything's been faulted in there yet. this never faults
+// anything in itself.
+fn present_page(task: &Task, addr: VirtAddr) -> Option<PhysAddr> {
+    let (frame, flags) = task
+        .arch_mut()
+        .address_space
+        .with_mapper(|mapper| mapper.translate(addr))?;
+    flags.contains(PageTableFlags::PRESENT).then_some(frame)
+}
+
+// how much of the area goes into the file. pages nobody touched yet are all zeroes anyway, so
+// the segment ends with the last one that was.
+fn touched_size(task: &Task, area: &VmemArea) -> usize {
+    if !area.prot.contains(MMapProt::PROT_READ) {
+        // there's nothing to read in memory that can't be read
+        return 0;
+    }
+    let vmem = task.vmem();
+    let _vmem = vmem.lock();
+    let mut addr = area.end_address();
+    while addr > area.start_address() {
+        if present_page(task, addr - PAGE_SIZE).is_some() {
+            return addr - area.start_address();
+        }
+        addr -= PAGE_SIZE;
+    }
+    0
+}
+
+// writes as much of `data` as RLIMIT_CORE leaves room for, and says whether there's any room
+// left after it
+fn write_capped(file: &InitRamFsFile, offset: usize, data: &[u8], limit: usize) -> KResult<bool> {
+    if offset >= limit {
+        return Ok(false);
+    }
+    let len = data.len().min(limit - offset);
+    file.write(offset, UserBuffer::from_slice(&data[..len]), &OpenFlags::empty())?;
+    Ok(offset + len < limit)
+}
+
+// writes an ELF core file for the current task to /core.<pid>, the way it was when the signal
+// killed it
+pub fn dump_core(task: &Arc<Task>, frame: &InterruptFrame, signal: Signal) -> KResult<()> {
+    let limit = task.rlimit(RLIMIT_CORE) as usize;
+    if limit == 0 {
+        kbail!(EFBIG, "dump_core(): RLIMIT_CORE is 0");
+    }
+
+    let notes = build_notes(task, frame, signal);
+    let areas = task.vmem().lock().areas().to_vec();
+    let sizes = areas
+        .iter()
+        .map(|area| touched_size(task, area))
+        .collect::<Vec<_>>();
+
+    let phnum = areas.len() + 1;
+    let notes_offset = size_of::<Elf64Ehdr>() + phnum * size_of::<Elf64Phdr>();
+    let mut data_offset = align_up(notes_offset + notes.len(), PAGE_SIZE);
+
+    let mut ident = [0; 16];
+    ident[..4].copy_from_slice(b"\x7fELF");
+    ident[4] = ELFCLASS64;
+    ident[5] = ELFDATA2LSB;
+    ident[6] = EV_CURRENT;
+    ident[7] = ELFOSABI_SYSV;
+    let mut headers = Vec::new();
+    push_struct(
+        &mut headers,
+        &Elf64Ehdr {
+            e_ident: ident,
+            e_type: ET_CORE,
+            e_machine: EM_X86_64,
+            e_version: EV_CURRENT as u32,
+            e_phoff: size_of::<Elf64Ehdr>() as u64,
+            e_ehsize: size_of::<Elf64Ehdr>() as u16,
+            e_phentsize: size_of::<Elf64Phdr>() as u16,
+            e_phnum: phnum as u16,
+            ..Default::default()
+        },
+    );
+    push_struct(
+        &mut headers,
+        &Elf64Phdr {
+            p_type: PT_NOTE,
+            p_offset: notes_offset as u64,
+            p_filesz: notes.len() as u64,
+            ..Default::default()
+        },
+    );
+    for (area, &filesz) in areas.iter().zip(&sizes) {
+        push_struct(
+            &mut headers,
+            &Elf64Phdr {
+                p_type: PT_LOAD,
+                p_flags: segment_flags(area.prot),
+                p_offset: data_offset as u64,
+                p_vaddr: area.start_address().value() as u64,
+                p_paddr: 0,
+                p_filesz: filesz as u64,
+                p_memsz: area.size_in_bytes() as u64,
+                p_align: PAGE_SIZE as u64,
+            },
+        );
+        data_offset += filesz;
+    }
+    headers.extend_from_slice(&notes);
+    headers.resize(align_up(headers.len(), PAGE_SIZE), 0);
+
+    // past RLIMIT_CORE, the file just gets cut off
+    let file = create_core_file(task)?;
+    if !write_capped(&file, 0, &headers, limit)? {
+        return Ok(());
+    }
+    // a page at a time, so the only copy of the memory is the one in the file
+    let vmem = task.vmem();
+    let mut data_offset = headers.len();
+    for (area, &filesz) in areas.iter().zip(&sizes) {
+        for offset in (0..filesz).step_by(PAGE_SIZE) {
+            let _vmem = vmem.lock();
+            // whatever wasn't touched reads back as a hole full of zeroes
+            let Some(frame) = present_page(task, area.start_address() + offset) else {
+                continue;
+            };
+            let page = unsafe {
+                core::slice::from_raw_parts(frame.as_hhdm_virt().as_raw_ptr::<u8>(), PAGE_SIZE)
+            };
+            if !write_capped(&file, data_offset + offset, page, limit)? {
+                return Ok(());
+            }
+        }
+        data_offset += filesz;
+    }
+    Ok(())
+}
+
+fn create_core_file(task: &Task) -> KResult<Arc<InitRamFsFile>> {
+    let cred = task.credentials();
+    let root = task.root_fs.lock();
+    let dir = root.lookup(Path::new("/"), true)?;
+    let dir = dir.as_dir()?;
+    cred.check_access(&dir.stat()?, Access::W_OK | Access::X_OK)?;
+
+    // a core from an earlier crash with the same pid makes way for the new one
+    let name = format!("core.{}", task.tgid().as_usize());
+    if dir.lookup(&name).is_ok() {
+        dir.unlink(&name)?;
+    }
+    let file = InitRamFsFile::new(name, alloc_inode_no());
+    file.chmod(FileMode::new(S_IFREG | 0o600));
+    file.chown(cred.euid, cred.egid);
+    let file = Arc::new(file);
+    dir.insert(INode::File(file.clone()));
+    Ok(file)
+}
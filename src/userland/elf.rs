@@ -15,7 +15,7 @@ use crate::{
     },
     task::vmem::{MMapFlags, MMapKind, MMapProt, Vmem},
     userland::buffer::UserBufferMut,
-    util::{align_up, KResult},
+    util::{align_up, error::KError, KResult},
 };
 
 pub fn gen_stack_canary() -> [u8; 16] {
@@ -66,14 +66,15 @@ pub fn load_elf(file: FileRef) -> KResult<UserlandEntry> {
         )
     };
     let ubuf = UserBufferMut::from_slice(buf);
-    file.read(0, ubuf, &OpenFlags::empty())?;
+    let read = file.read(0, ubuf, &OpenFlags::empty());
     current.switch();
-    let elf = ElfBinary::new(buf).map_err(|_e| kerror!("load_elf(): elf loader error"))?;
+    read?;
+    let elf = ElfBinary::new(buf).map_err(|_e| kerror!(ENOEXEC, "load_elf(): not an ELF file"))?;
 
     let mut start_of_image = usize::MAX;
     let mut end_of_image = 0;
     for hdr in elf.program_headers() {
-        if hdr.get_type().unwrap() == xmas_elf::program::Type::Load {
+        if hdr.get_type() == Ok(xmas_elf::program::Type::Load) {
             end_of_image = end_of_image.max((hdr.virtual_addr() + hdr.mem_size()) as usize);
             start_of_image = end_of_image.min(hdr.virtual_addr() as usize);
         }
@@ -86,10 +87,12 @@ pub fn load_elf(file: FileRef) -> KResult<UserlandEntry> {
                 symbol_table = Some(
                     symtab
                         .iter()
-                        .map(|e| SymTabEntry {
-                            name: e.get_name(&elf.file).unwrap().to_owned(),
-                            size: e.size(),
-                            value: e.value(),
+                        .filter_map(|e| {
+                            Some(SymTabEntry {
+                                name: e.get_name(&elf.file).ok()?.to_owned(),
+                                size: e.size(),
+                                value: e.value(),
+                            })
                         })
                         .collect::<Vec<_>>(),
                 );
@@ -129,9 +132,24 @@ pub fn load_elf(file: FileRef) -> KResult<UserlandEntry> {
         load_offset,
         file: file.clone(),
         entry_point,
+        err: None,
     };
 
-    elf.load(&mut loader).unwrap();
+    if let Err(err) = elf.load(&mut loader) {
+        let err = loader.err.take().unwrap_or_else(|| {
+            log::warn!("load_elf(): {}", err);
+            kerror!(ENOEXEC, "load_elf(): malformed ELF file")
+        });
+        current.switch();
+        // whatever got mapped before it went wrong
+        addr_space.with_mapper(|mut mapper| vmem.clear(&mut mapper));
+        return Err(err);
+    }
+
+    // the program break starts right after the highest loaded segment
+    loader
+        .vmem
+        .set_brk_base(VirtAddr::new(end_of_image + load_offset).align_up(PAGE_SIZE));
 
     let p2 = elf.file.header.pt2;
     log::debug!("Base address at {:?}", VirtAddr::new(loader.base_addr));
@@ -173,6 +191,17 @@ struct KadosElfLoader<'a> {
     load_offset: usize,
     file: FileRef,
     entry_point: VirtAddr,
+    // the callbacks can only return an ElfLoaderErr, so our own errors wait here
+    err: Option<KError<'static>>,
+}
+
+impl KadosElfLoader<'_> {
+    fn fail(&mut self, err: KError<'static>) -> elfloader::ElfLoaderErr {
+        self.err = Some(err);
+        elfloader::ElfLoaderErr::ElfParser {
+            source: "kernel error",
+        }
+    }
 }
 
 impl ElfLoader for KadosElfLoader<'_> {
@@ -181,7 +210,8 @@ impl ElfLoader for KadosElfLoader<'_> {
         load_headers: elfloader::LoadableHeaders,
     ) -> Result<(), elfloader::ElfLoaderErr> {
         for header in load_headers {
-            if header.get_type().unwrap() == Type::Load {
+            let ty = header.get_type()?;
+            if ty == Type::Load {
                 let start = VirtAddr::new(header.virtual_addr() as usize + self.load_offset)
                     .align_down(PAGE_SIZE);
                 let mem_end = VirtAddr::new(
@@ -202,21 +232,24 @@ impl ElfLoader for KadosElfLoader<'_> {
                     size: header.file_size() as usize,
                 };
                 log::debug!("Mapping region {:?} .. {:?}", start, mem_end);
-                self.addr_space.with_mapper(|mut mapper| {
+                let mapped = self.addr_space.with_mapper(|mut mapper| {
                     self.vmem
                         .map_area(start, mem_end, flags, prot, kind, &mut mapper)
-                        .unwrap();
                 });
-            } else if header.get_type().unwrap() == Type::Interp {
+                if mapped.is_err() {
+                    return Err(self.fail(kerror!(EIO, "load_elf(): couldn't map a segment")));
+                }
+            } else if ty == Type::Interp {
                 let ld = get_root()
-                    .unwrap()
-                    .lookup(Path::new("/usr/lib/ld.so"), true)
-                    .unwrap()
-                    .as_file()
-                    .unwrap()
-                    .clone();
-                let res = load_elf(ld).unwrap();
-                self.entry_point = res.entry_point;
+                    .map(|root| root.lookup(Path::new("/usr/lib/ld.so"), true))
+                    .and_then(|ld| Some(ld.ok()?.as_file().ok()?.clone()));
+                let Some(ld) = ld else {
+                    return Err(self.fail(kerror!(ENOEXEC, "load_elf(): no interpreter")));
+                };
+                match load_elf(ld) {
+                    Ok(res) => self.entry_point = res.entry_point,
+                    Err(err) => return Err(self.fail(err)),
+                }
             }
         }
 
@@ -231,10 +264,16 @@ impl ElfLoader for KadosElfLoader<'_> {
     ) -> Result<(), elfloader::ElfLoaderErr> {
         let region_start = VirtAddr::new(base as usize + self.load_offset);
         let region_end = region_start + region.len();
-        let area = self
+        if self
             .vmem
-            .area_containing_mut(region_start, region_end)
-            .unwrap();
+            .area_containing(region_start, region_end)
+            .is_none()
+        {
+            return Err(self.fail(kerror!(
+                ENOEXEC,
+                "load_elf(): segment outside of its mapping"
+            )));
+        }
         let mut prot = MMapProt::empty();
         if flags.is_read() {
             prot |= MMapProt::PROT_READ;
@@ -246,9 +285,13 @@ impl ElfLoader for KadosElfLoader<'_> {
             prot |= MMapProt::PROT_EXEC;
         }
         // this should be safe since the pages should already be mapped and writable in allocate()
-        unsafe { region_start.write_bytes(region).unwrap() };
+        if unsafe { region_start.write_bytes(region) }.is_err() {
+            return Err(self.fail(kerror!(EIO, "load_elf(): couldn't copy a segment")));
+        }
         // set the correct protections now
-        area.prot = prot;
+        if let Some(area) = self.vmem.area_containing_mut(region_start, region_end) {
+            area.prot = prot;
+        }
         Ok(())
     }
 
@@ -274,7 +317,10 @@ impl ElfLoader for KadosElfLoader<'_> {
         match entry.rtype {
             elfloader::RelocationType::x86_64(rtype) => match rtype {
                 RelocationTypes::R_AMD64_RELATIVE => {
-                    let reloc_value = entry.addend.unwrap() as usize + self.load_offset;
+                    let Some(addend) = entry.addend else {
+                        return Err(elfloader::ElfLoaderErr::UnsupportedRelocationEntry);
+                    };
+                    let reloc_value = addend as usize + self.load_offset;
                     log::trace!(
                         "Applying relocation R_AMD64_RELATIVE at location {:#x} -> {:#x}",
                         entry.offset,
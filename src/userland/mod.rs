@@ -1,3 +1,5 @@
+pub mod binfmt;
 pub mod buffer;
+pub mod coredump;
 pub mod elf;
 pub mod syscall;
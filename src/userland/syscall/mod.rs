@@ -10,6 +10,7 @@ use crate::{
     kerror,
     mem::addr::VirtAddr,
     task::{
+        cred::Access,
         current_task, get_scheduler,
         group::PgId,
         vmem::{MMapFlags, MMapProt},
@@ -40,6 +41,8 @@ pub const QUIET_SYSCALLS: &[usize] = &[
     SYS_UNLINK,
     SYS_CLOCK_GETTIME,
     SYS_NANOSLEEP,
+    SYS_CLOCK_NANOSLEEP,
+    SYS_RESTART_SYSCALL,
     SYS_LSEEK,
     SYS_WRITEV,
     SYS_READV,
@@ -50,6 +53,27 @@ pub const QUIET_SYSCALLS: &[usize] = &[
     SYS_STAT,
 ];
 
+// these come back with EINTR after a signal even under SA_RESTART, the same as on linux
+pub const NEVER_RESTARTED_SYSCALLS: &[usize] = &[
+    SYS_POLL,
+    SYS_SELECT,
+    SYS_RT_SIGRETURN,
+    SYS_RT_SIGTIMEDWAIT,
+];
+
+// these only restart when the signal didn't need a handler
+pub const NOT_RESTARTED_AFTER_HANDLER: &[usize] = &[
+    SYS_RT_SIGSUSPEND,
+    SYS_NANOSLEEP,
+    SYS_CLOCK_NANOSLEEP,
+    SYS_RESTART_SYSCALL,
+];
+
+// these left a RestartBlock behind, and restart through restart_syscall(2) instead of starting
+// over from scratch
+pub const RESTARTED_FROM_BLOCK: &[usize] =
+    &[SYS_NANOSLEEP, SYS_CLOCK_NANOSLEEP, SYS_RESTART_SYSCALL];
+
 pub struct SyscallHandler<'a> {
     pub frame: &'a mut InterruptFrame,
 }
@@ -123,8 +147,56 @@ impl SyscallHandler<'_> {
             }
         }
 
-        let res = match n {
+        // a tracer stopped at the syscall may have changed it and its arguments. changing it
+        // to -1 skips it, leaving whatever the tracer put in rax.
+        let (a1, a2, a3, a4, a5, a6, n) = match get_scheduler().ptrace_syscall_enter(self.frame, n)
+        {
+            Some(n) => (
+                self.frame.rdi,
+                self.frame.rsi,
+                self.frame.rdx,
+                self.frame.r10,
+                self.frame.r8,
+                self.frame.r9,
+                n,
+            ),
+            None => (a1, a2, a3, a4, a5, a6, n),
+        };
+        let res = if n == usize::MAX {
+            Ok(self.frame.rax as isize)
+        } else if let Some(res) = self.check_seccomp(n, [a1, a2, a3, a4, a5, a6]) {
+            res
+        } else {
+            self.call(a1, a2, a3, a4, a5, a6, n)
+        };
+        self.frame.rax = errno_to_isize(&res) as usize;
+
+        get_scheduler().ptrace_syscall_exit(self.frame, n);
+
+        if let Err(err) = get_scheduler().try_delivering_signal(self.frame, n) {
+            if !quiet {
+                log::error!("Failed to send signal: {:?}", err);
+            }
+        }
+
+        res
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn call(
+        &mut self,
+        a1: usize,
+        a2: usize,
+        a3: usize,
+        a4: usize,
+        a5: usize,
+        a6: usize,
+        n: usize,
+    ) -> KResult<isize> {
+        match n {
             SYS_ARCH_PRCTL => self.sys_arch_prctl(a1 as i32, VirtAddr::new(a2)),
+            SYS_PRCTL => self.sys_prctl(a1 as c_int, a2, a3),
+            SYS_SECCOMP => self.sys_seccomp(a1, a2, VirtAddr::new(a3)),
             SYS_SET_TID_ADDRESS => self.sys_set_tid_address(VirtAddr::new(a1)),
             SYS_WRITE => self.sys_write(a1 as FileDesc, VirtAddr::new(a2), a3),
             SYS_WRITEV => self.sys_writev(a1 as FileDesc, VirtAddr::new(a2), a3),
@@ -135,20 +207,30 @@ impl SyscallHandler<'_> {
                 self.sys_rt_sigprocmask(a1, VirtAddr::new(a2), VirtAddr::new(a3), a4)
             }
             SYS_FORK => self.sys_fork(),
+            SYS_VFORK => self.sys_vfork(),
             SYS_WAIT4 => self.sys_wait4(
-                TaskId::new(a1),
+                a1 as c_int,
                 VirtAddr::new(a2),
                 crate::bitflags_from_user!(WaitOptions, a3 as i32),
                 VirtAddr::new(a4),
             ),
+            SYS_WAITID => self.sys_waitid(
+                a1 as c_int,
+                a2 as c_int,
+                VirtAddr::new(a3),
+                crate::bitflags_from_user!(WaitOptions, a4 as i32),
+                VirtAddr::new(a5),
+            ),
             SYS_EXECVE => self.sys_execve(&resolve_path(a1)?, VirtAddr::new(a2), VirtAddr::new(a3)),
-            SYS_GETTID => self.sys_getpid(), // todo
+            SYS_GETTID => self.sys_gettid(),
             SYS_GETPID => self.sys_getpid(),
             SYS_GETPPID => self.sys_getppid(),
             SYS_GETPGID => self.sys_getpgid(TaskId::new(a1)),
             SYS_SETPGID => self.sys_setpgid(TaskId::new(a1), a2 as PgId),
+            SYS_SETSID => self.sys_setsid(),
+            SYS_GETSID => self.sys_getsid(TaskId::new(a1)),
             SYS_EXIT => self.sys_exit(a1 as c_int),
-            SYS_EXIT_GROUP => self.sys_exit(a1 as c_int), // todo
+            SYS_EXIT_GROUP => self.sys_exit_group(a1 as c_int),
             SYS_MMAP => self.sys_mmap(
                 VirtAddr::new(a1),
                 a2,
@@ -163,16 +245,78 @@ impl SyscallHandler<'_> {
                 crate::bitflags_from_user!(MMapProt, a3 as u64),
             ),
             SYS_MUNMAP => self.sys_munmap(VirtAddr::new(a1), a2),
-            // SYS_BRK => self.sys_brk(VirtAddr::new(a1)),
+            SYS_BRK => self.sys_brk(VirtAddr::new(a1)),
             SYS_MREMAP => self.sys_mremap(VirtAddr::new(a1), a2, a3),
             SYS_RT_SIGACTION => {
-                self.sys_rt_sigaction(a1 as c_int, VirtAddr::new(a2), VirtAddr::new(a3))
+                self.sys_rt_sigaction(a1 as c_int, VirtAddr::new(a2), VirtAddr::new(a3), a4)
+            }
+            SYS_GETUID => self.sys_getuid(),
+            SYS_GETEUID => self.sys_geteuid(),
+            SYS_GETGID => self.sys_getgid(),
+            SYS_GETEGID => self.sys_getegid(),
+            SYS_SETUID => self.sys_setuid(a1 as u32),
+            SYS_SETGID => self.sys_setgid(a1 as u32),
+            SYS_SETREUID => self.sys_setreuid(a1 as u32, a2 as u32),
+            SYS_SETREGID => self.sys_setregid(a1 as u32, a2 as u32),
+            SYS_SETRESUID => self.sys_setresuid(a1 as u32, a2 as u32, a3 as u32),
+            SYS_SETRESGID => self.sys_setresgid(a1 as u32, a2 as u32, a3 as u32),
+            SYS_GETRESUID => {
+                self.sys_getresuid(VirtAddr::new(a1), VirtAddr::new(a2), VirtAddr::new(a3))
+            }
+            SYS_GETRESGID => {
+                self.sys_getresgid(VirtAddr::new(a1), VirtAddr::new(a2), VirtAddr::new(a3))
             }
-            SYS_GETUID => Ok(0),    // TODO:
-            SYS_GETEUID => Ok(0),   // TODO:
-            SYS_SETUID => Ok(0),    // TODO:
-            SYS_SETGID => Ok(0),    // TODO:
-            SYS_SETGROUPS => Ok(0), // TODO:
+            SYS_GETGROUPS => self.sys_getgroups(a1 as c_int as usize, VirtAddr::new(a2)),
+            SYS_SETGROUPS => self.sys_setgroups(a1, VirtAddr::new(a2)),
+            SYS_GETITIMER => self.sys_getitimer(a1, VirtAddr::new(a2)),
+            SYS_SETITIMER => self.sys_setitimer(a1, VirtAddr::new(a2), VirtAddr::new(a3)),
+            SYS_ALARM => self.sys_alarm(a1 as u32),
+            SYS_TIMER_CREATE => self.sys_timer_create(a1, VirtAddr::new(a2), VirtAddr::new(a3)),
+            SYS_TIMER_SETTIME => self.sys_timer_settime(
+                a1 as c_int,
+                a2 as c_int,
+                VirtAddr::new(a3),
+                VirtAddr::new(a4),
+            ),
+            SYS_TIMER_GETTIME => self.sys_timer_gettime(a1 as c_int, VirtAddr::new(a2)),
+            SYS_TIMER_DELETE => self.sys_timer_delete(a1 as c_int),
+            SYS_TIMERFD_CREATE => self.sys_timerfd_create(a1, a2 as c_int),
+            SYS_TIMERFD_SETTIME => self.sys_timerfd_settime(
+                a1 as FileDesc,
+                a2 as c_int,
+                VirtAddr::new(a3),
+                VirtAddr::new(a4),
+            ),
+            SYS_TIMERFD_GETTIME => self.sys_timerfd_gettime(a1 as FileDesc, VirtAddr::new(a2)),
+            SYS_TIMES => self.sys_times(VirtAddr::new(a1)),
+            SYS_PTRACE => self.sys_ptrace(a1 as c_int, a2 as c_int, VirtAddr::new(a3), a4),
+            SYS_GETRUSAGE => self.sys_getrusage(a1 as c_int, VirtAddr::new(a2)),
+            SYS_SCHED_YIELD => self.sys_sched_yield(),
+            SYS_GETPRIORITY => self.sys_getpriority(a1 as c_int, a2 as c_int),
+            SYS_SETPRIORITY => self.sys_setpriority(a1 as c_int, a2 as c_int, a3 as c_int),
+            SYS_SCHED_SETPARAM => self.sys_sched_setparam(TaskId::new(a1), VirtAddr::new(a2)),
+            SYS_SCHED_GETPARAM => self.sys_sched_getparam(TaskId::new(a1), VirtAddr::new(a2)),
+            SYS_SCHED_SETSCHEDULER => {
+                self.sys_sched_setscheduler(TaskId::new(a1), a2 as c_int, VirtAddr::new(a3))
+            }
+            SYS_SCHED_GETSCHEDULER => self.sys_sched_getscheduler(TaskId::new(a1)),
+            SYS_SCHED_GET_PRIORITY_MAX => self.sys_sched_get_priority_max(a1 as c_int),
+            SYS_SCHED_GET_PRIORITY_MIN => self.sys_sched_get_priority_min(a1 as c_int),
+            SYS_SCHED_RR_GET_INTERVAL => {
+                self.sys_sched_rr_get_interval(TaskId::new(a1), VirtAddr::new(a2))
+            }
+            SYS_GETRLIMIT => self.sys_getrlimit(a1 as c_int, VirtAddr::new(a2)),
+            SYS_SETRLIMIT => self.sys_setrlimit(a1 as c_int, VirtAddr::new(a2)),
+            SYS_PRLIMIT64 => self.sys_prlimit64(
+                TaskId::new(a1),
+                a2 as c_int,
+                VirtAddr::new(a3),
+                VirtAddr::new(a4),
+            ),
+            SYS_ACCESS => self.sys_access(
+                &resolve_path(a1)?,
+                crate::bitflags_from_user!(Access, a2 as u32),
+            ),
             SYS_STAT => self.sys_stat(&resolve_path(a1)?, VirtAddr::new(a2)),
             SYS_LSTAT => self.sys_lstat(&resolve_path(a1)?, VirtAddr::new(a2)),
             SYS_FSTAT => self.sys_fstat(a1 as FileDesc, VirtAddr::new(a2)),
@@ -189,22 +333,54 @@ impl SyscallHandler<'_> {
             SYS_POLL => self.sys_poll(VirtAddr::new(a1), a2 as c_nfds, a3 as c_int),
             SYS_CHDIR => self.sys_chdir(&resolve_path(a1)?),
             SYS_RT_SIGRETURN => self.sys_rt_sigreturn(),
+            SYS_SIGALTSTACK => self.sys_sigaltstack(VirtAddr::new(a1), VirtAddr::new(a2)),
             SYS_PIPE => self.sys_pipe(VirtAddr::new(a1)),
             SYS_CLONE => self.sys_clone(
                 a1,
                 VirtAddr::new(a2),
-                a3,
+                VirtAddr::new(a3),
                 VirtAddr::new(a4),
-                a5,
-                VirtAddr::new(a6),
+                VirtAddr::new(a5),
             ),
-            SYS_KILL => self.sys_kill(TaskId::new(a1), a2 as c_int),
-            SYS_TKILL => self.sys_kill(TaskId::new(a1), a2 as c_int), // todo
+            SYS_KILL => self.sys_kill(a1 as c_int, a2 as c_int),
+            SYS_TKILL => self.sys_tgkill(-1, a1 as c_int, a2 as c_int),
+            SYS_TGKILL => self.sys_tgkill(a1 as c_int, a2 as c_int, a3 as c_int),
+            SYS_RT_SIGQUEUEINFO => {
+                self.sys_rt_sigqueueinfo(a1 as c_int, -1, a2 as c_int, VirtAddr::new(a3))
+            }
+            SYS_RT_TGSIGQUEUEINFO => {
+                self.sys_rt_sigqueueinfo(a1 as c_int, a2 as c_int, a3 as c_int, VirtAddr::new(a4))
+            }
+            SYS_RT_SIGPENDING => self.sys_rt_sigpending(VirtAddr::new(a1), a2),
+            SYS_RT_SIGSUSPEND => self.sys_rt_sigsuspend(VirtAddr::new(a1), a2),
+            SYS_RT_SIGTIMEDWAIT => self.sys_rt_sigtimedwait(
+                VirtAddr::new(a1),
+                VirtAddr::new(a2),
+                VirtAddr::new(a3),
+                a4,
+            ),
+            SYS_FUTEX => self.sys_futex(
+                VirtAddr::new(a1),
+                a2 as c_int,
+                a3 as u32,
+                a4,
+                VirtAddr::new(a5),
+                a6 as u32,
+            ),
+            SYS_SET_ROBUST_LIST => self.sys_set_robust_list(VirtAddr::new(a1), a2),
+            SYS_GET_ROBUST_LIST => {
+                self.sys_get_robust_list(TaskId::new(a1), VirtAddr::new(a2), VirtAddr::new(a3))
+            }
             SYS_UNLINK => self.sys_unlink(&resolve_path(a1)?),
             SYS_LSEEK => self.sys_lseek(a1 as FileDesc, a2, a3.into()),
             SYS_DUP2 => self.sys_dup2(a1 as FileDesc, a2 as FileDesc),
             SYS_CLOCK_GETTIME => self.sys_clock_gettime(a1, VirtAddr::new(a2)),
+            SYS_CLOCK_GETRES => self.sys_clock_getres(a1, VirtAddr::new(a2)),
+            SYS_CLOCK_NANOSLEEP => {
+                self.sys_clock_nanosleep(a1, a2 as c_int, VirtAddr::new(a3), VirtAddr::new(a4))
+            }
             SYS_NANOSLEEP => self.sys_nanosleep(VirtAddr::new(a1), VirtAddr::new(a2)),
+            SYS_RESTART_SYSCALL => self.sys_restart_syscall(),
             SYS_MKDIR => self.sys_mkdir(&resolve_path(a1)?, FileMode::new(a2 as u32)),
             SYS_GETRANDOM => self.sys_getrandom(VirtAddr::new(a1), a2),
             SYS_SOCKET => self.sys_socket(a1, a2, a3),
@@ -220,15 +396,7 @@ impl SyscallHandler<'_> {
                 ENOSYS,
                 "SyscallHandler::dispatch(): syscall not implemented"
             )),
-        };
-
-        if let Err(err) = get_scheduler().try_delivering_signal(self.frame, errno_to_isize(&res)) {
-            if !quiet {
-                log::error!("Failed to send signal: {:?}", err);
-            }
         }
-
-        res
     }
 }
 
@@ -620,12 +788,17 @@ pub const SYS_RT_SIGRETURN: usize = 15;
 pub const SYS_IOCTL: usize = 16;
 pub const SYS_READV: usize = 19;
 pub const SYS_WRITEV: usize = 20;
+pub const SYS_ACCESS: usize = 21;
 pub const SYS_PIPE: usize = 22;
 pub const SYS_SELECT: usize = 23;
+pub const SYS_SCHED_YIELD: usize = 24;
 pub const SYS_MREMAP: usize = 25;
 pub const SYS_MADVISE: usize = 28;
 pub const SYS_DUP2: usize = 33;
 pub const SYS_NANOSLEEP: usize = 35;
+pub const SYS_GETITIMER: usize = 36;
+pub const SYS_ALARM: usize = 37;
+pub const SYS_SETITIMER: usize = 38;
 pub const SYS_GETPID: usize = 39;
 pub const SYS_SOCKET: usize = 41;
 pub const SYS_CONNECT: usize = 42;
@@ -641,6 +814,7 @@ pub const SYS_SETSOCKOPT: usize = 54;
 pub const SYS_GETSOCKOPT: usize = 55;
 pub const SYS_CLONE: usize = 56;
 pub const SYS_FORK: usize = 57;
+pub const SYS_VFORK: usize = 58;
 pub const SYS_EXECVE: usize = 59;
 pub const SYS_EXIT: usize = 60;
 pub const SYS_WAIT4: usize = 61;
@@ -656,24 +830,73 @@ pub const SYS_UNLINK: usize = 87;
 pub const SYS_READLINK: usize = 89;
 pub const SYS_CHMOD: usize = 90;
 pub const SYS_CHOWN: usize = 92;
+pub const SYS_GETRLIMIT: usize = 97;
+pub const SYS_GETRUSAGE: usize = 98;
+pub const SYS_TIMES: usize = 100;
+pub const SYS_PTRACE: usize = 101;
 pub const SYS_GETUID: usize = 102;
 pub const SYS_SYSLOG: usize = 103;
+pub const SYS_GETGID: usize = 104;
 pub const SYS_SETUID: usize = 105;
 pub const SYS_SETGID: usize = 106;
 pub const SYS_GETEUID: usize = 107;
+pub const SYS_GETEGID: usize = 108;
 pub const SYS_SETPGID: usize = 109;
 pub const SYS_GETPPID: usize = 110;
 pub const SYS_GETPGRP: usize = 111;
+pub const SYS_SETSID: usize = 112;
+pub const SYS_SETREUID: usize = 113;
+pub const SYS_SETREGID: usize = 114;
+pub const SYS_GETGROUPS: usize = 115;
 pub const SYS_GETPGID: usize = 121;
+pub const SYS_GETSID: usize = 124;
+pub const SYS_RT_SIGPENDING: usize = 127;
+pub const SYS_RT_SIGTIMEDWAIT: usize = 128;
+pub const SYS_RT_SIGQUEUEINFO: usize = 129;
+pub const SYS_RT_SIGSUSPEND: usize = 130;
+pub const SYS_SIGALTSTACK: usize = 131;
 pub const SYS_SETGROUPS: usize = 116;
+pub const SYS_SETRESUID: usize = 117;
+pub const SYS_GETRESUID: usize = 118;
+pub const SYS_SETRESGID: usize = 119;
+pub const SYS_GETRESGID: usize = 120;
+pub const SYS_GETPRIORITY: usize = 140;
+pub const SYS_SETPRIORITY: usize = 141;
+pub const SYS_SCHED_SETPARAM: usize = 142;
+pub const SYS_SCHED_GETPARAM: usize = 143;
+pub const SYS_SCHED_SETSCHEDULER: usize = 144;
+pub const SYS_SCHED_GETSCHEDULER: usize = 145;
+pub const SYS_SCHED_GET_PRIORITY_MAX: usize = 146;
+pub const SYS_SCHED_GET_PRIORITY_MIN: usize = 147;
+pub const SYS_SCHED_RR_GET_INTERVAL: usize = 148;
+pub const SYS_PRCTL: usize = 157;
 pub const SYS_ARCH_PRCTL: usize = 158;
+pub const SYS_SETRLIMIT: usize = 160;
 pub const SYS_REBOOT: usize = 169;
 pub const SYS_GETTID: usize = 186;
 pub const SYS_TKILL: usize = 200;
+pub const SYS_FUTEX: usize = 202;
 pub const SYS_GETDENTS64: usize = 217;
 pub const SYS_SET_TID_ADDRESS: usize = 218;
+pub const SYS_RESTART_SYSCALL: usize = 219;
+pub const SYS_TIMER_CREATE: usize = 222;
+pub const SYS_TIMER_SETTIME: usize = 223;
+pub const SYS_TIMER_GETTIME: usize = 224;
+pub const SYS_TIMER_DELETE: usize = 226;
 pub const SYS_CLOCK_GETTIME: usize = 228;
+pub const SYS_CLOCK_GETRES: usize = 229;
+pub const SYS_CLOCK_NANOSLEEP: usize = 230;
 pub const SYS_EXIT_GROUP: usize = 231;
+pub const SYS_TGKILL: usize = 234;
 pub const SYS_UTIMES: usize = 235;
+pub const SYS_WAITID: usize = 247;
 pub const SYS_LINKAT: usize = 265;
+pub const SYS_SET_ROBUST_LIST: usize = 273;
+pub const SYS_GET_ROBUST_LIST: usize = 274;
+pub const SYS_TIMERFD_CREATE: usize = 283;
+pub const SYS_TIMERFD_SETTIME: usize = 286;
+pub const SYS_TIMERFD_GETTIME: usize = 287;
+pub const SYS_RT_TGSIGQUEUEINFO: usize = 297;
+pub const SYS_PRLIMIT64: usize = 302;
+pub const SYS_SECCOMP: usize = 317;
 pub const SYS_GETRANDOM: usize = 318;
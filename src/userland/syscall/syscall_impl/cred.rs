@@ -0,0 +1,141 @@
+use core::mem::size_of;
+
+use alloc::vec::Vec;
+
+use crate::{
+    fs::{GId, UId},
+    kbail,
+    mem::addr::VirtAddr,
+    task::{cred::NGROUPS_MAX, current_task},
+    userland::syscall::SyscallHandler,
+    util::KResult,
+};
+
+// -1 leaves the corresponding id untouched
+fn optional_uid(id: u32) -> Option<UId> {
+    (id != u32::MAX).then_some(UId::new(id))
+}
+
+fn optional_gid(id: u32) -> Option<GId> {
+    (id != u32::MAX).then_some(GId::new(id))
+}
+
+impl SyscallHandler<'_> {
+    pub fn sys_getuid(&mut self) -> KResult<isize> {
+        Ok(current_task().credentials().uid.as_u32() as isize)
+    }
+
+    pub fn sys_geteuid(&mut self) -> KResult<isize> {
+        Ok(current_task().credentials().euid.as_u32() as isize)
+    }
+
+    pub fn sys_getgid(&mut self) -> KResult<isize> {
+        Ok(current_task().credentials().gid.as_u32() as isize)
+    }
+
+    pub fn sys_getegid(&mut self) -> KResult<isize> {
+        Ok(current_task().credentials().egid.as_u32() as isize)
+    }
+
+    pub fn sys_setuid(&mut self, uid: u32) -> KResult<isize> {
+        current_task().cred.lock().setuid(UId::new(uid))?;
+        Ok(0)
+    }
+
+    pub fn sys_setgid(&mut self, gid: u32) -> KResult<isize> {
+        current_task().cred.lock().setgid(GId::new(gid))?;
+        Ok(0)
+    }
+
+    pub fn sys_setreuid(&mut self, ruid: u32, euid: u32) -> KResult<isize> {
+        current_task()
+            .cred
+            .lock()
+            .setreuid(optional_uid(ruid), optional_uid(euid))?;
+        Ok(0)
+    }
+
+    pub fn sys_setregid(&mut self, rgid: u32, egid: u32) -> KResult<isize> {
+        current_task()
+            .cred
+            .lock()
+            .setregid(optional_gid(rgid), optional_gid(egid))?;
+        Ok(0)
+    }
+
+    pub fn sys_setresuid(&mut self, ruid: u32, euid: u32, suid: u32) -> KResult<isize> {
+        current_task().cred.lock().setresuid(
+            optional_uid(ruid),
+            optional_uid(euid),
+            optional_uid(suid),
+        )?;
+        Ok(0)
+    }
+
+    pub fn sys_setresgid(&mut self, rgid: u32, egid: u32, sgid: u32) -> KResult<isize> {
+        current_task().cred.lock().setresgid(
+            optional_gid(rgid),
+            optional_gid(egid),
+            optional_gid(sgid),
+        )?;
+        Ok(0)
+    }
+
+    pub fn sys_getresuid(
+        &mut self,
+        ruid: VirtAddr,
+        euid: VirtAddr,
+        suid: VirtAddr,
+    ) -> KResult<isize> {
+        let cred = current_task().credentials();
+        unsafe {
+            ruid.write_user(cred.uid.as_u32())?;
+            euid.write_user(cred.euid.as_u32())?;
+            suid.write_user(cred.suid.as_u32())?;
+        }
+        Ok(0)
+    }
+
+    pub fn sys_getresgid(
+        &mut self,
+        rgid: VirtAddr,
+        egid: VirtAddr,
+        sgid: VirtAddr,
+    ) -> KResult<isize> {
+        let cred = current_task().credentials();
+        unsafe {
+            rgid.write_user(cred.gid.as_u32())?;
+            egid.write_user(cred.egid.as_u32())?;
+            sgid.write_user(cred.sgid.as_u32())?;
+        }
+        Ok(0)
+    }
+
+    pub fn sys_getgroups(&mut self, size: usize, list: VirtAddr) -> KResult<isize> {
+        let groups = current_task().credentials().groups;
+        // a zero size only asks how many there are
+        if size == 0 {
+            return Ok(groups.len() as isize);
+        }
+        if size < groups.len() {
+            kbail!(EINVAL, "sys_getgroups(): list too small");
+        }
+        for (i, gid) in groups.iter().enumerate() {
+            unsafe { (list + i * size_of::<u32>()).write_user(gid.as_u32()) }?;
+        }
+        Ok(groups.len() as isize)
+    }
+
+    pub fn sys_setgroups(&mut self, size: usize, list: VirtAddr) -> KResult<isize> {
+        if size > NGROUPS_MAX {
+            kbail!(EINVAL, "sys_setgroups(): too many groups");
+        }
+        let mut groups = Vec::with_capacity(size);
+        for i in 0..size {
+            let gid = unsafe { (list + i * size_of::<u32>()).read_user::<u32>() }?;
+            groups.push(GId::new(gid));
+        }
+        current_task().cred.lock().setgroups(groups)?;
+        Ok(0)
+    }
+}
@@ -10,11 +10,15 @@ use crate::{
         initramfs::{dir::InitRamFsDir, file::InitRamFsFile},
         opened_file::{FileDesc, LseekWhence, OpenFlags},
         path::Path,
-        FileMode, INode, PollStatus, O_RDWR, O_WRONLY, POLL_WAIT_QUEUE, S_IFDIR, S_IFREG,
+        FileMode, INode, PollStatus, O_ACCMODE, O_RDWR, O_WRONLY, POLL_WAIT_QUEUE, S_IFDIR,
+        S_IFREG,
     },
     kbail, kerror,
     mem::addr::VirtAddr,
-    task::current_task,
+    task::{
+        cred::{current_credentials, Access},
+        current_task,
+    },
     userland::{
         buffer::{UserBuffer, UserBufferMut, UserBufferReader, UserBufferWriter},
         syscall::SyscallHandler,
@@ -145,23 +149,29 @@ fn create(path: &Path, _flags: OpenFlags, mode: FileMode) -> KResult<INode> {
         .ok_or(kerror!(EINVAL, "create(): invalid path"))?;
 
     let current = current_task();
+    let cred = current.credentials();
     let root = current.root_fs.lock();
+    let parent = root.lookup(parent_dir, true)?;
+    let parent = parent.as_dir()?;
+    if parent.lookup(name).is_ok() {
+        kbail!(EEXIST, "create(): file exists");
+    }
+    cred.check_access(&parent.stat()?, Access::W_OK | Access::X_OK)?;
+
     let inode = if mode.is_regular_file() {
-        INode::File(Arc::new(InitRamFsFile::new(
-            name.to_owned(),
-            alloc_inode_no(),
-        )))
+        let file = InitRamFsFile::new(name.to_owned(), alloc_inode_no());
+        file.chmod(mode);
+        file.chown(cred.euid, cred.egid);
+        INode::File(Arc::new(file))
     } else if mode.is_directory() {
-        INode::Dir(Arc::new(InitRamFsDir::new(
-            name.to_owned(),
-            alloc_inode_no(),
-        )))
+        let dir = InitRamFsDir::new(name.to_owned(), alloc_inode_no());
+        dir.chmod(mode);
+        dir.chown(cred.euid, cred.egid);
+        INode::Dir(Arc::new(dir))
     } else {
         return Err(kerror!(EINVAL, "create(): invalid flags"));
     };
-    root.lookup(parent_dir, true)?
-        .as_dir()?
-        .insert(inode.clone());
+    parent.insert(inode.clone());
     Ok(inode)
 }
 
@@ -169,12 +179,14 @@ impl SyscallHandler<'_> {
     pub fn sys_open(&mut self, path: &Path, flags: OpenFlags, mode: FileMode) -> KResult<isize> {
         let current = current_task();
         // log::trace!("Attempting to open {}", path);
+        let mut created = false;
         if flags.contains(OpenFlags::O_CREAT) {
-            match create(path, flags, FileMode::new(S_IFREG | mode.access_mode())) {
-                Ok(_) => {}
+            match create(path, flags, FileMode::new(S_IFREG | mode.permissions())) {
+                Ok(_) => created = true,
                 Err(err) if err.errno() == Some(Errno::EINVAL) => {}
                 Err(err)
-                    if flags.contains(OpenFlags::O_EXCL) && err.errno() == Some(Errno::EEXIST) => {}
+                    if !flags.contains(OpenFlags::O_EXCL) && err.errno() == Some(Errno::EEXIST) => {
+                }
                 Err(err) => return Err(err),
             }
         }
@@ -185,10 +197,24 @@ impl SyscallHandler<'_> {
         if flags.contains(OpenFlags::O_DIRECTORY) && !path_comp.inode.is_dir() {
             kbail!(ENOTDIR, "sys_open(): not a directory");
         }
-        let access_mode = mode.access_mode();
+        let access_mode = flags.bits() as u32 & O_ACCMODE;
         if path_comp.inode.is_dir() && (access_mode == O_WRONLY || access_mode == O_RDWR) {
             kbail!(EISDIR, "sys_open(): is a directory");
         }
+        // whoever just created the file gets to open it no matter what mode they gave it
+        if !created {
+            let mut access = match access_mode {
+                O_WRONLY => Access::W_OK,
+                O_RDWR => Access::R_OK | Access::W_OK,
+                _ => Access::R_OK,
+            };
+            if flags.contains(OpenFlags::O_TRUNC) {
+                access |= Access::W_OK;
+            }
+            current
+                .credentials()
+                .check_access(&path_comp.inode.stat()?, access)?;
+        }
 
         let fd = opened_files.open(path_comp, flags)?;
         log::trace!("Opened {} as {}.", path, fd);
@@ -196,6 +222,14 @@ impl SyscallHandler<'_> {
         Ok(fd as isize)
     }
 
+    pub fn sys_access(&mut self, path: &Path, mode: Access) -> KResult<isize> {
+        let stat = current_task().root_fs.lock().lookup(path, true)?.stat()?;
+        current_credentials()
+            .with_real_ids()
+            .check_access(&stat, mode)?;
+        Ok(0)
+    }
+
     pub fn sys_close(&mut self, fd: FileDesc) -> KResult<isize> {
         let current = current_task();
         current.opened_files.lock().close(fd)?;
@@ -207,7 +241,7 @@ impl SyscallHandler<'_> {
         create(
             path,
             OpenFlags::empty(),
-            FileMode::new(S_IFDIR | mode.access_mode()),
+            FileMode::new(S_IFDIR | mode.permissions()),
         )?;
         Ok(0)
     }
@@ -40,14 +40,19 @@ impl SyscallHandler<'_> {
         Ok(0)
     }
 
-    // pub fn sys_brk(&mut self, addr: VirtAddr) -> KResult<isize> {
-    //     let current = current_task();
-    //     let new_addr = current
-    //         .arch_mut()
-    //         .address_space
-    //         .with_mapper(|mut mapper| current.vmem().lock().brk(&mut mapper, addr))?;
-    //     Ok(new_addr.value() as isize)
-    // }
+    pub fn sys_brk(&mut self, addr: VirtAddr) -> KResult<isize> {
+        let current = current_task();
+        let vmem = current.vmem();
+        let new_addr = current.arch_mut().address_space.with_mapper(|mut mapper| {
+            let mut vmem = vmem.lock();
+            // brk(2) reports failure by handing back the unchanged break
+            match vmem.brk(&mut mapper, addr) {
+                Ok(new_addr) => new_addr,
+                Err(_) => vmem.current_brk(),
+            }
+        });
+        Ok(new_addr.value() as isize)
+    }
 
     pub fn sys_munmap(&mut self, addr: VirtAddr, size: usize) -> KResult<isize> {
         let current = current_task();
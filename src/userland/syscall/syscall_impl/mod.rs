@@ -1,6 +1,12 @@
+pub mod cred;
 pub mod fs;
 pub mod mem;
+pub mod ptrace;
+pub mod rlimit;
+pub mod sched;
+pub mod seccomp;
 pub mod signal;
 pub mod sys;
 pub mod task;
 pub mod time;
+pub mod timer;
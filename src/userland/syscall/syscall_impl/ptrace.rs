@@ -0,0 +1,137 @@
+use alloc::sync::Arc;
+
+use crate::{
+    arch::ptrace::UserRegs,
+    kbail,
+    mem::addr::VirtAddr,
+    task::{
+        current_task, get_scheduler,
+        ptrace::{
+            read_word, write_word, PtraceResume, PTRACE_ATTACH, PTRACE_CONT, PTRACE_DETACH,
+            PTRACE_GETREGS, PTRACE_GETSIGINFO, PTRACE_KILL, PTRACE_PEEKDATA, PTRACE_PEEKTEXT,
+            PTRACE_POKEDATA, PTRACE_POKETEXT, PTRACE_SETOPTIONS, PTRACE_SETREGS, PTRACE_SINGLESTEP,
+            PTRACE_SUPPORTED_OPTIONS, PTRACE_SYSCALL, PTRACE_TRACEME,
+        },
+        signal::{SIGKILL, SIGMAX},
+        Task, TaskId,
+    },
+    userland::syscall::SyscallHandler,
+    util::{ctypes::c_int, error::KResult},
+};
+
+impl SyscallHandler<'_> {
+    pub fn sys_ptrace(
+        &mut self,
+        request: c_int,
+        pid: c_int,
+        addr: VirtAddr,
+        data: usize,
+    ) -> KResult<isize> {
+        let current = current_task();
+        let sched = get_scheduler();
+
+        if request == PTRACE_TRACEME {
+            let Some(parent) = current.parent() else {
+                kbail!(EPERM, "sys_ptrace(): no parent to trace us");
+            };
+            sched.ptrace_attach(&parent, current, false)?;
+            return Ok(0);
+        }
+
+        let Some(tracee) = sched
+            .find_task(TaskId::new(pid as usize))
+            .filter(|_| pid > 0)
+        else {
+            kbail!(ESRCH, "sys_ptrace(): no such task");
+        };
+
+        match request {
+            PTRACE_ATTACH => {
+                if !current.credentials().can_trace(&tracee.credentials()) {
+                    kbail!(EPERM, "sys_ptrace(): not allowed to trace that task");
+                }
+                sched.ptrace_attach(&current, tracee, true)?;
+                return Ok(0);
+            }
+            // the one request that doesn't have to wait for a stop
+            PTRACE_KILL => {
+                check_tracee(&current, &tracee, false)?;
+                sched.send_signal_to(tracee, SIGKILL);
+                return Ok(0);
+            }
+            _ => {}
+        }
+
+        check_tracee(&current, &tracee, true)?;
+        match request {
+            PTRACE_PEEKTEXT | PTRACE_PEEKDATA => {
+                // the raw syscall hands the word back through data, libc's wrapper returns it
+                let word = read_word(&tracee, addr)?;
+                unsafe { VirtAddr::new(data).write_user(word) }?;
+            }
+            PTRACE_POKETEXT | PTRACE_POKEDATA => write_word(&tracee, addr, data)?,
+            PTRACE_GETREGS => {
+                let fs_base = tracee.arch_mut().fsbase();
+                let Some(regs) = tracee.ptrace.lock().as_mut().and_then(|p| p.regs(fs_base)) else {
+                    kbail!(ESRCH, "sys_ptrace(): tracee isn't stopped");
+                };
+                unsafe { VirtAddr::new(data).write_user(regs) }?;
+            }
+            PTRACE_SETREGS => {
+                let regs = unsafe { VirtAddr::new(data).read_user::<UserRegs>() }?;
+                let mut ptrace = tracee.ptrace.lock();
+                let Some(ptrace) = ptrace.as_mut() else {
+                    kbail!(ESRCH, "sys_ptrace(): tracee isn't traced");
+                };
+                ptrace.set_regs(&regs)?;
+            }
+            PTRACE_CONT | PTRACE_SYSCALL | PTRACE_SINGLESTEP | PTRACE_DETACH => {
+                let signal = data as c_int;
+                if data >= SIGMAX as usize {
+                    kbail!(EIO, "sys_ptrace(): invalid signal number");
+                }
+                let resume = match request {
+                    PTRACE_SYSCALL => PtraceResume::Syscall,
+                    PTRACE_SINGLESTEP => PtraceResume::SingleStep,
+                    _ => PtraceResume::Continue,
+                };
+                if request == PTRACE_DETACH {
+                    sched.ptrace_detach(&current, tracee, signal);
+                } else {
+                    sched.ptrace_resume(tracee, resume, signal);
+                }
+            }
+            PTRACE_SETOPTIONS => {
+                if data & !PTRACE_SUPPORTED_OPTIONS != 0 {
+                    kbail!(EINVAL, "sys_ptrace(): unsupported options");
+                }
+                if let Some(ptrace) = tracee.ptrace.lock().as_mut() {
+                    ptrace.options = data;
+                }
+            }
+            PTRACE_GETSIGINFO => {
+                let siginfo = tracee.ptrace.lock().as_ref().and_then(|p| p.siginfo);
+                let Some(siginfo) = siginfo else {
+                    kbail!(EINVAL, "sys_ptrace(): tracee didn't stop for a signal");
+                };
+                unsafe { VirtAddr::new(data).write_user(siginfo) }?;
+            }
+            _ => kbail!(EIO, "sys_ptrace(): unsupported request"),
+        }
+
+        Ok(0)
+    }
+}
+
+// every request but PTRACE_ATTACH and PTRACE_TRACEME is only for the tracer, and most of them
+// only while the tracee sits in a stop
+fn check_tracee(tracer: &Task, tracee: &Arc<Task>, stopped: bool) -> KResult<()> {
+    let ptrace = tracee.ptrace.lock();
+    let Some(ptrace) = ptrace.as_ref().filter(|p| p.is_traced_by(tracer)) else {
+        kbail!(ESRCH, "sys_ptrace(): not tracing that task");
+    };
+    if stopped && !ptrace.is_stopped() {
+        kbail!(ESRCH, "sys_ptrace(): tracee isn't stopped");
+    }
+    Ok(())
+}
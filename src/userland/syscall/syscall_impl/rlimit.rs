@@ -0,0 +1,76 @@
+use alloc::sync::Arc;
+
+use crate::{
+    kbail, kerror,
+    mem::addr::VirtAddr,
+    task::{
+        current_task, get_scheduler,
+        rlimit::{RLimit, Resource},
+        Task, TaskId,
+    },
+    userland::syscall::SyscallHandler,
+    util::KResult,
+};
+
+// the same rule as ptrace(2): root, or someone whose ids all match the target's
+fn check_prlimit_target(target: &Arc<Task>) -> KResult<()> {
+    if !current_task()
+        .credentials()
+        .can_trace(&target.credentials())
+    {
+        kbail!(EPERM, "prlimit64(): not permitted");
+    }
+    Ok(())
+}
+
+impl SyscallHandler<'_> {
+    pub fn sys_getrlimit(&mut self, resource: Resource, rlim: VirtAddr) -> KResult<isize> {
+        let limit = current_task().rlimits.lock().get(resource)?;
+        unsafe { rlim.write_user(limit) }?;
+        Ok(0)
+    }
+
+    pub fn sys_setrlimit(&mut self, resource: Resource, rlim: VirtAddr) -> KResult<isize> {
+        let new = unsafe { rlim.read_user::<RLimit>() }?;
+        let current = current_task();
+        let privileged = current.credentials().is_privileged();
+        current.rlimits.lock().set(resource, new, privileged)?;
+        Ok(0)
+    }
+
+    pub fn sys_prlimit64(
+        &mut self,
+        pid: TaskId,
+        resource: Resource,
+        new_limit: VirtAddr,
+        old_limit: VirtAddr,
+    ) -> KResult<isize> {
+        let target = if pid.as_usize() == 0 {
+            current_task()
+        } else {
+            let target = get_scheduler()
+                .find_task(pid)
+                .ok_or(kerror!(ESRCH, "prlimit64(): no such process"))?;
+            check_prlimit_target(&target)?;
+            target
+        };
+
+        let new = if new_limit != VirtAddr::null() {
+            Some(unsafe { new_limit.read_user::<RLimit>() }?)
+        } else {
+            None
+        };
+        let privileged = current_task().credentials().is_privileged();
+        let mut rlimits = target.rlimits.lock();
+        let old = rlimits.get(resource)?;
+        if let Some(new) = new {
+            rlimits.set(resource, new, privileged)?;
+        }
+        drop(rlimits);
+
+        if old_limit != VirtAddr::null() {
+            unsafe { old_limit.write_user(old) }?;
+        }
+        Ok(0)
+    }
+}
@@ -0,0 +1,212 @@
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use crate::{
+    arch::time,
+    fs::UId,
+    kbail, kerror,
+    mem::addr::VirtAddr,
+    task::{
+        current_task, get_scheduler,
+        rlimit::{RLIMIT_NICE, RLIMIT_RTPRIO},
+        runqueue::{
+            SchedPolicy, NICE_MAX, NICE_MIN, RR_TIMESLICE_NS, RT_PRIORITY_MAX, RT_PRIORITY_MIN,
+            SCHED_RESET_ON_FORK,
+        },
+        Task, TaskId,
+    },
+    userland::syscall::SyscallHandler,
+    util::{ctypes::c_int, KResult},
+};
+
+use super::time::TimeSpec;
+
+const PRIO_PROCESS: c_int = 0;
+const PRIO_PGRP: c_int = 1;
+const PRIO_USER: c_int = 2;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct SchedParam {
+    sched_priority: c_int,
+}
+
+fn find_sched_target(pid: TaskId) -> KResult<Arc<Task>> {
+    if pid.as_usize() == 0 {
+        return Ok(current_task());
+    }
+    get_scheduler()
+        .find_task(pid)
+        .ok_or(kerror!(ESRCH, "find_sched_target(): no such process"))
+}
+
+// unprivileged users may only touch their own tasks
+fn check_sched_target(target: &Task) -> KResult<()> {
+    let cred = current_task().credentials();
+    let target = target.credentials();
+    if !cred.is_privileged() && cred.euid != target.uid && cred.euid != target.euid {
+        kbail!(EPERM, "check_sched_target(): not permitted");
+    }
+    Ok(())
+}
+
+// what setpriority(2) and getpriority(2) act on
+fn prio_targets(which: c_int, who: c_int) -> KResult<Vec<Arc<Task>>> {
+    let current = current_task();
+    let sched = get_scheduler();
+    let targets = match which {
+        PRIO_PROCESS => vec![find_sched_target(TaskId::new(who as usize))?],
+        PRIO_PGRP => {
+            let pgid = if who == 0 { current.pgid() } else { Some(who) };
+            sched.find_tasks(|t| pgid.is_some() && t.pgid() == pgid)
+        }
+        PRIO_USER => {
+            let uid = if who == 0 {
+                current.credentials().uid
+            } else {
+                UId::new(who as u32)
+            };
+            sched.find_tasks(|t| t.credentials().uid == uid)
+        }
+        _ => kbail!(EINVAL, "prio_targets(): invalid which"),
+    };
+    if targets.is_empty() {
+        kbail!(ESRCH, "prio_targets(): no such process");
+    }
+    Ok(targets)
+}
+
+fn set_sched_params(
+    target: &Arc<Task>,
+    policy: SchedPolicy,
+    priority: c_int,
+    reset_on_fork: bool,
+) -> KResult<()> {
+    let valid = if policy.is_realtime() {
+        (RT_PRIORITY_MIN..=RT_PRIORITY_MAX).contains(&priority)
+    } else {
+        priority == 0
+    };
+    if !valid {
+        kbail!(EINVAL, "set_sched_params(): invalid priority for policy");
+    }
+    check_sched_target(target)?;
+    let current = current_task();
+    if policy.is_realtime()
+        && !current.credentials().is_privileged()
+        && priority as u64 > current.rlimit(RLIMIT_RTPRIO)
+    {
+        kbail!(EPERM, "set_sched_params(): RLIMIT_RTPRIO too low");
+    }
+
+    {
+        let mut entity = target.sched.lock();
+        entity.policy = policy;
+        entity.rt_priority = priority;
+        entity.reset_on_fork = reset_on_fork;
+    }
+    get_scheduler().requeue(target);
+    Ok(())
+}
+
+impl SyscallHandler<'_> {
+    pub fn sys_sched_yield(&mut self) -> KResult<isize> {
+        get_scheduler().yield_now();
+        Ok(0)
+    }
+
+    pub fn sys_getpriority(&mut self, which: c_int, who: c_int) -> KResult<isize> {
+        let nice = prio_targets(which, who)?
+            .iter()
+            .map(|t| t.sched.lock().nice)
+            .min()
+            .unwrap();
+        // the raw syscall can't return negative values, so libc undoes this
+        Ok((20 - nice) as isize)
+    }
+
+    pub fn sys_setpriority(&mut self, which: c_int, who: c_int, nice: c_int) -> KResult<isize> {
+        let nice = nice.clamp(NICE_MIN, NICE_MAX);
+        let current = current_task();
+        let privileged = current.credentials().is_privileged();
+        for target in prio_targets(which, who)? {
+            check_sched_target(&target)?;
+            let mut entity = target.sched.lock();
+            if nice < entity.nice && !privileged && (20 - nice) as u64 > current.rlimit(RLIMIT_NICE)
+            {
+                kbail!(EACCES, "sys_setpriority(): not permitted to lower nice");
+            }
+            entity.nice = nice;
+        }
+        Ok(0)
+    }
+
+    pub fn sys_sched_setscheduler(
+        &mut self,
+        pid: TaskId,
+        policy: c_int,
+        param: VirtAddr,
+    ) -> KResult<isize> {
+        let reset_on_fork = policy & SCHED_RESET_ON_FORK != 0;
+        let policy = SchedPolicy::try_from(policy & !SCHED_RESET_ON_FORK)?;
+        let param = unsafe { param.read_user::<SchedParam>() }?;
+        let target = find_sched_target(pid)?;
+        set_sched_params(&target, policy, param.sched_priority, reset_on_fork)?;
+        Ok(0)
+    }
+
+    pub fn sys_sched_getscheduler(&mut self, pid: TaskId) -> KResult<isize> {
+        let target = find_sched_target(pid)?;
+        let entity = target.sched.lock();
+        let mut policy = c_int::from(entity.policy);
+        if entity.reset_on_fork {
+            policy |= SCHED_RESET_ON_FORK;
+        }
+        Ok(policy as isize)
+    }
+
+    pub fn sys_sched_setparam(&mut self, pid: TaskId, param: VirtAddr) -> KResult<isize> {
+        let param = unsafe { param.read_user::<SchedParam>() }?;
+        let target = find_sched_target(pid)?;
+        let (policy, reset_on_fork) = {
+            let entity = target.sched.lock();
+            (entity.policy, entity.reset_on_fork)
+        };
+        set_sched_params(&target, policy, param.sched_priority, reset_on_fork)?;
+        Ok(0)
+    }
+
+    pub fn sys_sched_getparam(&mut self, pid: TaskId, param: VirtAddr) -> KResult<isize> {
+        let target = find_sched_target(pid)?;
+        let sched_priority = target.sched.lock().rt_priority;
+        unsafe { param.write_user(SchedParam { sched_priority }) }?;
+        Ok(0)
+    }
+
+    pub fn sys_sched_get_priority_max(&mut self, policy: c_int) -> KResult<isize> {
+        if SchedPolicy::try_from(policy)?.is_realtime() {
+            Ok(RT_PRIORITY_MAX as isize)
+        } else {
+            Ok(0)
+        }
+    }
+
+    pub fn sys_sched_get_priority_min(&mut self, policy: c_int) -> KResult<isize> {
+        if SchedPolicy::try_from(policy)?.is_realtime() {
+            Ok(RT_PRIORITY_MIN as isize)
+        } else {
+            Ok(0)
+        }
+    }
+
+    pub fn sys_sched_rr_get_interval(&mut self, pid: TaskId, tp: VirtAddr) -> KResult<isize> {
+        let target = find_sched_target(pid)?;
+        let interval = match target.sched.lock().policy {
+            SchedPolicy::Fifo => 0,
+            SchedPolicy::RoundRobin => RR_TIMESLICE_NS,
+            // everyone else gets another look every tick
+            _ => time::TICK_NS,
+        };
+        unsafe { tp.write_user(TimeSpec::from_nanos(interval)) }?;
+        Ok(0)
+    }
+}
@@ -0,0 +1,98 @@
+use core::mem::size_of;
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    kbail, kerror,
+    mem::addr::VirtAddr,
+    task::{
+        current_task, get_scheduler,
+        seccomp::{
+            SeccompAction, SeccompData, SeccompFilter, SockFilter, SockFprog, AUDIT_ARCH_X86_64,
+            SECCOMP_GET_ACTION_AVAIL, SECCOMP_SET_MODE_FILTER, SECCOMP_SET_MODE_STRICT,
+        },
+        signal::{SigInfo, SIGSYS},
+    },
+    userland::syscall::{syscall_name_by_number, SyscallHandler},
+    util::{ctypes::c_int, KResult},
+};
+
+impl SyscallHandler<'_> {
+    pub fn sys_seccomp(&mut self, op: usize, flags: usize, args: VirtAddr) -> KResult<isize> {
+        if flags != 0 {
+            kbail!(EINVAL, "sys_seccomp(): unsupported flags");
+        }
+        match op {
+            SECCOMP_SET_MODE_FILTER => self.set_seccomp_filter(args)?,
+            SECCOMP_SET_MODE_STRICT => kbail!(EINVAL, "sys_seccomp(): strict mode isn't supported"),
+            SECCOMP_GET_ACTION_AVAIL => {
+                let action = unsafe { args.read_user::<u32>() }?;
+                if !SeccompAction::is_available(action) {
+                    kbail!(EOPNOTSUPP, "sys_seccomp(): unknown action");
+                }
+            }
+            _ => kbail!(EINVAL, "sys_seccomp(): unknown operation"),
+        }
+        Ok(0)
+    }
+
+    // SECCOMP_SET_MODE_FILTER and prctl(PR_SET_SECCOMP). filters can only ever be added, never
+    // taken away.
+    pub fn set_seccomp_filter(&mut self, fprog: VirtAddr) -> KResult<()> {
+        let current = current_task();
+        // otherwise a setuid program could be made to fail in ways it never expected
+        let cred = current.credentials();
+        if !cred.no_new_privs && !cred.is_privileged() {
+            kbail!(EACCES, "set_seccomp_filter(): no_new_privs isn't set");
+        }
+
+        let fprog = unsafe { fprog.read_user::<SockFprog>() }?;
+        let base = VirtAddr::new(fprog.filter);
+        let prog = (0..fprog.len as usize)
+            .map(|i| unsafe { (base + i * size_of::<SockFilter>()).read_user::<SockFilter>() })
+            .collect::<KResult<Vec<_>>>()?;
+
+        let mut seccomp = current.seccomp.lock();
+        let filter = SeccompFilter::new(prog, seccomp.clone())?;
+        *seccomp = Some(Arc::new(filter));
+        Ok(())
+    }
+
+    // runs a syscall past our filters before it gets to run, and gives back what it returns
+    // instead if it mustn't
+    pub fn check_seccomp(&mut self, n: usize, args: [usize; 6]) -> Option<KResult<isize>> {
+        let filter = current_task().seccomp.lock().clone()?;
+        let data = SeccompData {
+            nr: n as u32,
+            arch: AUDIT_ARCH_X86_64,
+            instruction_pointer: self.frame.rip as u64,
+            args: args.map(|arg| arg as u64),
+        };
+        match filter.evaluate(&data) {
+            SeccompAction::Allow => None,
+            SeccompAction::Log => {
+                log::info!("seccomp: allowed {}", syscall_name_by_number(n));
+                None
+            }
+            SeccompAction::Errno(errno) => Some(Ok(-(errno as isize))),
+            // we've no PTRACE_O_TRACESECCOMP, and without it linux acts like there's no tracer
+            SeccompAction::Trace => Some(Err(kerror!(ENOSYS, "check_seccomp(): no tracer"))),
+            SeccompAction::Trap(data) => {
+                // the handler sees the syscall as it was made, so it can emulate it
+                self.frame.rax = n;
+                let info =
+                    SigInfo::seccomp(self.frame.rip, n as c_int, AUDIT_ARCH_X86_64, data as c_int);
+                get_scheduler().force_fault_signal(self.frame, SIGSYS, &info);
+                Some(Ok(self.frame.rax as isize))
+            }
+            SeccompAction::KillThread => {
+                get_scheduler().seccomp_kill(self.frame, false);
+                Some(Ok(0))
+            }
+            SeccompAction::KillProcess => {
+                get_scheduler().seccomp_kill(self.frame, true);
+                Some(Ok(0))
+            }
+        }
+    }
+}
@@ -1,15 +1,25 @@
+use core::mem::size_of;
+
+use alloc::{sync::Arc, vec::Vec};
+
 use crate::{
+    arch::time,
     kbail, kerror,
     mem::addr::VirtAddr,
     task::{
         current_task, get_scheduler,
-        signal::{SigAction, SignalMask, DEFAULT_ACTIONS, SIG_DFL, SIG_ERR, SIG_IGN},
-        TaskId,
+        signal::{
+            KSigAction, SigInfo, SigSet, SigStack, SignalMask, SignalTarget, SIGCONT, SIGMAX,
+            SI_TKILL, SI_USER,
+        },
+        Task, TaskId,
     },
     userland::syscall::SyscallHandler,
     util::{ctypes::c_int, error::KResult},
 };
 
+use super::time::TimeSpec;
+
 impl SyscallHandler<'_> {
     pub fn sys_rt_sigprocmask(
         &mut self,
@@ -39,54 +49,299 @@ impl SyscallHandler<'_> {
         signum: c_int,
         act: VirtAddr,
         oldact: VirtAddr,
+        length: usize,
     ) -> KResult<isize> {
-        if oldact != VirtAddr::null() {
-            let action = current_task().signals.lock().get_action(signum);
-            let action = match action {
-                SigAction::Ignore => SIG_IGN,
-                SigAction::Terminate => SIG_ERR, // todo?
-                SigAction::Handler { handler } => handler as usize,
-            };
-            unsafe { oldact.write_user(action) }?;
-        }
-        if act != VirtAddr::null() {
-            let handler = unsafe { act.read_user::<usize>() }?;
-            let new_action = match handler {
-                SIG_IGN => SigAction::Ignore,
-                SIG_DFL => match DEFAULT_ACTIONS.get(signum as usize) {
-                    Some(def) => *def,
-                    None => {
-                        kbail!(EINVAL, "sys_rt_sigaction(): invalid signal number");
-                    }
-                },
-                _ => SigAction::Handler {
-                    handler: unsafe { core::mem::transmute::<usize, fn()>(handler) },
-                },
-            };
+        if signum <= 0 || signum >= SIGMAX {
+            kbail!(EINVAL, "sys_rt_sigaction(): invalid signal number");
+        }
+        if length != size_of::<u64>() {
+            kbail!(EINVAL, "sys_rt_sigaction(): sigset size isn't 8");
+        }
 
-            current_task()
-                .signals
-                .lock()
-                .set_action(signum, new_action)?;
+        let current = current_task();
+        let new_action = if act != VirtAddr::null() {
+            Some(unsafe { act.read_user::<KSigAction>() }?)
+        } else {
+            None
+        };
+        // read the old one and swap in the new one under the same lock
+        let mut signals = current.signals.lock();
+        let old_action = signals.get_sigaction(signum);
+        if let Some(new_action) = new_action {
+            signals.set_action(signum, new_action)?;
+        }
+        drop(signals);
+        if oldact != VirtAddr::null() {
+            unsafe { oldact.write_user(old_action) }?;
         }
 
         Ok(0)
     }
 
     pub fn sys_rt_sigreturn(&mut self) -> KResult<isize> {
-        get_scheduler().restore_signaled_user_stack(self.frame);
-        kbail!(EINTR, "sys_rt_sigreturn(): should not return")
+        get_scheduler().restore_signaled_user_stack(self.frame)?;
+        // whatever the interrupted code had in rax goes back where it was
+        Ok(self.frame.rax as isize)
+    }
+
+    pub fn sys_sigaltstack(&mut self, ss: VirtAddr, old_ss: VirtAddr) -> KResult<isize> {
+        let current = current_task();
+        let sp = self.frame.rsp;
+        let new = if ss != VirtAddr::null() {
+            Some(unsafe { ss.read_user::<SigStack>() }?)
+        } else {
+            None
+        };
+        let old = current.sigaltstack.load().status(sp);
+        if let Some(new) = new {
+            current.set_sigaltstack(&new, sp)?;
+        }
+        if old_ss != VirtAddr::null() {
+            unsafe { old_ss.write_user(old) }?;
+        }
+        Ok(0)
     }
 
-    pub fn sys_kill(&mut self, pid: TaskId, signum: c_int) -> KResult<isize> {
+    pub fn sys_kill(&mut self, pid: c_int, signum: c_int) -> KResult<isize> {
+        if !(0..SIGMAX).contains(&signum) {
+            kbail!(EINVAL, "sys_kill(): invalid signal number");
+        }
+        let sched = get_scheduler();
+        let info = sender_info(signum, SI_USER);
+
+        if pid > 0 {
+            let task = sched
+                .find_task(TaskId::new(pid as usize))
+                .ok_or(kerror!(ESRCH, "sys_kill(): pid not found"))?;
+            check_kill_permission(&task, signum)?;
+            // signal 0 only checks that the target exists
+            if signum != 0 {
+                sched.queue_signal(task, info, SignalTarget::Process)?;
+            }
+            return Ok(0);
+        }
+
+        let current = current_task();
+        let targets = if pid == -1 {
+            // everything but init, kernel tasks and ourselves
+            sched.find_tasks(|task| {
+                task.tgid() != current.tgid()
+                    && task.tgid().as_usize() != 1
+                    && task.arch_mut().is_user()
+            })
+        } else {
+            let group = match pid {
+                0 => current.group.lock().upgrade(),
+                _ => pid.checked_neg().and_then(|pgid| sched.find_group(pgid)),
+            }
+            .ok_or(kerror!(ESRCH, "sys_kill(): process group not found"))?;
+            let tasks = group.lock().tasks();
+            tasks
+        };
+
+        // it's only an error if there was nobody at all we were allowed to signal
+        let mut processes = Vec::new();
+        let mut signaled = false;
+        for task in targets {
+            if processes.contains(&task.tgid()) {
+                continue;
+            }
+            processes.push(task.tgid());
+            if check_kill_permission(&task, signum).is_err() {
+                continue;
+            }
+            signaled = true;
+            if signum != 0 {
+                sched.queue_signal(task, info, SignalTarget::Process).ok();
+            }
+        }
+        if processes.is_empty() {
+            kbail!(ESRCH, "sys_kill(): no processes to signal");
+        }
+        if !signaled {
+            kbail!(EPERM, "sys_kill(): not allowed to signal any of them");
+        }
+        Ok(0)
+    }
+
+    // tkill(2) is the same thing without the tgid check, which it gets by passing -1
+    pub fn sys_tgkill(&mut self, tgid: c_int, tid: c_int, signum: c_int) -> KResult<isize> {
+        if !(0..SIGMAX).contains(&signum) {
+            kbail!(EINVAL, "sys_tgkill(): invalid signal number");
+        }
+        let task = find_thread(tgid, tid)?;
+        check_kill_permission(&task, signum)?;
+        if signum != 0 {
+            let info = sender_info(signum, SI_TKILL);
+            get_scheduler().queue_signal(task, info, SignalTarget::Thread)?;
+        }
+        Ok(0)
+    }
+
+    // rt_sigqueueinfo(2) with a tgid of -1, and rt_tgsigqueueinfo(2) with a real one
+    pub fn sys_rt_sigqueueinfo(
+        &mut self,
+        tgid: c_int,
+        tid: c_int,
+        signum: c_int,
+        uinfo: VirtAddr,
+    ) -> KResult<isize> {
+        if signum <= 0 || signum >= SIGMAX {
+            kbail!(EINVAL, "sys_rt_sigqueueinfo(): invalid signal number");
+        }
+        let mut info = unsafe { uinfo.read_user::<SigInfo>() }?;
+        info.si_signo = signum;
+
         let sched = get_scheduler();
+        let (task, target) = if tgid == -1 {
+            let task = sched
+                .find_task(TaskId::new(tid as usize))
+                .ok_or(kerror!(ESRCH, "sys_rt_sigqueueinfo(): pid not found"))?;
+            (task, SignalTarget::Process)
+        } else {
+            (find_thread(tgid, tid)?, SignalTarget::Thread)
+        };
+        check_kill_permission(&task, signum)?;
+        // nobody gets to pass their signal off as coming from kill(2) or the kernel, except
+        // to themselves
+        if (info.si_code >= 0 || info.si_code == SI_TKILL) && task.tgid() != current_task().tgid() {
+            kbail!(EPERM, "sys_rt_sigqueueinfo(): can't forge si_code");
+        }
+        sched.queue_signal(task, info, target)?;
+        Ok(0)
+    }
 
-        sched.send_signal_to(
-            sched
-                .find_task(pid)
-                .ok_or(kerror!(ESRCH, "sys_kill(): pid not found"))?,
-            signum,
-        );
+    pub fn sys_rt_sigpending(&mut self, set: VirtAddr, length: usize) -> KResult<isize> {
+        if length != size_of::<u64>() {
+            kbail!(EINVAL, "sys_rt_sigpending(): sigset size isn't 8");
+        }
+        let current = current_task();
+        let pending = current.pending_signals() & current.signal_mask();
+        unsafe { set.write_user(pending.to_user()) }?;
         Ok(0)
     }
+
+    pub fn sys_rt_sigsuspend(&mut self, set: VirtAddr, length: usize) -> KResult<isize> {
+        if length != size_of::<u64>() {
+            kbail!(EINVAL, "sys_rt_sigsuspend(): sigset size isn't 8");
+        }
+        let mask = SigSet::from_user(unsafe { set.read_user::<u64>() }?);
+        let current = current_task();
+        // the old mask comes back once the signal has been delivered
+        current.saved_sigmask.store(Some(current.signal_mask()));
+        current.set_signal_mask_to(mask);
+        let sched = get_scheduler();
+        while sched.sleep(None).is_ok() {}
+        Err(kerror!(EINTR, "sys_rt_sigsuspend(): interrupted"))
+    }
+
+    pub fn sys_rt_sigtimedwait(
+        &mut self,
+        set: VirtAddr,
+        uinfo: VirtAddr,
+        timeout: VirtAddr,
+        length: usize,
+    ) -> KResult<isize> {
+        if length != size_of::<u64>() {
+            kbail!(EINVAL, "sys_rt_sigtimedwait(): sigset size isn't 8");
+        }
+        let wanted = SigSet::from_user(unsafe { set.read_user::<u64>() }?).blockable();
+        let deadline = if timeout != VirtAddr::null() {
+            let timeout = unsafe { timeout.read_user::<TimeSpec>() }?;
+            if !timeout.is_valid() {
+                kbail!(EINVAL, "sys_rt_sigtimedwait(): invalid timespec");
+            }
+            Some(time::get_uptime_ns().saturating_add(timeout.as_nanos()))
+        } else {
+            None
+        };
+
+        let current = current_task();
+        let take = || {
+            current
+                .signals
+                .lock()
+                .dequeue(&mut current.pending.lock(), |signal, _| {
+                    wanted.contains(signal)
+                })
+                .map(|(info, _)| info)
+        };
+        let info = match take() {
+            Some(info) => info,
+            None => {
+                // the signals we're waiting for have to be able to wake us up
+                let blocked = current.signal_mask();
+                current.set_signal_mask_to(blocked & !wanted);
+                let sched = get_scheduler();
+                let mut interrupted = false;
+                loop {
+                    let remaining = match deadline {
+                        Some(deadline) => {
+                            let now = time::get_uptime_ns();
+                            if now >= deadline {
+                                break;
+                            }
+                            Some((deadline - now).div_ceil(1000000))
+                        }
+                        None => None,
+                    };
+                    if sched.sleep(remaining).is_err() {
+                        interrupted = true;
+                        break;
+                    }
+                }
+                current.set_signal_mask_to(blocked);
+                match take() {
+                    Some(info) => info,
+                    // something we weren't waiting for has a handler to run first
+                    None if interrupted => {
+                        kbail!(EINTR, "sys_rt_sigtimedwait(): interrupted")
+                    }
+                    None => kbail!(EAGAIN, "sys_rt_sigtimedwait(): timed out"),
+                }
+            }
+        };
+
+        if uinfo != VirtAddr::null() {
+            unsafe { uinfo.write_user(info) }?;
+        }
+        Ok(info.si_signo as isize)
+    }
+}
+
+// what kill(2) and friends tell the receiver about who sent it
+fn sender_info(signum: c_int, code: c_int) -> SigInfo {
+    let current = current_task();
+    SigInfo::user(
+        signum,
+        code,
+        current.tgid().as_usize() as c_int,
+        current.credentials().uid.as_u32(),
+    )
+}
+
+// anyone may signal processes running as them, root may signal anything, and SIGCONT can wake
+// up anything in the same session
+fn check_kill_permission(target: &Task, signum: c_int) -> KResult<()> {
+    let current = current_task();
+    if current.credentials().can_signal(&target.credentials())
+        || (signum == SIGCONT && current.sid() == target.sid())
+    {
+        return Ok(());
+    }
+    kbail!(
+        EPERM,
+        "check_kill_permission(): not allowed to signal that process"
+    );
+}
+
+// a tgid of -1 matches any thread group
+fn find_thread(tgid: c_int, tid: c_int) -> KResult<Arc<Task>> {
+    if tid <= 0 || (tgid != -1 && tgid <= 0) {
+        kbail!(EINVAL, "find_thread(): invalid thread id");
+    }
+    get_scheduler()
+        .find_task(TaskId::new(tid as usize))
+        .filter(|task| tgid == -1 || task.tgid().as_usize() == tgid as usize)
+        .ok_or(kerror!(ESRCH, "find_thread(): thread not found"))
 }
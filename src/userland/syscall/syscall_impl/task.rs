@@ -8,12 +8,41 @@ use crate::{
     fs::path::Path,
     kbail, kerror,
     mem::addr::VirtAddr,
-    task::{current_task, get_scheduler, group::PgId, Task, TaskId, TaskState, JOIN_WAIT_QUEUE},
-    userland::{buffer::CStr, syscall::SyscallHandler},
-    util::{ctypes::c_int, errno::Errno, KResult},
+    task::{
+        cputime::CpuTime,
+        cred::Access,
+        current_task, exited_status,
+        futex::{
+            RobustListHead, FUTEX_BITSET_MATCH_ANY, FUTEX_CLOCK_REALTIME, FUTEX_CMD_MASK,
+            FUTEX_CMP_REQUEUE, FUTEX_REQUEUE, FUTEX_TABLE, FUTEX_WAIT, FUTEX_WAIT_BITSET,
+            FUTEX_PRIVATE_FLAG, FUTEX_WAKE, FUTEX_WAKE_BITSET,
+        },
+        get_scheduler,
+        group::{PgId, SessionId},
+        itimer::IntervalTimer,
+        seccomp::{SECCOMP_MODE_DISABLED, SECCOMP_MODE_FILTER},
+        signal::{RestartBlock, SigInfo, SIGCHLD},
+        CloneFlags, Task, TaskId, TaskState, CONTINUED_STATUS, JOIN_WAIT_QUEUE,
+    },
+    userland::{binfmt, buffer::CStr, syscall::SyscallHandler},
+    util::{ctypes::c_int, KResult},
 };
 
-use super::time::TimeSpec;
+use super::time::{
+    clock_now, clock_resolution, ITimerVal, RUsage, TimeSpec, Tms, CLOCK_BOOTTIME, CLOCK_MONOTONIC,
+    CLOCK_MONOTONIC_COARSE, CLOCK_PROCESS_CPUTIME_ID, CLOCK_REALTIME, ITIMER_REAL, TIMER_ABSTIME,
+};
+
+const RUSAGE_SELF: c_int = 0;
+const RUSAGE_CHILDREN: c_int = -1;
+const RUSAGE_THREAD: c_int = 1;
+
+const USER_HZ: usize = 100;
+
+const PR_GET_SECCOMP: c_int = 21;
+const PR_SET_SECCOMP: c_int = 22;
+const PR_SET_NO_NEW_PRIVS: c_int = 38;
+const PR_GET_NO_NEW_PRIVS: c_int = 39;
 
 const ARG_MAX: usize = 512;
 const ARG_LEN_MAX: usize = 4096;
@@ -21,6 +50,36 @@ const ENV_MAX: usize = 512;
 const ENV_LEN_MAX: usize = 4096;
 
 impl SyscallHandler<'_> {
+    pub fn sys_prctl(&mut self, option: c_int, arg2: usize, arg3: usize) -> KResult<isize> {
+        let current = current_task();
+        match option {
+            PR_SET_NO_NEW_PRIVS => {
+                // there's no taking it back
+                if arg2 != 1 {
+                    kbail!(EINVAL, "sys_prctl(): no_new_privs can only be set");
+                }
+                current.cred.lock().no_new_privs = true;
+            }
+            PR_GET_NO_NEW_PRIVS => return Ok(current.cred.lock().no_new_privs as isize),
+            PR_GET_SECCOMP => {
+                let mode = if current.seccomp.lock().is_some() {
+                    SECCOMP_MODE_FILTER
+                } else {
+                    SECCOMP_MODE_DISABLED
+                };
+                return Ok(mode as isize);
+            }
+            PR_SET_SECCOMP => {
+                if arg2 != SECCOMP_MODE_FILTER {
+                    kbail!(EINVAL, "sys_prctl(): only filter mode is supported");
+                }
+                self.set_seccomp_filter(VirtAddr::new(arg3))?;
+            }
+            _ => kbail!(EINVAL, "sys_prctl(): unsupported option"),
+        }
+        Ok(0)
+    }
+
     pub fn sys_arch_prctl(&mut self, code: i32, uaddr: VirtAddr) -> KResult<isize> {
         arch_prctl(current_task(), code, uaddr)?;
         Ok(0)
@@ -35,17 +94,46 @@ impl SyscallHandler<'_> {
 
     pub fn sys_clone(
         &mut self,
-        _clone_flags: usize,
+        clone_flags: usize,
         user_stack: VirtAddr,
-        r8: usize,
-        args: VirtAddr,
-        r9: usize,
-        entry_point: VirtAddr,
+        parent_tid: VirtAddr,
+        child_tid: VirtAddr,
+        tls: VirtAddr,
     ) -> KResult<isize> {
-        let child = current_task().clone_process(entry_point, user_stack, args, r8, r9, self.frame);
+        // the low byte is the exit signal, which we always treat as SIGCHLD for now
+        let flags = CloneFlags::from_bits_truncate(clone_flags);
+        if flags.contains(CloneFlags::CLONE_THREAD) && !flags.contains(CloneFlags::CLONE_SIGHAND) {
+            kbail!(EINVAL, "sys_clone(): CLONE_THREAD requires CLONE_SIGHAND");
+        }
+        if flags.contains(CloneFlags::CLONE_SIGHAND) && !flags.contains(CloneFlags::CLONE_VM) {
+            kbail!(EINVAL, "sys_clone(): CLONE_SIGHAND requires CLONE_VM");
+        }
+
+        let current = current_task();
+        let _guard = current.arch_mut().address_space.temporarily_switch();
+        let child = current.clone_process(flags, user_stack, tls, child_tid, self.frame)?;
+        if flags.contains(CloneFlags::CLONE_PARENT_SETTID) {
+            unsafe { parent_tid.write_user(child.pid().as_usize() as c_int) }?;
+        }
+        get_scheduler().push_runnable(child.clone());
+        if flags.contains(CloneFlags::CLONE_VFORK) {
+            // we're about to die anyway, the signal goes out on the way back to userspace
+            child.wait_for_vfork_done().ok();
+        }
         Ok(child.pid().as_usize() as isize)
     }
 
+    pub fn sys_vfork(&mut self) -> KResult<isize> {
+        let flags = CloneFlags::CLONE_VM | CloneFlags::CLONE_VFORK;
+        self.sys_clone(
+            flags.bits() | SIGCHLD as usize,
+            VirtAddr::null(),
+            VirtAddr::null(),
+            VirtAddr::null(),
+            VirtAddr::null(),
+        )
+    }
+
     pub fn sys_execve(
         &mut self,
         path: &Path,
@@ -61,6 +149,11 @@ impl SyscallHandler<'_> {
             .lookup(path, true)?
             .as_file()?
             .clone();
+        let stat = exefile.stat()?;
+        if !stat.mode.is_regular_file() {
+            kbail!(EACCES, "sys_execve(): not a regular file");
+        }
+        current.credentials().check_access(&stat, Access::X_OK)?;
 
         let mut argv = Vec::new();
         for i in 0..ARG_MAX {
@@ -83,23 +176,164 @@ impl SyscallHandler<'_> {
                 break;
             }
         }
-        let argv: Vec<&[u8]> = argv.as_slice().iter().map(|s| s.as_bytes()).collect();
+        let argv = argv.iter().map(|s| s.as_bytes().to_vec()).collect();
+        // scripts and registered formats run under their interpreter instead
+        let root_fs = current.root_fs.lock().clone();
+        let (exefile, argv) = binfmt::resolve_interpreter(&root_fs, path, exefile, argv)?;
+        let argv: Vec<&[u8]> = argv.iter().map(Vec::as_slice).collect();
         let envp: Vec<&[u8]> = envp.as_slice().iter().map(|s| s.as_bytes()).collect();
         current.exec(exefile, &argv, &envp)?;
         Ok(0)
     }
 
     pub fn sys_exit(&mut self, status: c_int) -> KResult<isize> {
-        get_scheduler().exit_current(status);
+        get_scheduler().exit_current(exited_status(status));
         Ok(0)
     }
 
-    pub fn sys_set_tid_address(&mut self, _addr: VirtAddr) -> KResult<isize> {
-        // todo: use addr
-        Ok(current_task().pid().as_usize() as isize)
+    pub fn sys_exit_group(&mut self, status: c_int) -> KResult<isize> {
+        get_scheduler().exit_group(exited_status(status));
+        Ok(0)
+    }
+
+    pub fn sys_futex(
+        &mut self,
+        uaddr: VirtAddr,
+        op: c_int,
+        val: u32,
+        timeout_or_val2: usize,
+        uaddr2: VirtAddr,
+        val3: u32,
+    ) -> KResult<isize> {
+        let read_timeout = |timeout: VirtAddr| -> KResult<Option<TimeSpec>> {
+            if timeout.is_null() {
+                return Ok(None);
+            }
+            let ts = unsafe { timeout.read_user::<TimeSpec>() }?;
+            if ts.tv_sec < 0 || !(0..1000000000).contains(&ts.tv_nsec) {
+                kbail!(EINVAL, "sys_futex(): invalid timeout");
+            }
+            Ok(Some(ts))
+        };
+
+        let cmd = op & FUTEX_CMD_MASK;
+        let private = op & FUTEX_PRIVATE_FLAG != 0;
+        // only the ops that take an absolute timeout get to pick its clock
+        if op & FUTEX_CLOCK_REALTIME != 0 && cmd != FUTEX_WAIT_BITSET {
+            kbail!(
+                ENOSYS,
+                "sys_futex(): FUTEX_CLOCK_REALTIME without FUTEX_WAIT_BITSET"
+            );
+        }
+
+        match cmd {
+            FUTEX_WAIT => {
+                let timeout = read_timeout(VirtAddr::new(timeout_or_val2))?;
+                FUTEX_TABLE.wait(
+                    uaddr,
+                    val,
+                    timeout.map(|ts| ts.as_millis()),
+                    FUTEX_BITSET_MATCH_ANY,
+                    private,
+                )?;
+                Ok(0)
+            }
+            FUTEX_WAIT_BITSET => {
+                // the timeout is an absolute point in time here, not a duration
+                let timeout = read_timeout(VirtAddr::new(timeout_or_val2))?;
+                let clock = if op & FUTEX_CLOCK_REALTIME != 0 {
+                    CLOCK_REALTIME
+                } else {
+                    CLOCK_MONOTONIC
+                };
+                let now = clock_now(clock)?;
+                FUTEX_TABLE.wait(
+                    uaddr,
+                    val,
+                    timeout.map(|ts| ts.as_nanos().saturating_sub(now).div_ceil(1000000)),
+                    val3,
+                    private,
+                )?;
+                Ok(0)
+            }
+            FUTEX_WAKE => Ok(FUTEX_TABLE.wake(
+                uaddr,
+                val as usize,
+                FUTEX_BITSET_MATCH_ANY,
+                private,
+            )? as isize),
+            FUTEX_WAKE_BITSET => {
+                Ok(FUTEX_TABLE.wake(uaddr, val as usize, val3, private)? as isize)
+            }
+            FUTEX_REQUEUE => {
+                let (woken, _) = FUTEX_TABLE.requeue(
+                    uaddr,
+                    val as usize,
+                    uaddr2,
+                    timeout_or_val2,
+                    None,
+                    private,
+                )?;
+                Ok(woken as isize)
+            }
+            FUTEX_CMP_REQUEUE => {
+                let (woken, moved) = FUTEX_TABLE.requeue(
+                    uaddr,
+                    val as usize,
+                    uaddr2,
+                    timeout_or_val2,
+                    Some(val3),
+                    private,
+                )?;
+                Ok((woken + moved) as isize)
+            }
+            _ => Err(kerror!(ENOSYS, "sys_futex(): unsupported futex op")),
+        }
+    }
+
+    pub fn sys_set_tid_address(&mut self, addr: VirtAddr) -> KResult<isize> {
+        let current = current_task();
+        current.clear_child_tid.store(addr);
+        Ok(current.pid().as_usize() as isize)
+    }
+
+    pub fn sys_set_robust_list(&mut self, head: VirtAddr, len: usize) -> KResult<isize> {
+        if len != size_of::<RobustListHead>() {
+            kbail!(EINVAL, "sys_set_robust_list(): invalid length");
+        }
+        current_task().robust_list.store(head);
+        Ok(0)
+    }
+
+    pub fn sys_get_robust_list(
+        &mut self,
+        pid: TaskId,
+        head_ptr: VirtAddr,
+        len_ptr: VirtAddr,
+    ) -> KResult<isize> {
+        let current = current_task();
+        let task = if pid.as_usize() == 0 {
+            current
+        } else {
+            let task = get_scheduler()
+                .find_task(pid)
+                .ok_or(kerror!(ESRCH, "sys_get_robust_list(): task not found"))?;
+            // it gives away where another process keeps its locks
+            if !current.credentials().can_trace(&task.credentials()) {
+                kbail!(EPERM, "sys_get_robust_list(): not permitted");
+            }
+            task
+        };
+        unsafe { head_ptr.write_user(task.robust_list.load().value()) }?;
+        unsafe { len_ptr.write_user(size_of::<RobustListHead>()) }?;
+        Ok(0)
     }
 
     pub fn sys_getpid(&mut self) -> KResult<isize> {
+        Ok(current_task().tgid().as_usize() as isize)
+    }
+
+    pub fn sys_gettid(&mut self) -> KResult<isize> {
         Ok(current_task().pid().as_usize() as isize)
     }
 
@@ -119,25 +353,81 @@ impl SyscallHandler<'_> {
         }
     }
 
-    pub fn sys_setpgid(&mut self, pid: TaskId, pgid: PgId) -> KResult<isize> {
-        if pid.as_usize() == 0 {
+    pub fn sys_getsid(&mut self, pid: TaskId) -> KResult<isize> {
+        let task = if pid.as_usize() == 0 {
             current_task()
-                .group
-                .borrow_mut()
-                .upgrade()
-                .unwrap()
-                .lock()
-                .set_pgid(pgid);
         } else {
             get_scheduler()
+                .find_task(pid)
+                .ok_or(kerror!(ESRCH, "sys_getsid(): task not found"))?
+        };
+        Ok(task.sid() as isize)
+    }
+
+    pub fn sys_setsid(&mut self) -> KResult<isize> {
+        let current = current_task();
+        let sid = current.tgid().as_usize() as SessionId;
+        // nobody may already be using our pid as their process group, us included
+        if get_scheduler()
+            .find_group(sid)
+            .is_some_and(|group| !group.lock().tasks().is_empty())
+        {
+            kbail!(EPERM, "sys_setsid(): a process group with our pid exists");
+        }
+        for thread in get_scheduler().thread_group(current.tgid()) {
+            thread.set_sid(sid);
+            thread.join_group(sid);
+        }
+        Ok(sid as isize)
+    }
+
+    pub fn sys_setpgid(&mut self, pid: TaskId, pgid: PgId) -> KResult<isize> {
+        if pgid < 0 {
+            kbail!(EINVAL, "sys_setpgid(): negative pgid");
+        }
+        let current = current_task();
+        let sched = get_scheduler();
+        let task = if pid.as_usize() == 0 {
+            current.clone()
+        } else {
+            sched
                 .find_task(pid)
                 .ok_or(kerror!(ESRCH, "sys_setpgid(): task not found"))?
-                .group
-                .borrow_mut()
-                .upgrade()
-                .unwrap()
-                .lock()
-                .set_pgid(pgid);
+        };
+        // we can only move ourselves and our own children
+        if task.tgid() != current.tgid() {
+            if task.ppid() != current.tgid() {
+                kbail!(ESRCH, "sys_setpgid(): not us or one of our children");
+            }
+            if task.sid() != current.sid() {
+                kbail!(EPERM, "sys_setpgid(): child is in another session");
+            }
+            if task.did_exec() {
+                kbail!(EACCES, "sys_setpgid(): child has already exec'd");
+            }
+        }
+        if task.is_session_leader() {
+            kbail!(EPERM, "sys_setpgid(): can't move a session leader");
+        }
+        let pgid = if pgid == 0 {
+            task.tgid().as_usize() as PgId
+        } else {
+            pgid
+        };
+        // a group can only be joined from inside its own session, and only the process whose
+        // pid it is can start a new one
+        if pgid != task.tgid().as_usize() as PgId {
+            let in_session = sched.find_group(pgid).is_some_and(|group| {
+                let tasks = group.lock().tasks();
+                tasks.iter().any(|t| t.sid() == current.sid())
+            });
+            if !in_session {
+                kbail!(EPERM, "sys_setpgid(): no such process group in our session");
+            }
+        }
+        // the whole process moves, not just the thread we were handed
+        for thread in sched.thread_group(task.tgid()) {
+            thread.join_group(pgid);
         }
         Ok(0)
     }
@@ -145,112 +435,410 @@ impl SyscallHandler<'_> {
 
 bitflags! {
     pub struct WaitOptions: c_int {
-        const WNOHANG   = 1;
-        const WUNTRACED = 2;
+        const WNOHANG    = 1;
+        const WUNTRACED  = 2;
+        const WSTOPPED   = 2;
+        const WEXITED    = 4;
+        const WCONTINUED = 8;
+        const WNOWAIT    = 0x01000000;
+        // debuggers pass these, and we don't tell clone children apart from any others
+        const WNOTHREAD  = 0x20000000;
+        const WALL       = 0x40000000;
+        const WCLONE     = 0x80000000_u32 as c_int;
+    }
+}
+
+pub const P_ALL: c_int = 0;
+pub const P_PID: c_int = 1;
+pub const P_PGID: c_int = 2;
+
+enum WaitTarget {
+    Any,
+    Pid(TaskId),
+    Group(PgId),
+}
+
+impl WaitTarget {
+    fn matches(&self, child: &Task) -> bool {
+        match self {
+            WaitTarget::Any => true,
+            WaitTarget::Pid(pid) => child.pid() == *pid,
+            WaitTarget::Group(pgid) => child.pgid() == Some(*pgid),
+        }
     }
 }
 
 impl SyscallHandler<'_> {
-    pub fn sys_wait4(
+    // returns the pid, wait status and cpu time of a child that has something to report,
+    // or None if WNOHANG was given and nothing is ready yet
+    fn wait_for_child(
         &mut self,
-        pid: TaskId,
-        status: VirtAddr,
+        target: WaitTarget,
         options: WaitOptions,
-        _rusage: VirtAddr, // could be null
-    ) -> KResult<isize> {
-        let (got_pid, status_val) = JOIN_WAIT_QUEUE.sleep_signalable_until(None, || {
+    ) -> KResult<Option<(TaskId, c_int, CpuTime)>> {
+        JOIN_WAIT_QUEUE.sleep_signalable_until(None, || {
             let current = current_task();
-            let children = current.children.lock();
-            if children.is_empty() {
-                kbail!(ECHILD, "sys_wait4(): all subprocesses have exited");
+            let mut children = current.children.lock();
+            let mut found = false;
+            for (i, child) in children.iter().enumerate() {
+                if !target.matches(child) {
+                    continue;
+                }
+                found = true;
+
+                if let TaskState::ExitedWith(status) = child.get_state() {
+                    // a group leader that exited early still has to wait for its threads
+                    if options.contains(WaitOptions::WEXITED)
+                        && !get_scheduler().is_thread_group_alive(child.tgid())
+                    {
+                        let status = child.group_exit_status.load().unwrap_or(status);
+                        let pid = child.pid();
+                        let spent = child.cumulative_cpu_time();
+                        if !options.contains(WaitOptions::WNOWAIT) {
+                            children.remove(i);
+                            current.process_times.lock().children += spent;
+                        }
+                        return Ok(Some(Some((pid, status, spent))));
+                    }
+                    continue;
+                }
+
+                if let Some(status) = child.wait_event.load() {
+                    let wanted = if status == CONTINUED_STATUS {
+                        options.contains(WaitOptions::WCONTINUED)
+                    } else {
+                        options.contains(WaitOptions::WSTOPPED)
+                    };
+                    if wanted {
+                        // stops and continues are only reported once
+                        if !options.contains(WaitOptions::WNOWAIT) {
+                            child.wait_event.compare_exchange(Some(status), None).ok();
+                        }
+                        return Ok(Some(Some((
+                            child.pid(),
+                            status,
+                            child.cumulative_cpu_time(),
+                        ))));
+                    }
+                }
             }
-            for child in children.iter() {
-                if pid.as_usize() as isize > 0 && pid != child.pid() {
+
+            // tracees report their ptrace-stops to us, and their exits too when they aren't
+            // our own children
+            let mut tracees = current.tracees.lock();
+            tracees.retain(|tracee| {
+                !matches!(tracee.get_state(), TaskState::ExitedWith(_))
+                    || !children.iter().any(|child| child.pid() == tracee.pid())
+            });
+            for (i, tracee) in tracees.iter().enumerate() {
+                if !target.matches(tracee) {
                     continue;
                 }
+                found = true;
 
-                if pid.as_usize() == 0 {
-                    todo!()
+                if let TaskState::ExitedWith(status) = tracee.get_state() {
+                    if options.contains(WaitOptions::WEXITED) {
+                        let pid = tracee.pid();
+                        let spent = tracee.cumulative_cpu_time();
+                        if !options.contains(WaitOptions::WNOWAIT) {
+                            tracees.remove(i);
+                        }
+                        return Ok(Some(Some((pid, status, spent))));
+                    }
+                    continue;
                 }
 
-                if let TaskState::ExitedWith(status_val) = child.get_state() {
-                    return Ok(Some((child.pid(), status_val)));
+                // a tracer doesn't need WUNTRACED to see these
+                let event = tracee.ptrace.lock().as_mut().and_then(|p| {
+                    if options.contains(WaitOptions::WNOWAIT) {
+                        p.event
+                    } else {
+                        p.event.take()
+                    }
+                });
+                if let Some(status) = event {
+                    return Ok(Some(Some((
+                        tracee.pid(),
+                        status,
+                        tracee.cumulative_cpu_time(),
+                    ))));
                 }
             }
 
+            if !found {
+                kbail!(ECHILD, "wait_for_child(): no matching children");
+            }
+
             if options.contains(WaitOptions::WNOHANG) {
-                return Ok(Some((TaskId::new(0), 0)));
+                return Ok(Some(None));
             }
 
             Ok(None)
-        })?;
+        })
+    }
 
-        log::debug!("wait4: status = {status_val}");
-        current_task()
-            .children
-            .lock()
-            .retain(|p| p.pid() != got_pid);
+    pub fn sys_wait4(
+        &mut self,
+        pid: c_int,
+        status: VirtAddr,
+        options: WaitOptions,
+        rusage: VirtAddr, // could be null
+    ) -> KResult<isize> {
+        if options.intersects(WaitOptions::WEXITED | WaitOptions::WNOWAIT) {
+            kbail!(EINVAL, "sys_wait4(): invalid options");
+        }
+        let target = match pid {
+            -1 => WaitTarget::Any,
+            0 => WaitTarget::Group(
+                current_task()
+                    .pgid()
+                    .ok_or(kerror!(ESRCH, "sys_wait4(): no process group"))?,
+            ),
+            pid if pid < 0 => WaitTarget::Group(
+                pid.checked_neg()
+                    .ok_or(kerror!(ECHILD, "sys_wait4(): invalid process group"))?,
+            ),
+            pid => WaitTarget::Pid(TaskId::new(pid as usize)),
+        };
 
+        let Some((got_pid, status_val, spent)) =
+            self.wait_for_child(target, options | WaitOptions::WEXITED)?
+        else {
+            return Ok(0);
+        };
+
+        log::debug!("wait4: status = {status_val:#x}");
         if status.value() != 0 {
             unsafe { status.write_user::<c_int>(status_val) }?;
         }
+        if rusage.value() != 0 {
+            unsafe { rusage.write_user(RUsage::from(spent)) }?;
+        }
 
         Ok(got_pid.as_usize() as isize)
     }
 
-    pub fn sys_nanosleep(&mut self, req: VirtAddr, _rem: VirtAddr) -> KResult<isize> {
-        let req = unsafe { req.read_user::<TimeSpec>() }?;
-        assert_eq!(req.tv_nsec % 1000000, 0);
-        let duration = req.tv_sec * 1000 + req.tv_nsec / 1000000;
-        #[allow(clippy::redundant_guards)]
-        match get_scheduler().sleep(Some(duration as usize)) {
-            Ok(_) => {}
-            Err(e) if e.errno == Some(Errno::EINTR) => {
-                todo!()
-                // return Err(KError::Errno { errno: Errno::EINTR, msg })
-            }
-            Err(_) => {
-                todo!()
-            }
+    pub fn sys_waitid(
+        &mut self,
+        id_type: c_int,
+        id: c_int,
+        infop: VirtAddr,
+        options: WaitOptions,
+        rusage: VirtAddr,
+    ) -> KResult<isize> {
+        if !options
+            .intersects(WaitOptions::WEXITED | WaitOptions::WSTOPPED | WaitOptions::WCONTINUED)
+        {
+            kbail!(EINVAL, "sys_waitid(): nothing to wait for");
+        }
+        let target = match id_type {
+            P_ALL => WaitTarget::Any,
+            P_PID if id > 0 => WaitTarget::Pid(TaskId::new(id as usize)),
+            P_PGID if id > 0 => WaitTarget::Group(id),
+            P_PGID if id == 0 => WaitTarget::Group(
+                current_task()
+                    .pgid()
+                    .ok_or(kerror!(ESRCH, "sys_waitid(): no process group"))?,
+            ),
+            _ => kbail!(EINVAL, "sys_waitid(): invalid id type"),
+        };
+
+        let mut info = SigInfo::default();
+        let mut spent = CpuTime::default();
+        if let Some((got_pid, status_val, child_time)) = self.wait_for_child(target, options)? {
+            info = SigInfo::child(got_pid.as_usize() as c_int, status_val);
+            info.si_utime = clock_ticks(child_time.user_ns);
+            info.si_stime = clock_ticks(child_time.system_ns);
+            spent = child_time;
+        }
+        // with WNOHANG and nothing to report, userspace gets back a zeroed si_pid
+        if infop.value() != 0 {
+            unsafe { infop.write_user(info) }?;
+        }
+        if rusage.value() != 0 {
+            unsafe { rusage.write_user(RUsage::from(spent)) }?;
         }
+
         Ok(0)
     }
 
-    pub fn sys_clock_gettime(&mut self, clk_id: usize, tp: VirtAddr) -> KResult<isize> {
-        match clk_id {
-            0 => {
-                let ts = time::get_rt_clock();
-                unsafe { tp.write_user(ts) }?;
-            }
-            1 => {
-                let ts = time::get_rt_clock();
-                unsafe { tp.write_user(ts) }?;
+    pub fn sys_nanosleep(&mut self, req: VirtAddr, rem: VirtAddr) -> KResult<isize> {
+        self.sys_clock_nanosleep(CLOCK_MONOTONIC, 0, req, rem)
+    }
+
+    pub fn sys_clock_nanosleep(
+        &mut self,
+        clockid: usize,
+        flags: c_int,
+        req: VirtAddr,
+        rem: VirtAddr,
+    ) -> KResult<isize> {
+        match clockid {
+            CLOCK_REALTIME | CLOCK_MONOTONIC | CLOCK_BOOTTIME => {}
+            CLOCK_MONOTONIC_COARSE | CLOCK_PROCESS_CPUTIME_ID => {
+                kbail!(
+                    EOPNOTSUPP,
+                    "sys_clock_nanosleep(): can't sleep on this clock"
+                )
             }
-            2 => {
-                let current = current_task();
-                let delta_ns = time::get_uptime_ns() - current.start_time.get().unwrap() * 1000000;
-                let delta_sec = delta_ns / 1000000000;
-                let ts = TimeSpec {
-                    tv_sec: delta_sec as isize,
-                    tv_nsec: (delta_ns % 1000000000) as isize,
-                };
-                unsafe { tp.write_user(ts) }?;
+            _ => kbail!(EINVAL, "sys_clock_nanosleep(): invalid clock"),
+        }
+        let req = unsafe { req.read_user::<TimeSpec>() }?;
+        if !req.is_valid() {
+            kbail!(EINVAL, "sys_clock_nanosleep(): invalid timespec");
+        }
+        let absolute = flags & TIMER_ABSTIME != 0;
+        let deadline = if absolute {
+            req.as_nanos()
+        } else {
+            clock_now(clockid)?.saturating_add(req.as_nanos())
+        };
+        self.nanosleep_until(clockid, deadline, absolute, rem)
+    }
+
+    pub fn sys_restart_syscall(&mut self) -> KResult<isize> {
+        match current_task().restart_block.take() {
+            Some(RestartBlock::Nanosleep {
+                clockid,
+                deadline,
+                absolute,
+                rem,
+            }) => self.nanosleep_until(clockid, deadline, absolute, rem),
+            None => kbail!(EINTR, "sys_restart_syscall(): nothing to restart"),
+        }
+    }
+
+    fn nanosleep_until(
+        &mut self,
+        clockid: usize,
+        deadline: usize,
+        absolute: bool,
+        rem: VirtAddr,
+    ) -> KResult<isize> {
+        // the deadline queue only counts whole ticks, so we may have to go back to sleep
+        loop {
+            let now = clock_now(clockid)?;
+            if now >= deadline {
+                return Ok(0);
             }
-            3 => {
-                let current = current_task();
-                let delta_ns = time::get_uptime_ns() - current.start_time.get().unwrap() * 1000000;
-                let delta_sec = delta_ns / 1000000000;
-                let ts = TimeSpec {
-                    tv_sec: delta_sec as isize,
-                    tv_nsec: (delta_ns % 1000000000) as isize,
-                };
-                unsafe { tp.write_user(ts) }?;
+            if let Err(err) = get_scheduler().sleep(Some((deadline - now).div_ceil(1000000))) {
+                // an absolute deadline is still good as it is, so nobody needs what's left
+                if !absolute && rem != VirtAddr::null() {
+                    let remaining = deadline.saturating_sub(clock_now(clockid)?);
+                    unsafe { rem.write_user(TimeSpec::from_nanos(remaining)) }?;
+                }
+                // a stop or an ignored signal carries on with the same deadline
+                current_task().restart_block.store(Some(RestartBlock::Nanosleep {
+                    clockid,
+                    deadline,
+                    absolute,
+                    rem,
+                }));
+                return Err(err);
             }
-            _ => unreachable!(),
         }
+    }
 
+    pub fn sys_clock_gettime(&mut self, clk_id: usize, tp: VirtAddr) -> KResult<isize> {
+        let ts = TimeSpec::from_nanos(clock_now(clk_id)?);
+        unsafe { tp.write_user(ts) }?;
         Ok(0)
     }
+
+    pub fn sys_clock_getres(&mut self, clk_id: usize, res: VirtAddr) -> KResult<isize> {
+        let ts = TimeSpec::from_nanos(clock_resolution(clk_id)?);
+        if res != VirtAddr::null() {
+            unsafe { res.write_user(ts) }?;
+        }
+        Ok(0)
+    }
+
+    pub fn sys_times(&mut self, buf: VirtAddr) -> KResult<isize> {
+        let current = current_task();
+        let own = current.process_cpu_time();
+        let children = current.process_times.lock().children;
+        if buf.value() != 0 {
+            let tms = Tms {
+                tms_utime: clock_ticks(own.user_ns),
+                tms_stime: clock_ticks(own.system_ns),
+                tms_cutime: clock_ticks(children.user_ns),
+                tms_cstime: clock_ticks(children.system_ns),
+            };
+            unsafe { buf.write_user(tms) }?;
+        }
+        Ok(clock_ticks(time::get_uptime_ns()) as isize)
+    }
+
+    pub fn sys_getrusage(&mut self, who: c_int, usage: VirtAddr) -> KResult<isize> {
+        let current = current_task();
+        let spent = match who {
+            RUSAGE_SELF => current.process_cpu_time(),
+            RUSAGE_CHILDREN => current.process_times.lock().children,
+            RUSAGE_THREAD => current.thread_cpu_time(),
+            _ => kbail!(EINVAL, "sys_getrusage(): invalid who"),
+        };
+        unsafe { usage.write_user(RUsage::from(spent)) }?;
+        Ok(0)
+    }
+
+    pub fn sys_getitimer(&mut self, which: usize, curr_value: VirtAddr) -> KResult<isize> {
+        let timer = current_task().itimers.lock().get(which)?;
+        unsafe { curr_value.write_user(ITimerVal::from(timer)) }?;
+        Ok(0)
+    }
+
+    pub fn sys_setitimer(
+        &mut self,
+        which: usize,
+        new_value: VirtAddr,
+        old_value: VirtAddr,
+    ) -> KResult<isize> {
+        // a null new value disarms the timer, which linux still allows for old binaries
+        let new = if new_value.value() != 0 {
+            IntervalTimer::try_from(unsafe { new_value.read_user::<ITimerVal>() }?)?
+        } else {
+            IntervalTimer::default()
+        };
+        let current = current_task();
+        let old = current
+            .itimers
+            .lock()
+            .set(which, new, &itimer_owner(&current))?;
+        if old_value.value() != 0 {
+            unsafe { old_value.write_user(ITimerVal::from(old)) }?;
+        }
+        Ok(0)
+    }
+
+    pub fn sys_alarm(&mut self, seconds: u32) -> KResult<isize> {
+        let new = IntervalTimer {
+            value: seconds as usize * 1_000_000_000,
+            interval: 0,
+        };
+        let current = current_task();
+        let old = current
+            .itimers
+            .lock()
+            .set(ITIMER_REAL, new, &itimer_owner(&current))?;
+        // whatever was left rounds to the nearest second, but never down to zero
+        let remaining = old.value.saturating_add(500_000_000) / 1_000_000_000;
+        if remaining == 0 && old.value != 0 {
+            return Ok(1);
+        }
+        Ok(remaining as isize)
+    }
+}
+
+// the interval timers belong to the whole process, so their signals go to the thread group leader
+fn itimer_owner(current: &Arc<Task>) -> Arc<Task> {
+    get_scheduler()
+        .find_task(current.tgid())
+        .unwrap_or(current.clone())
+}
+
+// times(2) and siginfo count in USER_HZ ticks, not in the timer's real frequency
+fn clock_ticks(ns: usize) -> i64 {
+    (ns / (1_000_000_000 / USER_HZ)) as i64
 }
 
 fn arch_prctl(current_task: Arc<Task>, code: i32, addr: VirtAddr) -> KResult<()> {
@@ -1,24 +1,203 @@
+use crate::{
+    arch::time,
+    kbail,
+    task::{cputime::CpuTime, current_task, itimer::IntervalTimer},
+    util::{ctypes::c_int, KError, KResult},
+};
+
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+pub const CLOCK_PROCESS_CPUTIME_ID: usize = 2;
+pub const CLOCK_THREAD_CPUTIME_ID: usize = 3;
+pub const CLOCK_MONOTONIC_COARSE: usize = 6;
+pub const CLOCK_BOOTTIME: usize = 7;
+
+pub const TIMER_ABSTIME: c_int = 1;
+
 pub const ITIMER_REAL: usize = 0;
 pub const ITIMER_VIRTUAL: usize = 1;
 pub const ITIMER_PROF: usize = 2;
 
-#[derive(Default, PartialEq)]
+#[derive(Clone, Copy, Default, PartialEq)]
 #[repr(C)]
 pub struct TimeVal {
     pub tv_sec: i64,
     pub tv_usec: i64,
 }
 
-#[derive(Default, PartialEq)]
+impl TimeVal {
+    pub fn from_nanos(ns: usize) -> TimeVal {
+        TimeVal {
+            tv_sec: (ns / 1_000_000_000) as i64,
+            tv_usec: (ns % 1_000_000_000 / 1000) as i64,
+        }
+    }
+
+    // like TimeSpec::as_nanos(), anything past what fits is as good as never
+    pub fn as_nanos(&self) -> usize {
+        (self.tv_sec as usize)
+            .saturating_mul(1_000_000_000)
+            .saturating_add(self.tv_usec as usize * 1000)
+    }
+}
+
+// nanoseconds on the given clock
+pub fn clock_now(clockid: usize) -> KResult<usize> {
+    Ok(match clockid {
+        CLOCK_REALTIME => time::get_rt_clock().as_nanos(),
+        // nothing ever suspends, so boot time never pulls ahead of the monotonic clock
+        CLOCK_MONOTONIC | CLOCK_BOOTTIME => time::get_uptime_ns(),
+        CLOCK_MONOTONIC_COARSE => time::get_uptime_ns() / time::TICK_NS * time::TICK_NS,
+        CLOCK_PROCESS_CPUTIME_ID => current_task().process_cpu_time().total_ns(),
+        CLOCK_THREAD_CPUTIME_ID => current_task().thread_cpu_time().total_ns(),
+        _ => kbail!(EINVAL, "clock_now(): invalid clock"),
+    })
+}
+
+pub fn clock_resolution(clockid: usize) -> KResult<usize> {
+    Ok(match clockid {
+        CLOCK_REALTIME
+        | CLOCK_MONOTONIC
+        | CLOCK_BOOTTIME
+        | CLOCK_PROCESS_CPUTIME_ID
+        | CLOCK_THREAD_CPUTIME_ID => time::resolution_ns(),
+        CLOCK_MONOTONIC_COARSE => time::TICK_NS,
+        _ => kbail!(EINVAL, "clock_resolution(): invalid clock"),
+    })
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
 #[repr(C)]
 pub struct ITimerVal {
     pub it_interval: TimeVal,
     pub it_value: TimeVal,
 }
 
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct RUsage {
+    pub ru_utime: TimeVal,
+    pub ru_stime: TimeVal,
+    pub ru_maxrss: i64,
+    pub ru_ixrss: i64,
+    pub ru_idrss: i64,
+    pub ru_isrss: i64,
+    pub ru_minflt: i64,
+    pub ru_majflt: i64,
+    pub ru_nswap: i64,
+    pub ru_inblock: i64,
+    pub ru_oublock: i64,
+    pub ru_msgsnd: i64,
+    pub ru_msgrcv: i64,
+    pub ru_nsignals: i64,
+    pub ru_nvcsw: i64,
+    pub ru_nivcsw: i64,
+}
+
+impl From<IntervalTimer> for ITimerVal {
+    fn from(timer: IntervalTimer) -> ITimerVal {
+        ITimerVal {
+            it_interval: TimeVal::from_nanos(timer.interval),
+            it_value: TimeVal::from_nanos(timer.value),
+        }
+    }
+}
+
+impl TryFrom<ITimerVal> for IntervalTimer {
+    type Error = KError<'static>;
+
+    fn try_from(val: ITimerVal) -> KResult<IntervalTimer> {
+        for tv in [val.it_interval, val.it_value] {
+            if tv.tv_sec < 0 || !(0..1_000_000).contains(&tv.tv_usec) {
+                kbail!(EINVAL, "ITimerVal: invalid timeval");
+            }
+        }
+        Ok(IntervalTimer {
+            value: val.it_value.as_nanos(),
+            interval: val.it_interval.as_nanos(),
+        })
+    }
+}
+
+// only the cpu times are tracked, everything else reads as zero
+impl From<CpuTime> for RUsage {
+    fn from(time: CpuTime) -> RUsage {
+        RUsage {
+            ru_utime: TimeVal::from_nanos(time.user_ns),
+            ru_stime: TimeVal::from_nanos(time.system_ns),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct Tms {
+    pub tms_utime: i64,
+    pub tms_stime: i64,
+    pub tms_cutime: i64,
+    pub tms_cstime: i64,
+}
+
 #[derive(Clone, Copy, Default, PartialEq)]
 #[repr(C)]
 pub struct TimeSpec {
     pub tv_sec: isize,
     pub tv_nsec: isize,
 }
+
+impl TimeSpec {
+    pub fn from_nanos(ns: usize) -> TimeSpec {
+        TimeSpec {
+            tv_sec: (ns / 1_000_000_000) as isize,
+            tv_nsec: (ns % 1_000_000_000) as isize,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.tv_sec >= 0 && (0..1_000_000_000).contains(&self.tv_nsec)
+    }
+
+    // anything too far off to count in nanoseconds might as well be never
+    pub fn as_nanos(&self) -> usize {
+        (self.tv_sec as usize)
+            .saturating_mul(1_000_000_000)
+            .saturating_add(self.tv_nsec as usize)
+    }
+
+    pub fn as_millis(&self) -> usize {
+        (self.tv_sec as usize)
+            .saturating_mul(1000)
+            .saturating_add((self.tv_nsec as usize).div_ceil(1000000))
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+#[repr(C)]
+pub struct ITimerSpec {
+    pub it_interval: TimeSpec,
+    pub it_value: TimeSpec,
+}
+
+impl From<IntervalTimer> for ITimerSpec {
+    fn from(timer: IntervalTimer) -> ITimerSpec {
+        ITimerSpec {
+            it_interval: TimeSpec::from_nanos(timer.interval),
+            it_value: TimeSpec::from_nanos(timer.value),
+        }
+    }
+}
+
+impl TryFrom<ITimerSpec> for IntervalTimer {
+    type Error = KError<'static>;
+
+    fn try_from(val: ITimerSpec) -> KResult<IntervalTimer> {
+        if !val.it_interval.is_valid() || !val.it_value.is_valid() {
+            kbail!(EINVAL, "ITimerSpec: invalid timespec");
+        }
+        Ok(IntervalTimer {
+            value: val.it_value.as_nanos(),
+            interval: val.it_interval.as_nanos(),
+        })
+    }
+}
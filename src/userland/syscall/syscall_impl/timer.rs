@@ -0,0 +1,199 @@
+use alloc::sync::Arc;
+
+use crate::{
+    fs::opened_file::{FileDesc, OpenFlags},
+    kbail, kerror,
+    mem::addr::VirtAddr,
+    task::{
+        current_task, get_scheduler,
+        itimer::IntervalTimer,
+        signal::{SigInfo, Signal, SignalTarget, SIGALRM, SIGMAX},
+        timer::{Timer, TimerId, TimerNotify},
+        TaskId,
+    },
+    userland::syscall::SyscallHandler,
+    util::{ctypes::c_int, KResult},
+};
+
+use super::time::{
+    clock_now, ITimerSpec, CLOCK_BOOTTIME, CLOCK_MONOTONIC, CLOCK_REALTIME, TIMER_ABSTIME,
+};
+
+const SIGEV_SIGNAL: c_int = 0;
+const SIGEV_NONE: c_int = 1;
+const SIGEV_THREAD_ID: c_int = 4;
+
+const TFD_TIMER_ABSTIME: c_int = 1;
+const TFD_TIMER_CANCEL_ON_SET: c_int = 2;
+const TFD_NONBLOCK: c_int = OpenFlags::O_NONBLOCK.bits();
+const TFD_CLOEXEC: c_int = OpenFlags::O_CLOEXEC.bits();
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct SigEvent {
+    sigev_value: usize,
+    sigev_signo: c_int,
+    sigev_notify: c_int,
+    // only the thread id of the union is of any use to the kernel
+    sigev_tid: c_int,
+    _pad: [c_int; 11],
+}
+
+fn check_clock(clockid: usize) -> KResult<()> {
+    match clockid {
+        CLOCK_REALTIME | CLOCK_MONOTONIC | CLOCK_BOOTTIME => Ok(()),
+        _ => kbail!(EINVAL, "check_clock(): unsupported clock"),
+    }
+}
+
+// the deadline queue only deals in relative times, so absolute ones get converted here
+fn read_timer_value(new_value: VirtAddr, absolute: bool, timer: &Timer) -> KResult<IntervalTimer> {
+    let mut value = IntervalTimer::try_from(unsafe { new_value.read_user::<ITimerSpec>() }?)?;
+    if absolute && value.value != 0 {
+        // a time that's already passed still has to go off right away
+        value.value = value.value.saturating_sub(clock_now(timer.clock())?).max(1);
+    }
+    Ok(value)
+}
+
+fn write_timer_value(old_value: VirtAddr, timer: IntervalTimer) -> KResult<()> {
+    if old_value != VirtAddr::null() {
+        unsafe { old_value.write_user(ITimerSpec::from(timer)) }?;
+    }
+    Ok(())
+}
+
+fn sigevent_notify(sevp: VirtAddr) -> KResult<TimerNotify> {
+    let current = current_task();
+    let sched = get_scheduler();
+    // process-directed signals go to the thread group leader, if it's still around
+    let leader = sched.find_task(current.tgid()).unwrap_or(current.clone());
+
+    if sevp == VirtAddr::null() {
+        return Ok(TimerNotify::Signal {
+            task: Arc::downgrade(&leader),
+            info: SigInfo::timer(SIGALRM, 0),
+            target: SignalTarget::Process,
+        });
+    }
+
+    let event = unsafe { sevp.read_user::<SigEvent>() }?;
+    let check_signal = |signal: Signal| {
+        if !(1..SIGMAX).contains(&signal) {
+            kbail!(EINVAL, "timer_create(): invalid signal");
+        }
+        Ok(signal)
+    };
+    match event.sigev_notify {
+        SIGEV_NONE => Ok(TimerNotify::Nothing),
+        SIGEV_SIGNAL => Ok(TimerNotify::Signal {
+            task: Arc::downgrade(&leader),
+            info: SigInfo::timer(check_signal(event.sigev_signo)?, event.sigev_value),
+            target: SignalTarget::Process,
+        }),
+        SIGEV_THREAD_ID => {
+            let task = sched
+                .find_task(TaskId::new(event.sigev_tid as usize))
+                .filter(|task| task.tgid() == current.tgid())
+                .ok_or(kerror!(
+                    EINVAL,
+                    "timer_create(): thread not in this process"
+                ))?;
+            Ok(TimerNotify::Signal {
+                task: Arc::downgrade(&task),
+                info: SigInfo::timer(check_signal(event.sigev_signo)?, event.sigev_value),
+                target: SignalTarget::Thread,
+            })
+        }
+        _ => kbail!(EINVAL, "timer_create(): unsupported sigev_notify"),
+    }
+}
+
+impl SyscallHandler<'_> {
+    pub fn sys_timer_create(
+        &mut self,
+        clockid: usize,
+        sevp: VirtAddr,
+        timerid: VirtAddr,
+    ) -> KResult<isize> {
+        check_clock(clockid)?;
+        let timer = Timer::new(clockid, sigevent_notify(sevp)?);
+        let current = current_task();
+        let id = current.posix_timers.lock().insert(timer);
+        if let Err(err) = unsafe { timerid.write_user(id) } {
+            current.posix_timers.lock().remove(id).ok();
+            return Err(err);
+        }
+        Ok(0)
+    }
+
+    pub fn sys_timer_settime(
+        &mut self,
+        timerid: TimerId,
+        flags: c_int,
+        new_value: VirtAddr,
+        old_value: VirtAddr,
+    ) -> KResult<isize> {
+        let timer = current_task().posix_timers.lock().get(timerid)?;
+        let new = read_timer_value(new_value, flags & TIMER_ABSTIME != 0, &timer)?;
+        let old = timer.set(new);
+        write_timer_value(old_value, old)?;
+        Ok(0)
+    }
+
+    pub fn sys_timer_gettime(&mut self, timerid: TimerId, curr_value: VirtAddr) -> KResult<isize> {
+        let timer = current_task().posix_timers.lock().get(timerid)?;
+        unsafe { curr_value.write_user(ITimerSpec::from(timer.get())) }?;
+        Ok(0)
+    }
+
+    pub fn sys_timer_delete(&mut self, timerid: TimerId) -> KResult<isize> {
+        current_task().posix_timers.lock().remove(timerid)?;
+        Ok(0)
+    }
+
+    pub fn sys_timerfd_create(&mut self, clockid: usize, flags: c_int) -> KResult<isize> {
+        check_clock(clockid)?;
+        if flags & !(TFD_NONBLOCK | TFD_CLOEXEC) != 0 {
+            kbail!(EINVAL, "timerfd_create(): invalid flags");
+        }
+        let options = OpenFlags::from_bits_truncate(flags);
+        let fd = current_task()
+            .opened_files
+            .lock()
+            .open_timerfd(clockid, options)?;
+        Ok(fd as isize)
+    }
+
+    pub fn sys_timerfd_settime(
+        &mut self,
+        fd: FileDesc,
+        flags: c_int,
+        new_value: VirtAddr,
+        old_value: VirtAddr,
+    ) -> KResult<isize> {
+        if flags & !(TFD_TIMER_ABSTIME | TFD_TIMER_CANCEL_ON_SET) != 0 {
+            kbail!(EINVAL, "timerfd_settime(): invalid flags");
+        }
+        let file = current_task().opened_files.lock().get(fd)?;
+        let timerfd = file
+            .as_file()?
+            .as_timerfd()
+            .ok_or(kerror!(EINVAL, "timerfd_settime(): not a timerfd"))?;
+        // the realtime clock is never set, so there's nothing to cancel on
+        let new = read_timer_value(new_value, flags & TFD_TIMER_ABSTIME != 0, timerfd.timer())?;
+        let old = timerfd.timer().set(new);
+        write_timer_value(old_value, old)?;
+        Ok(0)
+    }
+
+    pub fn sys_timerfd_gettime(&mut self, fd: FileDesc, curr_value: VirtAddr) -> KResult<isize> {
+        let file = current_task().opened_files.lock().get(fd)?;
+        let timerfd = file
+            .as_file()?
+            .as_timerfd()
+            .ok_or(kerror!(EINVAL, "timerfd_gettime(): not a timerfd"))?;
+        unsafe { curr_value.write_user(ITimerSpec::from(timerfd.timer().get())) }?;
+        Ok(0)
+    }
+}
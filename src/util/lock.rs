@@ -1,10 +1,12 @@
 use core::mem::ManuallyDrop;
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use spin::mutex::{SpinMutex, SpinMutexGuard};
 use x86::current::rflags::{self, RFlags};
 use x86_64::instructions::interrupts;
 
+use crate::arch::{cpu_local::cpu_id, smp};
 use crate::backtrace;
 use crate::task::wait_queue::WaitQueue;
 
@@ -109,13 +111,18 @@ impl<T: ?Sized> DerefMut for BlockingMutexGuard<'_, T> {
     }
 }
 
+const NO_OWNER: usize = usize::MAX;
+
 pub struct IrqMutex<T: ?Sized> {
+    // which cpu holds it, so relocking on the same cpu can be told apart from contention
+    owner: AtomicUsize,
     inner: SpinMutex<T>,
 }
 
 impl<T> IrqMutex<T> {
     pub const fn new(value: T) -> IrqMutex<T> {
         IrqMutex {
+            owner: AtomicUsize::new(NO_OWNER),
             inner: SpinMutex::new(value),
         }
     }
@@ -126,16 +133,44 @@ impl<T: ?Sized> IrqMutex<T> {
         self.inner.get_mut()
     }
 
+    // only this cpu ever writes its own id, and it clears it again before letting go, so
+    // seeing it here can't be a leftover from someone else's turn with the lock
+    fn held_by_this_cpu(&self) -> bool {
+        self.owner.load(Ordering::Acquire) == cpu_id()
+    }
+
+    // never waits, whoever it is that holds it
     pub fn try_lock(&self) -> KResult<IrqMutexGuard<'_, T>> {
-        if self.inner.is_locked() {
-            Err(kerror!("Cannot relock IrqMutex")) // todo: more verbose error message
+        let saved_intr_status = SavedInterruptStatus::save();
+        interrupts::disable();
+
+        let Some(guard) = self.inner.try_lock() else {
+            return Err(kerror!("Cannot relock IrqMutex")); // todo: more verbose error message
+        };
+        self.owner.store(cpu_id(), Ordering::Release);
+
+        Ok(IrqMutexGuard {
+            owner: &self.owner,
+            inner: ManuallyDrop::new(guard),
+            saved_intr_status: ManuallyDrop::new(saved_intr_status),
+        })
+    }
+
+    // waits for another cpu to let go like lock() does, but fails instead of deadlocking when
+    // it's this cpu that already holds it
+    pub fn lock_checked(&self) -> KResult<IrqMutexGuard<'_, T>> {
+        if self.held_by_this_cpu() {
+            Err(kerror!("Cannot relock IrqMutex on the same cpu"))
         } else {
             Ok(self.lock())
         }
     }
 
     pub fn lock(&self) -> IrqMutexGuard<'_, T> {
-        if self.inner.is_locked() {
+        let saved_intr_status = SavedInterruptStatus::save();
+        interrupts::disable();
+
+        if self.held_by_this_cpu() {
             serial0_println!(
                 "WARNING: Tried to relock IrqMutex of {}",
                 core::any::type_name::<T>()
@@ -143,12 +178,18 @@ impl<T: ?Sized> IrqMutex<T> {
             backtrace::unwind_stack().ok();
         }
 
-        let saved_intr_status = SavedInterruptStatus::save();
-        interrupts::disable();
-
-        let guard = self.inner.lock();
+        let guard = loop {
+            if let Some(guard) = self.inner.try_lock() {
+                break guard;
+            }
+            // whoever holds it might be waiting on us to flush our tlb
+            smp::handle_tlb_shootdown();
+            core::hint::spin_loop();
+        };
+        self.owner.store(cpu_id(), Ordering::Release);
 
         IrqMutexGuard {
+            owner: &self.owner,
             inner: ManuallyDrop::new(guard),
             saved_intr_status: ManuallyDrop::new(saved_intr_status),
         }
@@ -161,6 +202,7 @@ impl<T: ?Sized> IrqMutex<T> {
     /// # Safety
     /// See `spin::SpinMutex::force_unlock()`
     pub unsafe fn force_unlock(&self) {
+        self.owner.store(NO_OWNER, Ordering::Release);
         unsafe { self.inner.force_unlock() };
     }
 }
@@ -169,12 +211,14 @@ unsafe impl<T: ?Sized + Send> Sync for IrqMutex<T> {}
 unsafe impl<T: ?Sized + Send> Send for IrqMutex<T> {}
 
 pub struct IrqMutexGuard<'a, T: ?Sized> {
+    owner: &'a AtomicUsize,
     inner: ManuallyDrop<SpinMutexGuard<'a, T>>,
     saved_intr_status: ManuallyDrop<SavedInterruptStatus>,
 }
 
 impl<T: ?Sized> Drop for IrqMutexGuard<'_, T> {
     fn drop(&mut self) {
+        self.owner.store(NO_OWNER, Ordering::Release);
         unsafe {
             ManuallyDrop::drop(&mut self.inner);
         }